@@ -0,0 +1,381 @@
+use async_trait::async_trait;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use solana_sdk::{message::Message, pubkey::Pubkey};
+use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_JUPITER_URL: &str = "https://lite-api.jup.ag/v6";
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum SwapMode {
+    #[default]
+    ExactIn,
+    ExactOut,
+}
+
+impl SwapMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SwapMode::ExactIn => "ExactIn",
+            SwapMode::ExactOut => "ExactOut",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuoteParams {
+    pub input_mint: String,
+    pub output_mint: String,
+    /// Amount of the input mint, or of the output mint with `ExactOut`.
+    pub amount: u64,
+    pub slippage_bps: u16,
+    pub only_direct_routes: bool,
+    pub swap_mode: SwapMode,
+}
+
+/// A quote with the fields the backend relies on parsed out. `raw` is the
+/// aggregator's own response, which has to be sent back unchanged to build
+/// the swap.
+#[derive(Debug, Clone)]
+pub struct SwapQuote {
+    pub input_mint: String,
+    pub output_mint: String,
+    pub in_amount: u64,
+    pub out_amount: u64,
+    /// Worst amount accepted after slippage: the minimum out with `ExactIn`,
+    /// the maximum in with `ExactOut`.
+    pub other_amount_threshold: u64,
+    pub slippage_bps: u16,
+    pub swap_mode: SwapMode,
+    pub raw: Value,
+}
+
+#[derive(Debug, Clone)]
+pub struct SwapTransaction {
+    /// Base64-encoded unsigned transaction.
+    pub transaction: String,
+}
+
+#[derive(Debug)]
+pub enum AggregatorError {
+    Config(String),
+    Timeout,
+    Request(String),
+    /// The aggregator answered with an error status.
+    Status(u16, String),
+    InvalidResponse(String),
+}
+
+impl std::fmt::Display for AggregatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AggregatorError::Config(msg) => write!(f, "Aggregator configuration error: {}", msg),
+            AggregatorError::Timeout => write!(f, "Aggregator request timed out"),
+            AggregatorError::Request(msg) => write!(f, "Aggregator request failed: {}", msg),
+            AggregatorError::Status(status, body) => write!(f, "Aggregator returned {}: {}", status, body),
+            AggregatorError::InvalidResponse(msg) => write!(f, "Invalid aggregator response: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for AggregatorError {}
+
+impl From<reqwest::Error> for AggregatorError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            AggregatorError::Timeout
+        } else {
+            AggregatorError::Request(e.to_string())
+        }
+    }
+}
+
+#[async_trait]
+pub trait SwapAggregator: Send + Sync {
+    async fn quote(&self, params: &QuoteParams) -> Result<SwapQuote, AggregatorError>;
+
+    /// Builds the transaction for a quote previously returned by `quote`.
+    async fn swap_transaction(&self, user_public_key: &str, quote: &Value) -> Result<SwapTransaction, AggregatorError>;
+}
+
+#[derive(Deserialize)]
+struct JupiterQuoteResponse {
+    #[serde(rename = "inputMint")]
+    input_mint: String,
+    #[serde(rename = "outputMint")]
+    output_mint: String,
+    #[serde(rename = "inAmount")]
+    in_amount: String,
+    #[serde(rename = "outAmount")]
+    out_amount: String,
+    #[serde(rename = "otherAmountThreshold")]
+    other_amount_threshold: String,
+    #[serde(rename = "slippageBps")]
+    slippage_bps: u16,
+    #[serde(rename = "swapMode")]
+    swap_mode: SwapMode,
+}
+
+#[derive(Serialize)]
+struct JupiterSwapRequest<'a> {
+    #[serde(rename = "userPublicKey")]
+    user_public_key: &'a str,
+    #[serde(rename = "quoteResponse")]
+    quote_response: &'a Value,
+}
+
+#[derive(Deserialize)]
+struct JupiterSwapResponse {
+    #[serde(rename = "swapTransaction")]
+    swap_transaction: String,
+}
+
+fn parse_amount(field: &str, value: &str) -> Result<u64, AggregatorError> {
+    value
+        .parse()
+        .map_err(|_| AggregatorError::InvalidResponse(format!("{} is not an amount: {}", field, value)))
+}
+
+fn parse_quote(raw: Value) -> Result<SwapQuote, AggregatorError> {
+    let response: JupiterQuoteResponse =
+        serde_json::from_value(raw.clone()).map_err(|e| AggregatorError::InvalidResponse(e.to_string()))?;
+
+    Ok(SwapQuote {
+        in_amount: parse_amount("inAmount", &response.in_amount)?,
+        out_amount: parse_amount("outAmount", &response.out_amount)?,
+        other_amount_threshold: parse_amount("otherAmountThreshold", &response.other_amount_threshold)?,
+        input_mint: response.input_mint,
+        output_mint: response.output_mint,
+        slippage_bps: response.slippage_bps,
+        swap_mode: response.swap_mode,
+        raw,
+    })
+}
+
+pub struct JupiterClient {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl JupiterClient {
+    pub fn new(base_url: impl Into<String>, timeout: Duration) -> Result<Self, AggregatorError> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| AggregatorError::Config(e.to_string()))?;
+        Ok(Self {
+            client,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Reads `JUPITER_API_URL` and `JUPITER_TIMEOUT_MS`.
+    pub fn from_env() -> Result<Self, AggregatorError> {
+        let base_url = env::var("JUPITER_API_URL").unwrap_or_else(|_| DEFAULT_JUPITER_URL.to_string());
+        let timeout_ms = match env::var("JUPITER_TIMEOUT_MS") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| AggregatorError::Config(format!("Invalid JUPITER_TIMEOUT_MS: {}", v)))?,
+            Err(_) => DEFAULT_TIMEOUT_MS,
+        };
+        Self::new(base_url, Duration::from_millis(timeout_ms))
+    }
+
+    async fn read_json<T: serde::de::DeserializeOwned>(response: reqwest::Response) -> Result<T, AggregatorError> {
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(AggregatorError::Status(status.as_u16(), body));
+        }
+        response
+            .json()
+            .await
+            .map_err(|e| AggregatorError::InvalidResponse(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl SwapAggregator for JupiterClient {
+    async fn quote(&self, params: &QuoteParams) -> Result<SwapQuote, AggregatorError> {
+        let response = self
+            .client
+            .get(format!("{}/quote", self.base_url))
+            .query(&[
+                ("inputMint", params.input_mint.clone()),
+                ("outputMint", params.output_mint.clone()),
+                ("amount", params.amount.to_string()),
+                ("slippageBps", params.slippage_bps.to_string()),
+                ("onlyDirectRoutes", params.only_direct_routes.to_string()),
+                ("swapMode", params.swap_mode.as_str().to_string()),
+            ])
+            .send()
+            .await?;
+
+        parse_quote(Self::read_json(response).await?)
+    }
+
+    async fn swap_transaction(&self, user_public_key: &str, quote: &Value) -> Result<SwapTransaction, AggregatorError> {
+        let response = self
+            .client
+            .post(format!("{}/swap", self.base_url))
+            .json(&JupiterSwapRequest {
+                user_public_key,
+                quote_response: quote,
+            })
+            .send()
+            .await?;
+
+        let response: JupiterSwapResponse = Self::read_json(response).await?;
+        Ok(SwapTransaction {
+            transaction: response.swap_transaction,
+        })
+    }
+}
+
+/// Quotes at a fixed rate and returns an empty transaction for swaps, so
+/// `/quote` and `/swap` work without network access. Meant for local
+/// development and tests.
+pub struct MockAggregator {
+    /// Output units per input unit.
+    rate: f64,
+}
+
+impl MockAggregator {
+    pub fn new(rate: f64) -> Self {
+        Self { rate }
+    }
+}
+
+#[async_trait]
+impl SwapAggregator for MockAggregator {
+    async fn quote(&self, params: &QuoteParams) -> Result<SwapQuote, AggregatorError> {
+        let slippage = params.slippage_bps as f64 / 10_000.0;
+        let (in_amount, out_amount, threshold) = match params.swap_mode {
+            SwapMode::ExactIn => {
+                let out_amount = (params.amount as f64 * self.rate) as u64;
+                (params.amount, out_amount, (out_amount as f64 * (1.0 - slippage)) as u64)
+            }
+            SwapMode::ExactOut => {
+                let in_amount = (params.amount as f64 / self.rate).ceil() as u64;
+                (in_amount, params.amount, (in_amount as f64 * (1.0 + slippage)).ceil() as u64)
+            }
+        };
+
+        parse_quote(serde_json::json!({
+            "inputMint": params.input_mint,
+            "outputMint": params.output_mint,
+            "inAmount": in_amount.to_string(),
+            "outAmount": out_amount.to_string(),
+            "otherAmountThreshold": threshold.to_string(),
+            "slippageBps": params.slippage_bps,
+            "swapMode": params.swap_mode,
+            "routePlan": [],
+        }))
+    }
+
+    async fn swap_transaction(&self, user_public_key: &str, _quote: &Value) -> Result<SwapTransaction, AggregatorError> {
+        let payer = Pubkey::from_str(user_public_key)
+            .map_err(|e| AggregatorError::Request(format!("Invalid user public key: {}", e)))?;
+
+        // Wire format of an unsigned legacy transaction: one empty signature
+        // slot followed by the message.
+        let mut transaction = vec![1u8];
+        transaction.extend_from_slice(&[0u8; 64]);
+        transaction.extend_from_slice(&Message::new(&[], Some(&payer)).serialize());
+
+        Ok(SwapTransaction {
+            transaction: base64::engine::general_purpose::STANDARD.encode(transaction),
+        })
+    }
+}
+
+/// Uses the mock when `SWAP_AGGREGATOR=mock` (at `MOCK_SWAP_RATE`, 1 by
+/// default), otherwise Jupiter.
+pub fn aggregator_from_env() -> Result<Arc<dyn SwapAggregator>, AggregatorError> {
+    match env::var("SWAP_AGGREGATOR").as_deref() {
+        Ok("mock") => {
+            let rate = match env::var("MOCK_SWAP_RATE") {
+                Ok(v) => v
+                    .parse()
+                    .map_err(|_| AggregatorError::Config(format!("Invalid MOCK_SWAP_RATE: {}", v)))?,
+                Err(_) => 1.0,
+            };
+            Ok(Arc::new(MockAggregator::new(rate)))
+        }
+        _ => Ok(Arc::new(JupiterClient::from_env()?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOL: &str = "So11111111111111111111111111111111111111112";
+    const USDC: &str = "EPjFWvd5wAuVKeJnJBfB3T6QsaDt1AQYFxwHCHL5qD1F";
+
+    fn params(amount: u64, swap_mode: SwapMode) -> QuoteParams {
+        QuoteParams {
+            input_mint: SOL.to_string(),
+            output_mint: USDC.to_string(),
+            amount,
+            slippage_bps: 100,
+            only_direct_routes: false,
+            swap_mode,
+        }
+    }
+
+    #[test]
+    fn parses_jupiter_quote() {
+        let raw = serde_json::json!({
+            "inputMint": SOL,
+            "outputMint": USDC,
+            "inAmount": "1000000000",
+            "outAmount": "152340000",
+            "otherAmountThreshold": "151578300",
+            "swapMode": "ExactIn",
+            "slippageBps": 50,
+            "priceImpactPct": "0.0001",
+            "routePlan": []
+        });
+        let quote = parse_quote(raw.clone()).unwrap();
+        assert_eq!(quote.in_amount, 1_000_000_000);
+        assert_eq!(quote.out_amount, 152_340_000);
+        assert_eq!(quote.other_amount_threshold, 151_578_300);
+        assert_eq!(quote.swap_mode, SwapMode::ExactIn);
+        assert_eq!(quote.raw, raw);
+
+        let mut bad = raw;
+        bad["outAmount"] = Value::String("lots".to_string());
+        assert!(matches!(parse_quote(bad), Err(AggregatorError::InvalidResponse(_))));
+    }
+
+    #[tokio::test]
+    async fn mock_quotes_at_fixed_rate() {
+        let mock = MockAggregator::new(2.0);
+
+        let quote = mock.quote(&params(1_000, SwapMode::ExactIn)).await.unwrap();
+        assert_eq!((quote.in_amount, quote.out_amount), (1_000, 2_000));
+        assert_eq!(quote.other_amount_threshold, 1_980);
+
+        let quote = mock.quote(&params(1_000, SwapMode::ExactOut)).await.unwrap();
+        assert_eq!((quote.in_amount, quote.out_amount), (500, 1_000));
+        assert_eq!(quote.other_amount_threshold, 505);
+    }
+
+    #[tokio::test]
+    async fn mock_builds_decodable_transaction() {
+        let mock = MockAggregator::new(1.0);
+        let quote = mock.quote(&params(1_000, SwapMode::ExactIn)).await.unwrap();
+        let payer = Pubkey::new_unique();
+
+        let swap = mock.swap_transaction(&payer.to_string(), &quote.raw).await.unwrap();
+        let bytes = base64::engine::general_purpose::STANDARD.decode(swap.transaction).unwrap();
+        assert_eq!(bytes[0], 1);
+        assert_eq!(&bytes[65..], Message::new(&[], Some(&payer)).serialize().as_slice());
+    }
+}
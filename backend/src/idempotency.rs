@@ -0,0 +1,272 @@
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use store::idempotency::IdempotencyClaim;
+use store::models::idempotency::IdempotencyRecord;
+use store::Store;
+use uuid::Uuid;
+
+pub const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+const MAX_KEY_LENGTH: usize = 255;
+/// How long keys are remembered.
+pub const KEY_RETENTION: chrono::Duration = chrono::Duration::hours(24);
+/// How long a request holds its key. A request still unfinished after this is
+/// assumed to have died and a retry takes the key over. It is well past the
+/// lifetime of a blockhash, so anything the dead request signed with one can
+/// no longer land.
+pub const KEY_LEASE: chrono::Duration = chrono::Duration::minutes(5);
+
+/// Final result of a fund-moving request once signing has started. It is
+/// stored against the idempotency key and replayed on retries, even when it
+/// is an error, since a broadcast may have happened before the failure.
+pub struct Outcome {
+    pub status: StatusCode,
+    pub body: Value,
+    pub signature: Option<String>,
+}
+
+impl Outcome {
+    pub fn ok(body: impl Serialize, signature: String) -> Self {
+        Self {
+            status: StatusCode::OK,
+            body: serde_json::to_value(body).unwrap_or(Value::Null),
+            signature: Some(signature),
+        }
+    }
+
+    pub fn failed(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: Value::String(message.into()),
+            signature: None,
+        }
+    }
+
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::build(self.status).json(self.body)
+    }
+}
+
+/// A key held by the current request.
+pub struct IdempotencyKey {
+    user_id: Uuid,
+    key: String,
+}
+
+fn request_hash(endpoint: &str, body: &impl Serialize) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(endpoint.as_bytes());
+    hasher.update(serde_json::to_vec(body).unwrap_or_default());
+    hex::encode(hasher.finalize())
+}
+
+/// Claims the request's `Idempotency-Key`, if it sent one. When the key was
+/// used before, returns the response to send instead: the stored result, a
+/// 409 while the first request is still running, or a 422 if the key was
+/// used for a different request. A first request that outlived
+/// [`KEY_LEASE`] without finishing loses the key to the retry.
+pub async fn begin(
+    store: &Store,
+    user_id: Uuid,
+    http_req: &HttpRequest,
+    endpoint: &str,
+    body: &impl Serialize,
+) -> Result<Option<IdempotencyKey>, HttpResponse> {
+    let Some(key) = http_req.headers().get(IDEMPOTENCY_HEADER) else {
+        return Ok(None);
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_string(),
+        _ => return Err(HttpResponse::BadRequest().json("Invalid Idempotency-Key")),
+    };
+
+    let hash = request_hash(endpoint, body);
+    let locked_until = chrono::Utc::now() + KEY_LEASE;
+    let record = match store.claim_idempotency_key(user_id, &key, endpoint, &hash, locked_until).await {
+        Ok(IdempotencyClaim::Claimed) => return Ok(Some(IdempotencyKey { user_id, key })),
+        Ok(IdempotencyClaim::Existing(record)) => record,
+        Err(e) => {
+            log::error!("Failed to claim idempotency key: {}", e);
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    Err(existing_response(record, endpoint, &hash))
+}
+
+/// The response for a retry of a request whose key is already taken.
+fn existing_response(record: IdempotencyRecord, endpoint: &str, hash: &str) -> HttpResponse {
+    if record.endpoint != endpoint || record.request_hash != hash {
+        return HttpResponse::UnprocessableEntity()
+            .json("Idempotency-Key was already used for a different request");
+    }
+
+    match (record.response_status, record.response_body) {
+        (Some(status), Some(body)) if record.status == "completed" => {
+            let status = StatusCode::from_u16(status as u16).unwrap_or(StatusCode::OK);
+            HttpResponse::build(status)
+                .insert_header((REPLAYED_HEADER, "true"))
+                .json(body)
+        }
+        _ => HttpResponse::Conflict().json(serde_json::json!({ "status": "in_progress" })),
+    }
+}
+
+/// Turns the handler's result into a response and settles the key: an
+/// outcome is stored for replay, while an early rejection frees the key.
+pub async fn finish(
+    store: &Store,
+    key: Option<IdempotencyKey>,
+    result: Result<Outcome, HttpResponse>,
+) -> HttpResponse {
+    let Some(IdempotencyKey { user_id, key }) = key else {
+        return match result {
+            Ok(outcome) => outcome.into_response(),
+            Err(response) => response,
+        };
+    };
+
+    match result {
+        Ok(outcome) => {
+            if let Err(e) = store
+                .complete_idempotency_key(
+                    user_id,
+                    &key,
+                    outcome.status.as_u16() as i32,
+                    &outcome.body,
+                    outcome.signature.as_deref(),
+                )
+                .await
+            {
+                log::error!("Failed to store result for idempotency key {}: {}", key, e);
+            }
+            outcome.into_response()
+        }
+        Err(response) => {
+            if let Err(e) = store.release_idempotency_key(user_id, &key).await {
+                log::error!("Failed to release idempotency key {}: {}", key, e);
+            }
+            response
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body::to_bytes, test::TestRequest};
+    use serde_json::json;
+    use sqlx::PgPool;
+    use store::test_support::new_user;
+
+    async fn body_json(response: HttpResponse) -> Value {
+        serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap()
+    }
+
+    fn with_key(key: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((IDEMPOTENCY_HEADER, key))
+            .to_http_request()
+    }
+
+    #[sqlx::test(migrations = "../store/migrations")]
+    async fn test_retry_replays_the_stored_outcome(pool: PgPool) {
+        let store = Store::new(pool);
+        let user_id = new_user(&store).await;
+        let body = json!({ "amount": 1 });
+
+        let key = begin(&store, user_id, &with_key("k1"), "send", &body).await.ok().unwrap();
+        assert!(key.is_some());
+        let response = finish(&store, key, Ok(Outcome::ok(json!({ "signature": "sig" }), "sig".to_string()))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let replay = begin(&store, user_id, &with_key("k1"), "send", &body).await.err().unwrap();
+        assert_eq!(replay.status(), StatusCode::OK);
+        assert_eq!(replay.headers().get(REPLAYED_HEADER).unwrap(), "true");
+        assert_eq!(body_json(replay).await, json!({ "signature": "sig" }));
+    }
+
+    #[sqlx::test(migrations = "../store/migrations")]
+    async fn test_key_reused_for_another_body_is_rejected(pool: PgPool) {
+        let store = Store::new(pool);
+        let user_id = new_user(&store).await;
+
+        let key = begin(&store, user_id, &with_key("k1"), "send", &json!({ "amount": 1 })).await.ok().unwrap();
+        finish(&store, key, Ok(Outcome::ok(json!({}), "sig".to_string()))).await;
+
+        let other_body = begin(&store, user_id, &with_key("k1"), "send", &json!({ "amount": 2 })).await;
+        assert_eq!(other_body.err().unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let other_endpoint = begin(&store, user_id, &with_key("k1"), "swap", &json!({ "amount": 1 })).await;
+        assert_eq!(other_endpoint.err().unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[sqlx::test(migrations = "../store/migrations")]
+    async fn test_retry_while_in_progress_conflicts(pool: PgPool) {
+        let store = Store::new(pool);
+        let user_id = new_user(&store).await;
+        let body = json!({ "amount": 1 });
+
+        let key = begin(&store, user_id, &with_key("k1"), "send", &body).await.ok().unwrap();
+        let retry = begin(&store, user_id, &with_key("k1"), "send", &body).await.err().unwrap();
+        assert_eq!(retry.status(), StatusCode::CONFLICT);
+        assert_eq!(body_json(retry).await, json!({ "status": "in_progress" }));
+
+        // An early rejection, such as a failed step-up, frees the key again
+        let response = finish(&store, key, Err(HttpResponse::Unauthorized().finish())).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(begin(&store, user_id, &with_key("k1"), "send", &body).await.ok().unwrap().is_some());
+    }
+
+    #[sqlx::test(migrations = "../store/migrations")]
+    async fn test_retry_takes_over_a_stale_key(pool: PgPool) {
+        let store = Store::new(pool);
+        let user_id = new_user(&store).await;
+        let body = json!({ "amount": 1 });
+
+        let stale = begin(&store, user_id, &with_key("k1"), "send", &body).await.ok().unwrap();
+        assert!(stale.is_some());
+        sqlx::query("UPDATE idempotency_keys SET locked_until = NOW() - INTERVAL '1 second' WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&store.pool)
+            .await
+            .unwrap();
+
+        // Only a retry of the same request may take the key over
+        let other_body = begin(&store, user_id, &with_key("k1"), "send", &json!({ "amount": 2 })).await;
+        assert_eq!(other_body.err().unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let key = begin(&store, user_id, &with_key("k1"), "send", &body).await.ok().unwrap();
+        assert!(key.is_some());
+        let retry = begin(&store, user_id, &with_key("k1"), "send", &body).await.err().unwrap();
+        assert_eq!(retry.status(), StatusCode::CONFLICT);
+
+        finish(&store, key, Ok(Outcome::ok(json!({ "signature": "sig" }), "sig".to_string()))).await;
+        let replay = begin(&store, user_id, &with_key("k1"), "send", &body).await.err().unwrap();
+        assert_eq!(replay.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_existing_response_for_failed_outcome_is_replayed() {
+        let hash = request_hash("send", &json!({ "amount": 1 }));
+        let record = IdempotencyRecord {
+            user_id: Uuid::new_v4(),
+            key: "k1".to_string(),
+            endpoint: "send".to_string(),
+            request_hash: hash.clone(),
+            status: "completed".to_string(),
+            response_status: Some(502),
+            response_body: Some(json!("Broadcast failed")),
+            signature: None,
+            locked_until: chrono::Utc::now(),
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        };
+
+        let response = existing_response(record, "send", &hash);
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(response.headers().get(REPLAYED_HEADER).unwrap(), "true");
+    }
+}
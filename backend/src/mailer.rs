@@ -0,0 +1,211 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum MailerError {
+    Config(String),
+    InvalidAddress(String),
+    SendFailed(String),
+}
+
+impl std::fmt::Display for MailerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailerError::Config(msg) => write!(f, "Mailer configuration error: {}", msg),
+            MailerError::InvalidAddress(msg) => write!(f, "Invalid address: {}", msg),
+            MailerError::SendFailed(msg) => write!(f, "Failed to send email: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for MailerError {}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailerError>;
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: String,
+}
+
+impl SmtpMailer {
+    /// Reads `SMTP_HOST`, `SMTP_PORT`, `SMTP_USERNAME`, `SMTP_PASSWORD` and
+    /// `MAIL_FROM`. The connection uses STARTTLS.
+    pub fn from_env() -> Result<Self, MailerError> {
+        let host = env::var("SMTP_HOST").map_err(|_| MailerError::Config("SMTP_HOST must be set".to_string()))?;
+        let from = env::var("MAIL_FROM").map_err(|_| MailerError::Config("MAIL_FROM must be set".to_string()))?;
+
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)
+            .map_err(|e| MailerError::Config(e.to_string()))?;
+        if let Some(port) = env::var("SMTP_PORT").ok().and_then(|v| v.parse().ok()) {
+            builder = builder.port(port);
+        }
+        if let (Ok(username), Ok(password)) = (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD")) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        let message = Message::builder()
+            .from(self.from.parse().map_err(|_| MailerError::InvalidAddress(self.from.clone()))?)
+            .to(email.to.parse().map_err(|_| MailerError::InvalidAddress(email.to.clone()))?)
+            .subject(email.subject)
+            .body(email.body)
+            .map_err(|e| MailerError::SendFailed(e.to_string()))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| MailerError::SendFailed(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Logs every email and, if a directory is given, writes it there as a text
+/// file. Meant for local development and tests.
+pub struct FileMailer {
+    dir: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        log::info!("Email to {}: {}\n{}", email.to, email.subject, email.body);
+
+        if let Some(dir) = &self.dir {
+            let path = dir.join(format!("{}-{}.txt", Utc::now().format("%Y%m%dT%H%M%S"), Uuid::new_v4()));
+            let contents = format!("To: {}\nSubject: {}\n\n{}\n", email.to, email.subject, email.body);
+            tokio::fs::write(&path, contents)
+                .await
+                .map_err(|e| MailerError::SendFailed(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Uses SMTP when `MAILER=smtp`, otherwise the file mailer writing to `MAIL_DIR`.
+pub fn mailer_from_env() -> Result<Arc<dyn Mailer>, MailerError> {
+    match env::var("MAILER").as_deref() {
+        Ok("smtp") => Ok(Arc::new(SmtpMailer::from_env()?)),
+        _ => Ok(Arc::new(FileMailer::new(env::var("MAIL_DIR").ok().map(PathBuf::from)))),
+    }
+}
+
+/// Base URL of the web app, used to build links in emails.
+pub fn app_base_url() -> String {
+    env::var("APP_BASE_URL").unwrap_or_else(|_| "http://localhost:3000".to_string())
+}
+
+pub fn verification_email(to: &str, token: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Confirm your email address by opening this link:\n\n{}/verify-email?token={}\n\nThe link expires in 24 hours.",
+            app_base_url(),
+            token
+        ),
+    }
+}
+
+pub fn password_reset_email(to: &str, token: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Someone asked to reset the password for this account. If it was you, open this link:\n\n{}/reset-password?token={}\n\nThe link expires in 1 hour. If you did not ask for this, you can ignore this email.",
+            app_base_url(),
+            token
+        ),
+    }
+}
+
+pub fn strict_mode_relaxing_email(to: &str, relaxes_at: chrono::DateTime<Utc>) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Withdrawal allowlist is being turned off".to_string(),
+        body: format!(
+            "Someone asked to turn off strict mode for your withdrawal allowlist. Until {} withdrawals still only go to your allowlisted addresses; after that they can go anywhere.\n\nIf this was not you, turn strict mode back on and change your password.",
+            relaxes_at.format("%Y-%m-%d %H:%M UTC")
+        ),
+    }
+}
+
+pub fn withdrawal_address_email(to: &str, address: &str, label: &str, token: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Confirm new withdrawal address".to_string(),
+        body: format!(
+            "A withdrawal address was added to your account:\n\n{} ({})\n\nIf you added it, confirm it by opening this link:\n\n{}/confirm-withdrawal-address?token={}\n\nIf you did not add it, remove it and change your password.",
+            label,
+            address,
+            app_base_url(),
+            token
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verification_email, FileMailer, Mailer};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_file_mailer_writes_each_email() {
+        let dir = std::env::temp_dir().join(format!("mailer-test-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let mailer = FileMailer::new(Some(dir.clone()));
+
+        mailer.send(verification_email("alice@example.com", "token-1")).await.unwrap();
+        mailer.send(verification_email("alice@example.com", "token-2")).await.unwrap();
+
+        let mut contents: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        contents.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(contents.len(), 2);
+        assert!(contents[0].starts_with("To: alice@example.com\nSubject: Verify your email address\n\n"));
+        assert!(contents[0].contains("verify-email?token=token-1"));
+        assert!(contents[1].contains("verify-email?token=token-2"));
+    }
+
+    #[tokio::test]
+    async fn test_file_mailer_without_dir_only_logs() {
+        FileMailer::new(None)
+            .send(verification_email("alice@example.com", "token"))
+            .await
+            .unwrap();
+    }
+}
@@ -1,11 +1,22 @@
-use actix_web::{web, App, HttpServer};
+use actix_web::{middleware::from_fn, web, App, HttpServer};
 use dotenv::dotenv;
 use sqlx::PgPool;
 use std::env;
 use store::Store;
+use auth::JwtKeys;
+use rate_limit::RateLimiter;
+use std::time::Duration;
 
+mod auth;
 mod routes;
 mod middleware;
+mod metrics;
+mod totp;
+mod mailer;
+mod rate_limit;
+mod idempotency;
+mod aggregator;
+mod mpc_service;
 
 use routes::*;
 
@@ -20,20 +31,85 @@ async fn main() -> std::io::Result<()> {
         .expect("Failed to create pool.");
     let store = Store::new(pool);
     let store_data = web::Data::new(store);
+    let keys = JwtKeys::from_env().expect("Failed to load JWT keys");
+    let keys_data = web::Data::new(keys);
+    let mailer = mailer::mailer_from_env().expect("Failed to configure mailer");
+    let mailer_data: web::Data<dyn mailer::Mailer> = web::Data::from(mailer);
+    let aggregator = aggregator::aggregator_from_env().expect("Failed to configure swap aggregator");
+    let aggregator_data: web::Data<dyn aggregator::SwapAggregator> = web::Data::from(aggregator);
+    let mpc = mpc_service::mpc_service_from_env().expect("Failed to configure MPC service");
+    let mpc_data: web::Data<dyn mpc_service::MpcService> = web::Data::from(mpc);
+    let cipher = totp::SecretCipher::from_env().expect("Failed to load TOTP encryption key");
+    let cipher_data = web::Data::new(cipher);
+    let limiter = RateLimiter::from_env(store_data.clone()).expect("Invalid rate limit configuration");
+    let limiter_data = web::Data::new(limiter);
+
+    let pruned_limiter = limiter_data.clone();
+    let pruned_store = store_data.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(600));
+        loop {
+            ticker.tick().await;
+            pruned_limiter.prune().await;
+            if let Err(e) = pruned_store
+                .prune_idempotency_keys(chrono::Utc::now() - idempotency::KEY_RETENTION)
+                .await
+            {
+                log::error!("Failed to prune idempotency keys: {}", e);
+            }
+        }
+    });
 
     HttpServer::new(move || {
         App::new()
             .app_data(store_data.clone())
+            .app_data(keys_data.clone())
+            .app_data(mailer_data.clone())
+            .app_data(aggregator_data.clone())
+            .app_data(mpc_data.clone())
+            .app_data(limiter_data.clone())
+            .app_data(cipher_data.clone())
+            .wrap(from_fn(rate_limit::rate_limit))
+            .wrap(from_fn(metrics::track_requests))
+            .service(metrics_endpoint)
+            .service(healthz)
+            .service(readyz)
+            .service(jwks)
             .service(
                 web::scope("/api/v1")
                     .service(sign_up)
                     .service(sign_in)
+                    .service(refresh)
+                    .service(logout)
+                    .service(logout_all)
+                    .service(verify_email)
+                    .service(resend_verification)
+                    .service(forgot_password)
+                    .service(reset_password)
+                    .service(two_factor_status)
+                    .service(two_factor_setup)
+                    .service(two_factor_confirm)
+                    .service(two_factor_disable)
+                    .service(regenerate_recovery_codes)
                     .service(get_user)
                     .service(quote)
                     .service(swap)
                     .service(send)
+                    .service(swap_preview)
+                    .service(send_preview)
+                    .service(sign_message)
+                    .service(sign_in_with_solana)
+                    .service(list_withdrawal_addresses)
+                    .service(add_withdrawal_address)
+                    .service(confirm_withdrawal_address)
+                    .service(update_withdrawal_settings)
+                    .service(remove_withdrawal_address)
+                    .service(spending_limits)
                     .service(sol_balance)
-                    .service(token_balance),
+                    .service(token_balance)
+                    .service(transactions)
+                    .service(sign_transaction)
+                    .service(transfer),
             )
     })
     .bind("127.0.0.1:8080")?
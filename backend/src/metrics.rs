@@ -0,0 +1,19 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    Error,
+};
+use std::sync::LazyLock;
+use telemetry::HttpMetrics;
+
+pub use telemetry::render;
+
+pub static HTTP: LazyLock<HttpMetrics> = LazyLock::new(|| HttpMetrics::register("backend"));
+
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    HTTP.track(req, next).await
+}
@@ -1,35 +1,121 @@
 use actix_web::{
     dev::Payload,
-    error::ErrorUnauthorized,
-    http, FromRequest, HttpRequest,
+    error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
+    http, web, FromRequest, HttpRequest,
 };
 use serde::{Deserialize, Serialize};
-use std::future::{ready, Ready};
+use std::future::Future;
+use std::pin::Pin;
+use store::Store;
 use uuid::Uuid;
-use crate::auth::decode_jwt;
+use crate::auth::JwtKeys;
+use crate::totp::{verify_second_factor, SecondFactorError, SecretCipher};
+
+/// Header carrying a TOTP or recovery code for step-up checks.
+pub const TOTP_HEADER: &str = "X-TOTP-Code";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthenticatedUser {
     pub id: Uuid,
+    pub session_id: Uuid,
 }
 
 impl FromRequest for AuthenticatedUser {
     type Error = actix_web::Error;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let auth_header = req.headers().get(http::header::AUTHORIZATION);
-
-        if let Some(auth_header) = auth_header {
-            if let Ok(auth_str) = auth_header.to_str() {
-                if auth_str.starts_with("Bearer ") {
-                    let token = &auth_str[7..];
-                    if let Ok(claims) = decode_jwt(token) {
-                        return ready(Ok(AuthenticatedUser { id: claims.sub }));
-                    }
-                }
+        let keys = req.app_data::<web::Data<JwtKeys>>().cloned();
+        let token = req
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|auth_header| auth_header.to_str().ok())
+            .and_then(|auth_str| auth_str.strip_prefix("Bearer "))
+            .map(str::to_string);
+        let store = req.app_data::<web::Data<Store>>().cloned();
+
+        Box::pin(async move {
+            let keys = keys.ok_or_else(|| ErrorInternalServerError("JWT keys not configured"))?;
+            let claims = token
+                .and_then(|token| keys.decode_jwt(&token).ok())
+                .ok_or_else(|| ErrorUnauthorized("Invalid token"))?;
+            let store = store.ok_or_else(|| ErrorInternalServerError("Store not configured"))?;
+
+            // Signed tokens stay valid until they expire, so check the session
+            // they belong to has not been logged out in the meantime.
+            match store.is_session_active(claims.sid).await {
+                Ok(true) => Ok(AuthenticatedUser {
+                    id: claims.sub,
+                    session_id: claims.sid,
+                }),
+                Ok(false) => Err(ErrorUnauthorized("Session revoked")),
+                Err(_) => Err(ErrorInternalServerError("Failed to check session")),
+            }
+        })
+    }
+}
+
+/// An authenticated user with a verified email who also passed a fresh
+/// two-factor check, required for moving funds and changing security
+/// settings. Withdrawal address confirmations and security notices go to that
+/// email, so it has to be one the user can read.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StepUpUser {
+    pub id: Uuid,
+    pub session_id: Uuid,
+}
+
+impl StepUpUser {
+    /// Runs the step-up checks for a user who is already authenticated. For
+    /// handlers that must do something first, such as replaying an
+    /// idempotent request, which must not use up a fresh code.
+    pub async fn check(req: &HttpRequest, user: AuthenticatedUser) -> Result<Self, actix_web::Error> {
+        let code = req
+            .headers()
+            .get(TOTP_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let store = req
+            .app_data::<web::Data<Store>>()
+            .ok_or_else(|| ErrorInternalServerError("Store not configured"))?;
+        let cipher = req
+            .app_data::<web::Data<SecretCipher>>()
+            .ok_or_else(|| ErrorInternalServerError("TOTP cipher not configured"))?;
+        let code = code.ok_or_else(|| ErrorUnauthorized("Two-factor code required"))?;
+
+        // Checked first, so a code sent along is not used up for nothing
+        match store.get_user_by_id(user.id).await {
+            Ok(Some(user)) if user.email_verified_at.is_some() => {}
+            Ok(Some(_)) => return Err(ErrorForbidden("Email address must be verified")),
+            Ok(None) => return Err(ErrorUnauthorized("Invalid token")),
+            Err(_) => return Err(ErrorInternalServerError("Failed to load user")),
+        }
+
+        match verify_second_factor(store, cipher, user.id, &code).await {
+            Ok(()) => Ok(StepUpUser {
+                id: user.id,
+                session_id: user.session_id,
+            }),
+            Err(SecondFactorError::NotEnrolled) => Err(ErrorForbidden("Two-factor authentication must be enabled")),
+            Err(SecondFactorError::InvalidCode) => Err(ErrorUnauthorized("Invalid two-factor code")),
+            Err(SecondFactorError::Store(_) | SecondFactorError::UnreadableSecret) => {
+                Err(ErrorInternalServerError("Failed to check two-factor code"))
             }
         }
-        ready(Err(ErrorUnauthorized("Invalid token")))
+    }
+}
+
+impl FromRequest for StepUpUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = AuthenticatedUser::from_request(req, payload);
+        let req = req.clone();
+
+        Box::pin(async move {
+            let user = user.await?;
+            StepUpUser::check(&req, user).await
+        })
     }
 }
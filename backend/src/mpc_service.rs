@@ -0,0 +1,87 @@
+use async_trait::async_trait;
+use serde_json::Value;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A failed call to the MPC service. `status` is absent when no answer came
+/// back at all.
+pub struct MpcCallError {
+    pub status: Option<reqwest::StatusCode>,
+    pub message: String,
+}
+
+#[async_trait]
+pub trait MpcService: Send + Sync {
+    /// Posts `body` to an MPC endpoint such as `/agg-send-step1` and returns
+    /// the JSON answer.
+    async fn post(&self, path: &str, body: &Value) -> Result<Value, MpcCallError>;
+
+    /// Whether the service answers its health check.
+    async fn is_ready(&self) -> bool;
+}
+
+/// Talks to the MPC service over HTTP at `MPC_SERVICE_URL`.
+pub struct HttpMpcService {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl HttpMpcService {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+}
+
+#[async_trait]
+impl MpcService for HttpMpcService {
+    async fn post(&self, path: &str, body: &Value) -> Result<Value, MpcCallError> {
+        let url = self.url(path);
+        let response = self
+            .client
+            .post(&url)
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| MpcCallError { status: None, message: e.to_string() })?;
+        let status = response.status();
+        // Simulation failures and policy refusals are explained to the user
+        if status == reqwest::StatusCode::UNPROCESSABLE_ENTITY || status == reqwest::StatusCode::FORBIDDEN {
+            let message = response.text().await.unwrap_or_default();
+            return Err(MpcCallError { status: Some(status), message });
+        }
+        if !status.is_success() {
+            return Err(MpcCallError {
+                status: Some(status),
+                message: format!("{} returned {}", url, status),
+            });
+        }
+        response
+            .json()
+            .await
+            .map_err(|e| MpcCallError { status: None, message: e.to_string() })
+    }
+
+    async fn is_ready(&self) -> bool {
+        self.client
+            .get(self.url("/healthz"))
+            .timeout(Duration::from_secs(2))
+            .send()
+            .await
+            .map(|res| res.status().is_success())
+            .unwrap_or(false)
+    }
+}
+
+/// Reads `MPC_SERVICE_URL`.
+pub fn mpc_service_from_env() -> Result<Arc<dyn MpcService>, String> {
+    let base_url = env::var("MPC_SERVICE_URL").map_err(|_| "MPC_SERVICE_URL must be set".to_string())?;
+    Ok(Arc::new(HttpMpcService::new(base_url)))
+}
@@ -0,0 +1,434 @@
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{AUTHORIZATION, RETRY_AFTER},
+    middleware::Next,
+    web, Error, HttpResponse,
+};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use store::Store;
+use crate::auth::JwtKeys;
+
+/// A token bucket: `capacity` requests at once, refilled evenly over `period`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl Bucket {
+    pub fn per_minute(capacity: u32) -> Self {
+        Self {
+            capacity,
+            period: Duration::from_secs(60),
+        }
+    }
+
+    fn refill_per_second(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+
+    /// Time until one token is available again, given the tokens left.
+    fn retry_after(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64(((1.0 - tokens) / self.refill_per_second()).max(0.0))
+    }
+}
+
+/// What a bucket is keyed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Ip,
+    Account,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub scope: Scope,
+    pub bucket: Bucket,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    pub allowed: bool,
+    pub retry_after: Duration,
+}
+
+#[async_trait]
+pub trait RateLimitBackend: Send + Sync {
+    async fn take(&self, key: &str, bucket: &Bucket) -> Result<Decision, String>;
+
+    /// Drops buckets idle for longer than `idle`.
+    async fn prune(&self, idle: Duration) -> Result<(), String>;
+}
+
+/// Buckets held in process memory. Limits apply per backend instance.
+#[derive(Default)]
+pub struct InMemoryBackend {
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl InMemoryBackend {
+    fn take_at(&self, key: &str, bucket: &Bucket, now: Instant) -> Decision {
+        let mut buckets = self.buckets.lock().unwrap();
+        let (tokens, updated_at) = buckets
+            .entry(key.to_string())
+            .or_insert((bucket.capacity as f64, now));
+
+        let elapsed = now.saturating_duration_since(*updated_at).as_secs_f64();
+        *tokens = (*tokens + elapsed * bucket.refill_per_second()).min(bucket.capacity as f64);
+        *updated_at = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Decision {
+                allowed: true,
+                retry_after: Duration::ZERO,
+            }
+        } else {
+            Decision {
+                allowed: false,
+                retry_after: bucket.retry_after(*tokens),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitBackend for InMemoryBackend {
+    async fn take(&self, key: &str, bucket: &Bucket) -> Result<Decision, String> {
+        Ok(self.take_at(key, bucket, Instant::now()))
+    }
+
+    async fn prune(&self, idle: Duration) -> Result<(), String> {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, (_, updated_at)| now.saturating_duration_since(*updated_at) < idle);
+        Ok(())
+    }
+}
+
+/// Buckets stored in Postgres, shared by every backend instance.
+pub struct PostgresBackend {
+    store: web::Data<Store>,
+}
+
+impl PostgresBackend {
+    pub fn new(store: web::Data<Store>) -> Self {
+        Self { store }
+    }
+}
+
+#[async_trait]
+impl RateLimitBackend for PostgresBackend {
+    async fn take(&self, key: &str, bucket: &Bucket) -> Result<Decision, String> {
+        let (allowed, tokens) = self
+            .store
+            .take_rate_limit_token(key, bucket.capacity as f64, bucket.refill_per_second())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(Decision {
+            allowed,
+            retry_after: if allowed { Duration::ZERO } else { bucket.retry_after(tokens) },
+        })
+    }
+
+    async fn prune(&self, idle: Duration) -> Result<(), String> {
+        let idle = chrono::Duration::from_std(idle).map_err(|e| e.to_string())?;
+        self.store
+            .prune_rate_limit_buckets(chrono::Utc::now() - idle)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+pub struct RateLimiter {
+    backend: Arc<dyn RateLimitBackend>,
+    /// Rules by route pattern. The empty pattern applies to every route.
+    rules: HashMap<String, Vec<Rule>>,
+    trust_forwarded_for: bool,
+}
+
+impl RateLimiter {
+    /// Builds the limiter from `RATE_LIMIT_BACKEND` (`memory` or `postgres`),
+    /// `RATE_LIMIT_RULES` (overrides for the defaults, see [`parse_rules`]) and
+    /// `RATE_LIMIT_TRUST_FORWARDED_FOR`, which should only be set behind a
+    /// proxy that overwrites the header.
+    pub fn from_env(store: web::Data<Store>) -> Result<Self, String> {
+        let backend: Arc<dyn RateLimitBackend> = match env::var("RATE_LIMIT_BACKEND").as_deref() {
+            Ok("postgres") => Arc::new(PostgresBackend::new(store)),
+            Ok("memory") | Err(_) => Arc::new(InMemoryBackend::default()),
+            Ok(other) => return Err(format!("Unknown rate limit backend: {}", other)),
+        };
+
+        let mut rules = default_rules();
+        if let Ok(overrides) = env::var("RATE_LIMIT_RULES") {
+            rules.extend(parse_rules(&overrides)?);
+        }
+
+        Ok(Self {
+            backend,
+            rules,
+            trust_forwarded_for: env::var("RATE_LIMIT_TRUST_FORWARDED_FOR").is_ok_and(|v| v == "true"),
+        })
+    }
+
+    /// Drops buckets that have been idle long enough to be full again.
+    pub async fn prune(&self) {
+        let idle = self
+            .rules
+            .values()
+            .flatten()
+            .map(|rule| rule.bucket.period)
+            .max()
+            .unwrap_or_default();
+        if let Err(e) = self.backend.prune(idle).await {
+            log::error!("Failed to prune rate limit buckets: {}", e);
+        }
+    }
+
+    /// Takes a token from each of the route's buckets in turn and returns how
+    /// long to wait if one is empty. Buckets after the empty one are left
+    /// alone, so a request that is turned away does not use up the caller's
+    /// other limits.
+    async fn check(&self, route: &str, ip: Option<&str>, account: Option<&str>) -> Option<Duration> {
+        for rule in self.rules_for(route) {
+            let subject = match rule.scope {
+                Scope::Ip => ip,
+                Scope::Account => account,
+            };
+            let Some(subject) = subject else {
+                continue;
+            };
+            let scope = match rule.scope {
+                Scope::Ip => "ip",
+                Scope::Account => "account",
+            };
+            let key = format!("{}:{}:{}", scope, route, subject);
+
+            match self.backend.take(&key, &rule.bucket).await {
+                Ok(decision) if !decision.allowed => return Some(decision.retry_after),
+                Ok(_) => {}
+                Err(e) => log::error!("Rate limit check failed for {}: {}", key, e),
+            }
+        }
+        None
+    }
+
+    fn rules_for(&self, route: &str) -> &[Rule] {
+        self.rules
+            .get(route)
+            .or_else(|| self.rules.get(""))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
+/// Limits for routes that are expensive or sensitive. Everything else gets the
+/// catch-all per-IP bucket.
+pub fn default_rules() -> HashMap<String, Vec<Rule>> {
+    let ip = |capacity| Rule {
+        scope: Scope::Ip,
+        bucket: Bucket::per_minute(capacity),
+    };
+    let account = |capacity| Rule {
+        scope: Scope::Account,
+        bucket: Bucket::per_minute(capacity),
+    };
+
+    HashMap::from([
+        (String::new(), vec![ip(120)]),
+        ("/api/v1/signin".to_string(), vec![ip(10)]),
+        ("/api/v1/signup".to_string(), vec![ip(5)]),
+        ("/api/v1/auth/forgot-password".to_string(), vec![ip(5)]),
+        ("/api/v1/auth/reset-password".to_string(), vec![ip(10)]),
+        ("/api/v1/auth/2fa/confirm".to_string(), vec![ip(10), account(5)]),
+        ("/api/v1/quote".to_string(), vec![ip(60), account(30)]),
+        ("/api/v1/swap".to_string(), vec![ip(30), account(10)]),
+        ("/api/v1/send".to_string(), vec![ip(30), account(10)]),
+    ])
+}
+
+/// Parses `route=scope:capacity/seconds,...;route=...`, for example
+/// `/api/v1/send=ip:30/60,account:5/60`. Use `*` as the route for the
+/// catch-all rule.
+pub fn parse_rules(spec: &str) -> Result<HashMap<String, Vec<Rule>>, String> {
+    let mut rules = HashMap::new();
+
+    for entry in spec.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (route, buckets) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("Missing '=' in rate limit rule: {}", entry))?;
+
+        let parsed = buckets
+            .split(',')
+            .map(|bucket| {
+                let invalid = || format!("Invalid rate limit bucket: {}", bucket);
+                let (scope, limit) = bucket.trim().split_once(':').ok_or_else(invalid)?;
+                let (capacity, seconds) = limit.split_once('/').ok_or_else(invalid)?;
+                let scope = match scope {
+                    "ip" => Scope::Ip,
+                    "account" => Scope::Account,
+                    _ => return Err(invalid()),
+                };
+                let capacity: u32 = capacity.parse().map_err(|_| invalid())?;
+                let seconds: u64 = seconds.parse().map_err(|_| invalid())?;
+                if capacity == 0 || seconds == 0 {
+                    return Err(invalid());
+                }
+                Ok(Rule {
+                    scope,
+                    bucket: Bucket {
+                        capacity,
+                        period: Duration::from_secs(seconds),
+                    },
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let route = match route.trim() {
+            "*" => String::new(),
+            route => route.to_string(),
+        };
+        rules.insert(route, parsed);
+    }
+
+    Ok(rules)
+}
+
+/// Consecutive failed sign-ins allowed before the account is locked.
+const LOGIN_FAILURES_BEFORE_LOCKOUT: i32 = 5;
+const LOGIN_LOCKOUT_BASE: Duration = Duration::from_secs(30);
+const LOGIN_LOCKOUT_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// How long to lock sign-in after `failed_count` consecutive failures. The
+/// delay doubles with every failure past the threshold, up to an hour.
+pub fn login_lockout(failed_count: i32) -> Option<Duration> {
+    if failed_count < LOGIN_FAILURES_BEFORE_LOCKOUT {
+        return None;
+    }
+    let excess = (failed_count - LOGIN_FAILURES_BEFORE_LOCKOUT).min(31) as u32;
+    let factor = 2u32.saturating_pow(excess);
+    Some(LOGIN_LOCKOUT_BASE.saturating_mul(factor).min(LOGIN_LOCKOUT_MAX))
+}
+
+pub fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    // Round up so clients never retry a moment too early
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, seconds.max(1).to_string()))
+        .json("Too many requests")
+}
+
+/// Applies the route's per-IP and per-account buckets before the handler runs.
+/// The account is taken from the bearer token without checking the session;
+/// the handler still authenticates the request properly. If the backend
+/// fails, the request is let through.
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(limiter) = req.app_data::<web::Data<RateLimiter>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+
+    let route = req.match_pattern().unwrap_or_default();
+    let ip = if limiter.trust_forwarded_for {
+        req.connection_info().realip_remote_addr().map(str::to_string)
+    } else {
+        req.peer_addr().map(|addr| addr.ip().to_string())
+    };
+    let account = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .zip(req.app_data::<web::Data<JwtKeys>>())
+        .and_then(|(token, keys)| keys.decode_jwt(token).ok())
+        .map(|claims| claims.sub.to_string());
+
+    match limiter.check(&route, ip.as_deref(), account.as_deref()).await {
+        Some(retry_after) => Ok(req
+            .into_response(too_many_requests(retry_after))
+            .map_into_right_body()),
+        None => Ok(next.call(req).await?.map_into_left_body()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{login_lockout, parse_rules, Bucket, InMemoryBackend, RateLimiter, Scope};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_bucket_refills_over_time() {
+        let backend = InMemoryBackend::default();
+        let bucket = Bucket {
+            capacity: 2,
+            period: Duration::from_secs(10),
+        };
+        let start = Instant::now();
+
+        assert!(backend.take_at("key", &bucket, start).allowed);
+        assert!(backend.take_at("key", &bucket, start).allowed);
+        let denied = backend.take_at("key", &bucket, start);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Duration::from_secs(5));
+
+        // One token comes back every five seconds
+        assert!(backend.take_at("key", &bucket, start + Duration::from_secs(5)).allowed);
+        assert!(!backend.take_at("key", &bucket, start + Duration::from_secs(5)).allowed);
+
+        // Other keys have their own bucket
+        assert!(backend.take_at("other", &bucket, start).allowed);
+    }
+
+    #[tokio::test]
+    async fn test_denied_request_leaves_later_buckets_alone() {
+        let limiter = RateLimiter {
+            backend: Arc::new(InMemoryBackend::default()),
+            rules: parse_rules("/send=ip:1/60,account:1/60").unwrap(),
+            trust_forwarded_for: false,
+        };
+
+        assert_eq!(limiter.check("/send", Some("1.1.1.1"), Some("alice")).await, None);
+        // The IP is out of tokens, so bob's account bucket is not touched
+        assert!(limiter.check("/send", Some("1.1.1.1"), Some("bob")).await.is_some());
+        assert_eq!(limiter.check("/send", Some("2.2.2.2"), Some("bob")).await, None);
+        assert!(limiter.check("/send", Some("3.3.3.3"), Some("alice")).await.is_some());
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = parse_rules("/api/v1/send=ip:30/60,account:5/60; *=ip:100/60").unwrap();
+
+        let send = &rules["/api/v1/send"];
+        assert_eq!(send.len(), 2);
+        assert_eq!(send[1].scope, Scope::Account);
+        assert_eq!(send[1].bucket.capacity, 5);
+        assert_eq!(rules[""][0].bucket.period, Duration::from_secs(60));
+
+        assert!(parse_rules("/api/v1/send=ip:0/60").is_err());
+        assert!(parse_rules("/api/v1/send=user:5/60").is_err());
+        assert!(parse_rules("/api/v1/send").is_err());
+    }
+
+    #[test]
+    fn test_login_lockout_doubles_up_to_an_hour() {
+        assert_eq!(login_lockout(1), None);
+        assert_eq!(login_lockout(4), None);
+        assert_eq!(login_lockout(5), Some(Duration::from_secs(30)));
+        assert_eq!(login_lockout(6), Some(Duration::from_secs(60)));
+        assert_eq!(login_lockout(8), Some(Duration::from_secs(240)));
+        assert_eq!(login_lockout(12), Some(Duration::from_secs(3600)));
+        assert_eq!(login_lockout(i32::MAX), Some(Duration::from_secs(3600)));
+    }
+}
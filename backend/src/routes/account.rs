@@ -0,0 +1,125 @@
+use actix_web::{web, HttpResponse, Result};
+use chrono::Utc;
+use serde::Deserialize;
+use store::email_token::{EmailTokenError, EmailTokenPurpose};
+use store::models::user::User;
+use store::Store;
+use crate::auth::{generate_token, hash_token, EMAIL_VERIFICATION_TTL, PASSWORD_RESET_TTL};
+use crate::mailer::{password_reset_email, verification_email, Mailer};
+use crate::middleware::AuthenticatedUser;
+
+#[derive(Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+}
+
+/// Issues a verification token and mails it. Failures are logged rather than
+/// returned, since the user can always ask for another email.
+pub async fn send_verification_email(store: &Store, mailer: &dyn Mailer, user: &User) {
+    let token = generate_token();
+    if let Err(e) = store
+        .create_email_token(
+            user.id,
+            EmailTokenPurpose::VerifyEmail,
+            &hash_token(&token),
+            Utc::now() + EMAIL_VERIFICATION_TTL,
+        )
+        .await
+    {
+        log::error!("Failed to create verification token for {}: {}", user.id, e);
+        return;
+    }
+
+    if let Err(e) = mailer.send(verification_email(&user.email, &token)).await {
+        log::error!("Failed to send verification email to {}: {}", user.id, e);
+    }
+}
+
+#[actix_web::post("/auth/verify-email")]
+pub async fn verify_email(
+    store: web::Data<Store>,
+    req: web::Json<VerifyEmailRequest>,
+) -> Result<HttpResponse> {
+    match store.verify_email(&hash_token(&req.token)).await {
+        Ok(_) => Ok(HttpResponse::NoContent().finish()),
+        Err(EmailTokenError::InvalidToken) => Ok(HttpResponse::BadRequest().json("Invalid or expired token")),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[actix_web::post("/auth/resend-verification")]
+pub async fn resend_verification(
+    store: web::Data<Store>,
+    mailer: web::Data<dyn Mailer>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse> {
+    let user = match store.get_user_by_id(user.id).await {
+        Ok(Some(user)) => user,
+        _ => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    if user.email_verified_at.is_some() {
+        return Ok(HttpResponse::Conflict().json("Email already verified"));
+    }
+
+    send_verification_email(&store, mailer.get_ref(), &user).await;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Always answers 202 so the endpoint cannot be used to find registered emails.
+#[actix_web::post("/auth/forgot-password")]
+pub async fn forgot_password(
+    store: web::Data<Store>,
+    mailer: web::Data<dyn Mailer>,
+    req: web::Json<ForgotPasswordRequest>,
+) -> Result<HttpResponse> {
+    let user = match store.get_user_by_email(&req.email).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(HttpResponse::Accepted().finish()),
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    let token = generate_token();
+    if let Err(e) = store
+        .create_email_token(
+            user.id,
+            EmailTokenPurpose::ResetPassword,
+            &hash_token(&token),
+            Utc::now() + PASSWORD_RESET_TTL,
+        )
+        .await
+    {
+        log::error!("Failed to create password reset token for {}: {}", user.id, e);
+        return Ok(HttpResponse::InternalServerError().finish());
+    }
+
+    if let Err(e) = mailer.send(password_reset_email(&user.email, &token)).await {
+        log::error!("Failed to send password reset email to {}: {}", user.id, e);
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[actix_web::post("/auth/reset-password")]
+pub async fn reset_password(
+    store: web::Data<Store>,
+    req: web::Json<ResetPasswordRequest>,
+) -> Result<HttpResponse> {
+    match store.reset_password(&hash_token(&req.token), &req.password).await {
+        Ok(_) => Ok(HttpResponse::NoContent().finish()),
+        Err(EmailTokenError::InvalidToken) => Ok(HttpResponse::BadRequest().json("Invalid or expired token")),
+        Err(EmailTokenError::InvalidInput(msg)) => Ok(HttpResponse::BadRequest().json(msg)),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
@@ -0,0 +1,119 @@
+use actix_web::{web, HttpResponse, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use store::session::SessionError;
+use store::Store;
+use uuid::Uuid;
+use crate::auth::{
+    generate_token, hash_token, JwtKeys, ACCESS_TOKEN_TTL, REFRESH_TOKEN_TTL,
+};
+use crate::middleware::AuthenticatedUser;
+
+#[derive(Serialize)]
+pub struct AuthResponse {
+    pub token: String,
+    #[serde(rename = "refreshToken")]
+    pub refresh_token: String,
+    #[serde(rename = "expiresIn")]
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    #[serde(rename = "refreshToken")]
+    pub refresh_token: String,
+}
+
+#[derive(Serialize)]
+pub struct LogoutAllResponse {
+    pub revoked: u64,
+}
+
+/// Opens a new session for the user and returns its first token pair.
+pub async fn start_session(
+    store: &Store,
+    keys: &JwtKeys,
+    user_id: Uuid,
+) -> Result<AuthResponse, HttpResponse> {
+    let refresh_token = generate_token();
+    let session = store
+        .create_session(
+            user_id,
+            &hash_token(&refresh_token),
+            Utc::now() + REFRESH_TOKEN_TTL,
+        )
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+
+    let token = keys
+        .create_jwt(user_id, session.id)
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+
+    Ok(AuthResponse {
+        token,
+        refresh_token,
+        expires_in: ACCESS_TOKEN_TTL.num_seconds(),
+    })
+}
+
+#[actix_web::post("/auth/refresh")]
+pub async fn refresh(
+    store: web::Data<Store>,
+    keys: web::Data<JwtKeys>,
+    req: web::Json<RefreshRequest>,
+) -> Result<HttpResponse> {
+    let refresh_token = generate_token();
+    let session = match store
+        .rotate_session(
+            &hash_token(&req.refresh_token),
+            &hash_token(&refresh_token),
+            Utc::now() + REFRESH_TOKEN_TTL,
+        )
+        .await
+    {
+        Ok(session) => session,
+        Err(SessionError::TokenReused) => {
+            log::warn!("Refresh token reuse detected, session revoked");
+            return Ok(HttpResponse::Unauthorized().finish());
+        }
+        Err(SessionError::NotFound) => return Ok(HttpResponse::Unauthorized().finish()),
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    match keys.create_jwt(session.user_id, session.id) {
+        Ok(token) => Ok(HttpResponse::Ok().json(AuthResponse {
+            token,
+            refresh_token,
+            expires_in: ACCESS_TOKEN_TTL.num_seconds(),
+        })),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[actix_web::post("/auth/logout")]
+pub async fn logout(
+    store: web::Data<Store>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse> {
+    match store.revoke_session(user.id, user.session_id).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[actix_web::post("/auth/logout-all")]
+pub async fn logout_all(
+    store: web::Data<Store>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse> {
+    match store.revoke_all_sessions(user.id).await {
+        Ok(revoked) => Ok(HttpResponse::Ok().json(LogoutAllResponse { revoked })),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+/// Public keys that verify access tokens, for other services to consume.
+#[actix_web::get("/.well-known/jwks.json")]
+pub async fn jwks(keys: web::Data<JwtKeys>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(keys.jwks()))
+}
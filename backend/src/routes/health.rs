@@ -0,0 +1,36 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::Serialize;
+use store::Store;
+use crate::metrics;
+use crate::mpc_service::MpcService;
+
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub database: bool,
+    pub mpc: bool,
+}
+
+#[actix_web::get("/metrics")]
+pub async fn metrics_endpoint() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render()))
+}
+
+#[actix_web::get("/healthz")]
+pub async fn healthz() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().body("ok"))
+}
+
+#[actix_web::get("/readyz")]
+pub async fn readyz(store: web::Data<Store>, mpc: web::Data<dyn MpcService>) -> Result<HttpResponse> {
+    let database = store.ping().await.is_ok();
+    let mpc = mpc.is_ready().await;
+
+    let response = ReadinessResponse { database, mpc };
+    if database && mpc {
+        Ok(HttpResponse::Ok().json(response))
+    } else {
+        Ok(HttpResponse::ServiceUnavailable().json(response))
+    }
+}
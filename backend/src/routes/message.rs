@@ -0,0 +1,277 @@
+use actix_web::{web, HttpResponse, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use store::Store;
+use uuid::Uuid;
+use crate::middleware::AuthenticatedUser;
+use crate::mpc_service::{MpcCallError, MpcService};
+use mpc::offchain::MessageFormat;
+use mpc::serialization::{AggMessage1, PartialSignature};
+
+#[derive(Deserialize)]
+pub struct SignMessageRequest {
+    pub message: String,
+    /// `offchain` wraps the message in the off-chain message header, `raw`
+    /// signs the UTF-8 bytes as they are.
+    #[serde(default)]
+    pub format: MessageFormat,
+}
+
+#[derive(Serialize)]
+pub struct SignMessageResponse {
+    /// Base58 ed25519 signature by the wallet's aggregated key.
+    pub signature: String,
+    /// Base64 of the exact bytes signed.
+    #[serde(rename = "signedMessage")]
+    pub signed_message: String,
+    #[serde(rename = "publicKey")]
+    pub public_key: String,
+}
+
+/// Sign-In With Solana input, as a dApp passes it to `signIn`.
+#[derive(Deserialize)]
+pub struct SignInRequest {
+    pub domain: String,
+    /// Must match the wallet when given.
+    pub address: Option<String>,
+    pub statement: Option<String>,
+    pub uri: Option<String>,
+    pub version: Option<String>,
+    #[serde(rename = "chainId")]
+    pub chain_id: Option<String>,
+    pub nonce: Option<String>,
+    #[serde(rename = "issuedAt")]
+    pub issued_at: Option<String>,
+    #[serde(rename = "expirationTime")]
+    pub expiration_time: Option<String>,
+    #[serde(rename = "notBefore")]
+    pub not_before: Option<String>,
+    #[serde(rename = "requestId")]
+    pub request_id: Option<String>,
+    #[serde(default)]
+    pub resources: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct SignInResponse {
+    pub address: String,
+    /// The sign-in message text that was signed.
+    #[serde(rename = "signedMessage")]
+    pub signed_message: String,
+    pub signature: String,
+}
+
+impl SignInRequest {
+    /// The ABNF message text from the Sign-In With Solana spec, with only the
+    /// fields that were given.
+    fn message(&self, address: &str) -> String {
+        let mut message = format!("{} wants you to sign in with your Solana account:\n{}", self.domain, address);
+        if let Some(statement) = &self.statement {
+            message.push_str(&format!("\n\n{}", statement));
+        }
+
+        let mut fields = Vec::new();
+        let optional = [
+            ("URI", &self.uri),
+            ("Version", &self.version),
+            ("Chain ID", &self.chain_id),
+            ("Nonce", &self.nonce),
+            ("Issued At", &self.issued_at),
+            ("Expiration Time", &self.expiration_time),
+            ("Not Before", &self.not_before),
+            ("Request ID", &self.request_id),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                fields.push(format!("{}: {}", name, value));
+            }
+        }
+        if !self.resources.is_empty() {
+            let resources: String = self.resources.iter().map(|r| format!("\n- {}", r)).collect();
+            fields.push(format!("Resources:{}", resources));
+        }
+        if !fields.is_empty() {
+            message.push_str(&format!("\n\n{}", fields.join("\n")));
+        }
+        message
+    }
+
+    /// Line breaks would let one field pose as another.
+    fn has_line_breaks(&self) -> bool {
+        let optional = [
+            &self.address,
+            &self.uri,
+            &self.version,
+            &self.chain_id,
+            &self.nonce,
+            &self.issued_at,
+            &self.expiration_time,
+            &self.not_before,
+            &self.request_id,
+            &self.statement,
+        ];
+        std::iter::once(&self.domain)
+            .chain(optional.into_iter().flatten())
+            .chain(&self.resources)
+            .any(|value| value.contains('\n') || value.contains('\r'))
+    }
+}
+
+/// Runs both MPC rounds over `message`. The nodes refuse anything that
+/// parses as a transaction.
+async fn sign_message_with_mpc(
+    mpc: &dyn MpcService,
+    public_key: &str,
+    message: &[u8],
+    format: MessageFormat,
+) -> Result<SignMessageResponse, HttpResponse> {
+    let failed = |e: MpcCallError| match e.status {
+        Some(reqwest::StatusCode::BAD_REQUEST) => HttpResponse::BadRequest().json("Message cannot be signed"),
+        _ => {
+            log::error!("MPC message signing failed: {}", e.message);
+            HttpResponse::BadGateway().json("Signing failed")
+        }
+    };
+
+    let step1_req = serde_json::json!({
+        "end_user_pubkey": public_key,
+        "node_id": 1,
+        "message": base64::engine::general_purpose::STANDARD.encode(message),
+        "format": format,
+    });
+    let step1_res = mpc.post("/sign-message-step1", &step1_req).await.map_err(failed)?;
+    let session_id: Uuid = serde_json::from_value(step1_res["session_id"].clone())
+        .map_err(|_| HttpResponse::BadGateway().json("Signing failed"))?;
+    let agg_message_1: AggMessage1 = serde_json::from_value(step1_res["agg_message_1"].clone())
+        .map_err(|_| HttpResponse::BadGateway().json("Signing failed"))?;
+
+    let step2_req = serde_json::json!({
+        "session_id": session_id,
+        "node_id": 2,
+        "agg_message_1": agg_message_1
+    });
+    let step2_res = mpc.post("/agg-send-step2", &step2_req).await.map_err(failed)?;
+    let partial_signature_2: PartialSignature = serde_json::from_value(step2_res["partial_signature"].clone())
+        .map_err(|_| HttpResponse::BadGateway().json("Signing failed"))?;
+    let agg_message_2: AggMessage1 = serde_json::from_value(step2_res["agg_message_2"].clone())
+        .map_err(|_| HttpResponse::BadGateway().json("Signing failed"))?;
+
+    let aggregate_req = serde_json::json!({
+        "session_id": session_id,
+        "partial_signature_2": partial_signature_2,
+        "agg_message_2": agg_message_2
+    });
+    let signed = mpc.post("/sign-message-aggregate", &aggregate_req).await.map_err(failed)?;
+
+    match (
+        signed["signature"].as_str(),
+        signed["signed_message"].as_str(),
+        signed["public_key"].as_str(),
+    ) {
+        (Some(signature), Some(signed_message), Some(public_key)) => Ok(SignMessageResponse {
+            signature: signature.to_string(),
+            signed_message: signed_message.to_string(),
+            public_key: public_key.to_string(),
+        }),
+        _ => Err(HttpResponse::BadGateway().json("Signing failed")),
+    }
+}
+
+/// Signs an arbitrary message with the wallet key so the user can prove
+/// they own the address. Transactions are refused.
+#[actix_web::post("/sign-message")]
+pub async fn sign_message(
+    store: web::Data<Store>,
+    mpc: web::Data<dyn MpcService>,
+    user: AuthenticatedUser,
+    req: web::Json<SignMessageRequest>,
+) -> Result<HttpResponse> {
+    let user_model = match store.get_user_by_id(user.id).await {
+        Ok(Some(user)) => user,
+        _ => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    match sign_message_with_mpc(mpc.get_ref(), &user_model.public_key, req.message.as_bytes(), req.format).await {
+        Ok(signed) => Ok(HttpResponse::Ok().json(signed)),
+        Err(response) => Ok(response),
+    }
+}
+
+/// Builds and signs a Sign-In With Solana message for a dApp.
+#[actix_web::post("/sign-in-with-solana")]
+pub async fn sign_in_with_solana(
+    store: web::Data<Store>,
+    mpc: web::Data<dyn MpcService>,
+    user: AuthenticatedUser,
+    req: web::Json<SignInRequest>,
+) -> Result<HttpResponse> {
+    if req.domain.is_empty() || req.has_line_breaks() {
+        return Ok(HttpResponse::BadRequest().json("Invalid sign-in input"));
+    }
+
+    let user_model = match store.get_user_by_id(user.id).await {
+        Ok(Some(user)) => user,
+        _ => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+    if req.address.as_ref().is_some_and(|address| *address != user_model.public_key) {
+        return Ok(HttpResponse::BadRequest().json("Address does not belong to this wallet"));
+    }
+
+    let message = req.message(&user_model.public_key);
+    match sign_message_with_mpc(mpc.get_ref(), &user_model.public_key, message.as_bytes(), MessageFormat::Raw).await {
+        Ok(signed) => Ok(HttpResponse::Ok().json(SignInResponse {
+            address: signed.public_key,
+            signed_message: message,
+            signature: signed.signature,
+        })),
+        Err(response) => Ok(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SignInRequest;
+
+    fn request() -> SignInRequest {
+        SignInRequest {
+            domain: "example.com".to_string(),
+            address: None,
+            statement: Some("Sign in to Example".to_string()),
+            uri: Some("https://example.com/login".to_string()),
+            version: Some("1".to_string()),
+            chain_id: None,
+            nonce: Some("oBbLoEldZs".to_string()),
+            issued_at: None,
+            expiration_time: None,
+            not_before: None,
+            request_id: None,
+            resources: vec!["https://example.com/terms".to_string()],
+        }
+    }
+
+    #[test]
+    fn builds_sign_in_message() {
+        let message = request().message("9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM");
+        assert_eq!(
+            message,
+            "example.com wants you to sign in with your Solana account:\n\
+             9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM\n\
+             \n\
+             Sign in to Example\n\
+             \n\
+             URI: https://example.com/login\n\
+             Version: 1\n\
+             Nonce: oBbLoEldZs\n\
+             Resources:\n\
+             - https://example.com/terms"
+        );
+    }
+
+    #[test]
+    fn rejects_injected_lines() {
+        let mut req = request();
+        assert!(!req.has_line_breaks());
+        req.statement = Some("Sign in\nURI: https://evil.example".to_string());
+        assert!(req.has_line_breaks());
+    }
+}
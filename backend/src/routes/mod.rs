@@ -1,7 +1,21 @@
 pub mod user;
 pub mod solana;
 pub mod auth;
+pub mod transaction;
+pub mod health;
+pub mod two_factor;
+pub mod account;
+pub mod withdrawal_address;
+pub mod spending;
+pub mod message;
 
 pub use user::*;
 pub use solana::*;
 pub use auth::*;
+pub use transaction::*;
+pub use health::*;
+pub use two_factor::*;
+pub use account::*;
+pub use withdrawal_address::*;
+pub use spending::*;
+pub use message::*;
@@ -1,36 +1,77 @@
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Result};
+use base64::Engine;
 use serde::{Deserialize, Serialize};
+use solana_sdk::{pubkey::Pubkey, transaction::VersionedTransaction};
 use uuid::Uuid;
-use crate::middleware::AuthenticatedUser;
+use crate::aggregator::{QuoteParams, SwapAggregator, SwapMode};
+use crate::idempotency::{self, Outcome};
+use crate::middleware::{AuthenticatedUser, StepUpUser};
+use crate::mpc_service::{MpcCallError, MpcService};
+use store::models::quote::Quote;
+use store::solana::{NewQuote, QuoteError, SOL_MINT};
+use store::spending::SpendingError;
+use store::transaction::NewTransaction;
 use store::Store;
+use mpc::error::Error as MpcError;
+use mpc::fees::PriorityLevel;
+use mpc::inspect::{self, InstructionSummary};
+use mpc::policy::{self, MAX_MEMO_LEN};
 use mpc::serialization::{AggMessage1, PartialSignature};
 
+/// How long a quote can be swapped after it was fetched.
+const QUOTE_TTL: chrono::Duration = chrono::Duration::seconds(30);
+const DEFAULT_SLIPPAGE_BPS: u16 = 50;
+const MAX_SLIPPAGE_BPS: u16 = 5000;
+
 #[derive(Deserialize)]
 pub struct QuoteRequest {
     #[serde(rename = "inputMint")]
     pub input_mint: String,
     #[serde(rename = "outputMint")]
     pub output_mint: String,
-    #[serde(rename = "inAmount")]
-    pub in_amount: u64,
+    /// Amount of the input mint, or of the output mint with `ExactOut`.
+    #[serde(rename = "inAmount", alias = "amount")]
+    pub amount: u64,
+    #[serde(rename = "slippageBps", default)]
+    pub slippage_bps: Option<u16>,
+    #[serde(rename = "onlyDirectRoutes", default)]
+    pub only_direct_routes: bool,
+    #[serde(rename = "swapMode", default)]
+    pub swap_mode: SwapMode,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct QuoteResponse {
+    #[serde(rename = "inAmount")]
+    pub in_amount: String,
     #[serde(rename = "outAmount")]
     pub out_amount: String,
+    /// Least that will be received with `ExactIn`, or most that will be spent
+    /// with `ExactOut`, once slippage is applied.
+    #[serde(rename = "otherAmountThreshold")]
+    pub other_amount_threshold: String,
+    #[serde(rename = "slippageBps")]
+    pub slippage_bps: i32,
+    #[serde(rename = "swapMode")]
+    pub swap_mode: SwapMode,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: chrono::DateTime<chrono::Utc>,
     pub id: Uuid,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct SwapRequest {
     pub id: Uuid,
 }
 
 #[derive(Serialize)]
 pub struct SwapResponse {
+    /// Transfer id to follow the confirmation with `/transfers/{id}`.
+    pub id: Uuid,
     #[serde(rename = "swapTransaction")]
     pub swap_transaction: String,
+    /// Fee paid in lamports.
+    pub fee: u64,
 }
 
 #[derive(Serialize)]
@@ -45,6 +86,20 @@ pub struct TokenBalance {
     pub token_mint: String,
     pub symbol: String,
     pub decimals: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accounts: Option<Vec<TokenAccountBalance>>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct TokenAccountBalance {
+    pub address: String,
+    pub balance: u64,
+}
+
+#[derive(Deserialize)]
+pub struct TokenBalanceQuery {
+    #[serde(default)]
+    pub breakdown: bool,
 }
 
 #[derive(Serialize)]
@@ -52,122 +107,228 @@ pub struct TokenBalanceResponse {
     pub balances: Vec<TokenBalance>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct SendRequest {
     pub to: String,
     pub amount: u64,
     pub mint: Option<String>,
+    /// Attached with the memo program, such as an exchange deposit reference.
+    #[serde(default)]
+    pub memo: Option<String>,
+    /// Priority fee level, `medium` if absent.
+    #[serde(default)]
+    pub priority: Option<PriorityLevel>,
+}
+
+impl SendRequest {
+    fn memo_too_long(&self) -> bool {
+        self.memo.as_ref().is_some_and(|memo| memo.len() > MAX_MEMO_LEN)
+    }
+
+    /// Only native SOL transfers are built so far; sending a token would move
+    /// SOL instead.
+    fn is_sol(&self) -> bool {
+        self.mint.as_deref().is_none_or(|mint| mint == SOL_MINT)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BalanceChange {
+    pub mint: String,
+    pub before: u64,
+    pub after: u64,
+    pub change: i64,
+}
+
+/// Expected effect of a send or swap, from simulating it on the MPC node.
+#[derive(Serialize, Deserialize)]
+pub struct PreviewResponse {
+    /// Network fee in lamports, already included in the SOL change.
+    pub fee: u64,
+    #[serde(rename = "balanceChanges", alias = "balance_changes")]
+    pub balance_changes: Vec<BalanceChange>,
+    #[serde(rename = "unitsConsumed", alias = "units_consumed")]
+    pub units_consumed: Option<u64>,
 }
 
 #[derive(Serialize)]
 pub struct SendResponse {
+    /// Transfer id to follow the confirmation with `/transfers/{id}`.
+    pub id: Uuid,
     pub signature: String,
+    /// Fee paid in lamports, including the priority fee.
+    pub fee: u64,
+    #[serde(rename = "priorityFee")]
+    pub priority_fee: u64,
 }
 
-#[derive(Serialize)]
-struct JupiterSwapRequest {
-    #[serde(rename = "userPublicKey")]
-    user_public_key: String,
-    #[serde(rename = "quoteResponse")]
-    quote_response: serde_json::Value,
+/// A fund-moving request signed through an MPC session.
+struct OutgoingTransfer<'a> {
+    signature: &'a str,
+    mint: &'a str,
+    amount: u64,
+    counterparty: Option<String>,
+    kind: &'a str,
+    session_id: Uuid,
+}
+
+/// Records a fund-moving request in the user's history so it shows up with its
+/// MPC session before the indexer picks the transaction up on chain. It stays
+/// `submitted` until the MPC service confirms it.
+async fn record_outgoing_transaction(store: &Store, user_id: Uuid, transfer: OutgoingTransfer<'_>) {
+    let OutgoingTransfer { signature, mint, amount, counterparty, kind, session_id } = transfer;
+    let asset = match store.get_asset_by_mint(mint).await {
+        Ok(Some(asset)) => asset,
+        Ok(None) => {
+            log::warn!("Not recording {} {}: unknown asset {}", kind, signature, mint);
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to look up asset {}: {}", mint, e);
+            return;
+        }
+    };
+
+    let new_transaction = NewTransaction {
+        user_id,
+        asset_id: asset.id,
+        signature: signature.to_string(),
+        direction: "outgoing".to_string(),
+        amount: amount as i64,
+        counterparty,
+        slot: None,
+        block_time: chrono::Utc::now(),
+        kind: kind.to_string(),
+        status: "submitted".to_string(),
+        mpc_session_id: Some(session_id),
+    };
+
+    if let Err(e) = store.record_transaction(new_transaction).await {
+        log::error!("Failed to record {} {}: {}", kind, signature, e);
+    }
 }
 
 #[actix_web::post("/quote")]
 pub async fn quote(
     store: web::Data<Store>,
+    aggregator: web::Data<dyn SwapAggregator>,
     user: AuthenticatedUser,
     req: web::Json<QuoteRequest>,
 ) -> Result<HttpResponse> {
-    let client = reqwest::Client::new();
-    let url = format!(
-        "https://lite-api.jup.ag/v6/quote?inputMint={}&outputMint={}&amount={}&slippageBps=50",
-        req.input_mint, req.output_mint, req.in_amount
-    );
-
-    match client.get(&url).send().await {
-        Ok(response) => {
-            if response.status().is_success() {
-                match response.json::<serde_json::Value>().await {
-                    Ok(quote_response) => {
-                        match store
-                            .create_quote(user.id, quote_response.clone())
-                            .await
-                        {
-                            Ok(stored_quote) => {
-                                let out_amount = quote_response["outAmount"]
-                                    .as_str()
-                                    .unwrap_or_default()
-                                    .to_string();
-                                let response = QuoteResponse {
-                                    out_amount,
-                                    id: stored_quote.id,
-                                };
-                                Ok(HttpResponse::Ok().json(response))
-                            }
-                            Err(_) => Ok(HttpResponse::InternalServerError().finish()),
-                        }
-                    }
-                    Err(_) => Ok(HttpResponse::InternalServerError().finish()),
-                }
-            } else {
-                Ok(HttpResponse::InternalServerError().finish())
-            }
-        }
-        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    let slippage_bps = req.slippage_bps.unwrap_or(DEFAULT_SLIPPAGE_BPS);
+    if slippage_bps > MAX_SLIPPAGE_BPS {
+        return Ok(HttpResponse::BadRequest().json("slippageBps must be at most 5000"));
     }
-}
 
-#[actix_web::post("/swap")]
-pub async fn swap(
-    store: web::Data<Store>,
-    user: AuthenticatedUser,
-    req: web::Json<SwapRequest>,
-) -> Result<HttpResponse> {
-    let user_model = match store.get_user_by_id(user.id).await {
-        Ok(Some(user)) => user,
-        _ => return Ok(HttpResponse::InternalServerError().finish()),
+    let params = QuoteParams {
+        input_mint: req.input_mint.clone(),
+        output_mint: req.output_mint.clone(),
+        amount: req.amount,
+        slippage_bps,
+        only_direct_routes: req.only_direct_routes,
+        swap_mode: req.swap_mode,
     };
-
-    let quote = match store.get_quote(req.id).await {
-        Ok(Some(quote)) => quote,
-        _ => return Ok(HttpResponse::NotFound().finish()),
+    let swap_quote = match aggregator.quote(&params).await {
+        Ok(swap_quote) => swap_quote,
+        Err(e) => {
+            log::error!("Failed to fetch quote: {}", e);
+            return Ok(HttpResponse::BadGateway().json("Failed to fetch quote"));
+        }
     };
 
-    let swap_request_body = JupiterSwapRequest {
-        user_public_key: user_model.public_key.clone(),
-        quote_response: quote.quote_response,
+    let new_quote = NewQuote {
+        user_id: user.id,
+        input_mint: swap_quote.input_mint,
+        output_mint: swap_quote.output_mint,
+        in_amount: swap_quote.in_amount as i64,
+        out_amount: swap_quote.out_amount as i64,
+        slippage_bps: swap_quote.slippage_bps as i32,
+        swap_mode: swap_quote.swap_mode.as_str().to_string(),
+        quote_response: swap_quote.raw,
+        expires_at: chrono::Utc::now() + QUOTE_TTL,
     };
 
-    let client = reqwest::Client::new();
-    let url = "https://lite-api.jup.ag/v6/swap";
+    match store.create_quote(new_quote).await {
+        Ok(stored_quote) => Ok(HttpResponse::Ok().json(QuoteResponse {
+            in_amount: stored_quote.in_amount.to_string(),
+            out_amount: stored_quote.out_amount.to_string(),
+            other_amount_threshold: swap_quote.other_amount_threshold.to_string(),
+            slippage_bps: stored_quote.slippage_bps,
+            swap_mode: swap_quote.swap_mode,
+            expires_at: stored_quote.expires_at,
+            id: stored_quote.id,
+        })),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+/// Why an MPC signing attempt failed. Only a failure at the broadcast step
+/// leaves it unknown whether the transaction reached the chain.
+enum MpcSigningError {
+    /// The MPC node simulated the transaction and it would fail.
+    Simulation(String),
+    /// Another transfer from the wallet holds its durable nonce.
+    Busy(String),
+    /// The MPC node's own policy checks refused the transaction.
+    Policy(String),
+    Signing(String),
+    Broadcast(String),
+}
 
-    let jupiter_res = client.post(url).json(&swap_request_body).send().await.unwrap().json::<serde_json::Value>().await.unwrap();
-    let swap_transaction = jupiter_res["swapTransaction"].as_str().unwrap().to_string();
+impl From<MpcCallError> for MpcSigningError {
+    fn from(e: MpcCallError) -> Self {
+        match e.status {
+            Some(reqwest::StatusCode::UNPROCESSABLE_ENTITY) => MpcSigningError::Simulation(e.message),
+            Some(reqwest::StatusCode::CONFLICT) => MpcSigningError::Busy(e.message),
+            Some(reqwest::StatusCode::FORBIDDEN) => MpcSigningError::Policy(e.message),
+            _ => MpcSigningError::Signing(e.message),
+        }
+    }
+}
 
-    // Now sign the transaction with MPC
-    let mpc_service_url = std::env::var("MPC_SERVICE_URL").expect("MPC_SERVICE_URL must be set");
+/// A transaction signed, and unless only signing was asked for, broadcast by
+/// the MPC nodes.
+struct MpcSignature {
+    session_id: Uuid,
+    signature: String,
+    /// Base64 of the signed transaction.
+    signed_transaction: String,
+    /// Fees paid in lamports; the total includes the priority fee.
+    fee: u64,
+    priority_fee: u64,
+}
 
-    // Step 1: Call agg-send-step1 on node 1
-    let step1_req = serde_json::json!({
-        "end_user_pubkey": user_model.public_key,
-        "node_id": 1,
-        "to": "11111111111111111111111111111111", // Placeholder
-        "amount": 0, // Placeholder
-        "transaction": swap_transaction
-    });
+/// Signs in a single round with a presigned nonce pair when the key has
+/// one left, otherwise runs both MPC signing rounds and the
+/// aggregate-and-broadcast step. Either way it stops short of sending with
+/// `sign_only`.
+async fn sign_with_mpc(
+    mpc: &dyn MpcService,
+    step1_req: serde_json::Value,
+    sign_only: bool,
+) -> Result<MpcSignature, MpcSigningError> {
+    let mut presigned_req = step1_req.clone();
+    presigned_req["sign_only"] = serde_json::json!(sign_only);
+    match mpc.post("/agg-send-presigned", &presigned_req).await {
+        Ok(presigned_res) => {
+            let session_id: Uuid = serde_json::from_value(presigned_res["session_id"].clone())
+                .map_err(|e| MpcSigningError::Broadcast(e.to_string()))?;
+            return mpc_signature(session_id, &presigned_res);
+        }
+        // No pairs left for the key: fall back to exchanging nonces now
+        Err(e) if e.status == Some(reqwest::StatusCode::PRECONDITION_FAILED) => {}
+        // Refused before anything was sent
+        Err(e) if e.status.is_some_and(|status| status.is_client_error()) => return Err(e.into()),
+        Err(e) => return Err(MpcSigningError::Broadcast(e.message)),
+    }
 
-    let step1_res = client
-        .post(format!("{}/agg-send-step1", mpc_service_url))
-        .json(&step1_req)
-        .send()
-        .await
-        .unwrap()
-        .json::<serde_json::Value>()
-        .await
-        .unwrap();
+    // Step 1: Call agg-send-step1 on node 1
+    let step1_res = mpc.post("/agg-send-step1", &step1_req).await.map_err(MpcSigningError::from)?;
 
-    let session_id: Uuid = serde_json::from_value(step1_res["session_id"].clone()).unwrap();
-    let agg_message_1: AggMessage1 = serde_json::from_value(step1_res["agg_message_1"].clone()).unwrap();
+    let session_id: Uuid = serde_json::from_value(step1_res["session_id"].clone())
+        .map_err(|e| MpcSigningError::Signing(e.to_string()))?;
+    let agg_message_1: AggMessage1 = serde_json::from_value(step1_res["agg_message_1"].clone())
+        .map_err(|e| MpcSigningError::Signing(e.to_string()))?;
 
     // Step 2: Call agg-send-step2 on node 2
     let step2_req = serde_json::json!({
@@ -176,114 +337,597 @@ pub async fn swap(
         "agg_message_1": agg_message_1
     });
 
-    let step2_res = client
-        .post(format!("{}/agg-send-step2", mpc_service_url))
-        .json(&step2_req)
-        .send()
+    let step2_res = mpc
+        .post("/agg-send-step2", &step2_req)
         .await
-        .unwrap()
-        .json::<serde_json::Value>()
-        .await
-        .unwrap();
-    
-    let partial_signature_2: PartialSignature = serde_json::from_value(step2_res["partial_signature"].clone()).unwrap();
-    let agg_message_2: AggMessage1 = serde_json::from_value(step2_res["agg_message_2"].clone()).unwrap();
+        .map_err(|e| MpcSigningError::Signing(e.message))?;
+
+    let partial_signature_2: PartialSignature = serde_json::from_value(step2_res["partial_signature"].clone())
+        .map_err(|e| MpcSigningError::Signing(e.to_string()))?;
+    let agg_message_2: AggMessage1 = serde_json::from_value(step2_res["agg_message_2"].clone())
+        .map_err(|e| MpcSigningError::Signing(e.to_string()))?;
 
     // Step 3: Call aggregate-signatures-broadcast on node 1
     let broadcast_req = serde_json::json!({
         "session_id": session_id,
         "partial_signature_2": partial_signature_2,
-        "agg_message_2": agg_message_2
+        "agg_message_2": agg_message_2,
+        "sign_only": sign_only
     });
 
-    let broadcast_res = client
-        .post(format!("{}/aggregate-signatures-broadcast", mpc_service_url))
-        .json(&broadcast_req)
-        .send()
+    let broadcast_res = mpc
+        .post("/aggregate-signatures-broadcast", &broadcast_req)
         .await
-        .unwrap()
-        .json::<serde_json::Value>()
+        .map_err(|e| MpcSigningError::Broadcast(e.message))?;
+
+    mpc_signature(session_id, &broadcast_res)
+}
+
+fn mpc_signature(session_id: Uuid, broadcast_res: &serde_json::Value) -> Result<MpcSignature, MpcSigningError> {
+    let signature = broadcast_res["transaction_signature"]
+        .as_str()
+        .ok_or_else(|| MpcSigningError::Broadcast("Missing transaction signature".to_string()))?
+        .to_string();
+
+    Ok(MpcSignature {
+        session_id,
+        signature,
+        signed_transaction: broadcast_res["signed_transaction"].as_str().unwrap_or_default().to_string(),
+        fee: broadcast_res["fee"].as_u64().unwrap_or_default(),
+        priority_fee: broadcast_res["priority_fee"].as_u64().unwrap_or_default(),
+    })
+}
+
+/// Asks the MPC node to simulate a signing request without starting a
+/// session. A transaction that would fail comes back as a 422 with the
+/// reason.
+async fn simulate_with_mpc(mpc: &dyn MpcService, request: serde_json::Value) -> Result<PreviewResponse, HttpResponse> {
+    match mpc.post("/simulate", &request).await {
+        Ok(report) => serde_json::from_value(report).map_err(|e| {
+            log::error!("Unexpected simulation response: {}", e);
+            HttpResponse::BadGateway().json("Simulation failed")
+        }),
+        Err(e) if e.status == Some(reqwest::StatusCode::UNPROCESSABLE_ENTITY) => {
+            Err(HttpResponse::UnprocessableEntity().json(e.message))
+        }
+        Err(e) => {
+            log::error!("Simulation failed: {}", e.message);
+            Err(HttpResponse::BadGateway().json("Simulation failed"))
+        }
+    }
+}
+
+/// Maps a signing failure to a rejection when nothing was broadcast, so the
+/// idempotency key is freed, or to a stored outcome when it may have been.
+fn signing_failure(kind: &str, error: MpcSigningError) -> Result<Outcome, HttpResponse> {
+    match error {
+        MpcSigningError::Simulation(e) => {
+            log::warn!("Refused to sign {}: {}", kind, e);
+            Err(HttpResponse::UnprocessableEntity().json(e))
+        }
+        MpcSigningError::Busy(e) => {
+            log::warn!("Refused to sign {}: {}", kind, e);
+            Err(HttpResponse::Conflict().json("Another transfer from this wallet is still pending"))
+        }
+        MpcSigningError::Policy(e) => {
+            log::warn!("Refused to sign {}: {}", kind, e);
+            Err(HttpResponse::Forbidden().json(e))
+        }
+        MpcSigningError::Signing(e) => {
+            log::error!("MPC signing failed for {}: {}", kind, e);
+            Err(HttpResponse::BadGateway().json("Signing failed"))
+        }
+        MpcSigningError::Broadcast(e) => {
+            log::error!("MPC broadcast failed for {}: {}", kind, e);
+            Ok(Outcome::failed(
+                StatusCode::BAD_GATEWAY,
+                "Broadcast failed; check the transaction history before retrying",
+            ))
+        }
+    }
+}
+
+/// Counts the amount against the user's spending limits before signing
+/// starts, rejecting the request if it does not fit.
+async fn reserve_spend(
+    store: &Store,
+    user_id: Uuid,
+    mint: &str,
+    amount: u64,
+    kind: &str,
+) -> Result<Uuid, HttpResponse> {
+    let Ok(amount) = i64::try_from(amount) else {
+        return Err(HttpResponse::BadRequest().json("Amount is too large"));
+    };
+    match store.reserve_spend(user_id, mint, amount, kind).await {
+        Ok(ledger_id) => Ok(ledger_id),
+        Err(e @ SpendingError::LimitExceeded(_)) => Err(HttpResponse::Forbidden().json(e.to_string())),
+        Err(e) => {
+            log::error!("Failed to reserve {} spend for {}: {}", kind, user_id, e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Updates the ledger entry once signing is over. A failed broadcast keeps
+/// counting against the limits, since the transaction may still land.
+async fn settle_spend(store: &Store, ledger_id: Uuid, result: &Result<MpcSignature, MpcSigningError>) {
+    let settled = match result {
+        Ok(signed) => store.confirm_spend(ledger_id, &signed.signature).await,
+        Err(MpcSigningError::Simulation(_) | MpcSigningError::Busy(_) | MpcSigningError::Signing(_)) => {
+            store.fail_spend(ledger_id).await
+        }
+        Err(MpcSigningError::Broadcast(_)) => return,
+    };
+    if let Err(e) = settled {
+        log::error!("Failed to settle spend {}: {}", ledger_id, e);
+    }
+}
+
+#[actix_web::post("/swap")]
+pub async fn swap(
+    store: web::Data<Store>,
+    aggregator: web::Data<dyn SwapAggregator>,
+    mpc: web::Data<dyn MpcService>,
+    user: AuthenticatedUser,
+    http_req: HttpRequest,
+    req: web::Json<SwapRequest>,
+) -> Result<HttpResponse> {
+    let key = match idempotency::begin(&store, user.id, &http_req, "swap", &*req).await {
+        Ok(key) => key,
+        Err(response) => return Ok(response),
+    };
+    // Replays above go out without a fresh code; only new requests step up
+    let result = match StepUpUser::check(&http_req, user).await {
+        Ok(user) => execute_swap(&store, aggregator.get_ref(), mpc.get_ref(), user.id, &req).await,
+        Err(e) => Err(HttpResponse::from_error(e)),
+    };
+    Ok(idempotency::finish(&store, key, result).await)
+}
+
+async fn execute_swap(
+    store: &Store,
+    aggregator: &dyn SwapAggregator,
+    mpc: &dyn MpcService,
+    user_id: Uuid,
+    req: &SwapRequest,
+) -> Result<Outcome, HttpResponse> {
+    let quote = match store.claim_quote(req.id, user_id).await {
+        Ok(quote) => quote,
+        Err(QuoteError::NotFound) => return Err(HttpResponse::NotFound().finish()),
+        Err(QuoteError::NotOwner) => return Err(HttpResponse::Forbidden().json("Quote belongs to another user")),
+        Err(QuoteError::Expired) => return Err(HttpResponse::Gone().json("Quote has expired")),
+        Err(QuoteError::AlreadyExecuted) => return Err(HttpResponse::Conflict().json("Quote was already executed")),
+        Err(_) => return Err(HttpResponse::InternalServerError().finish()),
+    };
+
+    let quote_id = quote.id;
+    let result = swap_quote(store, aggregator, mpc, user_id, quote).await;
+    // Nothing was broadcast, so the quote can be retried while it is fresh.
+    if result.is_err() {
+        if let Err(e) = store.release_quote(quote_id).await {
+            log::error!("Failed to release quote {}: {}", quote_id, e);
+        }
+    }
+    result
+}
+
+async fn swap_quote(
+    store: &Store,
+    aggregator: &dyn SwapAggregator,
+    mpc: &dyn MpcService,
+    user_id: Uuid,
+    quote: Quote,
+) -> Result<Outcome, HttpResponse> {
+    let user_model = match store.get_user_by_id(user_id).await {
+        Ok(Some(user)) => user,
+        _ => return Err(HttpResponse::InternalServerError().finish()),
+    };
+
+    let input_mint = quote.input_mint;
+    let in_amount = quote.in_amount as u64;
+
+    let swap_transaction = match aggregator
+        .swap_transaction(&user_model.public_key, &quote.quote_response)
         .await
-        .unwrap();
-    
-    let signature = broadcast_res["transaction_signature"].as_str().unwrap().to_string();
+    {
+        Ok(swap_transaction) => swap_transaction.transaction,
+        Err(e) => {
+            log::error!("Failed to build swap transaction: {}", e);
+            return Err(HttpResponse::BadGateway().json("Failed to build swap transaction"));
+        }
+    };
+
+    let ledger_id = reserve_spend(store, user_id, &input_mint, in_amount, "swap").await?;
+
+    // Now sign the transaction with MPC
+    let step1_req = serde_json::json!({
+        "end_user_pubkey": user_model.public_key,
+        "node_id": 1,
+        "to": "11111111111111111111111111111111", // Placeholder
+        "amount": 0, // Placeholder
+        "transaction": swap_transaction
+    });
+
+    let signed = sign_with_mpc(mpc, step1_req, false).await;
+    settle_spend(store, ledger_id, &signed).await;
+    let signed = match signed {
+        Ok(signed) => signed,
+        Err(e) => return signing_failure("swap", e),
+    };
+
+    record_outgoing_transaction(
+        store,
+        user_id,
+        OutgoingTransfer {
+            signature: &signed.signature,
+            mint: &input_mint,
+            amount: in_amount,
+            counterparty: None,
+            kind: "swap",
+            session_id: signed.session_id,
+        },
+    )
+    .await;
 
-    Ok(HttpResponse::Ok().json(SwapResponse { swap_transaction: signature }))
+    Ok(Outcome::ok(
+        SwapResponse {
+            id: signed.session_id,
+            swap_transaction: signed.signature.clone(),
+            fee: signed.fee,
+        },
+        signed.signature,
+    ))
 }
 
+
 #[actix_web::post("/send")]
 pub async fn send(
     store: web::Data<Store>,
+    mpc: web::Data<dyn MpcService>,
     user: AuthenticatedUser,
+    http_req: HttpRequest,
     req: web::Json<SendRequest>,
 ) -> Result<HttpResponse> {
-    let user_model = store.get_user_by_id(user.id).await.unwrap().unwrap();
-    let mpc_service_url = std::env::var("MPC_SERVICE_URL").expect("MPC_SERVICE_URL must be set");
+    let key = match idempotency::begin(&store, user.id, &http_req, "send", &*req).await {
+        Ok(key) => key,
+        Err(response) => return Ok(response),
+    };
+    // Replays above go out without a fresh code; only new requests step up
+    let result = match StepUpUser::check(&http_req, user).await {
+        Ok(user) => execute_send(&store, mpc.get_ref(), user.id, &req).await,
+        Err(e) => Err(HttpResponse::from_error(e)),
+    };
+    Ok(idempotency::finish(&store, key, result).await)
+}
+
+async fn execute_send(
+    store: &Store,
+    mpc: &dyn MpcService,
+    user_id: Uuid,
+    req: &SendRequest,
+) -> Result<Outcome, HttpResponse> {
+    if req.memo_too_long() {
+        return Err(HttpResponse::BadRequest().json(format!("memo must be at most {} bytes", MAX_MEMO_LEN)));
+    }
+    if !req.is_sol() {
+        return Err(HttpResponse::BadRequest().json("Only SOL can be sent"));
+    }
 
-    let client = reqwest::Client::new();
+    let user_model = match store.get_user_by_id(user_id).await {
+        Ok(Some(user)) => user,
+        _ => return Err(HttpResponse::InternalServerError().finish()),
+    };
+
+    let mint = SOL_MINT;
+    match store.is_withdrawal_allowed(user_id, &req.to, mint).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err(HttpResponse::Forbidden().json("Destination is not an active withdrawal address"));
+        }
+        Err(_) => return Err(HttpResponse::InternalServerError().finish()),
+    }
+
+    let ledger_id = reserve_spend(store, user_id, mint, req.amount, "send").await?;
 
-    // Step 1: Call agg-send-step1 on node 1
     let step1_req = serde_json::json!({
         "end_user_pubkey": user_model.public_key,
         "node_id": 1,
         "to": req.to,
         "amount": req.amount as f64 / 1e9, // Convert lamports to SOL
-        "memo": req.mint
+        "memo": req.memo,
+        "priority": req.priority.unwrap_or_default()
     });
 
-    let step1_res = client
-        .post(format!("{}/agg-send-step1", mpc_service_url))
-        .json(&step1_req)
-        .send()
-        .await
-        .unwrap()
-        .json::<serde_json::Value>()
-        .await
-        .unwrap();
+    let signed = sign_with_mpc(mpc, step1_req, false).await;
+    settle_spend(store, ledger_id, &signed).await;
+    let signed = match signed {
+        Ok(signed) => signed,
+        Err(e) => return signing_failure("send", e),
+    };
 
-    let session_id: Uuid = serde_json::from_value(step1_res["session_id"].clone()).unwrap();
-    let agg_message_1: AggMessage1 = serde_json::from_value(step1_res["agg_message_1"].clone()).unwrap();
+    record_outgoing_transaction(
+        store,
+        user_id,
+        OutgoingTransfer {
+            signature: &signed.signature,
+            mint,
+            amount: req.amount,
+            counterparty: Some(req.to.clone()),
+            kind: "send",
+            session_id: signed.session_id,
+        },
+    )
+    .await;
 
-    // Step 2: Call agg-send-step2 on node 2
-    let step2_req = serde_json::json!({
-        "session_id": session_id,
-        "node_id": 2,
-        "agg_message_1": agg_message_1
+    Ok(Outcome::ok(
+        SendResponse {
+            id: signed.session_id,
+            signature: signed.signature.clone(),
+            fee: signed.fee,
+            priority_fee: signed.priority_fee,
+        },
+        signed.signature,
+    ))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignTransactionRequest {
+    /// Base64 legacy or versioned transaction with the wallet as a required
+    /// signer.
+    pub transaction: String,
+    /// Also send it once co-signed; every other signer must have signed
+    /// already.
+    #[serde(default)]
+    pub broadcast: bool,
+}
+
+#[derive(Serialize)]
+pub struct SignTransactionResponse {
+    /// Session id, to follow a broadcast with `/transfers/{id}`.
+    pub id: Uuid,
+    pub signature: String,
+    #[serde(rename = "signedTransaction")]
+    pub signed_transaction: String,
+    pub broadcast: bool,
+    /// Network fee in lamports.
+    pub fee: u64,
+    pub instructions: Vec<InstructionSummary>,
+    #[serde(rename = "balanceChanges")]
+    pub balance_changes: Vec<BalanceChange>,
+}
+
+/// Co-signs a transaction built by a dApp after decoding it, checking it
+/// against the wallet's policies and simulating it.
+#[actix_web::post("/transactions/sign")]
+pub async fn sign_transaction(
+    store: web::Data<Store>,
+    mpc: web::Data<dyn MpcService>,
+    user: AuthenticatedUser,
+    http_req: HttpRequest,
+    req: web::Json<SignTransactionRequest>,
+) -> Result<HttpResponse> {
+    let key = match idempotency::begin(&store, user.id, &http_req, "transactions/sign", &*req).await {
+        Ok(key) => key,
+        Err(response) => return Ok(response),
+    };
+    // Replays above go out without a fresh code; only new requests step up
+    let result = match StepUpUser::check(&http_req, user).await {
+        Ok(user) => execute_sign_transaction(&store, mpc.get_ref(), user.id, &req).await,
+        Err(e) => Err(HttpResponse::from_error(e)),
+    };
+    Ok(idempotency::finish(&store, key, result).await)
+}
+
+async fn execute_sign_transaction(
+    store: &Store,
+    mpc: &dyn MpcService,
+    user_id: Uuid,
+    req: &SignTransactionRequest,
+) -> Result<Outcome, HttpResponse> {
+    let user_model = match store.get_user_by_id(user_id).await {
+        Ok(Some(user)) => user,
+        _ => return Err(HttpResponse::InternalServerError().finish()),
+    };
+
+    let transaction: VersionedTransaction = match base64::engine::general_purpose::STANDARD
+        .decode(&req.transaction)
+        .ok()
+        .and_then(|bytes| bincode::deserialize(&bytes).ok())
+    {
+        Some(transaction) => transaction,
+        None => return Err(HttpResponse::BadRequest().json("Invalid transaction")),
+    };
+    let Ok(owner) = user_model.public_key.parse::<Pubkey>() else {
+        return Err(HttpResponse::InternalServerError().finish());
+    };
+    let Some(owner_index) = inspect::signer_index(&transaction.message, &owner) else {
+        return Err(HttpResponse::BadRequest().json("Wallet is not a required signer"));
+    };
+    if req.broadcast {
+        let missing = transaction
+            .verify_with_results()
+            .iter()
+            .enumerate()
+            .any(|(index, valid)| index != owner_index && !valid);
+        if missing {
+            return Err(HttpResponse::BadRequest().json("Other signers must sign before it can be broadcast"));
+        }
+    }
+
+    if inspect::uses_lookup_tables(&transaction.message) {
+        return Err(HttpResponse::BadRequest().json("Transactions using address lookup tables are not supported"));
+    }
+
+    let instructions = inspect::summarize(&transaction.message);
+    if let Err(e) = policy::check_transaction(&user_model.public_key, &instructions) {
+        return Err(HttpResponse::Forbidden().json(e.to_string()));
+    }
+    match policy::check_transaction_destinations(store, &user_model.public_key, &instructions).await {
+        Ok(()) => {}
+        Err(e @ MpcError::PolicyViolation(_)) => return Err(HttpResponse::Forbidden().json(e.to_string())),
+        Err(e) => {
+            log::error!("Failed to check transaction destinations for {}: {}", user_id, e);
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    }
+
+    let request = serde_json::json!({
+        "end_user_pubkey": user_model.public_key,
+        "to": "11111111111111111111111111111111", // Placeholder
+        "amount": 0, // Placeholder
+        "transaction": req.transaction,
     });
+    let preview = simulate_with_mpc(mpc, request.clone()).await?;
 
-    let step2_res = client
-        .post(format!("{}/agg-send-step2", mpc_service_url))
-        .json(&step2_req)
-        .send()
-        .await
-        .unwrap()
-        .json::<serde_json::Value>()
-        .await
-        .unwrap();
-    
-    let partial_signature_2: PartialSignature = serde_json::from_value(step2_res["partial_signature"].clone()).unwrap();
-    let agg_message_2: AggMessage1 = serde_json::from_value(step2_res["agg_message_2"].clone()).unwrap();
+    // Whatever leaves the wallet counts against the limits, fees aside
+    let mut spends = Vec::new();
+    for change in preview.balance_changes.iter().filter(|change| change.change < 0) {
+        let mut amount = change.change.unsigned_abs();
+        if change.mint == SOL_MINT {
+            amount = amount.saturating_sub(preview.fee);
+        }
+        if amount == 0 {
+            continue;
+        }
+        match reserve_spend(store, user_id, &change.mint, amount, "external").await {
+            Ok(ledger_id) => spends.push((ledger_id, change.mint.clone(), amount)),
+            Err(response) => {
+                for (ledger_id, _, _) in spends {
+                    if let Err(e) = store.fail_spend(ledger_id).await {
+                        log::error!("Failed to release spend {}: {}", ledger_id, e);
+                    }
+                }
+                return Err(response);
+            }
+        }
+    }
 
-    // Step 3: Call aggregate-signatures-broadcast on node 1
-    let broadcast_req = serde_json::json!({
-        "session_id": session_id,
-        "partial_signature_2": partial_signature_2,
-        "agg_message_2": agg_message_2
+    let mut step1_req = request;
+    step1_req["node_id"] = serde_json::json!(1);
+    step1_req["external"] = serde_json::json!(true);
+    let signed = sign_with_mpc(mpc, step1_req, !req.broadcast).await;
+    for (ledger_id, _, _) in &spends {
+        settle_spend(store, *ledger_id, &signed).await;
+    }
+    let signed = match signed {
+        Ok(signed) => signed,
+        Err(e) => return signing_failure("external transaction", e),
+    };
+
+    if req.broadcast {
+        for (_, mint, amount) in &spends {
+            record_outgoing_transaction(
+                store,
+                user_id,
+                OutgoingTransfer {
+                    signature: &signed.signature,
+                    mint,
+                    amount: *amount,
+                    counterparty: None,
+                    kind: "external",
+                    session_id: signed.session_id,
+                },
+            )
+            .await;
+        }
+    }
+
+    Ok(Outcome::ok(
+        SignTransactionResponse {
+            id: signed.session_id,
+            signature: signed.signature.clone(),
+            signed_transaction: signed.signed_transaction,
+            broadcast: req.broadcast,
+            fee: signed.fee,
+            instructions,
+            balance_changes: preview.balance_changes,
+        },
+        signed.signature,
+    ))
+}
+
+#[actix_web::post("/send/preview")]
+pub async fn send_preview(
+    store: web::Data<Store>,
+    mpc: web::Data<dyn MpcService>,
+    user: AuthenticatedUser,
+    req: web::Json<SendRequest>,
+) -> Result<HttpResponse> {
+    let user_model = match store.get_user_by_id(user.id).await {
+        Ok(Some(user)) => user,
+        _ => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    if req.memo_too_long() {
+        return Ok(HttpResponse::BadRequest().json(format!("memo must be at most {} bytes", MAX_MEMO_LEN)));
+    }
+    if !req.is_sol() {
+        return Ok(HttpResponse::BadRequest().json("Only SOL can be sent"));
+    }
+
+    let request = serde_json::json!({
+        "end_user_pubkey": user_model.public_key,
+        "to": req.to,
+        "amount": req.amount as f64 / 1e9, // Convert lamports to SOL
+        "memo": req.memo,
+        "priority": req.priority.unwrap_or_default(),
     });
+    match simulate_with_mpc(mpc.get_ref(), request).await {
+        Ok(preview) => Ok(HttpResponse::Ok().json(preview)),
+        Err(response) => Ok(response),
+    }
+}
 
-    let broadcast_res = client
-        .post(format!("{}/aggregate-signatures-broadcast", mpc_service_url))
-        .json(&broadcast_req)
-        .send()
-        .await
-        .unwrap()
-        .json::<serde_json::Value>()
-        .await
-        .unwrap();
+#[actix_web::post("/swap/preview")]
+pub async fn swap_preview(
+    store: web::Data<Store>,
+    aggregator: web::Data<dyn SwapAggregator>,
+    mpc: web::Data<dyn MpcService>,
+    user: AuthenticatedUser,
+    req: web::Json<SwapRequest>,
+) -> Result<HttpResponse> {
+    let quote = match store.get_quote(req.id).await {
+        Ok(Some(quote)) => quote,
+        Ok(None) => return Ok(HttpResponse::NotFound().finish()),
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+    if quote.user_id != user.id {
+        return Ok(HttpResponse::Forbidden().json("Quote belongs to another user"));
+    }
+    if quote.executed_at.is_some() {
+        return Ok(HttpResponse::Conflict().json("Quote was already executed"));
+    }
+    if quote.expires_at <= chrono::Utc::now() {
+        return Ok(HttpResponse::Gone().json("Quote has expired"));
+    }
+
+    let user_model = match store.get_user_by_id(user.id).await {
+        Ok(Some(user)) => user,
+        _ => return Ok(HttpResponse::InternalServerError().finish()),
+    };
 
-    let signature = broadcast_res["transaction_signature"].as_str().unwrap().to_string();
+    let swap_transaction = match aggregator
+        .swap_transaction(&user_model.public_key, &quote.quote_response)
+        .await
+    {
+        Ok(swap_transaction) => swap_transaction.transaction,
+        Err(e) => {
+            log::error!("Failed to build swap transaction: {}", e);
+            return Ok(HttpResponse::BadGateway().json("Failed to build swap transaction"));
+        }
+    };
 
-    Ok(HttpResponse::Ok().json(SendResponse { signature }))
+    let request = serde_json::json!({
+        "end_user_pubkey": user_model.public_key,
+        "to": "11111111111111111111111111111111", // Placeholder
+        "amount": 0, // Placeholder
+        "transaction": swap_transaction
+    });
+    match simulate_with_mpc(mpc.get_ref(), request).await {
+        Ok(preview) => Ok(HttpResponse::Ok().json(preview)),
+        Err(response) => Ok(response),
+    }
 }
 
 #[actix_web::get("/balance/sol")]
@@ -310,16 +954,39 @@ pub async fn sol_balance(
 pub async fn token_balance(
     store: web::Data<Store>,
     user: AuthenticatedUser,
+    query: web::Query<TokenBalanceQuery>,
 ) -> Result<HttpResponse> {
+    let holdings = if query.breakdown {
+        match store.get_token_account_holdings(user.id).await {
+            Ok(holdings) => Some(holdings),
+            Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+        }
+    } else {
+        None
+    };
+
     match store.get_token_balances(user.id).await {
         Ok(balances) => {
             let token_balances = balances
                 .into_iter()
-                .map(|(balance, asset)| TokenBalance {
-                    balance: balance.amount as u64,
-                    token_mint: asset.mint_address,
-                    symbol: asset.symbol,
-                    decimals: asset.decimals,
+                .map(|(balance, asset)| {
+                    let accounts = holdings.as_ref().map(|holdings| {
+                        holdings
+                            .iter()
+                            .filter(|holding| holding.mint_address == asset.mint_address)
+                            .map(|holding| TokenAccountBalance {
+                                address: holding.token_account.clone(),
+                                balance: holding.amount as u64,
+                            })
+                            .collect()
+                    });
+                    TokenBalance {
+                        balance: balance.amount as u64,
+                        token_mint: asset.mint_address,
+                        symbol: asset.symbol,
+                        decimals: asset.decimals,
+                        accounts,
+                    }
                 })
                 .collect();
             let response = TokenBalanceResponse {
@@ -330,3 +997,278 @@ pub async fn token_balance(
         Err(_) => Ok(HttpResponse::InternalServerError().finish()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::MockAggregator;
+    use crate::auth::{tests::test_keys, JwtKeys};
+    use crate::middleware::TOTP_HEADER;
+    use crate::totp::{generate_recovery_codes, generate_secret, hash_recovery_code, SecretCipher};
+    use actix_web::{test, App};
+    use async_trait::async_trait;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use std::sync::{Arc, Mutex};
+    use store::user::CreateUserRequest;
+
+    const USDC: &str = "EPjFWvd5wAuVKeJnJBfB3T6QsaDt1AQYFxwHCHL5qD1F";
+
+    /// Signs every request with a presigned pair and records the paths it
+    /// was called on.
+    #[derive(Default)]
+    struct MockMpc {
+        calls: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl MpcService for MockMpc {
+        async fn post(&self, path: &str, _body: &Value) -> Result<Value, MpcCallError> {
+            self.calls.lock().unwrap().push(path.to_string());
+            Ok(json!({
+                "session_id": Uuid::new_v4(),
+                "transaction_signature": "sig",
+                "signed_transaction": "",
+                "fee": 5000,
+            }))
+        }
+
+        async fn is_ready(&self) -> bool {
+            true
+        }
+    }
+
+    struct TestUser {
+        id: Uuid,
+        token: String,
+        recovery_codes: Vec<String>,
+    }
+
+    /// A signed-in user with a verified email and two-factor enabled.
+    async fn test_user(store: &Store, keys: &JwtKeys, cipher: &SecretCipher) -> TestUser {
+        let user = store
+            .create_user(CreateUserRequest {
+                email: format!("{}@example.com", Uuid::new_v4()),
+                password: "password".to_string(),
+                public_key: Pubkey::new_unique().to_string(),
+            })
+            .await
+            .unwrap();
+        sqlx::query("UPDATE users SET email_verified_at = NOW() WHERE id = $1")
+            .bind(user.id)
+            .execute(&store.pool)
+            .await
+            .unwrap();
+
+        let recovery_codes = generate_recovery_codes();
+        let hashes: Vec<String> = recovery_codes.iter().map(|code| hash_recovery_code(code)).collect();
+        store
+            .begin_totp_enrollment(user.id, &cipher.seal(user.id, &generate_secret()))
+            .await
+            .unwrap();
+        store.confirm_totp(user.id, 0, &hashes).await.unwrap();
+
+        let session = store
+            .create_session(user.id, "refresh-token-hash", chrono::Utc::now() + chrono::Duration::days(1))
+            .await
+            .unwrap();
+        TestUser {
+            id: user.id,
+            token: keys.create_jwt(user.id, session.id).unwrap(),
+            recovery_codes,
+        }
+    }
+
+    fn quote_request(token: &str, body: Value) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/api/v1/quote")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(body)
+    }
+
+    fn swap_request(token: &str, quote_id: Uuid, code: Option<&str>) -> test::TestRequest {
+        let request = test::TestRequest::post()
+            .uri("/api/v1/swap")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(json!({ "id": quote_id }));
+        match code {
+            Some(code) => request.insert_header((TOTP_HEADER, code)),
+            None => request,
+        }
+    }
+
+    #[sqlx::test(migrations = "../store/migrations")]
+    async fn test_token_send_is_rejected(pool: PgPool) {
+        let store = Store::new(pool);
+        let keys = test_keys();
+        let cipher = SecretCipher::new([7; 32]);
+        let user = test_user(&store, &keys, &cipher).await;
+        let mpc = Arc::new(MockMpc::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Store::new(store.pool.clone())))
+                .app_data(web::Data::new(keys))
+                .app_data(web::Data::from(mpc.clone() as Arc<dyn MpcService>))
+                .app_data(web::Data::new(cipher))
+                .service(web::scope("/api/v1").service(send)),
+        )
+        .await;
+
+        let request = test::TestRequest::post()
+            .uri("/api/v1/send")
+            .insert_header(("Authorization", format!("Bearer {}", user.token)))
+            .insert_header((TOTP_HEADER, user.recovery_codes[0].as_str()))
+            .set_json(json!({ "to": Pubkey::new_unique().to_string(), "amount": 1000, "mint": USDC }));
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(mpc.calls.lock().unwrap().is_empty());
+    }
+
+    /// Refuses presigned signing with `presigned_status` and fails every
+    /// other call, recording the paths it was called on.
+    struct RefusingMpc {
+        presigned_status: reqwest::StatusCode,
+        calls: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl MpcService for RefusingMpc {
+        async fn post(&self, path: &str, _body: &Value) -> Result<Value, MpcCallError> {
+            self.calls.lock().unwrap().push(path.to_string());
+            let status = match path {
+                "/agg-send-presigned" => self.presigned_status,
+                _ => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Err(MpcCallError { status: Some(status), message: String::new() })
+        }
+
+        async fn is_ready(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_running_out_of_presigned_nonces_falls_back() {
+        let exhausted = RefusingMpc {
+            presigned_status: reqwest::StatusCode::PRECONDITION_FAILED,
+            calls: Mutex::new(Vec::new()),
+        };
+        assert!(sign_with_mpc(&exhausted, json!({ "node_id": 1 }), false).await.is_err());
+        assert_eq!(*exhausted.calls.lock().unwrap(), vec!["/agg-send-presigned", "/agg-send-step1"]);
+
+        // A nonce in use or a refresh under way is not solved by another round
+        let busy = RefusingMpc {
+            presigned_status: reqwest::StatusCode::CONFLICT,
+            calls: Mutex::new(Vec::new()),
+        };
+        let result = sign_with_mpc(&busy, json!({ "node_id": 1 }), false).await;
+        assert!(matches!(result, Err(MpcSigningError::Busy(_))));
+        assert_eq!(*busy.calls.lock().unwrap(), vec!["/agg-send-presigned"]);
+    }
+
+    #[test]
+    fn test_mpc_policy_refusal_is_forbidden() {
+        let error = MpcSigningError::from(MpcCallError {
+            status: Some(reqwest::StatusCode::FORBIDDEN),
+            message: "rejected by policy".to_string(),
+        });
+        assert!(matches!(error, MpcSigningError::Policy(_)));
+        let response = signing_failure("external transaction", error).err().unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_memo_limit_counts_bytes() {
+        let send = |memo: String| SendRequest {
+            to: String::new(),
+            amount: 1,
+            mint: None,
+            memo: Some(memo),
+            priority: None,
+        };
+        assert!(!send("a".repeat(MAX_MEMO_LEN)).memo_too_long());
+        assert!(send("a".repeat(MAX_MEMO_LEN + 1)).memo_too_long());
+        assert!(send("é".repeat(MAX_MEMO_LEN / 2 + 1)).memo_too_long());
+    }
+
+    #[sqlx::test(migrations = "../store/migrations")]
+    async fn test_quote_is_stored_at_the_aggregator_rate(pool: PgPool) {
+        let store = Store::new(pool);
+        let keys = test_keys();
+        let cipher = SecretCipher::new([7; 32]);
+        let user = test_user(&store, &keys, &cipher).await;
+        let aggregator: Arc<dyn SwapAggregator> = Arc::new(MockAggregator::new(2.0));
+        let mpc = Arc::new(MockMpc::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Store::new(store.pool.clone())))
+                .app_data(web::Data::new(keys))
+                .app_data(web::Data::from(aggregator))
+                .app_data(web::Data::from(mpc.clone() as Arc<dyn MpcService>))
+                .app_data(web::Data::new(cipher))
+                .service(web::scope("/api/v1").service(quote).service(swap)),
+        )
+        .await;
+
+        let body = json!({ "inputMint": SOL_MINT, "outputMint": USDC, "inAmount": 1000, "slippageBps": 100 });
+        let response: Value = test::call_and_read_body_json(&app, quote_request(&user.token, body).to_request()).await;
+        assert_eq!(response["inAmount"], "1000");
+        assert_eq!(response["outAmount"], "2000");
+        assert_eq!(response["otherAmountThreshold"], "1980");
+
+        let quote_id: Uuid = serde_json::from_value(response["id"].clone()).unwrap();
+        let stored = store.get_quote(quote_id).await.unwrap().unwrap();
+        assert_eq!(stored.user_id, user.id);
+        assert_eq!(stored.out_amount, 2000);
+        assert!(stored.executed_at.is_none());
+
+        let too_loose = json!({ "inputMint": SOL_MINT, "outputMint": USDC, "inAmount": 1000, "slippageBps": 6000 });
+        let response = test::call_service(&app, quote_request(&user.token, too_loose).to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(mpc.calls.lock().unwrap().is_empty());
+    }
+
+    #[sqlx::test(migrations = "../store/migrations")]
+    async fn test_quote_is_swapped_once(pool: PgPool) {
+        let store = Store::new(pool);
+        let keys = test_keys();
+        let cipher = SecretCipher::new([7; 32]);
+        let user = test_user(&store, &keys, &cipher).await;
+        let aggregator: Arc<dyn SwapAggregator> = Arc::new(MockAggregator::new(2.0));
+        let mpc = Arc::new(MockMpc::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Store::new(store.pool.clone())))
+                .app_data(web::Data::new(keys))
+                .app_data(web::Data::from(aggregator))
+                .app_data(web::Data::from(mpc.clone() as Arc<dyn MpcService>))
+                .app_data(web::Data::new(cipher))
+                .service(web::scope("/api/v1").service(quote).service(swap)),
+        )
+        .await;
+
+        let body = json!({ "inputMint": SOL_MINT, "outputMint": USDC, "inAmount": 1000 });
+        let response: Value = test::call_and_read_body_json(&app, quote_request(&user.token, body).to_request()).await;
+        let quote_id: Uuid = serde_json::from_value(response["id"].clone()).unwrap();
+
+        // Without a second factor nothing is signed and the quote stays usable
+        let response = test::call_service(&app, swap_request(&user.token, quote_id, None).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(store.get_quote(quote_id).await.unwrap().unwrap().executed_at.is_none());
+        assert!(mpc.calls.lock().unwrap().is_empty());
+
+        let code = Some(user.recovery_codes[0].as_str());
+        let response = test::call_service(&app, swap_request(&user.token, quote_id, code).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: Value = test::read_body_json(response).await;
+        assert_eq!(response["swapTransaction"], "sig");
+        assert_eq!(response["fee"], 5000);
+        assert!(store.get_quote(quote_id).await.unwrap().unwrap().executed_at.is_some());
+        assert_eq!(*mpc.calls.lock().unwrap(), vec!["/agg-send-presigned".to_string()]);
+
+        let code = Some(user.recovery_codes[1].as_str());
+        let response = test::call_service(&app, swap_request(&user.token, quote_id, code).to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(mpc.calls.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,50 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::Serialize;
+use store::spending::SpendingAllowance;
+use store::Store;
+use crate::middleware::AuthenticatedUser;
+
+#[derive(Serialize)]
+pub struct SpendingLimitItem {
+    pub mint: String,
+    #[serde(rename = "perTransactionLimit")]
+    pub per_transaction_limit: Option<u64>,
+    #[serde(rename = "dailyLimit")]
+    pub daily_limit: Option<u64>,
+    #[serde(rename = "spentLast24h")]
+    pub spent_last_24h: u64,
+    /// What can still be sent in the rolling 24-hour window, or `null` when
+    /// there is no daily limit.
+    #[serde(rename = "remaining24h")]
+    pub remaining_24h: Option<u64>,
+}
+
+impl From<SpendingAllowance> for SpendingLimitItem {
+    fn from(allowance: SpendingAllowance) -> Self {
+        Self {
+            remaining_24h: allowance.remaining_24h().map(|v| v as u64),
+            mint: allowance.mint,
+            per_transaction_limit: allowance.per_transaction_limit.map(|v| v as u64),
+            daily_limit: allowance.daily_limit.map(|v| v as u64),
+            spent_last_24h: allowance.spent_24h as u64,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SpendingLimitsResponse {
+    pub limits: Vec<SpendingLimitItem>,
+}
+
+#[actix_web::get("/limits")]
+pub async fn spending_limits(
+    store: web::Data<Store>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse> {
+    match store.get_spending_allowances(user.id).await {
+        Ok(allowances) => Ok(HttpResponse::Ok().json(SpendingLimitsResponse {
+            limits: allowances.into_iter().map(SpendingLimitItem::from).collect(),
+        })),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
@@ -0,0 +1,179 @@
+use actix_web::{web, HttpResponse, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::middleware::AuthenticatedUser;
+use store::models::transaction::TransactionEntry;
+use store::transaction::TransactionFilter;
+use store::Store;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct TransactionsQuery {
+    pub mint: Option<String>,
+    pub direction: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct TransactionItem {
+    pub signature: String,
+    pub direction: String,
+    pub amount: u64,
+    #[serde(rename = "tokenMint")]
+    pub token_mint: String,
+    pub symbol: String,
+    pub decimals: i32,
+    pub counterparty: Option<String>,
+    pub slot: Option<i64>,
+    #[serde(rename = "blockTime")]
+    pub block_time: DateTime<Utc>,
+    pub kind: String,
+    pub status: String,
+    #[serde(rename = "sessionId")]
+    pub session_id: Option<Uuid>,
+}
+
+impl From<TransactionEntry> for TransactionItem {
+    fn from(entry: TransactionEntry) -> Self {
+        Self {
+            signature: entry.signature,
+            direction: entry.direction,
+            amount: entry.amount as u64,
+            token_mint: entry.mint_address,
+            symbol: entry.symbol,
+            decimals: entry.decimals,
+            counterparty: entry.counterparty,
+            slot: entry.slot,
+            block_time: entry.block_time,
+            kind: entry.kind,
+            status: entry.status,
+            session_id: entry.mpc_session_id,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TransactionsResponse {
+    pub transactions: Vec<TransactionItem>,
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<String>,
+}
+
+/// Encodes the position of the last entry of a page as an opaque cursor.
+fn encode_cursor(block_time: DateTime<Utc>, signature: &str, id: Uuid) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}:{}:{}", block_time.timestamp_micros(), signature, id))
+}
+
+fn decode_cursor(cursor: &str) -> Option<(DateTime<Utc>, String, Uuid)> {
+    let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    let raw = String::from_utf8(bytes).ok()?;
+    let (micros, rest) = raw.split_once(':')?;
+    let (signature, id) = rest.rsplit_once(':')?;
+    let block_time = DateTime::from_timestamp_micros(micros.parse().ok()?)?;
+    if signature.is_empty() {
+        return None;
+    }
+    Some((block_time, signature.to_string(), id.parse().ok()?))
+}
+
+#[actix_web::get("/transactions")]
+pub async fn transactions(
+    store: web::Data<Store>,
+    user: AuthenticatedUser,
+    query: web::Query<TransactionsQuery>,
+) -> Result<HttpResponse> {
+    let query = query.into_inner();
+
+    if let Some(direction) = &query.direction {
+        if direction != "incoming" && direction != "outgoing" {
+            return Ok(HttpResponse::BadRequest().json("direction must be incoming or outgoing"));
+        }
+    }
+
+    let before = match query.cursor.as_deref().map(decode_cursor) {
+        Some(Some(position)) => Some(position),
+        Some(None) => return Ok(HttpResponse::BadRequest().json("Invalid cursor")),
+        None => None,
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let filter = TransactionFilter {
+        mint: query.mint,
+        direction: query.direction,
+        from: query.from,
+        to: query.to,
+        before,
+    };
+
+    // Fetch one extra row to learn whether another page exists.
+    let mut entries = match store.list_transactions(user.id, &filter, limit + 1).await {
+        Ok(entries) => entries,
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    let next_cursor = if entries.len() as i64 > limit {
+        entries.truncate(limit as usize);
+        entries
+            .last()
+            .map(|last| encode_cursor(last.block_time, &last.signature, last.id))
+    } else {
+        None
+    };
+
+    let transactions = entries.into_iter().map(TransactionItem::from).collect();
+
+    Ok(HttpResponse::Ok().json(TransactionsResponse {
+        transactions,
+        next_cursor,
+    }))
+}
+
+/// A send or swap by its id, the `id` returned when it was submitted. The
+/// status moves from `submitted` to `confirmed`, `failed` or `expired` as the
+/// MPC service tracks the transaction.
+#[actix_web::get("/transfers/{id}")]
+pub async fn transfer(
+    store: web::Data<Store>,
+    user: AuthenticatedUser,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse> {
+    match store.get_transaction_by_session(user.id, path.into_inner()).await {
+        Ok(Some(entry)) => Ok(HttpResponse::Ok().json(TransactionItem::from(entry))),
+        Ok(None) => Ok(HttpResponse::NotFound().finish()),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_cursor, encode_cursor};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use chrono::DateTime;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let block_time = DateTime::from_timestamp_micros(1_730_000_000_123_456).unwrap();
+        let signature = "5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnbJLgp8uirBgmQpjKhoR4tjF3ZpRzrFmBV6UjKdiSZkQUW";
+        let id = Uuid::new_v4();
+
+        let cursor = encode_cursor(block_time, signature, id);
+        assert_eq!(decode_cursor(&cursor), Some((block_time, signature.to_string(), id)));
+    }
+
+    #[test]
+    fn test_cursor_rejects_garbage() {
+        assert_eq!(decode_cursor("not a cursor"), None);
+        assert_eq!(decode_cursor(""), None);
+        // Cursors without the row id cannot place a page between rows of one signature
+        let signature_only = URL_SAFE_NO_PAD.encode("1730000000123456:5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnb");
+        assert_eq!(decode_cursor(&signature_only), None);
+    }
+}
@@ -0,0 +1,238 @@
+use actix_web::{web, HttpResponse, Result};
+use bcrypt::verify;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use store::models::user::User;
+use store::totp::TotpError;
+use store::Store;
+use crate::middleware::{AuthenticatedUser, StepUpUser};
+use crate::totp::{
+    generate_recovery_codes, generate_secret, hash_recovery_code, provisioning_uri, verify_code,
+    SecretCipher,
+};
+
+#[derive(Serialize)]
+pub struct TwoFactorStatusResponse {
+    pub enabled: bool,
+    #[serde(rename = "recoveryCodesRemaining")]
+    pub recovery_codes_remaining: i64,
+}
+
+#[derive(Serialize)]
+pub struct TwoFactorSetupResponse {
+    pub secret: String,
+    #[serde(rename = "otpauthUri")]
+    pub otpauth_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorSetupRequest {
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorConfirmRequest {
+    pub code: String,
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodesResponse {
+    #[serde(rename = "recoveryCodes")]
+    pub recovery_codes: Vec<String>,
+}
+
+#[actix_web::get("/auth/2fa")]
+pub async fn two_factor_status(
+    store: web::Data<Store>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse> {
+    let enabled = match store.get_totp(user.id).await {
+        Ok(totp) => totp.is_some_and(|totp| totp.is_enabled()),
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+    let recovery_codes_remaining = match store.count_unused_recovery_codes(user.id).await {
+        Ok(count) => count,
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    Ok(HttpResponse::Ok().json(TwoFactorStatusResponse {
+        enabled,
+        recovery_codes_remaining,
+    }))
+}
+
+/// Loads the user if `password` is theirs. Enrollment has no second factor to
+/// step up with yet, so a stolen access token alone must not be enough to tie
+/// the account to someone else's authenticator.
+async fn check_password(store: &Store, user_id: Uuid, password: &str) -> Result<User, HttpResponse> {
+    let user = match store.get_user_by_id(user_id).await {
+        Ok(Some(user)) => user,
+        _ => return Err(HttpResponse::InternalServerError().finish()),
+    };
+    if !matches!(verify(password, &user.password_hash), Ok(true)) {
+        return Err(HttpResponse::Unauthorized().json("Invalid password"));
+    }
+    Ok(user)
+}
+
+/// Starts enrollment with a new secret. It only takes effect once confirmed.
+#[actix_web::post("/auth/2fa/setup")]
+pub async fn two_factor_setup(
+    store: web::Data<Store>,
+    cipher: web::Data<SecretCipher>,
+    user: AuthenticatedUser,
+    req: web::Json<TwoFactorSetupRequest>,
+) -> Result<HttpResponse> {
+    let user_model = match check_password(&store, user.id, &req.password).await {
+        Ok(user) => user,
+        Err(response) => return Ok(response),
+    };
+
+    let secret = generate_secret();
+    match store.begin_totp_enrollment(user.id, &cipher.seal(user.id, &secret)).await {
+        Ok(_) => Ok(HttpResponse::Ok().json(TwoFactorSetupResponse {
+            otpauth_uri: provisioning_uri(&secret, &user_model.email),
+            secret,
+        })),
+        Err(TotpError::AlreadyEnabled) => {
+            Ok(HttpResponse::Conflict().json("Two-factor authentication is already enabled"))
+        }
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+/// Enables two-factor once the user proves their app produces valid codes,
+/// and returns recovery codes. They are shown only this once.
+#[actix_web::post("/auth/2fa/confirm")]
+pub async fn two_factor_confirm(
+    store: web::Data<Store>,
+    cipher: web::Data<SecretCipher>,
+    user: AuthenticatedUser,
+    req: web::Json<TwoFactorConfirmRequest>,
+) -> Result<HttpResponse> {
+    if let Err(response) = check_password(&store, user.id, &req.password).await {
+        return Ok(response);
+    }
+
+    let totp = match store.get_totp(user.id).await {
+        Ok(Some(totp)) if !totp.is_enabled() => totp,
+        Ok(Some(_)) => {
+            return Ok(HttpResponse::Conflict().json("Two-factor authentication is already enabled"));
+        }
+        Ok(None) => return Ok(HttpResponse::NotFound().json("Two-factor setup not started")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    let Some(secret) = cipher.open(user.id, &totp.secret) else {
+        return Ok(HttpResponse::InternalServerError().finish());
+    };
+    let Some(step) = verify_code(&secret, req.code.trim(), Utc::now().timestamp()) else {
+        return Ok(HttpResponse::Unauthorized().json("Invalid two-factor code"));
+    };
+
+    let recovery_codes = generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes.iter().map(|code| hash_recovery_code(code)).collect();
+
+    match store.confirm_totp(user.id, step, &hashes).await {
+        Ok(()) => Ok(HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes })),
+        Err(TotpError::NotEnrolled) => Ok(HttpResponse::Conflict().finish()),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[actix_web::post("/auth/2fa/recovery-codes")]
+pub async fn regenerate_recovery_codes(
+    store: web::Data<Store>,
+    user: StepUpUser,
+) -> Result<HttpResponse> {
+    let recovery_codes = generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes.iter().map(|code| hash_recovery_code(code)).collect();
+
+    match store.replace_recovery_codes(user.id, &hashes).await {
+        Ok(()) => Ok(HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes })),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[actix_web::post("/auth/2fa/disable")]
+pub async fn two_factor_disable(
+    store: web::Data<Store>,
+    user: StepUpUser,
+) -> Result<HttpResponse> {
+    match store.disable_totp(user.id).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::tests::test_keys;
+    use crate::totp::code_at;
+    use data_encoding::BASE32_NOPAD;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use store::test_support::{new_user, PASSWORD};
+
+    fn post(uri: &str, token: &str, body: Value) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(body)
+    }
+
+    #[sqlx::test(migrations = "../store/migrations")]
+    async fn test_enrollment_requires_the_password(pool: PgPool) {
+        let store = Store::new(pool);
+        let keys = test_keys();
+        let user_id = new_user(&store).await;
+        let session = store
+            .create_session(user_id, "refresh-token-hash", Utc::now() + chrono::Duration::days(1))
+            .await
+            .unwrap();
+        let token = keys.create_jwt(user_id, session.id).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Store::new(store.pool.clone())))
+                .app_data(web::Data::new(keys))
+                .app_data(web::Data::new(SecretCipher::new([7; 32])))
+                .service(two_factor_setup)
+                .service(two_factor_confirm),
+        )
+        .await;
+
+        let response = test::call_service(&app, post("/auth/2fa/setup", &token, json!({})).to_request()).await;
+        assert_eq!(response.status(), 400);
+        let response =
+            test::call_service(&app, post("/auth/2fa/setup", &token, json!({ "password": "wrong" })).to_request()).await;
+        assert_eq!(response.status(), 401);
+        assert!(store.get_totp(user_id).await.unwrap().is_none());
+
+        let setup: Value =
+            test::call_and_read_body_json(&app, post("/auth/2fa/setup", &token, json!({ "password": PASSWORD })).to_request())
+                .await;
+        let secret = setup["secret"].as_str().unwrap();
+        let key = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+        let code = code_at(&key, Utc::now().timestamp() / 30);
+
+        let response = test::call_service(
+            &app,
+            post("/auth/2fa/confirm", &token, json!({ "code": code, "password": "wrong" })).to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 401);
+        assert!(!store.get_totp(user_id).await.unwrap().unwrap().is_enabled());
+
+        let response = test::call_service(
+            &app,
+            post("/auth/2fa/confirm", &token, json!({ "code": code, "password": PASSWORD })).to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        assert!(store.get_totp(user_id).await.unwrap().unwrap().is_enabled());
+    }
+}
@@ -1,11 +1,17 @@
 use actix_web::{web, HttpResponse, Result};
 use serde::{Deserialize, Serialize};
 use solana_sdk::signer::{keypair::Keypair, Signer};
-use store::user::CreateUserRequest;
+use store::user::{CreateUserRequest, UserError};
 use store::Store;
-use crate::auth::create_jwt;
 use bcrypt::verify;
+use chrono::Utc;
+use crate::auth::JwtKeys;
+use crate::mailer::Mailer;
 use crate::middleware::AuthenticatedUser;
+use crate::rate_limit::{login_lockout, too_many_requests};
+use crate::routes::account::send_verification_email;
+use crate::routes::auth::start_session;
+use crate::totp::{verify_second_factor, SecondFactorError, SecretCipher};
 
 #[derive(Deserialize)]
 pub struct SignUpRequest {
@@ -17,16 +23,16 @@ pub struct SignUpRequest {
 pub struct SignInRequest {
     pub email: String,
     pub password: String,
+    /// TOTP or recovery code, required once two-factor is enabled.
+    #[serde(rename = "totpCode")]
+    pub totp_code: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct UserResponse {
     pub email: String,
-}
-
-#[derive(Serialize)]
-pub struct AuthResponse {
-    pub token: String,
+    #[serde(rename = "emailVerified")]
+    pub email_verified: bool,
 }
 
 #[derive(Serialize)]
@@ -37,6 +43,7 @@ pub struct SignupResponse {
 #[actix_web::post("/signup")]
 pub async fn sign_up(
     store: web::Data<Store>,
+    mailer: web::Data<dyn Mailer>,
     req: web::Json<SignUpRequest>,
 ) -> Result<HttpResponse> {
     let keypair = Keypair::new();
@@ -49,16 +56,18 @@ pub async fn sign_up(
     };
 
     match store.create_user(create_user_request).await {
-        Ok(_) => {
+        Ok(user) => {
             if let Err(e) = store.add_public_key(&public_key).await {
                 // TODO: Handle this error case more gracefully
                 log::error!("Failed to add public key to watch list: {}", e);
             }
+            send_verification_email(&store, mailer.get_ref(), &user).await;
             let response = SignupResponse {
-                message: "User created successfully".to_string(),
+                message: "User created successfully. Check your email to verify your address.".to_string(),
             };
             Ok(HttpResponse::Created().json(response))
         }
+        Err(UserError::InvalidInput(msg)) => Ok(HttpResponse::BadRequest().json(msg)),
         Err(e) => Ok(HttpResponse::InternalServerError().json(e.to_string())),
     }
 }
@@ -66,21 +75,78 @@ pub async fn sign_up(
 #[actix_web::post("/signin")]
 pub async fn sign_in(
     store: web::Data<Store>,
+    keys: web::Data<JwtKeys>,
+    cipher: web::Data<SecretCipher>,
     req: web::Json<SignInRequest>,
 ) -> Result<HttpResponse> {
+    let lockout_key = req.email.trim().to_lowercase();
+    match store.get_login_lock(&lockout_key).await {
+        Ok(Some(locked_until)) => {
+            let retry_after = (locked_until - Utc::now()).to_std().unwrap_or_default();
+            return Ok(too_many_requests(retry_after));
+        }
+        Ok(None) => {}
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    }
+
     let user = match store.get_user_by_email(&req.email).await {
         Ok(Some(user)) => user,
-        Ok(None) => return Ok(HttpResponse::Unauthorized().finish()),
+        Ok(None) => {
+            record_failed_login(&store, &lockout_key).await;
+            return Ok(HttpResponse::Unauthorized().finish());
+        }
         Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
     };
 
-    match verify(&req.password, &user.password_hash) {
-        Ok(true) => {
-            let token = create_jwt(user.id).unwrap();
-            let response = AuthResponse { token };
-            Ok(HttpResponse::Ok().json(response))
+    if !matches!(verify(&req.password, &user.password_hash), Ok(true)) {
+        record_failed_login(&store, &lockout_key).await;
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    if let Some(code) = &req.totp_code {
+        match verify_second_factor(&store, &cipher, user.id, code).await {
+            Ok(()) | Err(SecondFactorError::NotEnrolled) => {}
+            Err(SecondFactorError::InvalidCode) => {
+                record_failed_login(&store, &lockout_key).await;
+                return Ok(HttpResponse::Unauthorized().json("Invalid two-factor code"));
+            }
+            Err(SecondFactorError::Store(_) | SecondFactorError::UnreadableSecret) => return Ok(HttpResponse::InternalServerError().finish()),
+        }
+    } else {
+        match store.get_totp(user.id).await {
+            Ok(Some(totp)) if totp.is_enabled() => {
+                return Ok(HttpResponse::Unauthorized().json("Two-factor code required"));
+            }
+            Ok(_) => {}
+            Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+        }
+    }
+
+    if let Err(e) = store.clear_failed_logins(&lockout_key).await {
+        log::error!("Failed to clear failed logins for {}: {}", user.id, e);
+    }
+
+    match start_session(&store, &keys, user.id).await {
+        Ok(response) => Ok(HttpResponse::Ok().json(response)),
+        Err(response) => Ok(response),
+    }
+}
+
+/// Counts a failed sign-in and locks the email once there are too many.
+async fn record_failed_login(store: &Store, email: &str) {
+    let failed_count = match store.record_failed_login(email).await {
+        Ok(count) => count,
+        Err(e) => {
+            log::error!("Failed to record failed login: {}", e);
+            return;
+        }
+    };
+
+    if let Some(delay) = login_lockout(failed_count) {
+        let locked_until = Utc::now() + chrono::Duration::from_std(delay).unwrap_or_default();
+        if let Err(e) = store.lock_login(email, locked_until).await {
+            log::error!("Failed to lock login: {}", e);
         }
-        _ => Ok(HttpResponse::Unauthorized().finish()),
     }
 }
 
@@ -91,7 +157,10 @@ pub async fn get_user(
 ) -> Result<HttpResponse> {
     match store.get_user_by_id(user.id).await {
         Ok(Some(user)) => {
-            let user_response = UserResponse { email: user.email };
+            let user_response = UserResponse {
+                email_verified: user.email_verified_at.is_some(),
+                email: user.email,
+            };
             Ok(HttpResponse::Ok().json(user_response))
         }
         Ok(None) => Ok(HttpResponse::NotFound().finish()),
@@ -0,0 +1,247 @@
+use actix_web::{web, HttpResponse, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::str::FromStr;
+use store::models::withdrawal_address::{WithdrawalAddress, WithdrawalSettings};
+use store::withdrawal_address::{NewWithdrawalAddress, WithdrawalAddressError};
+use store::Store;
+use uuid::Uuid;
+use crate::auth::{generate_token, hash_token};
+use crate::mailer::{strict_mode_relaxing_email, withdrawal_address_email, Mailer};
+use crate::middleware::{AuthenticatedUser, StepUpUser};
+
+const CONFIRMATION_TTL: Duration = Duration::hours(24);
+const MAX_LABEL_LENGTH: usize = 64;
+
+/// Delay between adding an address, or turning strict mode off, and it
+/// taking effect, from `WITHDRAWAL_ADDRESS_COOLING_OFF_HOURS` (24 by default).
+fn cooling_off_period() -> Duration {
+    let hours = std::env::var("WITHDRAWAL_ADDRESS_COOLING_OFF_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(24);
+    Duration::hours(hours)
+}
+
+#[derive(Deserialize)]
+pub struct AddWithdrawalAddressRequest {
+    pub address: String,
+    pub label: String,
+    pub mint: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmWithdrawalAddressRequest {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct WithdrawalSettingsRequest {
+    pub strict: bool,
+}
+
+#[derive(Serialize)]
+pub struct WithdrawalAddressItem {
+    pub id: Uuid,
+    pub address: String,
+    pub label: String,
+    pub mint: Option<String>,
+    /// `pending_confirmation`, `cooling_off` or `active`.
+    pub status: String,
+    #[serde(rename = "activatesAt")]
+    pub activates_at: DateTime<Utc>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<WithdrawalAddress> for WithdrawalAddressItem {
+    fn from(address: WithdrawalAddress) -> Self {
+        let status = if address.confirmed_at.is_none() {
+            "pending_confirmation"
+        } else if address.is_active(Utc::now()) {
+            "active"
+        } else {
+            "cooling_off"
+        };
+        Self {
+            id: address.id,
+            address: address.address,
+            label: address.label,
+            mint: address.mint,
+            status: status.to_string(),
+            activates_at: address.activates_at,
+            created_at: address.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct WithdrawalSettingsResponse {
+    pub strict: bool,
+    /// When strict mode turns off, if that was asked for.
+    #[serde(rename = "strictRelaxesAt")]
+    pub strict_relaxes_at: Option<DateTime<Utc>>,
+}
+
+impl From<WithdrawalSettings> for WithdrawalSettingsResponse {
+    fn from(settings: WithdrawalSettings) -> Self {
+        let strict = settings.is_strict(Utc::now());
+        Self {
+            strict,
+            strict_relaxes_at: settings.relaxes_at.filter(|_| strict),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct WithdrawalAddressesResponse {
+    #[serde(flatten)]
+    pub settings: WithdrawalSettingsResponse,
+    pub addresses: Vec<WithdrawalAddressItem>,
+}
+
+#[actix_web::get("/withdrawal-addresses")]
+pub async fn list_withdrawal_addresses(
+    store: web::Data<Store>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse> {
+    let settings = match store.get_withdrawal_settings(user.id).await {
+        Ok(settings) => settings,
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    match store.list_withdrawal_addresses(user.id).await {
+        Ok(addresses) => Ok(HttpResponse::Ok().json(WithdrawalAddressesResponse {
+            settings: settings.into(),
+            addresses: addresses.into_iter().map(WithdrawalAddressItem::from).collect(),
+        })),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+/// Adds a destination and emails a confirmation link. The address can be used
+/// once it is confirmed and the cooling-off period has passed.
+#[actix_web::post("/withdrawal-addresses")]
+pub async fn add_withdrawal_address(
+    store: web::Data<Store>,
+    mailer: web::Data<dyn Mailer>,
+    user: StepUpUser,
+    req: web::Json<AddWithdrawalAddressRequest>,
+) -> Result<HttpResponse> {
+    let req = req.into_inner();
+
+    if Pubkey::from_str(&req.address).is_err() {
+        return Ok(HttpResponse::BadRequest().json("Invalid address"));
+    }
+    if let Some(mint) = &req.mint {
+        if Pubkey::from_str(mint).is_err() {
+            return Ok(HttpResponse::BadRequest().json("Invalid mint"));
+        }
+    }
+    let label = req.label.trim().to_string();
+    if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+        return Ok(HttpResponse::BadRequest().json("Label must be 1 to 64 characters"));
+    }
+
+    let user_model = match store.get_user_by_id(user.id).await {
+        Ok(Some(user)) => user,
+        _ => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    let now = Utc::now();
+    let token = generate_token();
+    let new_address = NewWithdrawalAddress {
+        user_id: user.id,
+        address: req.address,
+        label,
+        mint: req.mint,
+        confirmation_token_hash: hash_token(&token),
+        confirmation_expires_at: now + CONFIRMATION_TTL,
+        activates_at: now + cooling_off_period(),
+    };
+
+    let address = match store.add_withdrawal_address(new_address).await {
+        Ok(address) => address,
+        Err(WithdrawalAddressError::AlreadyExists) => {
+            return Ok(HttpResponse::Conflict().json("Withdrawal address already exists"));
+        }
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    let email = withdrawal_address_email(&user_model.email, &address.address, &address.label, &token);
+    if let Err(e) = mailer.send(email).await {
+        log::error!("Failed to send withdrawal address confirmation to {}: {}", user.id, e);
+    }
+
+    Ok(HttpResponse::Created().json(WithdrawalAddressItem::from(address)))
+}
+
+#[actix_web::post("/withdrawal-addresses/confirm")]
+pub async fn confirm_withdrawal_address(
+    store: web::Data<Store>,
+    req: web::Json<ConfirmWithdrawalAddressRequest>,
+) -> Result<HttpResponse> {
+    match store.confirm_withdrawal_address(&hash_token(&req.token)).await {
+        Ok(address) => Ok(HttpResponse::Ok().json(WithdrawalAddressItem::from(address))),
+        Err(WithdrawalAddressError::InvalidToken) => {
+            Ok(HttpResponse::BadRequest().json("Invalid or expired token"))
+        }
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[actix_web::delete("/withdrawal-addresses/{id}")]
+pub async fn remove_withdrawal_address(
+    store: web::Data<Store>,
+    user: AuthenticatedUser,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse> {
+    match store.remove_withdrawal_address(user.id, path.into_inner()).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(WithdrawalAddressError::NotFound) => Ok(HttpResponse::NotFound().finish()),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+/// Turns strict mode on or off. In strict mode `/send` only accepts active
+/// allowlisted destinations. Turning it on is immediate; turning it off waits
+/// out the cooling-off period and emails the user, so a stolen session cannot
+/// lift the allowlist and withdraw right away.
+#[actix_web::put("/withdrawal-addresses/settings")]
+pub async fn update_withdrawal_settings(
+    store: web::Data<Store>,
+    mailer: web::Data<dyn Mailer>,
+    user: StepUpUser,
+    req: web::Json<WithdrawalSettingsRequest>,
+) -> Result<HttpResponse> {
+    if req.strict {
+        if store.enable_withdrawal_strict_mode(user.id).await.is_err() {
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+        return Ok(HttpResponse::Ok().json(WithdrawalSettingsResponse {
+            strict: true,
+            strict_relaxes_at: None,
+        }));
+    }
+
+    let user_model = match store.get_user_by_id(user.id).await {
+        Ok(Some(user)) => user,
+        _ => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+    let settings = match store
+        .relax_withdrawal_strict_mode(user.id, Utc::now() + cooling_off_period())
+        .await
+    {
+        Ok(settings) => settings,
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    if let Some(relaxes_at) = settings.relaxes_at.filter(|_| settings.is_strict(Utc::now())) {
+        if let Err(e) = mailer.send(strict_mode_relaxing_email(&user_model.email, relaxes_at)).await {
+            log::error!("Failed to send strict mode notice to {}: {}", user.id, e);
+        }
+        return Ok(HttpResponse::Accepted().json(WithdrawalSettingsResponse::from(settings)));
+    }
+    Ok(HttpResponse::Ok().json(WithdrawalSettingsResponse::from(settings)))
+}
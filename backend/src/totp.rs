@@ -0,0 +1,247 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use store::totp::TotpError;
+use std::env;
+use store::Store;
+use uuid::Uuid;
+
+/// RFC 6238 defaults, which is what authenticator apps assume.
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Accept codes from one step either side to absorb clock drift.
+const ALLOWED_DRIFT: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+pub const ISSUER: &str = "Purge";
+/// Version of the stored secret format; secrets without it are rejected.
+const SEALED_PREFIX: &str = "v1:";
+const NONCE_LEN: usize = 12;
+
+/// Encrypts TOTP secrets at rest, so a database dump alone cannot mint codes.
+pub struct SecretCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SecretCipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+
+    /// Reads the hex-encoded 32-byte key from `TOTP_ENCRYPTION_KEY`.
+    pub fn from_env() -> Result<Self, String> {
+        let hex_key = env::var("TOTP_ENCRYPTION_KEY").map_err(|_| "TOTP_ENCRYPTION_KEY must be set".to_string())?;
+        let key: [u8; 32] = hex::decode(hex_key.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| "TOTP_ENCRYPTION_KEY must be 32 bytes of hex".to_string())?;
+        Ok(Self::new(key))
+    }
+
+    /// Encrypts a secret for storage. The user id is bound in, so a secret
+    /// copied onto another user's row does not decrypt.
+    pub fn seal(&self, user_id: Uuid, secret: &str) -> String {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let payload = Payload {
+            msg: secret.as_bytes(),
+            aad: user_id.as_bytes(),
+        };
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(Nonce::from_slice(&nonce), payload)
+                .expect("encryption does not fail for in-memory buffers"),
+        );
+        format!("{}{}", SEALED_PREFIX, STANDARD.encode(sealed))
+    }
+
+    /// Decrypts a stored secret. Anything not sealed by `seal`, plaintext
+    /// included, does not open.
+    pub fn open(&self, user_id: Uuid, stored: &str) -> Option<String> {
+        let encoded = stored.strip_prefix(SEALED_PREFIX)?;
+        let sealed = STANDARD.decode(encoded).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: user_id.as_bytes(),
+        };
+        let secret = self.cipher.decrypt(Nonce::from_slice(nonce), payload).ok()?;
+        String::from_utf8(secret).ok()
+    }
+}
+
+/// Generates a 160-bit secret, base32 encoded for authenticator apps.
+pub fn generate_secret() -> String {
+    let bytes: [u8; 20] = rand::random();
+    BASE32_NOPAD.encode(&bytes)
+}
+
+pub fn provisioning_uri(secret: &str, account: &str) -> String {
+    let mut url = Url::parse("otpauth://totp/").expect("valid base url");
+    url.set_path(&format!("{}:{}", ISSUER, account));
+    url.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", ISSUER)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP_SECONDS.to_string());
+    url.to_string()
+}
+
+pub(crate) fn code_at(key: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+/// Returns the time step the code belongs to, if it is valid at `unix_time`.
+pub fn verify_code(secret: &str, code: &str, unix_time: i64) -> Option<i64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let current = unix_time / STEP_SECONDS;
+
+    (current - ALLOWED_DRIFT..=current + ALLOWED_DRIFT).find(|step| code_at(&key, *step) == code)
+}
+
+/// Generates single-use recovery codes such as `k3m9x-q2w7p`.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let bytes: [u8; 7] = rand::random();
+            let encoded = BASE32_NOPAD.encode(&bytes).to_lowercase();
+            format!("{}-{}", &encoded[..5], &encoded[5..10])
+        })
+        .collect()
+}
+
+/// Recovery codes are stored hashed and matched ignoring case and dashes.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+#[derive(Debug)]
+pub enum SecondFactorError {
+    NotEnrolled,
+    InvalidCode,
+    UnreadableSecret,
+    Store(TotpError),
+}
+
+impl std::fmt::Display for SecondFactorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecondFactorError::NotEnrolled => write!(f, "Two-factor authentication is not enabled"),
+            SecondFactorError::InvalidCode => write!(f, "Invalid two-factor code"),
+            SecondFactorError::UnreadableSecret => write!(f, "Stored two-factor secret cannot be decrypted"),
+            SecondFactorError::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SecondFactorError {}
+
+/// Checks a TOTP or recovery code for a user with two-factor enabled and
+/// consumes it, so the same code cannot be used twice.
+pub async fn verify_second_factor(
+    store: &Store,
+    cipher: &SecretCipher,
+    user_id: Uuid,
+    code: &str,
+) -> Result<(), SecondFactorError> {
+    let totp = match store.get_totp(user_id).await {
+        Ok(Some(totp)) if totp.is_enabled() => totp,
+        Ok(_) => return Err(SecondFactorError::NotEnrolled),
+        Err(e) => return Err(SecondFactorError::Store(e)),
+    };
+    let secret = cipher
+        .open(user_id, &totp.secret)
+        .ok_or(SecondFactorError::UnreadableSecret)?;
+
+    let code = code.trim();
+    let accepted = match verify_code(&secret, code, chrono::Utc::now().timestamp()) {
+        Some(step) => store.mark_totp_step_used(user_id, step).await,
+        None => store.use_recovery_code(user_id, &hash_recovery_code(code)).await,
+    }
+    .map_err(SecondFactorError::Store)?;
+
+    if accepted {
+        Ok(())
+    } else {
+        Err(SecondFactorError::InvalidCode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{code_at, hash_recovery_code, verify_code, SecretCipher};
+    use data_encoding::BASE32_NOPAD;
+    use uuid::Uuid;
+
+    // RFC 6238 appendix B SHA1 key
+    const KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        // The RFC lists 8-digit codes; these are their last 6 digits
+        assert_eq!(code_at(KEY, 59 / 30), "287082");
+        assert_eq!(code_at(KEY, 1_111_111_109 / 30), "081804");
+        assert_eq!(code_at(KEY, 1_234_567_890 / 30), "005924");
+        assert_eq!(code_at(KEY, 20_000_000_000 / 30), "353130");
+    }
+
+    #[test]
+    fn test_verify_allows_one_step_of_drift() {
+        let secret = BASE32_NOPAD.encode(KEY);
+        assert_eq!(verify_code(&secret, "287082", 59), Some(1));
+        assert_eq!(verify_code(&secret, "287082", 89), Some(1));
+        assert_eq!(verify_code(&secret, "287082", 150), None);
+        assert_eq!(verify_code(&secret, "28708", 59), None);
+    }
+
+    #[test]
+    fn test_recovery_code_normalization() {
+        assert_eq!(hash_recovery_code("k3m9x-q2w7p"), hash_recovery_code(" K3M9XQ2W7P "));
+        assert_ne!(hash_recovery_code("k3m9x-q2w7p"), hash_recovery_code("k3m9x-q2w7q"));
+    }
+
+    #[test]
+    fn test_secret_cipher_round_trip() {
+        let cipher = SecretCipher::new([7; 32]);
+        let user_id = Uuid::new_v4();
+        let sealed = cipher.seal(user_id, "JBSWY3DPEHPK3PXP");
+
+        assert!(!sealed.contains("JBSWY3DPEHPK3PXP"));
+        assert_eq!(cipher.open(user_id, &sealed).as_deref(), Some("JBSWY3DPEHPK3PXP"));
+        // Bound to the user and the key
+        assert_eq!(cipher.open(Uuid::new_v4(), &sealed), None);
+        assert_eq!(SecretCipher::new([8; 32]).open(user_id, &sealed), None);
+        // A plaintext secret planted in the database is not accepted
+        assert_eq!(cipher.open(user_id, "JBSWY3DPEHPK3PXP"), None);
+    }
+}
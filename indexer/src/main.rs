@@ -1,15 +1,35 @@
+use chrono::Utc;
 use dotenv::dotenv;
-use futures::StreamExt;
+use futures::{SinkExt, StreamExt};
 use log::{error, info};
 use spl_token::state::Account as TokenAccount;
 use sqlx::PgPool;
-use std::{collections::HashMap, env, str::FromStr};
+use std::{
+    collections::HashMap,
+    env,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use store::solana::SOL_MINT;
 use store::Store;
+use token_accounts::TokenAccountTracker;
+use tokio::sync::mpsc;
+use writer::{AccountChange, HistoryEntry, WriterConfig};
 use yellowstone::GeyserGrpcClient;
-use yellowstone_grpc_proto::prelude::{subscribe_update::UpdateOneof, SubscribeUpdateAccount};
+use yellowstone_grpc_proto::prelude::{
+    subscribe_update::UpdateOneof, CommitmentLevel, SubscribeUpdateAccount,
+    SubscribeUpdateTransaction,
+};
 
+pub mod metrics;
+pub mod token_accounts;
+pub mod writer;
 pub mod yellowstone;
 
+const GEYSER_ENDPOINT: &str = "https://grpc.triton.one:443";
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
@@ -19,7 +39,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let triton_api_token = env::var("TRITON_API_TOKEN").expect("TRITON_API_TOKEN must be set");
 
     let pool = PgPool::connect(&database_url).await?;
-    let store = Store::new(pool);
+    let store = Arc::new(Store::new(pool));
 
     let public_keys = store.get_all_public_keys().await?;
     let addresses_to_monitor: Vec<String> = public_keys
@@ -34,122 +54,325 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("Monitoring {} addresses", addresses_to_monitor.len());
 
-    let mut client = GeyserGrpcClient::build_from_static("https://grpc.triton.one:443")
-        .x_token(Some(&triton_api_token))?
+    let metrics_address =
+        env::var("INDEXER_METRICS_ADDR").unwrap_or_else(|_| "127.0.0.1:9090".to_string());
+    tokio::spawn(metrics::serve(store.clone(), metrics_address));
+
+    // Token accounts seen before a restart, so closes are still caught
+    let mut token_accounts = TokenAccountTracker::default();
+    for holding in store.get_all_token_account_holdings().await? {
+        token_accounts.upsert(&holding.token_account, &holding.owner, &holding.mint_address);
+    }
+
+    let addresses_set: std::collections::HashSet<String> =
+        addresses_to_monitor.iter().cloned().collect();
+
+    // Balance and history writes are flushed in batches by a separate task
+    let (changes, history, _writer) = writer::spawn_writer(store.clone(), WriterConfig::from_env());
+
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let started = Instant::now();
+        match run_subscription(
+            &triton_api_token,
+            &addresses_to_monitor,
+            &addresses_set,
+            &mut token_accounts,
+            &changes,
+            &history,
+        )
+        .await
+        {
+            Ok(()) => error!("Geyser stream ended"),
+            Err(e) => {
+                metrics::ERRORS.with_label_values(&["subscription"]).inc();
+                error!("Geyser subscription failed: {}", e);
+            }
+        }
+        metrics::CONNECTED.set(0);
+
+        // A connection that stayed up for a while starts the backoff over
+        if started.elapsed() > MAX_RECONNECT_BACKOFF {
+            backoff = Duration::from_secs(1);
+        }
+        info!("Reconnecting in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        metrics::RECONNECTS.inc();
+    }
+}
+
+/// Connects to Geyser and processes updates until the stream ends.
+async fn run_subscription(
+    triton_api_token: &str,
+    addresses_to_monitor: &[String],
+    addresses_set: &std::collections::HashSet<String>,
+    token_accounts: &mut TokenAccountTracker,
+    changes: &mpsc::Sender<AccountChange>,
+    history: &mpsc::Sender<HistoryEntry>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = GeyserGrpcClient::build_from_static(GEYSER_ENDPOINT)
+        .x_token(Some(triton_api_token))?
         .connect()
         .await?;
+    // The subscription borrows the client, so slot polling gets its own connection
+    let mut slot_client = GeyserGrpcClient::build_from_static(GEYSER_ENDPOINT)
+        .x_token(Some(triton_api_token))?
+        .connect_lazy()?;
 
-    let (_sink, mut stream) = client
-        .subscribe_to_addresses(addresses_to_monitor.clone())
+    let (mut sink, mut stream) = client
+        .subscribe_to_addresses(addresses_to_monitor.to_vec(), token_accounts.addresses())
         .await?;
 
+    metrics::CONNECTED.set(1);
     info!("Successfully subscribed to addresses. Waiting for updates...");
 
-    let addresses_set: std::collections::HashSet<String> =
-        addresses_to_monitor.into_iter().collect();
-
-    while let Some(update) = stream.next().await {
-        match update {
-            Ok(update) => {
-                if let Some(UpdateOneof::Account(account_update)) = update.update_oneof {
-                    if let Err(e) = handle_account_update(&store, &addresses_set, account_update).await {
-                        error!("Error handling account update: {}", e);
+    let mut slot_ticker = tokio::time::interval(Duration::from_secs(10));
+
+    loop {
+        tokio::select! {
+            update = stream.next() => {
+                let Some(update) = update else {
+                    return Ok(());
+                };
+                match update {
+                    Ok(update) => match update.update_oneof {
+                        Some(UpdateOneof::Account(account_update)) => {
+                            metrics::UPDATES_PROCESSED.with_label_values(&["account"]).inc();
+                            record_processed_slot(account_update.slot);
+
+                            let tracked_changed = match handle_account_update(changes, addresses_set, token_accounts, account_update).await {
+                                Ok(changed) => changed,
+                                Err(e) => {
+                                    metrics::ERRORS.with_label_values(&["account_update"]).inc();
+                                    error!("Error handling account update: {}", e);
+                                    false
+                                }
+                            };
+
+                            if tracked_changed {
+                                let request = yellowstone::addresses_subscribe_request(
+                                    addresses_to_monitor,
+                                    &token_accounts.addresses(),
+                                );
+                                if let Err(e) = sink.send(request).await {
+                                    metrics::ERRORS.with_label_values(&["subscription"]).inc();
+                                    error!("Failed to update subscription: {}", e);
+                                }
+                            }
+                        }
+                        Some(UpdateOneof::Transaction(transaction_update)) => {
+                            metrics::UPDATES_PROCESSED.with_label_values(&["transaction"]).inc();
+                            record_processed_slot(transaction_update.slot);
+
+                            if let Err(e) = handle_transaction_update(history, addresses_set, transaction_update).await {
+                                metrics::ERRORS.with_label_values(&["transaction_update"]).inc();
+                                error!("Error handling transaction update: {}", e);
+                            }
+                        }
+                        _ => {}
+                    },
+                    Err(e) => {
+                        metrics::ERRORS.with_label_values(&["stream"]).inc();
+                        error!("Stream error: {}", e);
+                        return Err(e.into());
                     }
                 }
             }
-            Err(e) => {
-                error!("Stream error: {}", e);
+            _ = slot_ticker.tick() => {
+                match slot_client.get_slot(Some(CommitmentLevel::Confirmed)).await {
+                    Ok(response) => metrics::CHAIN_SLOT.set(response.slot as i64),
+                    Err(e) => {
+                        metrics::ERRORS.with_label_values(&["get_slot"]).inc();
+                        error!("Failed to fetch current slot: {}", e);
+                    }
+                }
             }
         }
     }
+}
 
-    Ok(())
+fn record_processed_slot(slot: u64) {
+    let slot = slot as i64;
+    if slot > metrics::LAST_PROCESSED_SLOT.get() {
+        metrics::LAST_PROCESSED_SLOT.set(slot);
+    }
 }
 
 async fn handle_account_update(
-    store: &Store,
+    changes: &mpsc::Sender<AccountChange>,
     monitored_addresses: &std::collections::HashSet<String>,
+    token_accounts: &mut TokenAccountTracker,
     account_update: SubscribeUpdateAccount,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut tracked_changed = false;
     if let Some(account) = account_update.account {
         let pubkey_str = bs58::encode(&account.pubkey).into_string();
 
         // Check if this is a direct SOL balance update for one of our users
         if monitored_addresses.contains(&pubkey_str) {
-            handle_sol_balance_update(store, &pubkey_str, account.lamports).await?;
+            changes
+                .send(AccountChange::Sol {
+                    owner: pubkey_str.clone(),
+                    lamports: account.lamports,
+                })
+                .await?;
         }
 
         // Check if this is a token account update
-        if account.owner == spl_token::ID.to_bytes().to_vec() {
-            if let Ok(token_account) = TokenAccount::unpack(&account.data) {
+        let token_account = if account.owner == spl_token::ID.to_bytes().to_vec() {
+            TokenAccount::unpack(&account.data).ok()
+        } else {
+            None
+        };
+
+        match token_account {
+            Some(token_account) => {
                 let owner_pubkey_str = bs58::encode(&token_account.owner).into_string();
+                let mint_address = bs58::encode(&token_account.mint).into_string();
+
                 if monitored_addresses.contains(&owner_pubkey_str) {
-                    handle_token_balance_update(store, &owner_pubkey_str, token_account).await?;
+                    let previous = token_accounts.upsert(&pubkey_str, &owner_pubkey_str, &mint_address);
+                    tracked_changed = previous.is_none();
+                    changes
+                        .send(AccountChange::TokenAccount {
+                            address: pubkey_str,
+                            owner: owner_pubkey_str,
+                            mint: mint_address,
+                            decimals: token_account.mint.get_decimals()? as i32,
+                            amount: token_account.amount,
+                        })
+                        .await?;
+                } else if let Some(previous) = token_accounts.remove(&pubkey_str) {
+                    tracked_changed = true;
+                    info!("Token account {} reassigned away from {}", pubkey_str, previous.owner);
+                    changes
+                        .send(AccountChange::TokenAccountClosed { address: pubkey_str })
+                        .await?;
+                }
+            }
+            None => {
+                // Closed accounts come back with no lamports and empty data, and
+                // accounts handed to another program no longer unpack either
+                if let Some(previous) = token_accounts.remove(&pubkey_str) {
+                    tracked_changed = true;
+                    info!("Token account {} of {} closed", pubkey_str, previous.owner);
+                    changes
+                        .send(AccountChange::TokenAccountClosed { address: pubkey_str })
+                        .await?;
                 }
             }
         }
     }
-    Ok(())
+    Ok(tracked_changed)
 }
 
-async fn handle_sol_balance_update(
-    store: &Store,
-    pubkey: &str,
-    lamports: u64,
+async fn handle_transaction_update(
+    history: &mpsc::Sender<HistoryEntry>,
+    monitored_addresses: &std::collections::HashSet<String>,
+    transaction_update: SubscribeUpdateTransaction,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let user = match store.get_user_by_public_key(pubkey).await? {
-        Some(u) => u,
-        None => {
-            error!("SOL balance update for a public key not associated with any user: {}", pubkey);
-            return Ok(());
-        }
+    let Some(info) = transaction_update.transaction else {
+        return Ok(());
+    };
+    let (Some(transaction), Some(meta)) = (info.transaction, info.meta) else {
+        return Ok(());
+    };
+    let Some(message) = transaction.message else {
+        return Ok(());
     };
 
-    let sol_asset = store
-        .upsert_asset("So11111111111111111111111111111111111111112", 9, "Solana", "SOL")
-        .await?;
-
-    store
-        .upsert_balance(user.id, sol_asset.id, lamports as i64)
-        .await?;
+    let signature = bs58::encode(&info.signature).into_string();
+    let slot = transaction_update.slot as i64;
+    // Transaction updates carry no block time; at confirmed commitment the
+    // time we receive them is within a few slots of it.
+    let block_time = Utc::now();
 
-    info!("Updated SOL balance for {}: {} SOL", pubkey, lamports as f64 / 1e9);
+    // Balances are indexed over the static keys followed by the addresses
+    // loaded from lookup tables.
+    let account_keys: Vec<String> = message
+        .account_keys
+        .iter()
+        .chain(meta.loaded_writable_addresses.iter())
+        .chain(meta.loaded_readonly_addresses.iter())
+        .map(|key| bs58::encode(key).into_string())
+        .collect();
 
-    Ok(())
-}
+    let sol_deltas: Vec<i128> = meta
+        .pre_balances
+        .iter()
+        .zip(meta.post_balances.iter())
+        .map(|(pre, post)| *post as i128 - *pre as i128)
+        .collect();
 
-async fn handle_token_balance_update(
-    store: &Store,
-    owner_pubkey: &str,
-    token_account: TokenAccount,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let user = match store.get_user_by_public_key(owner_pubkey).await? {
-        Some(u) => u,
-        None => {
-             error!("Token balance update for a public key not associated with any user: {}", owner_pubkey);
-            return Ok(());
+    for (index, address) in account_keys.iter().enumerate() {
+        if !monitored_addresses.contains(address) {
+            continue;
         }
-    };
-
-    let mint_address = bs58::encode(&token_account.mint).into_string();
-    let (name, symbol) = get_token_metadata(&mint_address);
-
-    let asset = store
-        .upsert_asset(&mint_address, token_account.mint.get_decimals()? as i32, &name, &symbol)
-        .await?;
+        let delta = sol_deltas.get(index).copied().unwrap_or(0);
+        if delta == 0 {
+            continue;
+        }
+        history
+            .send(HistoryEntry {
+                pubkey: address.clone(),
+                mint_address: SOL_MINT.to_string(),
+                decimals: 9,
+                signature: signature.clone(),
+                delta,
+                counterparty: find_counterparty(&account_keys, &sol_deltas, delta),
+                slot,
+                block_time,
+            })
+            .await?;
+    }
 
-    store
-        .upsert_balance(user.id, asset.id, token_account.amount as i64)
-        .await?;
+    // Net token movement per (owner, mint), with the mint's decimals
+    let mut token_deltas: HashMap<(String, String), (i128, u32)> = HashMap::new();
+    for (balances, sign) in [(&meta.pre_token_balances, -1), (&meta.post_token_balances, 1)] {
+        for balance in balances {
+            let Some(ui_amount) = &balance.ui_token_amount else {
+                continue;
+            };
+            let amount = ui_amount.amount.parse::<i128>().unwrap_or(0);
+            let entry = token_deltas
+                .entry((balance.owner.clone(), balance.mint.clone()))
+                .or_insert((0, ui_amount.decimals));
+            entry.0 += sign * amount;
+        }
+    }
 
-    info!(
-        "Updated token balance for {} [{}]: {}",
-        owner_pubkey, symbol, token_account.amount
-    );
+    for ((owner, mint), (delta, decimals)) in token_deltas {
+        if delta == 0 || !monitored_addresses.contains(&owner) {
+            continue;
+        }
+        history
+            .send(HistoryEntry {
+                pubkey: owner,
+                mint_address: mint,
+                decimals: decimals as i32,
+                signature: signature.clone(),
+                delta,
+                counterparty: None,
+                slot,
+                block_time,
+            })
+            .await?;
+    }
 
     Ok(())
 }
 
+/// Picks the account that moved the most lamports in the opposite direction.
+fn find_counterparty(account_keys: &[String], deltas: &[i128], delta: i128) -> Option<String> {
+    deltas
+        .iter()
+        .enumerate()
+        .filter(|(_, other)| other.signum() == -delta.signum())
+        .max_by_key(|(_, other)| other.abs())
+        .and_then(|(index, _)| account_keys.get(index).cloned())
+}
+
 fn get_token_metadata(mint_address: &str) -> (String, String) {
     let mut known_tokens = HashMap::new();
     known_tokens.insert(
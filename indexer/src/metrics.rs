@@ -0,0 +1,101 @@
+use actix_web::{web, App, HttpResponse, HttpServer};
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Histogram, IntCounter, IntCounterVec, IntGauge,
+};
+use serde::Serialize;
+use std::sync::{Arc, LazyLock};
+use store::Store;
+use telemetry::render;
+
+/// Updates handled by kind: `account` or `transaction`.
+pub static UPDATES_PROCESSED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "indexer_updates_processed_total",
+        "Geyser updates processed by kind",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+pub static ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("indexer_errors_total", "Indexer errors by kind", &["kind"]).unwrap()
+});
+
+pub static LAST_PROCESSED_SLOT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "indexer_last_processed_slot",
+        "Slot of the most recent update processed"
+    )
+    .unwrap()
+});
+
+/// Latest slot reported by the Geyser endpoint, to compare against the
+/// last processed slot.
+pub static CHAIN_SLOT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("indexer_chain_slot", "Latest slot reported by the Geyser endpoint").unwrap()
+});
+
+pub static CONNECTED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "indexer_geyser_connected",
+        "1 while the Geyser subscription is up"
+    )
+    .unwrap()
+});
+
+pub static RECONNECTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("indexer_reconnects_total", "Geyser reconnect attempts").unwrap()
+});
+
+pub static FLUSH_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "indexer_flush_duration_seconds",
+        "Time spent writing a batch of balance updates"
+    )
+    .unwrap()
+});
+
+#[derive(Serialize)]
+struct ReadinessResponse {
+    database: bool,
+    geyser: bool,
+}
+
+async fn metrics_endpoint() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render())
+}
+
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+async fn readyz(store: web::Data<Arc<Store>>) -> HttpResponse {
+    let response = ReadinessResponse {
+        database: store.ping().await.is_ok(),
+        geyser: CONNECTED.get() == 1,
+    };
+    if response.database && response.geyser {
+        HttpResponse::Ok().json(response)
+    } else {
+        HttpResponse::ServiceUnavailable().json(response)
+    }
+}
+
+/// Serves `/metrics`, `/healthz` and `/readyz` next to the indexing loop.
+pub async fn serve(store: Arc<Store>, bind_address: String) -> std::io::Result<()> {
+    let store = web::Data::new(store);
+    HttpServer::new(move || {
+        App::new()
+            .app_data(store.clone())
+            .route("/metrics", web::get().to(metrics_endpoint))
+            .route("/healthz", web::get().to(healthz))
+            .route("/readyz", web::get().to(readyz))
+    })
+    .workers(1)
+    .bind(bind_address)?
+    .run()
+    .await
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackedTokenAccount {
+    pub owner: String,
+    pub mint: String,
+}
+
+/// Token accounts seen for monitored wallets, keyed by token-account address.
+///
+/// A closed account arrives as an empty, zero-lamport update that can no longer
+/// be unpacked, so this mapping is how we know which accounts to keep
+/// subscribed to and which updates to treat as a close.
+#[derive(Debug, Default)]
+pub struct TokenAccountTracker {
+    accounts: HashMap<String, TrackedTokenAccount>,
+}
+
+impl TokenAccountTracker {
+    /// Records the owner and mint of a token account and returns its previous state.
+    pub fn upsert(&mut self, address: &str, owner: &str, mint: &str) -> Option<TrackedTokenAccount> {
+        self.accounts.insert(
+            address.to_string(),
+            TrackedTokenAccount {
+                owner: owner.to_string(),
+                mint: mint.to_string(),
+            },
+        )
+    }
+
+    /// Stops tracking a closed or reassigned account and returns its last state.
+    pub fn remove(&mut self, address: &str) -> Option<TrackedTokenAccount> {
+        self.accounts.remove(address)
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        self.accounts.keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenAccountTracker;
+
+    const OWNER: &str = "owner";
+    const MINT: &str = "mint";
+
+    #[test]
+    fn test_upsert_reports_new_accounts() {
+        let mut tracker = TokenAccountTracker::default();
+        assert!(tracker.upsert("ata", OWNER, MINT).is_none());
+        assert!(tracker.upsert("aux", OWNER, MINT).is_none());
+
+        // Later updates for the same account are not new
+        assert!(tracker.upsert("ata", OWNER, MINT).is_some());
+
+        let mut addresses = tracker.addresses();
+        addresses.sort();
+        assert_eq!(addresses, vec!["ata".to_string(), "aux".to_string()]);
+    }
+
+    #[test]
+    fn test_remove_closed_account() {
+        let mut tracker = TokenAccountTracker::default();
+        tracker.upsert("ata", OWNER, MINT);
+        tracker.upsert("aux", OWNER, MINT);
+
+        let closed = tracker.remove("aux").unwrap();
+        assert_eq!(closed.owner, OWNER);
+        assert_eq!(tracker.addresses(), vec!["ata".to_string()]);
+        assert!(tracker.remove("aux").is_none());
+    }
+
+    #[test]
+    fn test_reassigned_account_moves_owner() {
+        let mut tracker = TokenAccountTracker::default();
+        tracker.upsert("ata", OWNER, MINT);
+
+        let previous = tracker.upsert("ata", "new-owner", MINT).unwrap();
+        assert_eq!(previous.owner, OWNER);
+        assert_eq!(tracker.remove("ata").unwrap().owner, "new-owner");
+    }
+}
@@ -0,0 +1,617 @@
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use std::{collections::HashMap, env, sync::Arc, time::Duration};
+use store::solana::{BalanceUpdate, TokenAccountBalanceUpdate, SOL_MINT};
+use store::transaction::NewTransaction;
+use store::Store;
+use tokio::{sync::mpsc, task::JoinHandle};
+use uuid::Uuid;
+
+use crate::{get_token_metadata, metrics};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct WriterConfig {
+    pub flush_interval: Duration,
+    pub channel_capacity: usize,
+    pub max_batch_size: usize,
+    /// Failed flushes after which a change is written on its own, and moved
+    /// to the dead-letter table if that fails too.
+    pub max_flush_attempts: u32,
+}
+
+impl WriterConfig {
+    pub fn from_env() -> Self {
+        let flush_interval_ms = env::var("INDEXER_FLUSH_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(500);
+        let channel_capacity = env::var("INDEXER_CHANNEL_CAPACITY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10_000);
+        let max_batch_size = env::var("INDEXER_MAX_BATCH_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1_000);
+        let max_flush_attempts = env::var("INDEXER_MAX_FLUSH_ATTEMPTS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5);
+
+        Self {
+            flush_interval: Duration::from_millis(flush_interval_ms),
+            channel_capacity,
+            max_batch_size,
+            max_flush_attempts,
+        }
+    }
+}
+
+/// A balance change decoded from a Geyser account update.
+#[derive(Debug, Clone)]
+pub enum AccountChange {
+    Sol {
+        owner: String,
+        lamports: u64,
+    },
+    TokenAccount {
+        address: String,
+        owner: String,
+        mint: String,
+        decimals: i32,
+        amount: u64,
+    },
+    TokenAccountClosed {
+        address: String,
+    },
+}
+
+impl AccountChange {
+    /// Changes to the same on-chain account supersede each other.
+    fn account(&self) -> &str {
+        match self {
+            AccountChange::Sol { owner, .. } => owner,
+            AccountChange::TokenAccount { address, .. } => address,
+            AccountChange::TokenAccountClosed { address } => address,
+        }
+    }
+}
+
+/// A monitored account's net movement of one asset in a transaction.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub pubkey: String,
+    pub mint_address: String,
+    pub decimals: i32,
+    pub signature: String,
+    /// Positive when the account received the asset.
+    pub delta: i128,
+    pub counterparty: Option<String>,
+    pub slot: i64,
+    pub block_time: DateTime<Utc>,
+}
+
+/// A change waiting for the next flush.
+#[derive(Debug)]
+struct PendingChange {
+    change: AccountChange,
+    /// Flushes of this change that failed so far.
+    attempts: u32,
+}
+
+/// A history entry waiting for the next flush. Entries are never coalesced.
+#[derive(Debug)]
+struct PendingEntry {
+    entry: HistoryEntry,
+    /// Flushes of this entry that failed so far.
+    attempts: u32,
+}
+
+/// Where flushed batches are written.
+trait BatchSink {
+    async fn write(&mut self, changes: &[AccountChange]) -> Result<(), BoxError>;
+
+    async fn write_history(&mut self, entries: &[HistoryEntry]) -> Result<(), BoxError>;
+
+    /// Sets aside an update of `account` that cannot be written, for later
+    /// inspection.
+    async fn dead_letter(&mut self, account: &str, update: &str, error: &str, attempts: u32) -> Result<(), BoxError>;
+}
+
+/// In-memory pubkey -> user id and mint -> asset id lookups.
+#[derive(Debug, Default)]
+struct IdCache {
+    users: HashMap<String, Uuid>,
+    assets: HashMap<String, Uuid>,
+}
+
+/// Starts the task that batches account changes and history entries into
+/// the database.
+pub fn spawn_writer(
+    store: Arc<Store>,
+    config: WriterConfig,
+) -> (mpsc::Sender<AccountChange>, mpsc::Sender<HistoryEntry>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::channel(config.channel_capacity);
+    let (history_sender, history_receiver) = mpsc::channel(config.channel_capacity);
+    let sink = StoreSink {
+        store,
+        cache: IdCache::default(),
+    };
+    let writer = BalanceWriter::new(sink, config.max_flush_attempts);
+    let handle = tokio::spawn(writer.run(receiver, history_receiver, config));
+    (sender, history_sender, handle)
+}
+
+struct BalanceWriter<S> {
+    sink: S,
+    pending: HashMap<String, PendingChange>,
+    history: Vec<PendingEntry>,
+    max_flush_attempts: u32,
+}
+
+impl<S: BatchSink> BalanceWriter<S> {
+    fn new(sink: S, max_flush_attempts: u32) -> Self {
+        Self {
+            sink,
+            pending: HashMap::new(),
+            history: Vec::new(),
+            max_flush_attempts,
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut receiver: mpsc::Receiver<AccountChange>,
+        mut history: mpsc::Receiver<HistoryEntry>,
+        config: WriterConfig,
+    ) {
+        let start = tokio::time::Instant::now() + config.flush_interval;
+        let mut ticker = tokio::time::interval_at(start, config.flush_interval);
+
+        loop {
+            tokio::select! {
+                change = receiver.recv() => match change {
+                    Some(change) => {
+                        // A newer update supersedes the pending one and starts over
+                        let account = change.account().to_string();
+                        self.pending.insert(account, PendingChange { change, attempts: 0 });
+                        if self.pending.len() >= config.max_batch_size {
+                            self.flush().await;
+                        }
+                    }
+                    None => {
+                        self.flush().await;
+                        break;
+                    }
+                },
+                Some(entry) = history.recv() => {
+                    self.history.push(PendingEntry { entry, attempts: 0 });
+                    if self.history.len() >= config.max_batch_size {
+                        self.flush().await;
+                    }
+                }
+                _ = ticker.tick() => self.flush().await,
+            }
+        }
+    }
+
+    async fn flush(&mut self) {
+        self.flush_balances().await;
+        self.flush_history().await;
+    }
+
+    async fn flush_balances(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let batch: Vec<PendingChange> = self.pending.drain().map(|(_, pending)| pending).collect();
+        let changes: Vec<AccountChange> = batch.iter().map(|pending| pending.change.clone()).collect();
+        let timer = metrics::FLUSH_DURATION.start_timer();
+        let result = self.sink.write(&changes).await;
+        timer.observe_duration();
+
+        let Err(e) = result else {
+            info!("Flushed {} account updates", changes.len());
+            return;
+        };
+        metrics::ERRORS.with_label_values(&["flush"]).inc();
+        error!("Failed to flush {} account updates: {}", changes.len(), e);
+
+        for mut pending in batch {
+            pending.attempts += 1;
+            if pending.attempts >= self.max_flush_attempts {
+                self.write_alone(pending).await;
+            } else {
+                self.requeue(pending);
+            }
+        }
+    }
+
+    /// Writes a change that keeps failing in batches on its own, so one bad
+    /// row cannot hold back the rest. If it fails alone too, it goes to the
+    /// dead-letter table; if even that fails the database is likely down, and
+    /// the change is retried.
+    async fn write_alone(&mut self, pending: PendingChange) {
+        let error = match self.sink.write(std::slice::from_ref(&pending.change)).await {
+            Ok(()) => return,
+            Err(e) => e.to_string(),
+        };
+        let account = pending.change.account().to_string();
+        let update = format!("{:?}", pending.change);
+        match self.sink.dead_letter(&account, &update, &error, pending.attempts).await {
+            Ok(()) => {
+                metrics::ERRORS.with_label_values(&["dead_letter"]).inc();
+                error!(
+                    "Moved update for {} to the dead-letter table after {} attempts: {}",
+                    account, pending.attempts, error
+                );
+            }
+            Err(e) => {
+                error!("Failed to dead-letter update for {}: {}", account, e);
+                self.requeue(pending);
+            }
+        }
+    }
+
+    /// Retries on the next flush unless a newer update has arrived since.
+    fn requeue(&mut self, pending: PendingChange) {
+        self.pending
+            .entry(pending.change.account().to_string())
+            .or_insert(pending);
+    }
+
+    async fn flush_history(&mut self) {
+        if self.history.is_empty() {
+            return;
+        }
+
+        let batch = std::mem::take(&mut self.history);
+        let entries: Vec<HistoryEntry> = batch.iter().map(|pending| pending.entry.clone()).collect();
+        let Err(e) = self.sink.write_history(&entries).await else {
+            info!("Recorded {} history entries", entries.len());
+            return;
+        };
+        metrics::ERRORS.with_label_values(&["flush"]).inc();
+        error!("Failed to record {} history entries: {}", entries.len(), e);
+
+        for mut pending in batch {
+            pending.attempts += 1;
+            if pending.attempts >= self.max_flush_attempts {
+                self.write_entry_alone(pending).await;
+            } else {
+                self.history.push(pending);
+            }
+        }
+    }
+
+    /// Like `write_alone`, for a history entry.
+    async fn write_entry_alone(&mut self, pending: PendingEntry) {
+        let error = match self.sink.write_history(std::slice::from_ref(&pending.entry)).await {
+            Ok(()) => return,
+            Err(e) => e.to_string(),
+        };
+        let update = format!("{:?}", pending.entry);
+        match self.sink.dead_letter(&pending.entry.pubkey, &update, &error, pending.attempts).await {
+            Ok(()) => {
+                metrics::ERRORS.with_label_values(&["dead_letter"]).inc();
+                error!(
+                    "Moved history entry {} of {} to the dead-letter table after {} attempts: {}",
+                    pending.entry.signature, pending.entry.pubkey, pending.attempts, error
+                );
+            }
+            Err(e) => {
+                error!("Failed to dead-letter history entry {}: {}", pending.entry.signature, e);
+                self.history.push(pending);
+            }
+        }
+    }
+}
+
+/// Writes batches to the store, resolving owners and mints to ids.
+struct StoreSink {
+    store: Arc<Store>,
+    cache: IdCache,
+}
+
+impl BatchSink for StoreSink {
+    async fn write(&mut self, changes: &[AccountChange]) -> Result<(), BoxError> {
+        self.write_batch(changes).await
+    }
+
+    async fn write_history(&mut self, entries: &[HistoryEntry]) -> Result<(), BoxError> {
+        self.write_history_batch(entries).await
+    }
+
+    async fn dead_letter(&mut self, account: &str, update: &str, error: &str, attempts: u32) -> Result<(), BoxError> {
+        self.store
+            .record_dead_letter(account, update, error, attempts as i32)
+            .await?;
+        Ok(())
+    }
+}
+
+impl StoreSink {
+    async fn write_batch(&mut self, changes: &[AccountChange]) -> Result<(), BoxError> {
+        let owners = changes.iter().filter_map(|change| match change {
+            AccountChange::Sol { owner, .. } | AccountChange::TokenAccount { owner, .. } => Some(owner.as_str()),
+            AccountChange::TokenAccountClosed { .. } => None,
+        });
+        let mints = changes.iter().filter_map(|change| match change {
+            AccountChange::Sol { .. } => Some((SOL_MINT, 9)),
+            AccountChange::TokenAccount { mint, decimals, .. } => Some((mint.as_str(), *decimals)),
+            AccountChange::TokenAccountClosed { .. } => None,
+        });
+        self.resolve_ids(owners, mints).await?;
+
+        let mut balances = Vec::new();
+        let mut token_accounts = Vec::new();
+        let mut closed_token_accounts = Vec::new();
+
+        for change in changes {
+            match change {
+                AccountChange::Sol { owner, lamports } => {
+                    let Some(user_id) = self.cache.users.get(owner) else {
+                        error!("SOL balance update for a public key not associated with any user: {}", owner);
+                        continue;
+                    };
+                    balances.push(BalanceUpdate {
+                        user_id: *user_id,
+                        asset_id: self.cache.assets[SOL_MINT],
+                        amount: *lamports as i64,
+                    });
+                }
+                AccountChange::TokenAccount { address, owner, mint, amount, .. } => {
+                    let Some(user_id) = self.cache.users.get(owner) else {
+                        error!("Token balance update for a public key not associated with any user: {}", owner);
+                        continue;
+                    };
+                    token_accounts.push(TokenAccountBalanceUpdate {
+                        token_account: address.clone(),
+                        user_id: *user_id,
+                        asset_id: self.cache.assets[mint],
+                        amount: *amount as i64,
+                    });
+                }
+                AccountChange::TokenAccountClosed { address } => {
+                    closed_token_accounts.push(address.clone());
+                }
+            }
+        }
+
+        self.store
+            .apply_balance_batch(&balances, &token_accounts, &closed_token_accounts)
+            .await?;
+        Ok(())
+    }
+
+    async fn write_history_batch(&mut self, entries: &[HistoryEntry]) -> Result<(), BoxError> {
+        let owners = entries.iter().map(|entry| entry.pubkey.as_str());
+        let mints = entries.iter().map(|entry| (entry.mint_address.as_str(), entry.decimals));
+        self.resolve_ids(owners, mints).await?;
+
+        let mut transactions = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let Some(user_id) = self.cache.users.get(&entry.pubkey) else {
+                error!("Transaction for a public key not associated with any user: {}", entry.pubkey);
+                continue;
+            };
+            let direction = if entry.delta > 0 { "incoming" } else { "outgoing" };
+            transactions.push(NewTransaction {
+                user_id: *user_id,
+                asset_id: self.cache.assets[&entry.mint_address],
+                signature: entry.signature.clone(),
+                direction: direction.to_string(),
+                amount: entry.delta.unsigned_abs() as i64,
+                counterparty: entry.counterparty.clone(),
+                slot: Some(entry.slot),
+                block_time: entry.block_time,
+                kind: "transfer".to_string(),
+                status: "confirmed".to_string(),
+                mpc_session_id: None,
+            });
+        }
+
+        self.store.record_transactions(transactions).await?;
+        Ok(())
+    }
+
+    /// Fills the cache for every owner and mint given, loading unknown users
+    /// in a single query.
+    async fn resolve_ids<'a>(
+        &mut self,
+        owners: impl Iterator<Item = &'a str>,
+        mints: impl Iterator<Item = (&'a str, i32)>,
+    ) -> Result<(), BoxError> {
+        let mut missing_users: Vec<String> = owners
+            .filter(|owner| !self.cache.users.contains_key(*owner))
+            .map(str::to_string)
+            .collect();
+        missing_users.sort();
+        missing_users.dedup();
+
+        if !missing_users.is_empty() {
+            for (public_key, user_id) in self.store.get_user_ids_by_public_keys(&missing_users).await? {
+                self.cache.users.insert(public_key, user_id);
+            }
+        }
+
+        for (mint, decimals) in mints {
+            if self.cache.assets.contains_key(mint) {
+                continue;
+            }
+            let asset = if mint == SOL_MINT {
+                self.store.upsert_asset(SOL_MINT, 9, "Solana", "SOL").await?
+            } else {
+                let (name, symbol) = get_token_metadata(mint);
+                self.store.upsert_asset(mint, decimals, &name, &symbol).await?
+            };
+            self.cache.assets.insert(mint.to_string(), asset.id);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    /// Records what was written; fails writes that include a bad account.
+    #[derive(Clone, Default)]
+    struct FakeSink {
+        bad_accounts: HashSet<String>,
+        down: bool,
+        batches: Arc<Mutex<Vec<Vec<String>>>>,
+        history: Arc<Mutex<Vec<Vec<String>>>>,
+        dead_letters: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl BatchSink for FakeSink {
+        async fn write(&mut self, changes: &[AccountChange]) -> Result<(), BoxError> {
+            let accounts: Vec<String> = changes.iter().map(|c| c.account().to_string()).collect();
+            if self.down || accounts.iter().any(|a| self.bad_accounts.contains(a)) {
+                return Err("write failed".into());
+            }
+            self.batches.lock().unwrap().push(accounts);
+            Ok(())
+        }
+
+        async fn write_history(&mut self, entries: &[HistoryEntry]) -> Result<(), BoxError> {
+            let signatures: Vec<String> = entries.iter().map(|e| e.signature.clone()).collect();
+            if self.down || entries.iter().any(|e| self.bad_accounts.contains(&e.pubkey)) {
+                return Err("write failed".into());
+            }
+            self.history.lock().unwrap().push(signatures);
+            Ok(())
+        }
+
+        async fn dead_letter(&mut self, account: &str, _update: &str, _error: &str, _attempts: u32) -> Result<(), BoxError> {
+            if self.down {
+                return Err("database down".into());
+            }
+            self.dead_letters.lock().unwrap().push(account.to_string());
+            Ok(())
+        }
+    }
+
+    fn sol(owner: &str, lamports: u64) -> AccountChange {
+        AccountChange::Sol { owner: owner.to_string(), lamports }
+    }
+
+    fn history_entry(pubkey: &str, signature: &str) -> HistoryEntry {
+        HistoryEntry {
+            pubkey: pubkey.to_string(),
+            mint_address: SOL_MINT.to_string(),
+            decimals: 9,
+            signature: signature.to_string(),
+            delta: 1,
+            counterparty: None,
+            slot: 1,
+            block_time: Utc::now(),
+        }
+    }
+
+    fn queue(writer: &mut BalanceWriter<FakeSink>, change: AccountChange) {
+        let account = change.account().to_string();
+        writer.pending.insert(account, PendingChange { change, attempts: 0 });
+    }
+
+    #[tokio::test]
+    async fn coalesces_updates_per_account() {
+        let sink = FakeSink::default();
+        let writer = BalanceWriter::new(sink.clone(), 3);
+        let config = WriterConfig {
+            flush_interval: Duration::from_secs(3600),
+            channel_capacity: 16,
+            max_batch_size: 2,
+            max_flush_attempts: 3,
+        };
+        let (sender, receiver) = mpsc::channel(16);
+        let (_history_sender, history_receiver) = mpsc::channel(16);
+        for change in [sol("a", 1), sol("a", 2), sol("b", 3), sol("c", 4)] {
+            sender.send(change).await.unwrap();
+        }
+        drop(sender);
+        writer.run(receiver, history_receiver, config).await;
+
+        let mut batches = sink.batches.lock().unwrap().clone();
+        for batch in &mut batches {
+            batch.sort();
+        }
+        // Two updates of `a` collapse into one; the batch size forces a flush
+        // before `c`, which is flushed when the channel closes
+        assert_eq!(batches, vec![vec!["a".to_string(), "b".to_string()], vec!["c".to_string()]]);
+    }
+
+    #[tokio::test]
+    async fn sets_a_bad_row_aside_after_max_attempts() {
+        let sink = FakeSink { bad_accounts: HashSet::from(["bad".to_string()]), ..Default::default() };
+        let mut writer = BalanceWriter::new(sink.clone(), 3);
+        queue(&mut writer, sol("good", 1));
+        queue(&mut writer, sol("bad", 2));
+
+        for _ in 0..2 {
+            writer.flush().await;
+            assert_eq!(writer.pending.len(), 2);
+        }
+        writer.flush().await;
+
+        assert!(writer.pending.is_empty());
+        assert_eq!(*sink.batches.lock().unwrap(), vec![vec!["good".to_string()]]);
+        assert_eq!(*sink.dead_letters.lock().unwrap(), vec!["bad".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn keeps_retrying_while_the_database_is_down() {
+        let sink = FakeSink { down: true, ..Default::default() };
+        let mut writer = BalanceWriter::new(sink.clone(), 2);
+        queue(&mut writer, sol("a", 1));
+
+        for _ in 0..4 {
+            writer.flush().await;
+        }
+        assert_eq!(writer.pending["a"].attempts, 4);
+        assert!(sink.dead_letters.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn newer_update_replaces_a_failing_one() {
+        let sink = FakeSink { bad_accounts: HashSet::from(["a".to_string()]), ..Default::default() };
+        let mut writer = BalanceWriter::new(sink, 3);
+        queue(&mut writer, sol("a", 1));
+        writer.flush().await;
+        assert_eq!(writer.pending["a"].attempts, 1);
+
+        queue(&mut writer, sol("a", 2));
+        assert_eq!(writer.pending["a"].attempts, 0);
+        assert!(matches!(writer.pending["a"].change, AccountChange::Sol { lamports: 2, .. }));
+    }
+
+    #[tokio::test]
+    async fn batches_history_and_sets_a_bad_entry_aside() {
+        let sink = FakeSink { bad_accounts: HashSet::from(["bad".to_string()]), ..Default::default() };
+        let mut writer = BalanceWriter::new(sink.clone(), 2);
+        for entry in [history_entry("a", "s1"), history_entry("a", "s2"), history_entry("bad", "s3")] {
+            writer.history.push(PendingEntry { entry, attempts: 0 });
+        }
+
+        writer.flush().await;
+        assert_eq!(writer.history.len(), 3);
+        writer.flush().await;
+
+        // Both entries of `a` are kept, unlike coalesced balance updates
+        assert!(writer.history.is_empty());
+        assert_eq!(*sink.history.lock().unwrap(), vec![vec!["s1".to_string()], vec!["s2".to_string()]]);
+        assert_eq!(*sink.dead_letters.lock().unwrap(), vec!["bad".to_string()]);
+
+        writer.history.push(PendingEntry { entry: history_entry("b", "s4"), attempts: 0 });
+        writer.history.push(PendingEntry { entry: history_entry("b", "s5"), attempts: 0 });
+        writer.flush().await;
+        assert_eq!(sink.history.lock().unwrap()[2], vec!["s4".to_string(), "s5".to_string()]);
+    }
+}
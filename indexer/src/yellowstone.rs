@@ -22,7 +22,7 @@ use {
         IsBlockhashValidRequest, IsBlockhashValidResponse, PingRequest, PongResponse,
         SubscribeReplayInfoRequest, SubscribeReplayInfoResponse, SubscribeRequest, SubscribeUpdate,
         subscribe_request_filter_accounts_filter, SubscribeRequestFilterAccounts, SubscribeRequestFilterAccountsFilter, SubscribeRequestFilterAccountsFilterMemcmp,
-        subscribe_request_filter_accounts_filter_memcmp,
+        subscribe_request_filter_accounts_filter_memcmp, SubscribeRequestFilterTransactions,
     },
 };
 
@@ -210,55 +210,90 @@ impl<F: Interceptor> GeyserGrpcClient<F> {
     pub async fn subscribe_to_addresses(
         &mut self,
         addresses: Vec<String>,
+        token_accounts: Vec<String>,
     ) -> GeyserGrpcClientResult<(
         impl Sink<SubscribeRequest, Error = mpsc::SendError>,
         impl Stream<Item = Result<SubscribeUpdate, Status>>,
     )> {
-        let mut accounts_filter = std::collections::HashMap::new();
-
-        // Subscribe to all monitored addresses for lamport changes
-        for (i, address) in addresses.iter().enumerate() {
-            accounts_filter.insert(
-                format!("address_{}", i),
-                SubscribeRequestFilterAccounts {
-                    account: vec![address.clone()],
-                    owner: vec![],
-                    filters: vec![],
-                },
-            );
-        }
+        let request = addresses_subscribe_request(&addresses, &token_accounts);
+        self.subscribe_with_request(Some(request)).await
+    }
+}
 
-        // Also subscribe to all SPL token accounts owned by our users
+/// Builds the subscription for the monitored wallets and the token accounts
+/// already known to belong to them. Sending a new request on the subscribe sink
+/// replaces the filters of the running stream.
+pub fn addresses_subscribe_request(addresses: &[String], token_accounts: &[String]) -> SubscribeRequest {
+    let mut accounts_filter = std::collections::HashMap::new();
+
+    // Subscribe to all monitored addresses for lamport changes
+    for (i, address) in addresses.iter().enumerate() {
         accounts_filter.insert(
-            "user_token_accounts".to_string(),
+            format!("address_{}", i),
             SubscribeRequestFilterAccounts {
-                account: vec![],
-                owner: vec!["TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA".to_string()], // SPL Token Program
-                filters: addresses
-                    .iter()
-                    .map(|addr| SubscribeRequestFilterAccountsFilter {
-                        filter: Some(subscribe_request_filter_accounts_filter::Filter::Memcmp(
-                            SubscribeRequestFilterAccountsFilterMemcmp {
-                                offset: 32, // Owner field in token account
-                                data: Some(
-                                    subscribe_request_filter_accounts_filter_memcmp::Data::Base58(
-                                        addr.clone(),
-                                    ),
-                                ),
-                            },
-                        )),
-                    })
-                    .collect(),
+                account: vec![address.clone()],
+                owner: vec![],
+                filters: vec![],
             },
         );
+    }
 
-        let request = SubscribeRequest {
-            accounts: accounts_filter,
-            commitment: Some(CommitmentLevel::Confirmed as i32),
-            ..Default::default()
-        };
+    // Also subscribe to all SPL token accounts owned by our users
+    accounts_filter.insert(
+        "user_token_accounts".to_string(),
+        SubscribeRequestFilterAccounts {
+            account: vec![],
+            owner: vec!["TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA".to_string()], // SPL Token Program
+            filters: addresses
+                .iter()
+                .map(|addr| SubscribeRequestFilterAccountsFilter {
+                    filter: Some(subscribe_request_filter_accounts_filter::Filter::Memcmp(
+                        SubscribeRequestFilterAccountsFilterMemcmp {
+                            offset: 32, // Owner field in token account
+                            data: Some(
+                                subscribe_request_filter_accounts_filter_memcmp::Data::Base58(
+                                    addr.clone(),
+                                ),
+                            ),
+                        },
+                    )),
+                })
+                .collect(),
+        },
+    );
+
+    // Follow tracked token accounts by address as well, so we still hear about
+    // them once they are closed or handed to another program
+    if !token_accounts.is_empty() {
+        accounts_filter.insert(
+            "tracked_token_accounts".to_string(),
+            SubscribeRequestFilterAccounts {
+                account: token_accounts.to_vec(),
+                owner: vec![],
+                filters: vec![],
+            },
+        );
+    }
 
-        self.subscribe_with_request(Some(request)).await
+    // Transactions touching our users feed the transaction history
+    let mut transactions_filter = std::collections::HashMap::new();
+    transactions_filter.insert(
+        "user_transactions".to_string(),
+        SubscribeRequestFilterTransactions {
+            vote: Some(false),
+            failed: Some(false),
+            signature: None,
+            account_include: addresses.iter().chain(token_accounts).cloned().collect(),
+            account_exclude: vec![],
+            account_required: vec![],
+        },
+    );
+
+    SubscribeRequest {
+        accounts: accounts_filter,
+        transactions: transactions_filter,
+        commitment: Some(CommitmentLevel::Confirmed as i32),
+        ..Default::default()
     }
 }
 
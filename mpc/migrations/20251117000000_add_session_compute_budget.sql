@@ -0,0 +1,4 @@
+-- Compute budget chosen when a session starts, so both signing rounds and the
+-- broadcast build the same transfer message.
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS compute_unit_limit INTEGER;
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS compute_unit_price BIGINT;
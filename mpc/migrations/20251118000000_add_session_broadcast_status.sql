@@ -0,0 +1,13 @@
+-- Broadcast tracking. A session is `pending` while signing, `submitted` once
+-- sent, then `confirmed`, `failed` or `expired` when its blockhash runs out.
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'pending';
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS transaction_signature TEXT;
+-- Base64 wire transaction, kept to rebroadcast until it lands.
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS signed_transaction TEXT;
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS last_valid_block_height BIGINT;
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS error TEXT;
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS submitted_at TIMESTAMPTZ;
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS settled_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS mpc_signing_sessions_submitted_idx
+    ON mpc_signing_sessions (submitted_at) WHERE status = 'submitted';
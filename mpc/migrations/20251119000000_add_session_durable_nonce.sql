@@ -0,0 +1,4 @@
+-- Durable nonce a transfer session was built against. Sessions with one are
+-- not bound to a recent blockhash and may stay open for hours.
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS nonce_account TEXT;
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS durable_nonce TEXT;
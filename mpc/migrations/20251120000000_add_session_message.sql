@@ -0,0 +1,3 @@
+-- Off-chain message a session signs instead of a transaction. Message
+-- sessions have no recipient or amount and are never broadcast.
+ALTER TABLE mpc_signing_sessions ADD COLUMN IF NOT EXISTS message BYTEA;
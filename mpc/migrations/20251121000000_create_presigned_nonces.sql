@@ -0,0 +1,19 @@
+-- MuSig2 nonce pairs generated ahead of signing so a signature takes a
+-- single round. Each node keeps its own secret nonces and the other node's
+-- public nonces for the same pair.
+CREATE TABLE IF NOT EXISTS mpc_presigned_nonces (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    pair_id UUID NOT NULL,
+    end_user_pubkey TEXT NOT NULL,
+    node_id INT NOT NULL,
+    -- Cleared when the pair is used, so a nonce can never sign twice.
+    secret_state BYTEA,
+    peer_message TEXT NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (pair_id, node_id),
+    CHECK ((used_at IS NULL) = (secret_state IS NOT NULL))
+);
+
+CREATE INDEX IF NOT EXISTS mpc_presigned_nonces_unused_idx
+    ON mpc_presigned_nonces (end_user_pubkey, node_id, created_at) WHERE used_at IS NULL;
@@ -0,0 +1,29 @@
+-- Shares are re-randomized by refreshes. A refreshed share is the scalar of
+-- `private_key` plus `share_offset`, while `public_key` keeps the key the
+-- share was generated with so the aggregated key never changes.
+ALTER TABLE mpc_keys ADD COLUMN IF NOT EXISTS share_offset TEXT;
+ALTER TABLE mpc_keys ADD COLUMN IF NOT EXISTS share_version INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE mpc_keys ADD COLUMN IF NOT EXISTS share_refreshed_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+-- The next share, held until every node has prepared its own.
+ALTER TABLE mpc_keys ADD COLUMN IF NOT EXISTS refresh_id UUID;
+ALTER TABLE mpc_keys ADD COLUMN IF NOT EXISTS next_private_key TEXT;
+ALTER TABLE mpc_keys ADD COLUMN IF NOT EXISTS next_share_offset TEXT;
+
+-- Audit trail of refreshes; each node records its own part.
+CREATE TABLE IF NOT EXISTS mpc_key_refreshes (
+    id BIGSERIAL PRIMARY KEY,
+    refresh_id UUID NOT NULL,
+    end_user_pubkey TEXT NOT NULL,
+    node_id INTEGER NOT NULL,
+    from_version INTEGER NOT NULL,
+    -- Hex of the new share times the base point.
+    share_commitment TEXT NOT NULL,
+    status TEXT NOT NULL CHECK (status IN ('prepared', 'committed', 'aborted')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    completed_at TIMESTAMPTZ,
+    UNIQUE (refresh_id, node_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_mpc_key_refreshes_key
+    ON mpc_key_refreshes (end_user_pubkey, node_id, created_at);
@@ -0,0 +1,3 @@
+-- Starting a session checks for other open sessions on the same nonce account.
+CREATE INDEX IF NOT EXISTS mpc_signing_sessions_nonce_account_idx
+    ON mpc_signing_sessions (nonce_account) WHERE nonce_account IS NOT NULL;
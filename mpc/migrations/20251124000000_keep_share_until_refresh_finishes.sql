@@ -0,0 +1,8 @@
+-- A committed node keeps its previous share, and `refresh_id` stays set,
+-- until every node has committed the refresh.
+ALTER TABLE mpc_keys ADD COLUMN IF NOT EXISTS prev_private_key TEXT;
+ALTER TABLE mpc_keys ADD COLUMN IF NOT EXISTS prev_share_offset TEXT;
+
+-- The refresh task picks up refreshes an earlier attempt left pending.
+CREATE INDEX IF NOT EXISTS idx_mpc_keys_pending_refresh
+    ON mpc_keys (node_id) WHERE refresh_id IS NOT NULL;
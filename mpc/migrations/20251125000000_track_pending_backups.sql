@@ -0,0 +1,6 @@
+-- Share version a finished refresh left to be backed up. Cleared once the
+-- backup is written, so a failed backup is retried.
+ALTER TABLE mpc_keys ADD COLUMN IF NOT EXISTS backup_pending_version INTEGER;
+
+CREATE INDEX IF NOT EXISTS idx_mpc_keys_pending_backup
+    ON mpc_keys (node_id) WHERE backup_pending_version IS NOT NULL;
@@ -0,0 +1,709 @@
+use crate::db::{MpcKey, MpcStore};
+use crate::error::Error;
+use crate::tss;
+use base64::Engine;
+use chacha20poly1305::aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use chrono::{DateTime, Utc};
+use curv::elliptic::curves::{Ed25519, Point};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Format version written into every backup. Bump it whenever the header,
+/// the key derivation or the sealed share layout changes.
+pub const BACKUP_VERSION: u32 = 1;
+const HKDF_INFO: &[u8] = b"mpc key share backup v1";
+/// Argon2id cost for passphrase backups, the OWASP minimum.
+const ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const ARGON2_ITERATIONS: u32 = 2;
+const ARGON2_PARALLELISM: u32 = 1;
+/// Commands `run_command` handles instead of starting the server.
+pub const COMMANDS: [&str; 3] = ["export-shares", "restore-shares", "recovery-keygen"];
+
+/// One node's share of one wallet, as sealed in a backup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupShare {
+    pub end_user_pubkey: String,
+    pub node_id: i32,
+    /// Public keys of all nodes in node order, so the share can be checked
+    /// even when the other nodes are lost too.
+    pub public_keys: Vec<String>,
+    pub private_key: String,
+    pub share_offset: Option<String>,
+    pub share_version: i32,
+}
+
+/// How the backup key is derived.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Protection {
+    Argon2id {
+        salt: String,
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    /// Sealed to an offline recovery key; only its secret can open it.
+    X25519 {
+        ephemeral_public_key: String,
+        recovery_public_key: String,
+    },
+}
+
+/// Everything about a backup that is authenticated but not encrypted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupHeader {
+    pub version: u32,
+    pub node_id: i32,
+    pub created_at: DateTime<Utc>,
+    pub protection: Protection,
+}
+
+/// A backup file: the header, then the shares encrypted with
+/// XChaCha20-Poly1305 under the header as associated data.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupFile {
+    #[serde(flatten)]
+    pub header: BackupHeader,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+pub enum Recipient {
+    Passphrase(Zeroizing<String>),
+    RecoveryKey(PublicKey),
+}
+
+pub enum Unlock {
+    Passphrase(Zeroizing<String>),
+    RecoverySecret(StaticSecret),
+}
+
+/// Backs up a wallet's shares as soon as a refresh commits, since a backup
+/// from before the refresh no longer restores. Each node's share is sealed
+/// to that node's recovery key.
+#[derive(Clone)]
+pub struct AutoBackup {
+    pub(crate) dir: PathBuf,
+    /// Indexed by node id minus one.
+    pub(crate) recovery_keys: Vec<PublicKey>,
+}
+
+/// Current version and share commitment of one node's share of a wallet.
+pub struct ShareState {
+    pub share_version: i32,
+    pub commitment: Point<Ed25519>,
+}
+
+impl BackupShare {
+    fn from_key(key: &MpcKey, public_keys: Vec<String>) -> Self {
+        BackupShare {
+            end_user_pubkey: key.end_user_pubkey.clone(),
+            node_id: key.node_id,
+            public_keys,
+            private_key: key.private_key.clone(),
+            share_offset: key.share_offset.clone(),
+            share_version: key.share_version,
+        }
+    }
+
+    fn to_key(&self) -> Result<MpcKey, Error> {
+        let public_key = usize::try_from(self.node_id - 1)
+            .ok()
+            .and_then(|index| self.public_keys.get(index))
+            .ok_or_else(|| backup_error("Share's node is not among its public keys"))?;
+        Ok(MpcKey {
+            end_user_pubkey: self.end_user_pubkey.clone(),
+            node_id: self.node_id,
+            public_key: public_key.clone(),
+            private_key: self.private_key.clone(),
+            share_offset: self.share_offset.clone(),
+            share_version: self.share_version,
+            refresh_id: None,
+        })
+    }
+
+    fn state(&self) -> Result<ShareState, Error> {
+        Ok(ShareState {
+            share_version: self.share_version,
+            commitment: self.to_key()?.share()?.commitment(),
+        })
+    }
+}
+
+/// Hex secret and public key of a new recovery key.
+pub fn generate_recovery_key() -> (Zeroizing<String>, String) {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    (Zeroizing::new(hex::encode(secret.as_bytes())), hex::encode(public.as_bytes()))
+}
+
+pub fn parse_recovery_key(encoded: &str) -> Result<PublicKey, Error> {
+    Ok(PublicKey::from(*decode_key(encoded)?))
+}
+
+pub fn parse_recovery_secret(encoded: &str) -> Result<StaticSecret, Error> {
+    Ok(StaticSecret::from(*decode_key(encoded)?))
+}
+
+/// Encrypts `shares` of node `node_id` for `recipient`.
+pub fn seal(node_id: i32, shares: &[BackupShare], recipient: &Recipient) -> Result<BackupFile, Error> {
+    let (protection, key) = match recipient {
+        Recipient::Passphrase(passphrase) => {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let protection = Protection::Argon2id {
+                salt: base64::engine::general_purpose::STANDARD.encode(salt),
+                memory_kib: ARGON2_MEMORY_KIB,
+                iterations: ARGON2_ITERATIONS,
+                parallelism: ARGON2_PARALLELISM,
+            };
+            let key = passphrase_key(passphrase, &protection)?;
+            (protection, key)
+        }
+        Recipient::RecoveryKey(recovery_public_key) => {
+            let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+            let ephemeral_public_key = PublicKey::from(&ephemeral);
+            let shared = ephemeral.diffie_hellman(recovery_public_key);
+            let key = recovery_key(shared.as_bytes(), &ephemeral_public_key, recovery_public_key);
+            let protection = Protection::X25519 {
+                ephemeral_public_key: hex::encode(ephemeral_public_key.as_bytes()),
+                recovery_public_key: hex::encode(recovery_public_key.as_bytes()),
+            };
+            (protection, key)
+        }
+    };
+
+    let header = BackupHeader {
+        version: BACKUP_VERSION,
+        node_id,
+        created_at: Utc::now(),
+        protection,
+    };
+    let plaintext = Zeroizing::new(serde_json::to_vec(shares).unwrap());
+    let aad = serde_json::to_vec(&header).unwrap();
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&key[..]))
+        .encrypt(&nonce, Payload { msg: &plaintext, aad: &aad })
+        .map_err(|_| backup_error("Encryption failed"))?;
+
+    Ok(BackupFile {
+        header,
+        nonce: base64::engine::general_purpose::STANDARD.encode(nonce),
+        ciphertext: base64::engine::general_purpose::STANDARD.encode(ciphertext),
+    })
+}
+
+/// Decrypts a backup, failing if it was made for another key or any part of
+/// it, header included, was changed.
+pub fn open(file: &BackupFile, unlock: &Unlock) -> Result<Vec<BackupShare>, Error> {
+    let header = &file.header;
+    if header.version != BACKUP_VERSION {
+        return Err(backup_error(&format!("Unsupported backup version {}", header.version)));
+    }
+
+    let key = match (&header.protection, unlock) {
+        (protection @ Protection::Argon2id { .. }, Unlock::Passphrase(passphrase)) => {
+            passphrase_key(passphrase, protection)?
+        }
+        (
+            Protection::X25519 { ephemeral_public_key, recovery_public_key },
+            Unlock::RecoverySecret(secret),
+        ) => {
+            let recovery_public_key = parse_recovery_key(recovery_public_key)?;
+            if PublicKey::from(secret) != recovery_public_key {
+                return Err(backup_error("Backup was sealed to a different recovery key"));
+            }
+            let ephemeral_public_key = parse_recovery_key(ephemeral_public_key)?;
+            let shared = secret.diffie_hellman(&ephemeral_public_key);
+            if !shared.was_contributory() {
+                return Err(backup_error("Invalid ephemeral key"));
+            }
+            recovery_key(shared.as_bytes(), &ephemeral_public_key, &recovery_public_key)
+        }
+        (Protection::Argon2id { .. }, _) => return Err(backup_error("Backup is protected by a passphrase")),
+        (Protection::X25519 { .. }, _) => return Err(backup_error("Backup is sealed to a recovery key")),
+    };
+
+    let nonce = decode_base64(&file.nonce)?;
+    if nonce.len() != 24 {
+        return Err(backup_error("Invalid nonce"));
+    }
+    let ciphertext = decode_base64(&file.ciphertext)?;
+    let aad = serde_json::to_vec(header).unwrap();
+    let plaintext = Zeroizing::new(
+        XChaCha20Poly1305::new(Key::from_slice(&key[..]))
+            .decrypt(XNonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: &aad })
+            .map_err(|_| backup_error("Wrong key, or the backup was tampered with"))?,
+    );
+    let shares: Vec<BackupShare> =
+        serde_json::from_slice(&plaintext).map_err(|_| backup_error("Backup contents are malformed"))?;
+    if shares.iter().any(|share| share.node_id != header.node_id) {
+        return Err(backup_error("Backup holds shares of another node"));
+    }
+    Ok(shares)
+}
+
+/// Checks that a wallet can sign with the given shares: `public_keys` must
+/// aggregate to `end_user_pubkey` through `tss::key_agg`, and the shares of
+/// all nodes, in node order, must be of the same version and add up to it.
+/// Shares from before a refresh never combine with ones from after it.
+pub fn verify_wallet(end_user_pubkey: &str, public_keys: &[String], states: &[ShareState]) -> Result<(), Error> {
+    let pubkeys = public_keys
+        .iter()
+        .map(|key| Pubkey::from_str(key))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| backup_error("Invalid public key"))?;
+    let aggkey = tss::key_agg(pubkeys.clone(), None)?.agg_public_key;
+    if Pubkey::new_from_array(aggkey.to_bytes(true)).to_string() != end_user_pubkey {
+        return Err(backup_error(&format!("Public keys do not aggregate to {}", end_user_pubkey)));
+    }
+    if states.windows(2).any(|pair| pair[0].share_version != pair[1].share_version) {
+        return Err(backup_error(&format!(
+            "Shares of {} are from different refreshes; back up again after every refresh",
+            end_user_pubkey
+        )));
+    }
+    let commitments: Vec<_> = states.iter().map(|state| state.commitment.clone()).collect();
+    tss::check_share_commitments(pubkeys, &commitments)
+        .map_err(|_| backup_error(&format!("Shares do not add up to {}", end_user_pubkey)))
+}
+
+/// Runs a backup command instead of the server:
+///
+/// - `export-shares <node_id> <file> [--recovery-key <hex>]` writes the
+///   node's shares, sealed to the recovery key or else to the passphrase in
+///   `BACKUP_PASSPHRASE`.
+/// - `restore-shares <file>... [--dry-run]` checks every share against the
+///   other backups given and the live nodes, then restores those the nodes
+///   no longer hold. Opens backups with `BACKUP_RECOVERY_SECRET` if set,
+///   else `BACKUP_PASSPHRASE`.
+/// - `recovery-keygen` prints a new recovery key pair.
+pub async fn run_command(command: &str, args: &[String]) -> Result<(), Error> {
+    match command {
+        "export-shares" => {
+            let (node_id, path) = match args {
+                [node_id, path, ..] => (node_id.parse().map_err(|_| backup_error("Invalid node id"))?, path),
+                _ => return Err(backup_error("Usage: export-shares <node_id> <file> [--recovery-key <hex>]")),
+            };
+            let recipient = match flag_value(args, "--recovery-key") {
+                Some(key) => Recipient::RecoveryKey(parse_recovery_key(key)?),
+                None => Recipient::Passphrase(passphrase()?),
+            };
+            let stores = node_stores().await?;
+            let file = export(&stores, node_id, &recipient).await?;
+            write_backup(Path::new(path), &file)?;
+            println!("Exported node {} shares to {}", node_id, path);
+            Ok(())
+        }
+        "restore-shares" => {
+            let paths: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+            if paths.is_empty() {
+                return Err(backup_error("Usage: restore-shares <file>... [--dry-run]"));
+            }
+            let unlock = match std::env::var("BACKUP_RECOVERY_SECRET") {
+                Ok(secret) => Unlock::RecoverySecret(parse_recovery_secret(&Zeroizing::new(secret))?),
+                Err(_) => Unlock::Passphrase(passphrase()?),
+            };
+            let mut shares = Vec::new();
+            for path in paths {
+                let file: BackupFile = serde_json::from_slice(&std::fs::read(path)?)
+                    .map_err(|_| backup_error(&format!("{} is not a backup file", path)))?;
+                shares.extend(open(&file, &unlock)?);
+            }
+            let stores = node_stores().await?;
+            let dry_run = args.iter().any(|arg| arg == "--dry-run");
+            let (restored, present) = restore(&stores, &shares, dry_run).await?;
+            if dry_run {
+                println!("Verified {} shares; {} would be restored", shares.len(), restored);
+            } else {
+                println!("Restored {} shares, {} were still present", restored, present);
+            }
+            Ok(())
+        }
+        "recovery-keygen" => {
+            let (secret, public) = generate_recovery_key();
+            println!("Recovery public key: {}", public);
+            println!("Recovery secret key: {}", *secret);
+            Ok(())
+        }
+        _ => Err(backup_error(&format!("Unknown command {}", command))),
+    }
+}
+
+impl AutoBackup {
+    /// Reads `BACKUP_DIR` and the nodes' `BACKUP_RECOVERY_KEY_1` and
+    /// `BACKUP_RECOVERY_KEY_2`. Returns None unless `BACKUP_DIR` is set.
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        let Ok(dir) = std::env::var("BACKUP_DIR") else {
+            return Ok(None);
+        };
+        let mut recovery_keys = Vec::new();
+        for var in ["BACKUP_RECOVERY_KEY_1", "BACKUP_RECOVERY_KEY_2"] {
+            let key = std::env::var(var).map_err(|_| backup_error(&format!("{} must be set", var)))?;
+            recovery_keys.push(parse_recovery_key(&key)?);
+        }
+        Ok(Some(AutoBackup { dir: dir.into(), recovery_keys }))
+    }
+
+    /// Writes each node's current share of `end_user_pubkey` to a file of
+    /// its own, named after the wallet, node and share version. Versions
+    /// already backed up are skipped.
+    pub async fn export_wallet(&self, stores: &[&MpcStore], end_user_pubkey: &str) -> Result<(), Error> {
+        let mut keys = Vec::with_capacity(stores.len());
+        for (store, node_id) in stores.iter().zip(1..) {
+            let key = store.get_key(end_user_pubkey, node_id).await?;
+            key.check_settled()?;
+            keys.push(key);
+        }
+        let public_keys: Vec<String> = keys.iter().map(|key| key.public_key.clone()).collect();
+        for (key, recovery_key) in keys.iter().zip(&self.recovery_keys) {
+            let path = self.dir.join(format!("{}-node{}-v{}.json", end_user_pubkey, key.node_id, key.share_version));
+            if path.exists() {
+                continue;
+            }
+            let shares = [BackupShare::from_key(key, public_keys.clone())];
+            write_backup(&path, &seal(key.node_id, &shares, &Recipient::RecoveryKey(*recovery_key))?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Seals every share node `node_id` holds. The other nodes are asked for
+/// their public keys so the backup can be checked on its own.
+async fn export(stores: &[MpcStore], node_id: i32, recipient: &Recipient) -> Result<BackupFile, Error> {
+    let store = node_store(stores, node_id)?;
+    let mut shares = Vec::new();
+    for key in store.list_keys(node_id).await? {
+        // Mid-refresh the nodes' shares may not combine
+        key.check_settled()?;
+        let mut public_keys = Vec::with_capacity(stores.len());
+        for (peer, peer_id) in stores.iter().zip(1..) {
+            if peer_id == node_id {
+                public_keys.push(key.public_key.clone());
+            } else {
+                public_keys.push(peer.get_key(&key.end_user_pubkey, peer_id).await?.public_key);
+            }
+        }
+        shares.push(BackupShare::from_key(&key, public_keys));
+    }
+    seal(node_id, &shares, recipient)
+}
+
+/// Verifies every wallet the backups cover before writing anything, then
+/// restores the shares the nodes no longer hold. Returns how many were
+/// (or, on a dry run, would be) restored and how many were still present.
+async fn restore(stores: &[MpcStore], shares: &[BackupShare], dry_run: bool) -> Result<(usize, usize), Error> {
+    let mut wallets: BTreeMap<&str, Vec<&BackupShare>> = BTreeMap::new();
+    for share in shares {
+        wallets.entry(&share.end_user_pubkey).or_default().push(share);
+    }
+
+    let mut missing = Vec::new();
+    let mut present = 0;
+    for (end_user_pubkey, backed_up) in &wallets {
+        let public_keys = &backed_up[0].public_keys;
+        if backed_up.iter().any(|share| &share.public_keys != public_keys) {
+            return Err(backup_error(&format!("Backups disagree on the public keys of {}", end_user_pubkey)));
+        }
+
+        let mut states = Vec::with_capacity(public_keys.len());
+        for (store, node_id) in stores.iter().zip(1..) {
+            let live = store.get_keys_for_user(end_user_pubkey).await?.into_iter().find(|key| key.node_id == node_id);
+            let state = match (backed_up.iter().find(|share| share.node_id == node_id), &live) {
+                (Some(share), _) => share.state()?,
+                (None, Some(key)) => ShareState {
+                    share_version: key.share_version,
+                    commitment: key.share()?.commitment(),
+                },
+                (None, None) => {
+                    return Err(backup_error(&format!("No share of {} for node {}", end_user_pubkey, node_id)));
+                }
+            };
+            states.push(state);
+            match backed_up.iter().find(|share| share.node_id == node_id) {
+                Some(_) if live.is_some() => present += 1,
+                Some(share) => missing.push(*share),
+                None => {}
+            }
+        }
+        verify_wallet(end_user_pubkey, public_keys, &states)?;
+    }
+
+    if dry_run {
+        return Ok((missing.len(), present));
+    }
+    let mut restored = 0;
+    for share in missing {
+        if node_store(stores, share.node_id)?.restore_key(&share.to_key()?).await? {
+            restored += 1;
+        } else {
+            present += 1;
+        }
+    }
+    Ok((restored, present))
+}
+
+async fn node_stores() -> Result<Vec<MpcStore>, Error> {
+    let mut stores = Vec::new();
+    for var in ["MPC_DATABASE_URL_1", "MPC_DATABASE_URL_2"] {
+        let url = std::env::var(var).map_err(|_| backup_error(&format!("{} must be set", var)))?;
+        stores.push(MpcStore::new(sqlx::PgPool::connect(&url).await?));
+    }
+    Ok(stores)
+}
+
+fn node_store(stores: &[MpcStore], node_id: i32) -> Result<&MpcStore, Error> {
+    usize::try_from(node_id - 1)
+        .ok()
+        .and_then(|index| stores.get(index))
+        .ok_or_else(|| Error::InvalidRequest("Invalid node_id".to_string()))
+}
+
+/// Backups are created, never overwritten, and only readable by the owner.
+fn write_backup(path: &Path, file: &BackupFile) -> Result<(), Error> {
+    let mut out = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    out.write_all(&serde_json::to_vec_pretty(file).unwrap())?;
+    out.sync_all()?;
+    Ok(())
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).map(String::as_str)
+}
+
+fn passphrase() -> Result<Zeroizing<String>, Error> {
+    match std::env::var("BACKUP_PASSPHRASE") {
+        Ok(passphrase) if !passphrase.is_empty() => Ok(Zeroizing::new(passphrase)),
+        _ => Err(backup_error("BACKUP_PASSPHRASE must be set")),
+    }
+}
+
+fn passphrase_key(passphrase: &str, protection: &Protection) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let Protection::Argon2id { salt, memory_kib, iterations, parallelism } = protection else {
+        return Err(backup_error("Backup is not protected by a passphrase"));
+    };
+    let params = argon2::Params::new(*memory_kib, *iterations, *parallelism, Some(32))
+        .map_err(|e| backup_error(&format!("Invalid key derivation parameters: {}", e)))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &decode_base64(salt)?, &mut key[..])
+        .map_err(|e| backup_error(&format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+fn recovery_key(
+    shared: &[u8; 32],
+    ephemeral_public_key: &PublicKey,
+    recovery_public_key: &PublicKey,
+) -> Zeroizing<[u8; 32]> {
+    let salt = [ephemeral_public_key.as_bytes().as_slice(), recovery_public_key.as_bytes().as_slice()].concat();
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(HKDF_INFO, &mut key[..])
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+fn decode_key(encoded: &str) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let bytes = Zeroizing::new(hex::decode(encoded.trim()).map_err(|_| backup_error("Keys must be hex"))?);
+    let mut key = Zeroizing::new([0u8; 32]);
+    if bytes.len() != key.len() {
+        return Err(backup_error("Keys must be 32 bytes"));
+    }
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn decode_base64(encoded: &str) -> Result<Vec<u8>, Error> {
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|_| backup_error("Invalid base64 in backup"))
+}
+
+fn backup_error(message: &str) -> Error {
+    Error::BackupFailed(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tss::{refresh_randomness, refresh_share, KeyShare};
+    use curv::elliptic::curves::Scalar;
+    use solana_sdk::signature::{Keypair, Signer};
+
+    /// Two nodes' shares of a fresh wallet, refreshed once when `refreshed`.
+    fn wallet_shares(refreshed: bool) -> (Vec<BackupShare>, Vec<BackupShare>) {
+        let keypairs = [Keypair::new(), Keypair::new()];
+        let pubkeys: Vec<_> = keypairs.iter().map(|k| k.pubkey()).collect();
+        let aggkey = tss::key_agg(pubkeys.clone(), None).unwrap().agg_public_key;
+        let end_user_pubkey = Pubkey::new_from_array(aggkey.to_bytes(true)).to_string();
+        let public_keys: Vec<String> = pubkeys.iter().map(|k| k.to_string()).collect();
+
+        let share = |node_id: i32, keypair: &Keypair, offset: Option<String>, share_version: i32| BackupShare {
+            end_user_pubkey: end_user_pubkey.clone(),
+            node_id,
+            public_keys: public_keys.clone(),
+            private_key: bs58::encode(keypair.to_bytes()).into_string(),
+            share_offset: offset,
+            share_version,
+        };
+        let original: Vec<_> = keypairs.iter().zip(1..).map(|(kp, node_id)| share(node_id, kp, None, 0)).collect();
+        if !refreshed {
+            return (original.clone(), original);
+        }
+
+        let rho = refresh_randomness();
+        let tweaks = [rho.clone(), Scalar::zero() - rho];
+        let current = keypairs
+            .iter()
+            .zip(&tweaks)
+            .zip(1..)
+            .map(|((kp, tweak), node_id)| {
+                let next = refresh_share(&KeyShare::from(kp.insecure_clone()), pubkeys.clone(), tweak).unwrap();
+                share(node_id, &next.keypair, next.offset.as_ref().map(crate::db::encode_scalar), 1)
+            })
+            .collect();
+        (original, current)
+    }
+
+    fn verify(shares: &[BackupShare]) -> Result<(), Error> {
+        let states: Vec<_> = shares.iter().map(|share| share.state().unwrap()).collect();
+        verify_wallet(&shares[0].end_user_pubkey, &shares[0].public_keys, &states)
+    }
+
+    fn passphrase(passphrase: &str) -> Zeroizing<String> {
+        Zeroizing::new(passphrase.to_string())
+    }
+
+    #[test]
+    fn passphrase_backup_round_trips() {
+        let (_, shares) = wallet_shares(false);
+        let file = seal(1, &shares[..1], &Recipient::Passphrase(passphrase("correct horse"))).unwrap();
+        let file: BackupFile = serde_json::from_slice(&serde_json::to_vec(&file).unwrap()).unwrap();
+
+        let opened = open(&file, &Unlock::Passphrase(passphrase("correct horse"))).unwrap();
+        assert_eq!(opened[0].private_key, shares[0].private_key);
+        assert!(open(&file, &Unlock::Passphrase(passphrase("wrong"))).is_err());
+
+        // The header is authenticated too
+        let mut tampered = file;
+        tampered.header.node_id = 2;
+        assert!(open(&tampered, &Unlock::Passphrase(passphrase("correct horse"))).is_err());
+    }
+
+    #[test]
+    fn recovery_key_backup_round_trips() {
+        let (_, shares) = wallet_shares(false);
+        let (secret, public) = generate_recovery_key();
+        let file = seal(2, &shares[1..], &Recipient::RecoveryKey(parse_recovery_key(&public).unwrap())).unwrap();
+
+        let opened = open(&file, &Unlock::RecoverySecret(parse_recovery_secret(&secret).unwrap())).unwrap();
+        assert_eq!(opened[0].private_key, shares[1].private_key);
+
+        let (other_secret, _) = generate_recovery_key();
+        assert!(open(&file, &Unlock::RecoverySecret(parse_recovery_secret(&other_secret).unwrap())).is_err());
+    }
+
+    #[test]
+    fn restore_drill_checks_shares_add_up() {
+        let (original, current) = wallet_shares(true);
+        verify(&original).unwrap();
+        verify(&current).unwrap();
+
+        // A backup from before the refresh does not combine with the live share
+        assert!(verify(&[original[0].clone(), current[1].clone()]).is_err());
+
+        // Nor does a share swapped in from another wallet
+        let (_, other) = wallet_shares(false);
+        let mut foreign = other[0].clone();
+        foreign.public_keys = current[0].public_keys.clone();
+        foreign.end_user_pubkey = current[0].end_user_pubkey.clone();
+        foreign.share_version = 1;
+        assert!(verify(&[foreign, current[1].clone()]).is_err());
+    }
+
+    async fn live_key(store: &MpcStore, share: &BackupShare) -> Option<MpcKey> {
+        store
+            .get_keys_for_user(&share.end_user_pubkey)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|key| key.node_id == share.node_id)
+    }
+
+    #[sqlx::test(migrations = "migrations")]
+    async fn restore_puts_back_only_missing_shares(pool: sqlx::PgPool) {
+        let stores = vec![MpcStore::new(pool.clone()), MpcStore::new(pool)];
+        let (_, shares) = wallet_shares(true);
+        stores[0].restore_key(&shares[0].to_key().unwrap()).await.unwrap();
+
+        // A dry run counts without writing
+        assert_eq!(restore(&stores, &shares, true).await.unwrap(), (1, 1));
+        assert!(live_key(&stores[1], &shares[1]).await.is_none());
+
+        assert_eq!(restore(&stores, &shares, false).await.unwrap(), (1, 1));
+        let restored = live_key(&stores[1], &shares[1]).await.unwrap();
+        assert_eq!(restored.private_key, shares[1].private_key);
+        assert_eq!(restored.share_version, 1);
+
+        // Restoring again leaves the live shares alone
+        assert_eq!(restore(&stores, &shares, false).await.unwrap(), (0, 2));
+    }
+
+    #[sqlx::test(migrations = "migrations")]
+    async fn restore_checks_backups_against_live_shares(pool: sqlx::PgPool) {
+        let stores = vec![MpcStore::new(pool.clone()), MpcStore::new(pool)];
+        let (original, current) = wallet_shares(true);
+
+        // Neither a backup nor a live share of node 1
+        assert!(restore(&stores, &current[1..], false).await.is_err());
+
+        // A backup from before the refresh does not match the live share
+        stores[0].restore_key(&current[0].to_key().unwrap()).await.unwrap();
+        assert!(restore(&stores, &original[1..], true).await.is_err());
+        assert!(restore(&stores, &original[1..], false).await.is_err());
+        assert!(live_key(&stores[1], &original[1]).await.is_none());
+
+        assert_eq!(restore(&stores, &current[1..], false).await.unwrap(), (1, 0));
+    }
+
+    #[sqlx::test(migrations = "migrations")]
+    async fn refreshed_wallets_are_backed_up_per_version(pool: sqlx::PgPool) {
+        let store = MpcStore::new(pool);
+        let (_, shares) = wallet_shares(true);
+        for share in &shares {
+            store.restore_key(&share.to_key().unwrap()).await.unwrap();
+        }
+        let dir = std::env::temp_dir().join(format!("mpc-backup-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let keys: Vec<_> = (0..2).map(|_| generate_recovery_key()).collect();
+        let backup = AutoBackup {
+            dir: dir.clone(),
+            recovery_keys: keys.iter().map(|(_, public)| parse_recovery_key(public).unwrap()).collect(),
+        };
+
+        backup.export_wallet(&[&store, &store], &shares[0].end_user_pubkey).await.unwrap();
+        // The same version is written once
+        backup.export_wallet(&[&store, &store], &shares[0].end_user_pubkey).await.unwrap();
+
+        let mut opened = Vec::new();
+        for (share, (secret, _)) in shares.iter().zip(&keys) {
+            let path = dir.join(format!("{}-node{}-v1.json", share.end_user_pubkey, share.node_id));
+            let file: BackupFile = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+            opened.extend(open(&file, &Unlock::RecoverySecret(parse_recovery_secret(secret).unwrap())).unwrap());
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        verify(&opened).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
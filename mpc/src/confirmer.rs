@@ -0,0 +1,230 @@
+use crate::db::{MpcStore, SubmittedTransaction};
+use crate::error::Error;
+use crate::metrics;
+use crate::nonce::NonceManager;
+use base64::Engine;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::rpc_config::RpcSendTransactionConfig;
+use solana_sdk::{pubkey::Pubkey, signature::Signature, transaction::VersionedTransaction};
+use solana_transaction_status_client_types::TransactionStatus;
+use std::{str::FromStr, sync::Arc, time::Duration};
+use store::Store;
+use uuid::Uuid;
+
+/// Most signatures `getSignatureStatuses` accepts in one call.
+const BATCH_SIZE: i64 = 256;
+
+#[derive(Debug)]
+enum Settlement {
+    Confirmed,
+    Failed(String),
+    Expired,
+}
+
+impl Settlement {
+    fn status(&self) -> &'static str {
+        match self {
+            Settlement::Confirmed => "confirmed",
+            Settlement::Failed(_) => "failed",
+            Settlement::Expired => "expired",
+        }
+    }
+}
+
+/// Checks submitted transactions every `interval` until each one confirms,
+/// fails or outlives its blockhash or nonce, rebroadcasting those still in
+/// flight.
+pub async fn run(mpc_store: MpcStore, main_store: Arc<Store>, rpc_client: Arc<RpcClient>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = confirm_submitted(&mpc_store, &main_store, &rpc_client).await {
+            log::error!("Confirmation pass failed: {}", e);
+        }
+    }
+}
+
+async fn confirm_submitted(mpc_store: &MpcStore, main_store: &Store, rpc_client: &RpcClient) -> Result<(), Error> {
+    let submitted = mpc_store.list_submitted_transactions(BATCH_SIZE).await?;
+    if submitted.is_empty() {
+        return Ok(());
+    }
+
+    let settlements = check_transactions(rpc_client, &submitted).await?;
+
+    for (session_id, signature, settlement) in settlements {
+        let error = match &settlement {
+            Settlement::Failed(error) => Some(error.as_str()),
+            _ => None,
+        };
+        mpc_store.settle_session(session_id, settlement.status(), error).await?;
+        if let Err(e) = main_store.settle_outgoing_transaction(&signature, settlement.status()).await {
+            log::error!("Failed to record outcome of {}: {}", signature, e);
+        }
+        metrics::SESSIONS.with_label_values(&[settlement.status()]).inc();
+        log::info!("Transaction {} for session {} {}", signature, session_id, settlement.status());
+    }
+    Ok(())
+}
+
+async fn check_transactions(
+    rpc_client: &RpcClient,
+    submitted: &[SubmittedTransaction],
+) -> Result<Vec<(Uuid, String, Settlement)>, Error> {
+    let signatures: Vec<Signature> = submitted
+        .iter()
+        .map(|t| Signature::from_str(&t.transaction_signature).unwrap_or_default())
+        .collect();
+    // Read before the statuses: a transaction missing from them cannot land
+    // anymore if its blockhash had already expired at this height
+    let block_height = rpc_client.get_block_height().await?;
+    let statuses = rpc_client.get_signature_statuses(&signatures).await?.value;
+
+    let mut settlements = Vec::new();
+    for ((submitted, signature), status) in submitted.iter().zip(&signatures).zip(statuses) {
+        let status = match status {
+            Some(status) => status,
+            None => {
+                let can_still_land = match submitted.nonce_account {
+                    Some(_) => !nonce_advanced(rpc_client, submitted).await?,
+                    None => block_height <= submitted.last_valid_block_height as u64,
+                };
+                if can_still_land {
+                    rebroadcast(rpc_client, submitted).await;
+                    continue;
+                }
+                // Recent statuses only reach back a few minutes, and the transaction may
+                // have landed since they were fetched, advancing its own nonce; search the
+                // whole history before giving up on it
+                let history = rpc_client.get_signature_statuses_with_history(&[*signature]).await?.value;
+                match history.into_iter().flatten().next() {
+                    Some(status) => status,
+                    None => {
+                        settlements.push((
+                            submitted.session_id,
+                            submitted.transaction_signature.clone(),
+                            Settlement::Expired,
+                        ));
+                        continue;
+                    }
+                }
+            }
+        };
+        let settlement = match status {
+            TransactionStatus { err: Some(err), .. } => Settlement::Failed(format!("{:?}", err)),
+            status if status.satisfies_commitment(rpc_client.commitment()) => Settlement::Confirmed,
+            // Landed but not yet at the required commitment
+            _ => continue,
+        };
+        settlements.push((submitted.session_id, submitted.transaction_signature.clone(), settlement));
+    }
+    Ok(settlements)
+}
+
+fn decode(submitted: &SubmittedTransaction) -> Option<VersionedTransaction> {
+    base64::engine::general_purpose::STANDARD
+        .decode(&submitted.signed_transaction)
+        .ok()
+        .and_then(|bytes| bincode::deserialize(&bytes).ok())
+}
+
+/// A durable nonce transaction never expires by block height; it can no
+/// longer land once its nonce account has moved on to another nonce.
+async fn nonce_advanced(rpc_client: &RpcClient, submitted: &SubmittedTransaction) -> Result<bool, Error> {
+    let (Some(account), Some(transaction)) = (submitted.nonce_account.as_deref(), decode(submitted)) else {
+        return Ok(false);
+    };
+    let account = Pubkey::from_str(account).map_err(|e| Error::InvalidRequest(e.to_string()))?;
+    let current = NonceManager::current_nonce(rpc_client, &account).await?;
+    Ok(current != Some(*transaction.message.recent_blockhash()))
+}
+
+async fn rebroadcast(rpc_client: &RpcClient, submitted: &SubmittedTransaction) {
+    let Some(transaction) = decode(submitted) else {
+        log::error!("Stored transaction for session {} cannot be decoded", submitted.session_id);
+        return;
+    };
+
+    let config = RpcSendTransactionConfig {
+        skip_preflight: true,
+        ..Default::default()
+    };
+    if let Err(e) = rpc_client.send_transaction_with_config(&transaction, config).await {
+        log::warn!("Rebroadcast of {} failed: {}", submitted.transaction_signature, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use solana_client::rpc_request::RpcRequest;
+    use std::collections::HashMap;
+
+    fn submitted(last_valid_block_height: i64, nonce_account: Option<Pubkey>) -> SubmittedTransaction {
+        SubmittedTransaction {
+            session_id: Uuid::new_v4(),
+            transaction_signature: Signature::new_unique().to_string(),
+            // Undecodable, so nothing is actually rebroadcast
+            signed_transaction: String::new(),
+            last_valid_block_height,
+            nonce_account: nonce_account.map(|account| account.to_string()),
+        }
+    }
+
+    fn status(err: Option<&str>, confirmations: Option<u64>) -> serde_json::Value {
+        json!({
+            "slot": 90,
+            "confirmations": confirmations,
+            "status": match err {
+                Some(err) => json!({ "Err": err }),
+                None => json!({ "Ok": null }),
+            },
+            "err": err,
+            "confirmationStatus": if confirmations.is_some() { "confirmed" } else { "finalized" },
+        })
+    }
+
+    #[tokio::test]
+    async fn settles_by_status_and_block_height() {
+        let transactions = vec![
+            submitted(50, None),
+            submitted(150, None),
+            submitted(150, None),
+            submitted(150, None),
+            submitted(150, None),
+            submitted(0, Some(Pubkey::new_unique())),
+        ];
+        let mut mocks = HashMap::new();
+        mocks.insert(RpcRequest::GetBlockHeight, json!(100));
+        // Also the answer to the history search for the expired transaction,
+        // which the mock gives the first status
+        mocks.insert(
+            RpcRequest::GetSignatureStatuses,
+            json!({
+                "context": { "slot": 100 },
+                "value": [
+                    null,
+                    status(Some("AccountInUse"), None),
+                    status(None, None),
+                    status(None, Some(1)),
+                    null,
+                    null,
+                ],
+            }),
+        );
+        let rpc_client = RpcClient::new_mock_with_mocks("succeeds".to_string(), mocks);
+
+        let settlements = check_transactions(&rpc_client, &transactions).await.unwrap();
+        let settled: HashMap<Uuid, &Settlement> = settlements.iter().map(|(id, _, s)| (*id, s)).collect();
+        assert_eq!(settled.len(), 3);
+        assert!(matches!(settled[&transactions[0].session_id], Settlement::Expired));
+        assert!(matches!(settled[&transactions[1].session_id], Settlement::Failed(e) if e.contains("AccountInUse")));
+        assert!(matches!(settled[&transactions[2].session_id], Settlement::Confirmed));
+        // Landed below the required commitment, blockhash still valid, and a
+        // nonce that has not moved on: all still waiting
+        for waiting in [&transactions[3], &transactions[4], &transactions[5]] {
+            assert!(!settled.contains_key(&waiting.session_id));
+        }
+    }
+}
@@ -1,9 +1,18 @@
 use crate::error::Error;
+use crate::fees::ComputeBudget;
+use crate::nonce::DurableNonce;
 use chrono::{Duration, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, PgPool};
 use uuid::Uuid;
-use crate::serialization::SecretAggStepOne;
+use crate::serialization::{self, AggMessage1, SecretAggStepOne};
+use crate::tss::KeyShare;
+use curv::elliptic::curves::{Ed25519, Scalar};
+use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+use std::str::FromStr;
+
+/// How long a session built on a durable nonce stays open.
+const NONCE_SESSION_TTL_HOURS: i64 = 6;
 
 #[derive(Debug, FromRow)]
 pub struct MpcKey {
@@ -11,6 +20,96 @@ pub struct MpcKey {
     pub node_id: i32,
     pub public_key: String,
     pub private_key: String, // Encrypted at rest
+    /// Hex scalar added to the keypair's scalar once the share was refreshed.
+    pub share_offset: Option<String>,
+    pub share_version: i32,
+    /// Refresh this share is part of until every node has committed it.
+    pub refresh_id: Option<Uuid>,
+}
+
+impl MpcKey {
+    pub fn keypair(&self) -> Result<Keypair, Error> {
+        bs58::decode(&self.private_key)
+            .into_vec()
+            .ok()
+            .and_then(|bytes| Keypair::from_bytes(&bytes).ok())
+            .ok_or_else(|| Error::StoreError("Stored key share is corrupt".to_string()))
+    }
+
+    /// Fails while a refresh of this key is pending: the nodes may hold
+    /// shares from either side of it, which do not sign together.
+    pub fn check_settled(&self) -> Result<(), Error> {
+        match self.refresh_id {
+            Some(_) => Err(Error::RefreshInProgress),
+            None => Ok(()),
+        }
+    }
+
+    pub fn share(&self) -> Result<KeyShare, Error> {
+        let public_key = Pubkey::from_str(&self.public_key)
+            .map_err(|_| Error::StoreError("Stored key share is corrupt".to_string()))?;
+        let offset = match &self.share_offset {
+            Some(offset) => Some(decode_scalar(offset)?),
+            None => None,
+        };
+        Ok(KeyShare { keypair: self.keypair()?, public_key, offset })
+    }
+}
+
+/// Reserves the key's nonce account for a new session. Of several
+/// transactions built on the same nonce only the first to land is valid, so
+/// sessions that have not signed yet give way, and one that has signed makes
+/// the new session wait with `NonceInUse`.
+async fn claim_nonce_account(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    end_user_pubkey: &str,
+    account: &Pubkey,
+) -> Result<(), Error> {
+    // Serializes session starts for the key
+    sqlx::query!("SELECT node_id FROM mpc_keys WHERE end_user_pubkey = $1 FOR UPDATE", end_user_pubkey)
+        .fetch_all(&mut **tx)
+        .await?;
+
+    let account = account.to_string();
+    sqlx::query!(
+        r#"
+        UPDATE mpc_signing_sessions
+        SET expires_at = NOW()
+        WHERE nonce_account = $1 AND status = 'pending' AND secret_state_1 IS NOT NULL AND expires_at > NOW()
+        "#,
+        account
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    let in_use = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM mpc_signing_sessions
+            WHERE nonce_account = $1
+              AND (status = 'submitted' OR (status = 'pending' AND expires_at > NOW()))
+        ) AS "in_use!"
+        "#,
+        account
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    if in_use {
+        return Err(Error::NonceInUse);
+    }
+    Ok(())
+}
+
+pub fn encode_scalar(scalar: &Scalar<Ed25519>) -> String {
+    hex::encode(&*scalar.to_bytes())
+}
+
+fn decode_scalar(encoded: &str) -> Result<Scalar<Ed25519>, Error> {
+    let bytes = hex::decode(encoded).map_err(|_| Error::StoreError("Stored key share is corrupt".to_string()))?;
+    Scalar::from_bytes(&bytes).map_err(|e| Error::DeserializationFailed {
+        error: serialization::Error::InvalidScalar(e),
+        field_name: "share_offset",
+    })
 }
 
 #[derive(Debug, FromRow, Serialize, Deserialize)]
@@ -25,6 +124,64 @@ pub struct MpcSigningSession {
     pub amount: f64,
     pub memo: Option<String>,
     pub transaction: Option<String>,
+    pub compute_unit_limit: Option<i32>,
+    pub compute_unit_price: Option<i64>,
+    pub nonce_account: Option<String>,
+    pub durable_nonce: Option<String>,
+    /// Exact bytes to sign for a message session.
+    pub message: Option<Vec<u8>>,
+}
+
+impl MpcSigningSession {
+    /// Compute budget of a transfer session; sessions started before budgets
+    /// were recorded have none.
+    pub fn compute_budget(&self) -> Option<ComputeBudget> {
+        Some(ComputeBudget {
+            unit_limit: self.compute_unit_limit? as u32,
+            unit_price: self.compute_unit_price? as u64,
+        })
+    }
+
+    /// Durable nonce the transfer was built against, if the session uses one.
+    pub fn durable_nonce(&self) -> Option<DurableNonce> {
+        Some(DurableNonce {
+            account: self.nonce_account.as_deref()?.parse().ok()?,
+            nonce: self.durable_nonce.as_deref()?.parse().ok()?,
+        })
+    }
+}
+
+/// What a transfer or transaction signing session is started with.
+pub struct NewSession<'a> {
+    pub end_user_pubkey: &'a str,
+    /// Node 1's secret nonces, left out when the session is signed right
+    /// away with a presigned pair.
+    pub secret_state_1: Option<&'a SecretAggStepOne>,
+    pub to_address: &'a str,
+    pub amount: f64,
+    pub memo: Option<String>,
+    pub transaction: Option<String>,
+    pub compute_budget: Option<ComputeBudget>,
+    pub durable_nonce: Option<DurableNonce>,
+}
+
+/// A broadcast transaction the confirmer is waiting on.
+#[derive(Debug, FromRow)]
+pub struct SubmittedTransaction {
+    pub session_id: Uuid,
+    pub transaction_signature: String,
+    pub signed_transaction: String,
+    pub last_valid_block_height: i64,
+    /// Set when the transaction uses a durable nonce instead of a blockhash.
+    pub nonce_account: Option<String>,
+}
+
+/// One node's half of a nonce pair generated ahead of signing.
+pub struct PresignedNonce {
+    pub pair_id: Uuid,
+    pub secret_state: SecretAggStepOne,
+    /// The other node's public nonces for the same pair.
+    pub peer_message: AggMessage1,
 }
 
 #[derive(Clone)]
@@ -37,6 +194,11 @@ impl MpcStore {
         Self { pool }
     }
 
+    pub async fn ping(&self) -> Result<(), Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
     pub async fn store_key(&self, key: &MpcKey) -> Result<(), Error> {
         sqlx::query!(
             r#"
@@ -57,7 +219,8 @@ impl MpcStore {
         let key = sqlx::query_as!(
             MpcKey,
             r#"
-            SELECT end_user_pubkey, node_id, public_key, private_key FROM mpc_keys
+            SELECT end_user_pubkey, node_id, public_key, private_key, share_offset, share_version, refresh_id
+            FROM mpc_keys
             WHERE end_user_pubkey = $1 AND node_id = $2
             "#,
             end_user_pubkey,
@@ -68,11 +231,52 @@ impl MpcStore {
         Ok(key)
     }
 
+    /// Every key share this node holds, for backups.
+    pub async fn list_keys(&self, node_id: i32) -> Result<Vec<MpcKey>, Error> {
+        let keys = sqlx::query_as!(
+            MpcKey,
+            r#"
+            SELECT end_user_pubkey, node_id, public_key, private_key, share_offset, share_version, refresh_id
+            FROM mpc_keys
+            WHERE node_id = $1
+            ORDER BY end_user_pubkey
+            "#,
+            node_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(keys)
+    }
+
+    /// Puts back a key share from a backup. Returns false and leaves the
+    /// row alone if this node still holds a share of the key.
+    pub async fn restore_key(&self, key: &MpcKey) -> Result<bool, Error> {
+        let restored = sqlx::query!(
+            r#"
+            INSERT INTO mpc_keys (end_user_pubkey, node_id, public_key, private_key, share_offset, share_version)
+            SELECT $1, $2, $3, $4, $5, $6
+            WHERE NOT EXISTS (
+                SELECT 1 FROM mpc_keys WHERE end_user_pubkey = $1 AND node_id = $2
+            )
+            "#,
+            key.end_user_pubkey,
+            key.node_id,
+            key.public_key,
+            key.private_key,
+            key.share_offset,
+            key.share_version
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(restored.rows_affected() > 0)
+    }
+
     pub async fn get_keys_for_user(&self, end_user_pubkey: &str) -> Result<Vec<MpcKey>, Error> {
         let keys = sqlx::query_as!(
             MpcKey,
             r#"
-            SELECT end_user_pubkey, node_id, public_key, private_key FROM mpc_keys
+            SELECT end_user_pubkey, node_id, public_key, private_key, share_offset, share_version, refresh_id
+            FROM mpc_keys
             WHERE end_user_pubkey = $1
             ORDER BY node_id
             "#,
@@ -83,14 +287,64 @@ impl MpcStore {
         Ok(keys)
     }
 
-    pub async fn create_session(
+    pub async fn create_session(&self, session: NewSession<'_>) -> Result<Uuid, Error> {
+        let NewSession {
+            end_user_pubkey,
+            secret_state_1,
+            to_address,
+            amount,
+            memo,
+            transaction,
+            compute_budget,
+            durable_nonce,
+        } = session;
+        let session_id = Uuid::new_v4();
+        let secret_state_1_bytes = secret_state_1.map(|secret| serde_json::to_vec(secret).unwrap());
+        // A durable nonce does not expire, so the session can wait for slow signers
+        let ttl = if durable_nonce.is_some() {
+            Duration::hours(NONCE_SESSION_TTL_HOURS)
+        } else {
+            Duration::minutes(5)
+        };
+        let expires_at = Utc::now() + ttl;
+
+        let mut tx = self.pool.begin().await?;
+        if let Some(nonce) = durable_nonce {
+            claim_nonce_account(&mut tx, end_user_pubkey, &nonce.account).await?;
+        }
+        sqlx::query!(
+            r#"
+            INSERT INTO mpc_signing_sessions 
+            (session_id, end_user_pubkey, secret_state_1, to_address, amount, memo, expires_at, transaction,
+             compute_unit_limit, compute_unit_price, nonce_account, durable_nonce)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            "#,
+            session_id,
+            end_user_pubkey,
+            secret_state_1_bytes,
+            to_address,
+            amount,
+            memo,
+            expires_at,
+            transaction,
+            compute_budget.map(|budget| budget.unit_limit as i32),
+            compute_budget.map(|budget| budget.unit_price as i64),
+            durable_nonce.map(|nonce| nonce.account.to_string()),
+            durable_nonce.map(|nonce| nonce.nonce.to_string())
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(session_id)
+    }
+
+    /// Starts a session that signs `message` rather than a transaction.
+    pub async fn create_message_session(
         &self,
         end_user_pubkey: &str,
         secret_state_1: &SecretAggStepOne,
-        to_address: &str,
-        amount: f64,
-        memo: Option<String>,
-        transaction: Option<String>,
+        message: &[u8],
     ) -> Result<Uuid, Error> {
         let session_id = Uuid::new_v4();
         let secret_state_1_bytes = serde_json::to_vec(secret_state_1).unwrap();
@@ -98,18 +352,15 @@ impl MpcStore {
 
         sqlx::query!(
             r#"
-            INSERT INTO mpc_signing_sessions 
-            (session_id, end_user_pubkey, secret_state_1, to_address, amount, memo, expires_at, transaction)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            INSERT INTO mpc_signing_sessions
+            (session_id, end_user_pubkey, secret_state_1, to_address, amount, expires_at, message)
+            VALUES ($1, $2, $3, '', 0, $4, $5)
             "#,
             session_id,
             end_user_pubkey,
             secret_state_1_bytes,
-            to_address,
-            amount,
-            memo,
             expires_at,
-            transaction
+            message
         )
         .execute(&self.pool)
         .await?;
@@ -141,13 +392,38 @@ impl MpcStore {
         Ok(())
     }
 
+    /// Takes node 1's secret nonces out of a session so they sign exactly
+    /// once; `None` if they were already used.
+    pub async fn take_session_secret(&self, session_id: Uuid) -> Result<Option<SecretAggStepOne>, Error> {
+        let secret = sqlx::query_scalar!(
+            r#"
+            WITH old AS (
+                SELECT session_id, secret_state_1
+                FROM mpc_signing_sessions
+                WHERE session_id = $1 AND secret_state_1 IS NOT NULL AND expires_at > NOW()
+                FOR UPDATE
+            )
+            UPDATE mpc_signing_sessions s
+            SET secret_state_1 = NULL
+            FROM old
+            WHERE s.session_id = old.session_id
+            RETURNING old.secret_state_1 AS "secret_state_1!"
+            "#,
+            session_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(secret.map(|secret| serde_json::from_slice(&secret).unwrap()))
+    }
+
     pub async fn get_session(&self, session_id: Uuid) -> Result<MpcSigningSession, Error> {
         let session = sqlx::query_as!(
             MpcSigningSession,
             r#"
             SELECT 
                 session_id, end_user_pubkey, secret_state_1, secret_state_2,
-                partial_sig_2, agg_message_2, to_address, amount, memo, transaction
+                partial_sig_2, agg_message_2, to_address, amount, memo, transaction,
+                compute_unit_limit, compute_unit_price, nonce_account, durable_nonce, message
             FROM mpc_signing_sessions
             WHERE session_id = $1 AND expires_at > NOW()
             "#,
@@ -164,4 +440,452 @@ impl MpcStore {
         })?;
         Ok(session)
     }
+
+    pub async fn mark_session_submitted(
+        &self,
+        session_id: Uuid,
+        transaction_signature: &str,
+        signed_transaction: &str,
+        last_valid_block_height: u64,
+    ) -> Result<(), Error> {
+        sqlx::query!(
+            r#"
+            UPDATE mpc_signing_sessions
+            SET status = 'submitted', transaction_signature = $2, signed_transaction = $3,
+                last_valid_block_height = $4, submitted_at = NOW()
+            WHERE session_id = $1
+            "#,
+            session_id,
+            transaction_signature,
+            signed_transaction,
+            last_valid_block_height as i64
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Submitted transactions not yet settled, oldest first. Sessions past
+    /// their signing expiry are included, since confirmation can outlast it.
+    pub async fn list_submitted_transactions(&self, limit: i64) -> Result<Vec<SubmittedTransaction>, Error> {
+        let transactions = sqlx::query_as!(
+            SubmittedTransaction,
+            r#"
+            SELECT session_id,
+                   transaction_signature AS "transaction_signature!",
+                   signed_transaction AS "signed_transaction!",
+                   last_valid_block_height AS "last_valid_block_height!",
+                   nonce_account
+            FROM mpc_signing_sessions
+            WHERE status = 'submitted'
+            ORDER BY submitted_at
+            LIMIT $1
+            "#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(transactions)
+    }
+
+    /// Moves a submitted session to `confirmed`, `failed` or `expired`.
+    pub async fn settle_session(&self, session_id: Uuid, status: &str, error: Option<&str>) -> Result<(), Error> {
+        sqlx::query!(
+            r#"
+            UPDATE mpc_signing_sessions
+            SET status = $2, error = $3, settled_at = NOW()
+            WHERE session_id = $1 AND status = 'submitted'
+            "#,
+            session_id,
+            status,
+            error
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn store_presigned_nonce(
+        &self,
+        pair_id: Uuid,
+        end_user_pubkey: &str,
+        node_id: i32,
+        secret_state: &SecretAggStepOne,
+        peer_message: &AggMessage1,
+    ) -> Result<(), Error> {
+        let secret_state_bytes = serde_json::to_vec(secret_state).unwrap();
+        sqlx::query!(
+            r#"
+            INSERT INTO mpc_presigned_nonces (pair_id, end_user_pubkey, node_id, secret_state, peer_message)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            pair_id,
+            end_user_pubkey,
+            node_id,
+            secret_state_bytes,
+            serde_json::to_string(peer_message).unwrap()
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Takes an unused nonce pair, the oldest one or `pair_id` when given.
+    /// Its secret is erased in the same statement, so no pair can be handed
+    /// out twice even under concurrent claims.
+    pub async fn claim_presigned_nonce(
+        &self,
+        end_user_pubkey: &str,
+        node_id: i32,
+        pair_id: Option<Uuid>,
+    ) -> Result<Option<PresignedNonce>, Error> {
+        let claimed = sqlx::query!(
+            r#"
+            WITH claimed AS (
+                SELECT id, secret_state
+                FROM mpc_presigned_nonces
+                WHERE end_user_pubkey = $1 AND node_id = $2 AND used_at IS NULL
+                  AND ($3::uuid IS NULL OR pair_id = $3)
+                ORDER BY created_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            UPDATE mpc_presigned_nonces n
+            SET used_at = NOW(), secret_state = NULL
+            FROM claimed
+            WHERE n.id = claimed.id
+            RETURNING n.pair_id, claimed.secret_state AS "secret_state!", n.peer_message
+            "#,
+            end_user_pubkey,
+            node_id,
+            pair_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(claimed.map(|row| PresignedNonce {
+            pair_id: row.pair_id,
+            secret_state: serde_json::from_slice(&row.secret_state).unwrap(),
+            peer_message: serde_json::from_str(&row.peer_message).unwrap(),
+        }))
+    }
+
+    pub async fn count_presigned_nonces(&self, end_user_pubkey: &str, node_id: i32) -> Result<i64, Error> {
+        let count = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!"
+            FROM mpc_presigned_nonces
+            WHERE end_user_pubkey = $1 AND node_id = $2 AND used_at IS NULL
+            "#,
+            end_user_pubkey,
+            node_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count)
+    }
+
+    /// Records this node's next share for `refresh_id` without signing with
+    /// it yet. Fails if the share is no longer at `from_version` or another
+    /// refresh is pending.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn prepare_key_refresh(
+        &self,
+        refresh_id: Uuid,
+        end_user_pubkey: &str,
+        node_id: i32,
+        from_version: i32,
+        next_private_key: &str,
+        next_share_offset: &str,
+        share_commitment: &str,
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        let prepared = sqlx::query!(
+            r#"
+            UPDATE mpc_keys
+            SET refresh_id = $1, next_private_key = $2, next_share_offset = $3
+            WHERE end_user_pubkey = $4 AND node_id = $5 AND share_version = $6 AND refresh_id IS NULL
+            "#,
+            refresh_id,
+            next_private_key,
+            next_share_offset,
+            end_user_pubkey,
+            node_id,
+            from_version
+        )
+        .execute(&mut *tx)
+        .await?;
+        if prepared.rows_affected() == 0 {
+            return Err(Error::RefreshInProgress);
+        }
+
+        sqlx::query!(
+            r#"
+            INSERT INTO mpc_key_refreshes (refresh_id, end_user_pubkey, node_id, from_version, share_commitment, status)
+            VALUES ($1, $2, $3, $4, $5, 'prepared')
+            "#,
+            refresh_id,
+            end_user_pubkey,
+            node_id,
+            from_version,
+            share_commitment
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Switches this node to the share prepared for `refresh_id`. The old
+    /// share is kept, and the key stays marked as refreshing, until
+    /// `finish_key_refresh`. Returns false if the refresh is not pending
+    /// here, e.g. because it was already committed.
+    pub async fn commit_key_refresh(&self, refresh_id: Uuid, end_user_pubkey: &str, node_id: i32) -> Result<bool, Error> {
+        let mut tx = self.pool.begin().await?;
+        let committed = sqlx::query!(
+            r#"
+            UPDATE mpc_keys
+            SET prev_private_key = private_key,
+                prev_share_offset = share_offset,
+                private_key = next_private_key,
+                share_offset = next_share_offset,
+                share_version = share_version + 1,
+                share_refreshed_at = NOW(),
+                next_private_key = NULL,
+                next_share_offset = NULL
+            WHERE end_user_pubkey = $1 AND node_id = $2 AND refresh_id = $3 AND next_private_key IS NOT NULL
+            "#,
+            end_user_pubkey,
+            node_id,
+            refresh_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if committed.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            r#"
+            UPDATE mpc_key_refreshes
+            SET status = 'committed', completed_at = NOW()
+            WHERE refresh_id = $1 AND node_id = $2
+            "#,
+            refresh_id,
+            node_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Drops the share prepared for `refresh_id` and keeps the current one.
+    /// Returns false if the refresh was not pending here, including when
+    /// this node already committed it.
+    pub async fn abort_key_refresh(&self, refresh_id: Uuid, end_user_pubkey: &str, node_id: i32) -> Result<bool, Error> {
+        let mut tx = self.pool.begin().await?;
+        let aborted = sqlx::query!(
+            r#"
+            UPDATE mpc_keys
+            SET refresh_id = NULL, next_private_key = NULL, next_share_offset = NULL
+            WHERE end_user_pubkey = $1 AND node_id = $2 AND refresh_id = $3 AND next_private_key IS NOT NULL
+            "#,
+            end_user_pubkey,
+            node_id,
+            refresh_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            UPDATE mpc_key_refreshes
+            SET status = 'aborted', completed_at = NOW()
+            WHERE refresh_id = $1 AND node_id = $2 AND status = 'prepared'
+            "#,
+            refresh_id,
+            node_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(aborted.rows_affected() > 0)
+    }
+
+    /// Drops the share this node held before committing `refresh_id`, once
+    /// every node has committed it, and marks the new share as not yet
+    /// backed up. Returns false if this node has not committed it or
+    /// already finished.
+    pub async fn finish_key_refresh(&self, refresh_id: Uuid, end_user_pubkey: &str, node_id: i32) -> Result<bool, Error> {
+        let finished = sqlx::query!(
+            r#"
+            UPDATE mpc_keys
+            SET refresh_id = NULL, prev_private_key = NULL, prev_share_offset = NULL,
+                backup_pending_version = share_version
+            WHERE end_user_pubkey = $1 AND node_id = $2 AND refresh_id = $3 AND next_private_key IS NULL
+            "#,
+            end_user_pubkey,
+            node_id,
+            refresh_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(finished.rows_affected() > 0)
+    }
+
+    /// Keys with a refresh still pending on this node.
+    pub async fn list_pending_refreshes(&self, node_id: i32) -> Result<Vec<String>, Error> {
+        let keys = sqlx::query_scalar!(
+            r#"
+            SELECT end_user_pubkey FROM mpc_keys
+            WHERE node_id = $1 AND refresh_id IS NOT NULL
+            ORDER BY end_user_pubkey
+            "#,
+            node_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(keys)
+    }
+
+    /// Keys whose refreshed share on this node is not backed up yet.
+    pub async fn list_pending_backups(&self, node_id: i32) -> Result<Vec<String>, Error> {
+        let keys = sqlx::query_scalar!(
+            r#"
+            SELECT end_user_pubkey FROM mpc_keys
+            WHERE node_id = $1 AND backup_pending_version IS NOT NULL
+            ORDER BY end_user_pubkey
+            "#,
+            node_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(keys)
+    }
+
+    /// Records that `share_version` of this node's share is backed up. A
+    /// later refresh leaves its own marker in place.
+    pub async fn clear_backup_pending(&self, end_user_pubkey: &str, node_id: i32, share_version: i32) -> Result<(), Error> {
+        sqlx::query!(
+            r#"
+            UPDATE mpc_keys
+            SET backup_pending_version = NULL
+            WHERE end_user_pubkey = $1 AND node_id = $2 AND backup_pending_version <= $3
+            "#,
+            end_user_pubkey,
+            node_id,
+            share_version
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Status this node recorded for `refresh_id`, if it took part.
+    pub async fn key_refresh_status(&self, refresh_id: Uuid, node_id: i32) -> Result<Option<String>, Error> {
+        let status = sqlx::query_scalar!(
+            r#"
+            SELECT status FROM mpc_key_refreshes
+            WHERE refresh_id = $1 AND node_id = $2
+            "#,
+            refresh_id,
+            node_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(status)
+    }
+
+    /// Keys whose shares were last refreshed before `refreshed_before`,
+    /// oldest first.
+    pub async fn list_keys_due_for_refresh(
+        &self,
+        node_id: i32,
+        refreshed_before: chrono::DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<String>, Error> {
+        let keys = sqlx::query_scalar!(
+            r#"
+            SELECT end_user_pubkey FROM mpc_keys
+            WHERE node_id = $1 AND share_refreshed_at < $2
+            ORDER BY share_refreshed_at
+            LIMIT $3
+            "#,
+            node_id,
+            refreshed_before,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tss;
+    use solana_sdk::hash::Hash;
+
+    fn transfer<'a>(owner: &'a str, secret: &'a SecretAggStepOne, nonce: DurableNonce) -> NewSession<'a> {
+        NewSession {
+            end_user_pubkey: owner,
+            secret_state_1: Some(secret),
+            to_address: "11111111111111111111111111111111",
+            amount: 0.1,
+            memo: None,
+            transaction: None,
+            compute_budget: None,
+            durable_nonce: Some(nonce),
+        }
+    }
+
+    #[sqlx::test(migrations = "migrations")]
+    async fn one_signed_session_per_durable_nonce(pool: PgPool) {
+        let store = MpcStore::new(pool);
+        let owner = Pubkey::new_unique().to_string();
+        let nonce = DurableNonce { account: Pubkey::new_unique(), nonce: Hash::new_unique() };
+        let (_, secret) = tss::step_one(Keypair::new());
+
+        // A session that has not signed yet gives way to a newer one
+        let unsigned = store.create_session(transfer(&owner, &secret, nonce)).await.unwrap();
+        let signed = store.create_session(transfer(&owner, &secret, nonce)).await.unwrap();
+        assert!(matches!(store.get_session(unsigned).await, Err(Error::SessionNotFound)));
+        assert!(store.take_session_secret(unsigned).await.unwrap().is_none());
+
+        // Once one has signed, others wait until it settles
+        assert!(store.take_session_secret(signed).await.unwrap().is_some());
+        assert!(matches!(
+            store.create_session(transfer(&owner, &secret, nonce)).await,
+            Err(Error::NonceInUse)
+        ));
+        store.mark_session_submitted(signed, "sig", "", 0).await.unwrap();
+        assert!(matches!(
+            store.create_session(transfer(&owner, &secret, nonce)).await,
+            Err(Error::NonceInUse)
+        ));
+        store.settle_session(signed, "confirmed", None).await.unwrap();
+        assert!(store.create_session(transfer(&owner, &secret, nonce)).await.is_ok());
+
+        // Other nonce accounts are unaffected
+        let other = DurableNonce { account: Pubkey::new_unique(), nonce: Hash::new_unique() };
+        assert!(store.create_session(transfer(&owner, &secret, other)).await.is_ok());
+    }
+
+    #[sqlx::test(migrations = "migrations")]
+    async fn presigned_nonce_is_claimed_once(pool: PgPool) {
+        let store = MpcStore::new(pool);
+        let owner = Pubkey::new_unique().to_string();
+        let pair_id = Uuid::new_v4();
+        let (peer_message, _) = tss::step_one(Keypair::new());
+        let (_, secret) = tss::step_one(Keypair::new());
+        store.store_presigned_nonce(pair_id, &owner, 1, &secret, &peer_message).await.unwrap();
+        assert_eq!(store.count_presigned_nonces(&owner, 1).await.unwrap(), 1);
+
+        let claimed = store.claim_presigned_nonce(&owner, 1, None).await.unwrap().unwrap();
+        assert_eq!(claimed.pair_id, pair_id);
+        assert_eq!(store.count_presigned_nonces(&owner, 1).await.unwrap(), 0);
+        assert!(store.claim_presigned_nonce(&owner, 1, None).await.unwrap().is_none());
+        assert!(store.claim_presigned_nonce(&owner, 1, Some(pair_id)).await.unwrap().is_none());
+    }
 }
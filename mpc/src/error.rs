@@ -1,4 +1,5 @@
 use crate::serialization;
+use actix_web::{http::StatusCode, ResponseError};
 use solana_client::client_error;
 
 #[derive(Debug, thiserror::Error)]
@@ -35,5 +36,41 @@ pub enum Error {
 
     #[error("invalid request: {0}")]
     InvalidRequest(String),
+
+    #[error("rejected by policy: {0}")]
+    PolicyViolation(String),
+
+    #[error("store error: {0}")]
+    StoreError(String),
+
+    #[error("simulation failed: {0}")]
+    SimulationFailed(String),
+
+    #[error("no presigned nonces left for this key")]
+    NoPresignedNonces,
+
+    #[error("another transfer is still using this key's durable nonce")]
+    NonceInUse,
+
+    #[error("a share refresh is already in progress for this key")]
+    RefreshInProgress,
+
+    #[error("backup failed: {0}")]
+    BackupFailed(String),
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::InvalidRequest(_) | Error::DeserializationFailed { .. } => StatusCode::BAD_REQUEST,
+            Error::SessionNotFound | Error::KeyNotFound => StatusCode::NOT_FOUND,
+            Error::PolicyViolation(_) => StatusCode::FORBIDDEN,
+            Error::SimulationFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            // Its own status, since callers fall back to the two-round flow on it
+            Error::NoPresignedNonces => StatusCode::PRECONDITION_FAILED,
+            Error::NonceInUse | Error::RefreshInProgress => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
 
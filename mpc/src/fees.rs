@@ -0,0 +1,196 @@
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_compute_budget_interface::ComputeBudgetInstruction;
+use solana_sdk::{instruction::Instruction, pubkey::Pubkey};
+
+const MICRO_LAMPORTS_PER_LAMPORT: u64 = 1_000_000;
+/// Compute units allowed for advancing a durable nonce, a system program
+/// instruction like the transfer itself.
+const NONCE_ADVANCE_UNITS: u32 = 1_000;
+/// Compute units allowed for a memo. The memo program validates and logs the
+/// whole text, so its cost grows with the length; these are generous upper
+/// bounds, since running out fails the transfer while the extra units only
+/// cost their price.
+const MEMO_BASE_UNITS: u32 = 10_000;
+const MEMO_UNITS_PER_BYTE: u32 = 200;
+
+/// How much priority fee to bid, as a percentile of recent fees paid for the
+/// accounts the transaction writes to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PriorityLevel {
+    /// No priority fee.
+    None,
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+/// Compute-unit limit and price added in front of a transaction's own
+/// instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComputeBudget {
+    pub unit_limit: u32,
+    /// Price per compute unit in micro-lamports.
+    pub unit_price: u64,
+}
+
+impl ComputeBudget {
+    pub fn instructions(&self) -> Vec<Instruction> {
+        vec![
+            ComputeBudgetInstruction::set_compute_unit_limit(self.unit_limit),
+            ComputeBudgetInstruction::set_compute_unit_price(self.unit_price),
+        ]
+    }
+
+    /// Priority fee in lamports, on top of the base signature fee.
+    pub fn priority_fee(&self) -> u64 {
+        (self.unit_limit as u64 * self.unit_price).div_ceil(MICRO_LAMPORTS_PER_LAMPORT)
+    }
+}
+
+pub struct FeeStrategy {
+    /// Percentiles used for the low, medium and high levels.
+    percentiles: [u8; 3],
+    /// Highest unit price ever bid, in micro-lamports.
+    max_unit_price: u64,
+    transfer_unit_limit: u32,
+}
+
+impl Default for FeeStrategy {
+    fn default() -> Self {
+        Self {
+            percentiles: [25, 50, 75],
+            max_unit_price: 1_000_000,
+            transfer_unit_limit: 1_000,
+        }
+    }
+}
+
+impl FeeStrategy {
+    /// Reads `PRIORITY_FEE_PERCENTILES` (low,medium,high; `25,50,75` by
+    /// default), `PRIORITY_FEE_MAX_MICRO_LAMPORTS` and
+    /// `TRANSFER_COMPUTE_UNIT_LIMIT`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut strategy = Self::default();
+
+        if let Ok(value) = std::env::var("PRIORITY_FEE_PERCENTILES") {
+            let percentiles: Vec<u8> = value
+                .split(',')
+                .map(|p| p.trim().parse().ok().filter(|p| *p <= 100))
+                .collect::<Option<_>>()
+                .ok_or_else(|| format!("Invalid PRIORITY_FEE_PERCENTILES: {}", value))?;
+            strategy.percentiles = percentiles
+                .try_into()
+                .map_err(|_| format!("PRIORITY_FEE_PERCENTILES needs three values: {}", value))?;
+        }
+        if let Ok(value) = std::env::var("PRIORITY_FEE_MAX_MICRO_LAMPORTS") {
+            strategy.max_unit_price = value
+                .parse()
+                .map_err(|_| format!("Invalid PRIORITY_FEE_MAX_MICRO_LAMPORTS: {}", value))?;
+        }
+        if let Ok(value) = std::env::var("TRANSFER_COMPUTE_UNIT_LIMIT") {
+            strategy.transfer_unit_limit = value
+                .parse()
+                .map_err(|_| format!("Invalid TRANSFER_COMPUTE_UNIT_LIMIT: {}", value))?;
+        }
+
+        Ok(strategy)
+    }
+
+    /// Compute-unit limit for a transfer with an optional memo, advancing a
+    /// durable nonce first if `advances_nonce`.
+    pub fn transfer_unit_limit(&self, memo: Option<&str>, advances_nonce: bool) -> u32 {
+        let nonce = if advances_nonce { NONCE_ADVANCE_UNITS } else { 0 };
+        let memo = memo.map_or(0, |memo| {
+            MEMO_BASE_UNITS.saturating_add(MEMO_UNITS_PER_BYTE.saturating_mul(memo.len() as u32))
+        });
+        self.transfer_unit_limit.saturating_add(nonce).saturating_add(memo)
+    }
+
+    /// Picks the unit price for a transfer from the fees recently paid to
+    /// write to `writable_accounts`, capped at the configured maximum, and
+    /// the limit from [`Self::transfer_unit_limit`].
+    pub async fn transfer_budget(
+        &self,
+        rpc_client: &RpcClient,
+        level: PriorityLevel,
+        writable_accounts: &[Pubkey],
+        memo: Option<&str>,
+        advances_nonce: bool,
+    ) -> Result<ComputeBudget, Error> {
+        let unit_limit = self.transfer_unit_limit(memo, advances_nonce);
+        let percentile = match level {
+            PriorityLevel::None => return Ok(ComputeBudget { unit_limit, unit_price: 0 }),
+            PriorityLevel::Low => self.percentiles[0],
+            PriorityLevel::Medium => self.percentiles[1],
+            PriorityLevel::High => self.percentiles[2],
+        };
+
+        let mut fees: Vec<u64> = rpc_client
+            .get_recent_prioritization_fees(writable_accounts)
+            .await?
+            .into_iter()
+            .map(|fee| fee.prioritization_fee)
+            .collect();
+
+        Ok(ComputeBudget {
+            unit_limit,
+            unit_price: percentile_of(&mut fees, percentile).min(self.max_unit_price),
+        })
+    }
+}
+
+/// Nearest-rank percentile, 0 when there are no samples.
+fn percentile_of(samples: &mut [u64], percentile: u8) -> u64 {
+    if samples.is_empty() {
+        return 0;
+    }
+    samples.sort_unstable();
+    let rank = (percentile as usize * samples.len()).div_ceil(100).max(1);
+    samples[rank.min(samples.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_percentile() {
+        let mut fees = vec![500, 0, 100, 300, 200, 400, 0, 0, 1000, 50];
+        assert_eq!(percentile_of(&mut fees, 50), 100);
+        assert_eq!(percentile_of(&mut fees, 75), 400);
+        assert_eq!(percentile_of(&mut fees, 100), 1000);
+        assert_eq!(percentile_of(&mut fees, 0), 0);
+        assert_eq!(percentile_of(&mut [], 75), 0);
+    }
+
+    #[test]
+    fn unit_limit_covers_the_memo_and_nonce_advance() {
+        let strategy = FeeStrategy::default();
+        let plain = strategy.transfer_unit_limit(None, false);
+        assert_eq!(plain, 1_000);
+        assert_eq!(strategy.transfer_unit_limit(None, true), plain + NONCE_ADVANCE_UNITS);
+
+        // A memo at the policy's maximum length is the most a transfer carries
+        let memo = "a".repeat(crate::policy::MAX_MEMO_LEN);
+        let limit = strategy.transfer_unit_limit(Some(&memo), true);
+        assert_eq!(
+            limit,
+            plain + NONCE_ADVANCE_UNITS + MEMO_BASE_UNITS + MEMO_UNITS_PER_BYTE * crate::policy::MAX_MEMO_LEN as u32
+        );
+        assert!(limit > strategy.transfer_unit_limit(Some("ref"), true));
+    }
+
+    #[test]
+    fn priority_fee_rounds_up() {
+        let budget = ComputeBudget {
+            unit_limit: 1_000,
+            unit_price: 1_500,
+        };
+        assert_eq!(budget.priority_fee(), 2);
+        assert_eq!(ComputeBudget { unit_limit: 1_000, unit_price: 0 }.priority_fee(), 0);
+    }
+}
@@ -0,0 +1,219 @@
+use base64::Engine;
+use serde::Serialize;
+use solana_sdk::{message::VersionedMessage, pubkey::Pubkey, system_program};
+use std::str::FromStr;
+
+const TOKEN_PROGRAM_IDS: [&str; 2] = [
+    "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
+    "TokenzQdBNbLqP5VEhdkAS6EPFLC1PEnBqCXEpPxuEb",
+];
+const COMPUTE_BUDGET_PROGRAM_ID: &str = "ComputeBudget111111111111111111111111111111";
+const MEMO_PROGRAM_ID: &str = "MemoSq4gqABAXKb96qhANdi6UKFs5qhvcHVL7LkhPsJ";
+const ASSOCIATED_TOKEN_PROGRAM_ID: &str = "ATokenGPvbdGVxr1b4hzZaKahnHttYt1fK4aCkTUFGrKr";
+
+/// What an instruction does, for the instructions the wallet knows how to
+/// read. Everything else is `Unknown` and shown as raw data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum Action {
+    SolTransfer { from: String, to: String, lamports: u64 },
+    /// Hands the account to another program.
+    Assign { account: String, owner: String },
+    TokenTransfer { source: String, destination: String, authority: String, amount: u64 },
+    /// Lets `delegate` move tokens out of `source`.
+    TokenApprove { source: String, delegate: String, owner: String, amount: u64 },
+    TokenSetAuthority { account: String, authority: String },
+    TokenCloseAccount { account: String, destination: String, owner: String },
+    ComputeBudget,
+    Memo { text: String },
+    CreateAssociatedTokenAccount,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstructionSummary {
+    pub program_id: String,
+    /// Accounts in order. Accounts loaded from lookup tables are shown as
+    /// `lookup:<index>`, since resolving them needs the chain.
+    pub accounts: Vec<String>,
+    /// Base64 instruction data.
+    pub data: String,
+    pub action: Action,
+}
+
+/// Decodes the instructions of `message` for display and policy checks.
+pub fn summarize(message: &VersionedMessage) -> Vec<InstructionSummary> {
+    let keys = message.static_account_keys();
+    let key = |index: u8| {
+        keys.get(index as usize)
+            .map(|key| key.to_string())
+            .unwrap_or_else(|| format!("lookup:{}", index))
+    };
+
+    message
+        .instructions()
+        .iter()
+        .map(|instruction| {
+            let program_id = key(instruction.program_id_index);
+            let accounts: Vec<String> = instruction.accounts.iter().map(|index| key(*index)).collect();
+            let action = decode(&program_id, &accounts, &instruction.data).unwrap_or(Action::Unknown);
+            InstructionSummary {
+                program_id,
+                accounts,
+                data: base64::engine::general_purpose::STANDARD.encode(&instruction.data),
+                action,
+            }
+        })
+        .collect()
+}
+
+/// Index of `signer` among the signatures `message` requires.
+pub fn signer_index(message: &VersionedMessage, signer: &Pubkey) -> Option<usize> {
+    let required = message.header().num_required_signatures as usize;
+    message.static_account_keys().iter().take(required).position(|key| key == signer)
+}
+
+/// Whether `message` loads accounts from address lookup tables.
+pub fn uses_lookup_tables(message: &VersionedMessage) -> bool {
+    message.address_table_lookups().is_some_and(|lookups| !lookups.is_empty())
+}
+
+fn decode(program_id: &str, accounts: &[String], data: &[u8]) -> Option<Action> {
+    let account = |index: usize| accounts.get(index).cloned();
+    let u64_at = |offset: usize| Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?));
+
+    if Pubkey::from_str(program_id).ok()? == system_program::id() {
+        let tag = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+        return match tag {
+            1 => Some(Action::Assign {
+                account: account(0)?,
+                owner: Pubkey::try_from(data.get(4..36)?).ok()?.to_string(),
+            }),
+            2 => Some(Action::SolTransfer {
+                from: account(0)?,
+                to: account(1)?,
+                lamports: u64_at(4)?,
+            }),
+            _ => None,
+        };
+    }
+
+    if TOKEN_PROGRAM_IDS.contains(&program_id) {
+        return match *data.first()? {
+            3 => Some(Action::TokenTransfer {
+                source: account(0)?,
+                destination: account(1)?,
+                authority: account(2)?,
+                amount: u64_at(1)?,
+            }),
+            12 => Some(Action::TokenTransfer {
+                source: account(0)?,
+                destination: account(2)?,
+                authority: account(3)?,
+                amount: u64_at(1)?,
+            }),
+            4 => Some(Action::TokenApprove {
+                source: account(0)?,
+                delegate: account(1)?,
+                owner: account(2)?,
+                amount: u64_at(1)?,
+            }),
+            13 => Some(Action::TokenApprove {
+                source: account(0)?,
+                delegate: account(2)?,
+                owner: account(3)?,
+                amount: u64_at(1)?,
+            }),
+            6 => Some(Action::TokenSetAuthority {
+                account: account(0)?,
+                authority: account(1)?,
+            }),
+            9 => Some(Action::TokenCloseAccount {
+                account: account(0)?,
+                destination: account(1)?,
+                owner: account(2)?,
+            }),
+            _ => None,
+        };
+    }
+
+    match program_id {
+        COMPUTE_BUDGET_PROGRAM_ID => Some(Action::ComputeBudget),
+        MEMO_PROGRAM_ID => Some(Action::Memo {
+            text: String::from_utf8_lossy(data).into_owned(),
+        }),
+        ASSOCIATED_TOKEN_PROGRAM_ID => Some(Action::CreateAssociatedTokenAccount),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::{
+        hash::Hash,
+        instruction::{AccountMeta, Instruction},
+        message::{v0, Message, MessageAddressTableLookup},
+        system_instruction,
+    };
+
+    #[test]
+    fn decodes_known_instructions() {
+        let owner = Pubkey::new_unique();
+        let to = Pubkey::new_unique();
+        let token_program = Pubkey::from_str(TOKEN_PROGRAM_IDS[0]).unwrap();
+        let (source, delegate) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut approve_data = vec![4];
+        approve_data.extend_from_slice(&500u64.to_le_bytes());
+        let approve = Instruction::new_with_bytes(
+            token_program,
+            &approve_data,
+            vec![
+                AccountMeta::new(source, false),
+                AccountMeta::new_readonly(delegate, false),
+                AccountMeta::new_readonly(owner, true),
+            ],
+        );
+
+        let mut message = Message::new(&[system_instruction::transfer(&owner, &to, 42), approve], Some(&owner));
+        message.recent_blockhash = Hash::new_unique();
+        let message = VersionedMessage::Legacy(message);
+
+        let summary = summarize(&message);
+        assert_eq!(
+            summary[0].action,
+            Action::SolTransfer { from: owner.to_string(), to: to.to_string(), lamports: 42 }
+        );
+        assert_eq!(
+            summary[1].action,
+            Action::TokenApprove {
+                source: source.to_string(),
+                delegate: delegate.to_string(),
+                owner: owner.to_string(),
+                amount: 500,
+            }
+        );
+        assert_eq!(signer_index(&message, &owner), Some(0));
+        assert_eq!(signer_index(&message, &to), None);
+    }
+
+    #[test]
+    fn detects_lookup_tables() {
+        let owner = Pubkey::new_unique();
+        let legacy = VersionedMessage::Legacy(Message::new(&[], Some(&owner)));
+        assert!(!uses_lookup_tables(&legacy));
+
+        let mut v0 = v0::Message {
+            account_keys: vec![owner],
+            ..Default::default()
+        };
+        assert!(!uses_lookup_tables(&VersionedMessage::V0(v0.clone())));
+        v0.address_table_lookups.push(MessageAddressTableLookup {
+            account_key: Pubkey::new_unique(),
+            writable_indexes: vec![0],
+            readonly_indexes: Vec::new(),
+        });
+        assert!(uses_lookup_tables(&VersionedMessage::V0(v0)));
+    }
+}
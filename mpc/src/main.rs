@@ -1,25 +1,43 @@
-use actix_web::{web::{self, post, Json}, App, HttpResponse, HttpServer, Responder};
-use db::{MpcKey, MpcStore};
+use actix_web::{middleware::from_fn, web::{self, get, post, Json}, App, HttpResponse, HttpServer, Responder};
+use db::{MpcKey, MpcSigningSession, MpcStore, NewSession};
 use dotenv::dotenv;
 use error::Error;
 use serde::{Deserialize, Serialize};
-use solana_client::rpc_client::RpcClient;
+use solana_client::nonblocking::rpc_client::RpcClient;
 use solana_sdk::{
     hash::Hash,
     pubkey::Pubkey,
     signature::{Keypair, Signer},
-    transaction::{Transaction, Message},
+    transaction::{Transaction, Message, VersionedTransaction},
     system_instruction,
 };
-use std::{str::FromStr, sync::Arc};
+use base64::Engine;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::{str::FromStr, sync::Arc, time::Duration};
+use store::solana::SOL_MINT;
 use store::Store;
 use uuid::Uuid;
 
-use crate::serialization::{AggMessage1, PartialSignature, SecretAggStepOne};
+use crate::backup::AutoBackup;
+use crate::fees::{ComputeBudget, FeeStrategy, PriorityLevel};
+use crate::nonce::{DurableNonce, NonceManager};
+use crate::offchain::MessageFormat;
+use crate::serialization::{AggMessage1, PartialSignature};
 
+pub mod backup;
+pub mod confirmer;
 pub mod db;
 pub mod error;
+pub mod fees;
+pub mod inspect;
+pub mod metrics;
+pub mod nonce;
+pub mod offchain;
+pub mod policy;
+pub mod refresh;
 pub mod serialization;
+pub mod simulation;
 pub mod tss;
 
 #[derive(Serialize)]
@@ -46,6 +64,82 @@ struct AggSendStep1Request {
     to: String,
     amount: f64,
     memo: Option<String>,
+    /// Mint of the asset being sent, SOL if absent. Only SOL transfers are
+    /// built, so any other mint is refused.
+    #[serde(default)]
+    mint: Option<String>,
+    /// Base64-encoded transaction built elsewhere, such as a swap. It is
+    /// simulated instead of the transfer.
+    #[serde(default)]
+    transaction: Option<String>,
+    /// Priority fee level for transfers; transactions built elsewhere keep
+    /// their own compute budget.
+    #[serde(default)]
+    priority: PriorityLevel,
+    /// Set for transactions a third party handed in to be co-signed. Only
+    /// those are held to the withdrawal allowlist in strict mode; swaps come
+    /// from routes the backend fetched itself.
+    #[serde(default)]
+    external: bool,
+}
+
+#[derive(Deserialize)]
+struct SimulateRequest {
+    end_user_pubkey: String,
+    to: String,
+    amount: f64,
+    #[serde(default)]
+    memo: Option<String>,
+    #[serde(default)]
+    transaction: Option<String>,
+    #[serde(default)]
+    priority: PriorityLevel,
+}
+
+#[derive(Deserialize)]
+struct PresignRequest {
+    end_user_pubkey: String,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct PresignResponse {
+    /// Unused pairs for the key after this batch.
+    available: i64,
+}
+
+#[derive(Deserialize)]
+struct AggSendPresignedRequest {
+    #[serde(flatten)]
+    request: AggSendStep1Request,
+    /// Return the signed transaction without sending it.
+    #[serde(default)]
+    sign_only: bool,
+}
+
+#[derive(Serialize)]
+struct AggSendPresignedResponse {
+    session_id: Uuid,
+    #[serde(flatten)]
+    result: AggregateSignaturesResponse,
+}
+
+#[derive(Deserialize)]
+struct SignMessageStep1Request {
+    end_user_pubkey: String,
+    node_id: i32,
+    /// Base64-encoded message.
+    message: String,
+    #[serde(default)]
+    format: MessageFormat,
+}
+
+#[derive(Serialize)]
+struct SignMessageResponse {
+    signature: String,
+    /// Base64 of the exact bytes signed, including any off-chain header.
+    signed_message: String,
+    public_key: String,
 }
 
 #[derive(Serialize)]
@@ -72,18 +166,41 @@ struct AggregateSignaturesRequest {
     session_id: Uuid,
     partial_signature_2: PartialSignature,
     agg_message_2: AggMessage1,
+    /// Return the signed transaction without sending it.
+    #[serde(default)]
+    sign_only: bool,
 }
 
 #[derive(Serialize)]
 struct AggregateSignaturesResponse {
     transaction_signature: String,
+    /// Total fee paid in lamports, including the priority fee.
+    fee: u64,
+    priority_fee: u64,
+    /// Block height after which the transaction can no longer land.
+    last_valid_block_height: u64,
+    /// `signed` when only signing was asked for, otherwise `submitted`; the
+    /// confirmer settles submitted sessions later.
+    status: &'static str,
+    /// Base64 of the signed transaction.
+    signed_transaction: String,
 }
 
 struct AppState {
     mpc_store_1: MpcStore,
     mpc_store_2: MpcStore,
     main_store: Arc<Store>,
-    rpc_client: RpcClient,
+    rpc_client: Arc<RpcClient>,
+    fee_strategy: FeeStrategy,
+    /// Set when durable nonces are enabled.
+    nonces: Option<NonceManager>,
+    /// Presigned nonce pairs to keep per key; the pool is topped up once it
+    /// falls below half of this.
+    presign_pool_size: usize,
+    /// Keys with a refill under way, so concurrent signings start only one.
+    refilling: Mutex<HashSet<String>>,
+    /// Set when refreshed shares are backed up automatically.
+    backup: Option<AutoBackup>,
 }
 
 impl AppState {
@@ -96,9 +213,18 @@ impl AppState {
     }
 }
 
+#[derive(Serialize)]
+struct ReadinessResponse {
+    mpc_database_1: bool,
+    mpc_database_2: bool,
+    main_database: bool,
+    solana_rpc: bool,
+}
+
 async fn generate(
     app_state: web::Data<AppState>,
 ) -> Result<impl Responder, Error> {
+    let _timer = metrics::STEP_DURATION.with_label_values(&["generate"]).start_timer();
     let mut rng = rand::thread_rng();
     let kp1 = Keypair::new(&mut rng);
     let kp2 = Keypair::new(&mut rng);
@@ -115,18 +241,27 @@ async fn generate(
         node_id: 1,
         public_key: kp1.pubkey().to_string(),
         private_key: bs58::encode(kp1.to_bytes()).into_string(),
+        share_offset: None,
+        share_version: 0,
+        refresh_id: None,
     };
     let mpc_key2 = MpcKey {
         end_user_pubkey: end_user_pubkey.clone(),
         node_id: 2,
         public_key: kp2.pubkey().to_string(),
         private_key: bs58::encode(kp2.to_bytes()).into_string(),
+        share_offset: None,
+        share_version: 0,
+        refresh_id: None,
     };
 
     mpc_store_1.store_key(&mpc_key1).await?;
     mpc_store_2.store_key(&mpc_key2).await?;
 
     app_state.main_store.add_public_key(&end_user_pubkey).await.unwrap();
+    if let Err(e) = refill_presigned_nonces(&app_state, &end_user_pubkey).await {
+        log::error!("Failed to start presigning for {}: {}", end_user_pubkey, e);
+    }
 
     Ok(Json(GenerateResponse {
         end_user_pubkey,
@@ -147,39 +282,192 @@ async fn aggregate_keys(req: Json<AggregateKeysRequest>) -> Result<impl Responde
     Ok(Json(AggregateKeysResponse { aggregated_pubkey }))
 }
 
+/// SOL transfer from `from`, followed by the memo if there is one. With a
+/// durable nonce the nonce replaces `recent_blockhash` and the transaction
+/// advances it first.
+fn transfer_transaction(
+    from: &Pubkey,
+    to: &Pubkey,
+    amount: f64,
+    memo: Option<&str>,
+    compute_budget: Option<ComputeBudget>,
+    durable_nonce: Option<DurableNonce>,
+    recent_blockhash: Hash,
+) -> Transaction {
+    let mut instructions: Vec<_> = durable_nonce.iter().map(|nonce| nonce.advance_instruction(from)).collect();
+    instructions.extend(compute_budget.map(|budget| budget.instructions()).unwrap_or_default());
+    instructions.push(system_instruction::transfer(from, to, (amount * 1e9) as u64));
+    if let Some(memo) = memo {
+        instructions.push(spl_memo::build_memo(memo.as_bytes(), &[]));
+    }
+    let mut message = Message::new(&instructions, Some(from));
+    message.recent_blockhash = durable_nonce.map_or(recent_blockhash, |nonce| nonce.nonce);
+    Transaction::new_unsigned(message)
+}
+
+fn decode_transaction(encoded: &str) -> Result<VersionedTransaction, Error> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| Error::InvalidRequest(format!("Invalid transaction encoding: {}", e)))?;
+    bincode::deserialize(&bytes).map_err(|e| Error::InvalidRequest(format!("Invalid transaction: {}", e)))
+}
+
+/// The transaction a signing request would produce: the given transaction if
+/// there is one, otherwise the SOL transfer with a compute budget for the
+/// requested priority, advancing the owner's durable nonce first when those
+/// are enabled. Returns the budget and nonce a session has to sign with.
+async fn build_request_transaction(
+    app_state: &web::Data<AppState>,
+    owner: Pubkey,
+    to: &str,
+    amount: f64,
+    memo: Option<&str>,
+    transaction: Option<&str>,
+    priority: PriorityLevel,
+) -> Result<(VersionedTransaction, Option<ComputeBudget>, Option<DurableNonce>), Error> {
+    if let Some(encoded) = transaction {
+        // Given transactions carry their own blockhash
+        return Ok((decode_transaction(encoded)?, None, None));
+    }
+
+    let to = Pubkey::from_str(to).map_err(|_| Error::InvalidRequest("Invalid recipient".to_string()))?;
+    let durable_nonce = durable_nonce(app_state, owner).await?;
+
+    let budget = app_state
+        .fee_strategy
+        .transfer_budget(&app_state.rpc_client, priority, &[owner, to], memo, durable_nonce.is_some())
+        .await?;
+
+    // The blockhash is replaced during simulation
+    let transfer = transfer_transaction(&owner, &to, amount, memo, Some(budget), durable_nonce, Hash::default());
+    Ok((VersionedTransaction::from(transfer), Some(budget), durable_nonce))
+}
+
+async fn simulate_transaction(
+    app_state: &web::Data<AppState>,
+    owner: Pubkey,
+    transaction: VersionedTransaction,
+) -> Result<simulation::SimulationReport, Error> {
+    simulation::simulate(&app_state.rpc_client, &transaction, &owner).await
+}
+
+/// Current nonce of the owner's nonce account if durable nonces are enabled,
+/// creating the account on first use.
+async fn durable_nonce(app_state: &web::Data<AppState>, owner: Pubkey) -> Result<Option<DurableNonce>, Error> {
+    match &app_state.nonces {
+        Some(nonces) => nonces.durable_nonce(&app_state.rpc_client, &owner).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+fn parse_owner(end_user_pubkey: &str) -> Result<Pubkey, Error> {
+    Pubkey::from_str(end_user_pubkey).map_err(|_| Error::InvalidRequest("Invalid end_user_pubkey".to_string()))
+}
+
+async fn simulate(
+    app_state: web::Data<AppState>,
+    req: Json<SimulateRequest>,
+) -> Result<impl Responder, Error> {
+    let _timer = metrics::STEP_DURATION.with_label_values(&["simulate"]).start_timer();
+    let owner = parse_owner(&req.end_user_pubkey)?;
+    policy::check_memo(req.memo.as_deref())?;
+    let (transaction, _, _) = build_request_transaction(
+        &app_state,
+        owner,
+        &req.to,
+        req.amount,
+        req.memo.as_deref(),
+        req.transaction.as_deref(),
+        req.priority,
+    )
+    .await?;
+    let report = simulate_transaction(&app_state, owner, transaction).await?;
+    Ok(Json(report))
+}
+
 async fn agg_send_step1(
     app_state: web::Data<AppState>,
     req: Json<AggSendStep1Request>,
 ) -> Result<impl Responder, Error> {
+    let _timer = metrics::STEP_DURATION.with_label_values(&["step1"]).start_timer();
     let mpc_store = app_state.get_mpc_store(req.node_id)?;
     let key = mpc_store.get_key(&req.end_user_pubkey, req.node_id).await?;
+    key.check_settled()?;
+    let (compute_budget, durable_nonce) = prepare_signing(&app_state, &req).await?;
     let keypair = Keypair::from_bytes(&bs58::decode(key.private_key).into_vec().unwrap()).unwrap();
 
     let (agg_message_1, secret_state_1) = tss::step_one(keypair);
     let session_id = mpc_store
-        .create_session(
-            &req.end_user_pubkey,
-            &secret_state_1,
-            &req.to,
-            req.amount,
-            req.memo.clone(),
-            None, // No generic transaction for SOL send
-        )
+        .create_session(NewSession {
+            end_user_pubkey: &req.end_user_pubkey,
+            secret_state_1: Some(&secret_state_1),
+            to_address: &req.to,
+            amount: req.amount,
+            memo: req.memo.clone(),
+            transaction: req.transaction.clone(),
+            compute_budget,
+            durable_nonce,
+        })
         .await?;
-    
+    metrics::SESSIONS.with_label_values(&["started"]).inc();
+
     Ok(Json(AggSendStep1Response { session_id, agg_message_1 }))
 }
 
+/// Checks a signing request against policy and simulation before a session
+/// is started, returning the compute budget and durable nonce to sign with.
+async fn prepare_signing(
+    app_state: &web::Data<AppState>,
+    req: &AggSendStep1Request,
+) -> Result<(Option<ComputeBudget>, Option<DurableNonce>), Error> {
+    // Transactions built elsewhere carry a placeholder `to`; they are checked
+    // by inspecting their instructions instead
+    if req.transaction.is_none() {
+        if req.mint.as_deref().is_some_and(|mint| mint != SOL_MINT) {
+            return Err(Error::InvalidRequest("only SOL transfers are supported".to_string()));
+        }
+        policy::check_transfer(&app_state.main_store, &req.end_user_pubkey, &req.to).await?;
+    }
+    let owner = parse_owner(&req.end_user_pubkey)?;
+    policy::check_memo(req.memo.as_deref())?;
+    // Simulated with the nonce advance, exactly as it will be signed
+    let (transaction, compute_budget, durable_nonce) = build_request_transaction(
+        app_state,
+        owner,
+        &req.to,
+        req.amount,
+        req.memo.as_deref(),
+        req.transaction.as_deref(),
+        req.priority,
+    )
+    .await?;
+    if req.transaction.is_some() {
+        // The wallet has to be a signer, and nothing may give control of it away
+        if inspect::signer_index(&transaction.message, &owner).is_none() {
+            return Err(Error::InvalidRequest("Wallet is not a required signer".to_string()));
+        }
+        let instructions = inspect::summarize(&transaction.message);
+        policy::check_transaction(&req.end_user_pubkey, &instructions)?;
+        if req.external {
+            policy::check_transaction_destinations(&app_state.main_store, &req.end_user_pubkey, &instructions).await?;
+        }
+    }
+    // Refuse to start a session for a transaction that would fail on chain
+    simulate_transaction(app_state, owner, transaction).await?;
+    Ok((compute_budget, durable_nonce))
+}
+
 async fn agg_send_step2(
     app_state: web::Data<AppState>,
     req: Json<AggSendStep2Request>,
 ) -> Result<impl Responder, Error> {
+    let _timer = metrics::STEP_DURATION.with_label_values(&["step2"]).start_timer();
     let mpc_store = app_state.get_mpc_store(req.node_id)?;
     let session = mpc_store.get_session(req.session_id).await?;
     let key = mpc_store.get_key(&session.end_user_pubkey, req.node_id).await?;
-    let keypair = Keypair::from_bytes(&bs58::decode(key.private_key).into_vec().unwrap()).unwrap();
+    key.check_settled()?;
 
-    let (agg_message_2, secret_state_2) = tss::step_one(keypair);
+    let (agg_message_2, secret_state_2) = tss::step_one(key.keypair()?);
 
     let keys_from_db = mpc_store.get_keys_for_user(&session.end_user_pubkey).await?;
     let pubkeys: Vec<Pubkey> = keys_from_db
@@ -187,24 +475,31 @@ async fn agg_send_step2(
         .map(|k| Pubkey::from_str(&k.public_key).unwrap())
         .collect();
     
-    let rpc_client = &app_state.rpc_client;
-    let recent_blockhash = rpc_client.get_latest_blockhash()?;
+    let recent_blockhash = app_state.rpc_client.get_latest_blockhash().await?;
 
-    let message = if let Some(tx_str) = session.transaction {
-        let tx: Transaction = serde_json::from_str(&tx_str).unwrap();
-        tx.message_data()
+    let message = if let Some(message) = session.message {
+        message
+    } else if let Some(tx_str) = session.transaction {
+        decode_transaction(&tx_str)?.message.serialize()
     } else {
-        // Create SOL transfer message
+        // Create SOL transfer message from the aggregated key, as the broadcast does
+        let agg_pubkey = tss::key_agg(pubkeys.clone(), None).unwrap().agg_public_key;
+        let agg_pubkey = Pubkey::new_from_array(agg_pubkey.to_bytes(true));
         let to_pubkey = Pubkey::from_str(&session.to_address).unwrap();
-        let from_pubkey = Pubkey::from_str(&keys_from_db.iter().find(|k| k.node_id == req.node_id).unwrap().public_key).unwrap();
-        let ix = system_instruction::transfer(&from_pubkey, &to_pubkey, (session.amount * 1e9) as u64);
-        let mut msg = Message::new(&[ix], Some(&from_pubkey));
-        msg.recent_blockhash = recent_blockhash;
-        msg.serialize()
+        transfer_transaction(
+            &agg_pubkey,
+            &to_pubkey,
+            session.amount,
+            session.memo.as_deref(),
+            session.compute_budget(),
+            session.durable_nonce(),
+            recent_blockhash,
+        )
+        .message_data()
     };
     
     let partial_signature = tss::step_two(
-        keypair,
+        key.share()?,
         &message,
         pubkeys,
         vec![req.agg_message_1.clone()],
@@ -228,51 +523,428 @@ async fn aggregate_signatures_broadcast(
     app_state: web::Data<AppState>,
     req: Json<AggregateSignaturesRequest>,
 ) -> Result<impl Responder, Error> {
+    let _timer = metrics::STEP_DURATION.with_label_values(&["aggregate_broadcast"]).start_timer();
+    match aggregate_and_broadcast(&app_state, &req).await {
+        Ok(response) => {
+            let outcome = if req.sign_only { "signed" } else { "broadcast" };
+            metrics::SESSIONS.with_label_values(&[outcome]).inc();
+            Ok(Json(response))
+        }
+        Err(e) => {
+            metrics::SESSIONS.with_label_values(&["failed"]).inc();
+            Err(e)
+        }
+    }
+}
+
+async fn aggregate_and_broadcast(
+    app_state: &AppState,
+    req: &AggregateSignaturesRequest,
+) -> Result<AggregateSignaturesResponse, Error> {
     let mpc_store_1 = app_state.get_mpc_store(1)?;
     let session = mpc_store_1.get_session(req.session_id).await?;
+    if session.message.is_some() {
+        return Err(Error::InvalidRequest("Message sessions cannot be broadcast".to_string()));
+    }
     let keys_from_db = mpc_store_1.get_keys_for_user(&session.end_user_pubkey).await?;
-    let key1 = &keys_from_db[0];
-    let keypair1 = Keypair::from_bytes(&bs58::decode(&key1.private_key).into_vec().unwrap()).unwrap();
+    keys_from_db.iter().try_for_each(MpcKey::check_settled)?;
+    let share1 = keys_from_db[0].share()?;
     
     let pubkeys: Vec<Pubkey> = keys_from_db
         .iter()
         .map(|k| Pubkey::from_str(&k.public_key).unwrap())
         .collect();
 
+    let (tx, last_valid_block_height) = session_transaction(app_state, &session, &pubkeys).await?;
+    // Signing two messages with the same nonces would leak the key share
+    let secret_state_1 = mpc_store_1
+        .take_session_secret(req.session_id)
+        .await?
+        .ok_or_else(|| Error::InvalidRequest("Session was already signed".to_string()))?;
+
+    let partial_signature_1 = tss::step_two(
+        share1,
+        &tx.message.serialize(),
+        pubkeys.clone(),
+        vec![req.agg_message_2.clone()],
+        secret_state_1,
+    )?;
+
+    complete_transaction(
+        app_state,
+        &session,
+        tx,
+        pubkeys,
+        vec![partial_signature_1, req.partial_signature_2],
+        last_valid_block_height,
+        req.sign_only,
+    )
+    .await
+}
+
+/// The unsigned transaction a session signs, with the last block height its
+/// blockhash is valid for: the stored transaction if it has one, otherwise
+/// the SOL transfer from the aggregated key.
+async fn session_transaction(
+    app_state: &AppState,
+    session: &MpcSigningSession,
+    pubkeys: &[Pubkey],
+) -> Result<(VersionedTransaction, u64), Error> {
     let rpc_client = &app_state.rpc_client;
-    let recent_blockhash = rpc_client.get_latest_blockhash()?;
-    
-    let secret_state_1: SecretAggStepOne = serde_json::from_slice(&session.secret_state_1.unwrap()).unwrap();
+    let (recent_blockhash, last_valid_block_height) =
+        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment()).await?;
 
-    let tx = if let Some(tx_str) = session.transaction {
-        serde_json::from_str(&tx_str).unwrap()
+    let tx = if let Some(tx_str) = &session.transaction {
+        decode_transaction(tx_str)?
     } else {
-        let agg_pubkey = tss::key_agg(pubkeys.clone(), None).unwrap().agg_public_key;
+        let agg_pubkey = tss::key_agg(pubkeys.to_vec(), None)?.agg_public_key;
         let agg_pubkey = Pubkey::new_from_array(agg_pubkey.to_bytes(true));
-        let to_pubkey = Pubkey::from_str(&session.to_address).unwrap();
-        let ix = system_instruction::transfer(&agg_pubkey, &to_pubkey, (session.amount * 1e9) as u64);
-        let mut message = Message::new(&[ix], Some(&agg_pubkey));
-        message.recent_blockhash = recent_blockhash;
-        Transaction::new_unsigned(message)
+        let to_pubkey = Pubkey::from_str(&session.to_address)
+            .map_err(|_| Error::InvalidRequest("Invalid recipient".to_string()))?;
+        VersionedTransaction::from(transfer_transaction(
+            &agg_pubkey,
+            &to_pubkey,
+            session.amount,
+            session.memo.as_deref(),
+            session.compute_budget(),
+            session.durable_nonce(),
+            recent_blockhash,
+        ))
     };
+    Ok((tx, last_valid_block_height))
+}
+
+/// Combines the partial signatures into the session's transaction and,
+/// unless only signing was asked for, sends it and hands it to the confirmer.
+async fn complete_transaction(
+    app_state: &AppState,
+    session: &MpcSigningSession,
+    tx: VersionedTransaction,
+    pubkeys: Vec<Pubkey>,
+    partial_signatures: Vec<PartialSignature>,
+    last_valid_block_height: u64,
+    sign_only: bool,
+) -> Result<AggregateSignaturesResponse, Error> {
+    let final_tx = if session.transaction.is_some() {
+        // Built elsewhere: co-sign it as the aggregated key, leaving other signers' slots alone
+        tss::cosign_transaction(tx, pubkeys, partial_signatures)?
+    } else {
+        let tx = tx
+            .into_legacy_transaction()
+            .ok_or_else(|| Error::InvalidRequest("Transfers are legacy transactions".to_string()))?;
+        VersionedTransaction::from(tss::sign_and_broadcast_transaction(tx, pubkeys, partial_signatures)?)
+    };
+
+    let fee = simulation::network_fee(&app_state.rpc_client, &final_tx.message).await?;
+    let tx_sig = final_tx.signatures[0];
+    let signed_transaction = base64::engine::general_purpose::STANDARD
+        .encode(bincode::serialize(&final_tx).map_err(|e| Error::InvalidRequest(e.to_string()))?);
+    let response = AggregateSignaturesResponse {
+        transaction_signature: tx_sig.to_string(),
+        fee,
+        priority_fee: session.compute_budget().map_or(0, |budget| budget.priority_fee()),
+        last_valid_block_height,
+        status: "signed",
+        signed_transaction,
+    };
+    if sign_only {
+        return Ok(response);
+    }
+
+    if final_tx.verify_with_results().contains(&false) {
+        return Err(Error::InvalidRequest("Transaction still needs other signatures".to_string()));
+    }
+
+    // Keep the signed bytes before sending, so the confirmer tracks and
+    // rebroadcasts the transaction even if this request dies after the send
+    app_state
+        .get_mpc_store(1)?
+        .mark_session_submitted(session.session_id, &tx_sig.to_string(), &response.signed_transaction, last_valid_block_height)
+        .await?;
+    app_state.rpc_client.send_transaction(&final_tx).await?;
+
+    Ok(AggregateSignaturesResponse {
+        status: "submitted",
+        ..response
+    })
+}
+
+/// Most nonce pairs generated in one batch.
+const MAX_PRESIGN_BATCH: usize = 100;
+
+/// Runs the nonce exchange for `count` future signatures ahead of time. Both
+/// nodes generate their nonces, and each stores its secret half with the
+/// other's public half under a shared pair id.
+async fn generate_presigned_nonces(app_state: &AppState, end_user_pubkey: &str, count: usize) -> Result<i64, Error> {
+    let mpc_store_1 = app_state.get_mpc_store(1)?;
+    let mpc_store_2 = app_state.get_mpc_store(2)?;
+    let key1 = mpc_store_1.get_key(end_user_pubkey, 1).await?;
+    let key2 = mpc_store_2.get_key(end_user_pubkey, 2).await?;
+    key1.check_settled()?;
+    key2.check_settled()?;
+    let key1 = bs58::decode(key1.private_key).into_vec().unwrap();
+    let key2 = bs58::decode(key2.private_key).into_vec().unwrap();
+
+    for _ in 0..count {
+        let (agg_message_1, secret_state_1) = tss::step_one(Keypair::from_bytes(&key1).unwrap());
+        let (agg_message_2, secret_state_2) = tss::step_one(Keypair::from_bytes(&key2).unwrap());
+        let pair_id = Uuid::new_v4();
+        // Node 2 first: node 1 hands out pairs, so each of its pairs always has a partner
+        mpc_store_2
+            .store_presigned_nonce(pair_id, end_user_pubkey, 2, &secret_state_2, &agg_message_1)
+            .await?;
+        mpc_store_1
+            .store_presigned_nonce(pair_id, end_user_pubkey, 1, &secret_state_1, &agg_message_2)
+            .await?;
+    }
+    mpc_store_1.count_presigned_nonces(end_user_pubkey, 1).await
+}
+
+async fn presign(
+    app_state: web::Data<AppState>,
+    req: Json<PresignRequest>,
+) -> Result<impl Responder, Error> {
+    let _timer = metrics::STEP_DURATION.with_label_values(&["presign"]).start_timer();
+    if req.count == 0 || req.count > MAX_PRESIGN_BATCH {
+        return Err(Error::InvalidRequest(format!("count must be between 1 and {}", MAX_PRESIGN_BATCH)));
+    }
+    let available = generate_presigned_nonces(&app_state, &req.end_user_pubkey, req.count).await?;
+    Ok(Json(PresignResponse { available }))
+}
+
+/// Tops the key's pool back up in the background once it runs low. The key
+/// stays marked as refilling from the count until the new pairs are stored,
+/// so concurrent calls cannot each top it up.
+async fn refill_presigned_nonces(app_state: &web::Data<AppState>, end_user_pubkey: &str) -> Result<(), Error> {
+    let mpc_store_1 = app_state.get_mpc_store(1)?;
+    if !app_state.refilling.lock().unwrap().insert(end_user_pubkey.to_string()) {
+        return Ok(());
+    }
+    let done = |state: &AppState, end_user_pubkey: &str| {
+        state.refilling.lock().unwrap().remove(end_user_pubkey);
+    };
+
+    let target = app_state.presign_pool_size as i64;
+    let available = match mpc_store_1.count_presigned_nonces(end_user_pubkey, 1).await {
+        Ok(available) if available < target / 2 => available,
+        Ok(_) => {
+            done(app_state, end_user_pubkey);
+            return Ok(());
+        }
+        Err(e) => {
+            done(app_state, end_user_pubkey);
+            return Err(e);
+        }
+    };
+
+    let state = app_state.clone();
+    let end_user_pubkey = end_user_pubkey.to_string();
+    actix_web::rt::spawn(async move {
+        let count = (target - available) as usize;
+        if let Err(e) = generate_presigned_nonces(&state, &end_user_pubkey, count).await {
+            log::error!("Failed to refill presigned nonces for {}: {}", end_user_pubkey, e);
+        }
+        done(&state, &end_user_pubkey);
+    });
+    Ok(())
+}
+
+/// Signs a transfer or given transaction in a single round with a presigned
+/// nonce pair, then sends it unless only signing was asked for. Answers 409
+/// when the key has no pairs left, in which case the caller falls back to
+/// the step-by-step rounds.
+async fn agg_send_presigned(
+    app_state: web::Data<AppState>,
+    req: Json<AggSendPresignedRequest>,
+) -> Result<impl Responder, Error> {
+    let _timer = metrics::STEP_DURATION.with_label_values(&["presigned"]).start_timer();
+    let request = &req.request;
+    let mpc_store_1 = app_state.get_mpc_store(1)?;
+    let mpc_store_2 = app_state.get_mpc_store(2)?;
+    let key1 = mpc_store_1.get_key(&request.end_user_pubkey, 1).await?;
+    let key2 = mpc_store_2.get_key(&request.end_user_pubkey, 2).await?;
+    key1.check_settled()?;
+    key2.check_settled()?;
+    let (compute_budget, durable_nonce) = prepare_signing(&app_state, request).await?;
+
+    let nonce_1 = mpc_store_1
+        .claim_presigned_nonce(&request.end_user_pubkey, 1, None)
+        .await?
+        .ok_or(Error::NoPresignedNonces)?;
+    let nonce_2 = mpc_store_2
+        .claim_presigned_nonce(&request.end_user_pubkey, 2, Some(nonce_1.pair_id))
+        .await?
+        .ok_or(Error::NoPresignedNonces)?;
+    refill_presigned_nonces(&app_state, &request.end_user_pubkey).await?;
+
+    let session_id = mpc_store_1
+        .create_session(NewSession {
+            end_user_pubkey: &request.end_user_pubkey,
+            secret_state_1: None,
+            to_address: &request.to,
+            amount: request.amount,
+            memo: request.memo.clone(),
+            transaction: request.transaction.clone(),
+            compute_budget,
+            durable_nonce,
+        })
+        .await?;
+    metrics::SESSIONS.with_label_values(&["started"]).inc();
+
+    let session = mpc_store_1.get_session(session_id).await?;
+    let pubkeys: Vec<Pubkey> = mpc_store_1
+        .get_keys_for_user(&request.end_user_pubkey)
+        .await?
+        .iter()
+        .map(|k| Pubkey::from_str(&k.public_key).unwrap())
+        .collect();
+    let (tx, last_valid_block_height) = session_transaction(&app_state, &session, &pubkeys).await?;
+    let message = tx.message.serialize();
+
+    let partial_signature_1 =
+        tss::step_two(key1.share()?, &message, pubkeys.clone(), vec![nonce_1.peer_message], nonce_1.secret_state)?;
+    let partial_signature_2 =
+        tss::step_two(key2.share()?, &message, pubkeys.clone(), vec![nonce_2.peer_message], nonce_2.secret_state)?;
+
+    let result = complete_transaction(
+        &app_state,
+        &session,
+        tx,
+        pubkeys,
+        vec![partial_signature_1, partial_signature_2],
+        last_valid_block_height,
+        req.sign_only,
+    )
+    .await;
+    let outcome = match (&result, req.sign_only) {
+        (Err(_), _) => "failed",
+        (Ok(_), true) => "signed",
+        (Ok(_), false) => "broadcast",
+    };
+    metrics::SESSIONS.with_label_values(&[outcome]).inc();
+
+    Ok(Json(AggSendPresignedResponse { session_id, result: result? }))
+}
+
+/// First round for signing an off-chain message. The second round is the
+/// usual `/agg-send-step2`, which signs the stored message.
+async fn sign_message_step1(
+    app_state: web::Data<AppState>,
+    req: Json<SignMessageStep1Request>,
+) -> Result<impl Responder, Error> {
+    let _timer = metrics::STEP_DURATION.with_label_values(&["sign_message_step1"]).start_timer();
+    let mpc_store = app_state.get_mpc_store(req.node_id)?;
+    let key = mpc_store.get_key(&req.end_user_pubkey, req.node_id).await?;
+    key.check_settled()?;
+    let message = base64::engine::general_purpose::STANDARD
+        .decode(&req.message)
+        .map_err(|e| Error::InvalidRequest(format!("Invalid message encoding: {}", e)))?;
+    let payload = offchain::signing_payload(&message, req.format)?;
+    let keypair = Keypair::from_bytes(&bs58::decode(key.private_key).into_vec().unwrap()).unwrap();
+
+    let (agg_message_1, secret_state_1) = tss::step_one(keypair);
+    let session_id = mpc_store
+        .create_message_session(&req.end_user_pubkey, &secret_state_1, &payload)
+        .await?;
+
+    Ok(Json(AggSendStep1Response { session_id, agg_message_1 }))
+}
+
+/// Combines both partial signatures over a message session into the
+/// aggregated key's signature. Nothing is sent to the chain.
+async fn sign_message_aggregate(
+    app_state: web::Data<AppState>,
+    req: Json<AggregateSignaturesRequest>,
+) -> Result<impl Responder, Error> {
+    let _timer = metrics::STEP_DURATION.with_label_values(&["sign_message_aggregate"]).start_timer();
+    let mpc_store_1 = app_state.get_mpc_store(1)?;
+    let session = mpc_store_1.get_session(req.session_id).await?;
+    let message = session
+        .message
+        .ok_or_else(|| Error::InvalidRequest("Session is not a message session".to_string()))?;
+    let keys_from_db = mpc_store_1.get_keys_for_user(&session.end_user_pubkey).await?;
+    keys_from_db.iter().try_for_each(MpcKey::check_settled)?;
+    let share1 = keys_from_db[0].share()?;
+
+    let pubkeys: Vec<Pubkey> = keys_from_db
+        .iter()
+        .map(|k| Pubkey::from_str(&k.public_key).unwrap())
+        .collect();
+    let secret_state_1 = mpc_store_1
+        .take_session_secret(req.session_id)
+        .await?
+        .ok_or_else(|| Error::InvalidRequest("Session was already signed".to_string()))?;
 
     let partial_signature_1 = tss::step_two(
-        keypair1,
-        &tx.message_data(),
+        share1,
+        &message,
         pubkeys.clone(),
         vec![req.agg_message_2.clone()],
         secret_state_1,
-    ).unwrap();
+    )?;
+    let signature = tss::sign_message(&message, pubkeys, vec![partial_signature_1, req.partial_signature_2])?;
 
-    let final_tx = tss::sign_and_broadcast_transaction(
-        tx,
-        pubkeys,
-        vec![partial_signature_1, req.partial_signature_2],
-    ).unwrap();
+    Ok(Json(SignMessageResponse {
+        signature: signature.to_string(),
+        signed_message: base64::engine::general_purpose::STANDARD.encode(&message),
+        public_key: session.end_user_pubkey,
+    }))
+}
+
+#[derive(Deserialize)]
+struct RefreshSharesRequest {
+    end_user_pubkey: String,
+}
+
+#[derive(Serialize)]
+struct RefreshSharesResponse {
+    refresh_id: Uuid,
+    share_version: i32,
+}
+
+/// Re-randomizes both nodes' shares of a key now, without waiting for the
+/// periodic refresh. The address does not change.
+async fn refresh_shares(
+    app_state: web::Data<AppState>,
+    req: Json<RefreshSharesRequest>,
+) -> Result<impl Responder, Error> {
+    let _timer = metrics::STEP_DURATION.with_label_values(&["refresh"]).start_timer();
+    let stores = [app_state.get_mpc_store(1)?, app_state.get_mpc_store(2)?];
+    let refreshed = refresh::refresh_key(&stores, &req.end_user_pubkey, app_state.backup.as_ref()).await?;
+    Ok(Json(RefreshSharesResponse {
+        refresh_id: refreshed.refresh_id,
+        share_version: refreshed.share_version,
+    }))
+}
 
-    let tx_sig = rpc_client.send_and_confirm_transaction(&final_tx)?;
+async fn metrics_endpoint() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
 
-    Ok(Json(AggregateSignaturesResponse { transaction_signature: tx_sig.to_string() }))
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+async fn readyz(app_state: web::Data<AppState>) -> HttpResponse {
+    let mpc_database_1 = app_state.mpc_store_1.ping().await.is_ok();
+    let mpc_database_2 = app_state.mpc_store_2.ping().await.is_ok();
+    let main_database = app_state.main_store.ping().await.is_ok();
+
+    let solana_rpc = app_state.rpc_client.get_health().await.is_ok();
+
+    let response = ReadinessResponse {
+        mpc_database_1,
+        mpc_database_2,
+        main_database,
+        solana_rpc,
+    };
+    if mpc_database_1 && mpc_database_2 && main_database && solana_rpc {
+        HttpResponse::Ok().json(response)
+    } else {
+        HttpResponse::ServiceUnavailable().json(response)
+    }
 }
 
 async fn send_single() -> Result<HttpResponse, Error> {
@@ -285,6 +957,13 @@ async fn main() -> std::io::Result<()> {
     dotenv().ok();
     env_logger::init();
 
+    // Backup commands run instead of the server, see `backup::run_command`
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = args.split_first().filter(|(command, _)| backup::COMMANDS.contains(&command.as_str()));
+    if let Some((command, args)) = command {
+        return backup::run_command(command, args).await.map_err(std::io::Error::other);
+    }
+
     let mpc_database_url_1 = std::env::var("MPC_DATABASE_URL_1").expect("MPC_DATABASE_URL_1 must be set");
     let mpc_database_url_2 = std::env::var("MPC_DATABASE_URL_2").expect("MPC_DATABASE_URL_2 must be set");
     let main_database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
@@ -298,17 +977,59 @@ async fn main() -> std::io::Result<()> {
         mpc_store_1: MpcStore::new(mpc_pool_1),
         mpc_store_2: MpcStore::new(mpc_pool_2),
         main_store: Arc::new(Store::new(main_pool)),
-        rpc_client: RpcClient::new(rpc_url),
+        rpc_client: Arc::new(RpcClient::new(rpc_url)),
+        fee_strategy: FeeStrategy::from_env().expect("Invalid priority fee configuration"),
+        nonces: NonceManager::from_env().expect("Invalid durable nonce configuration"),
+        presign_pool_size: std::env::var("PRESIGN_POOL_SIZE")
+            .ok()
+            .map(|size| size.parse().expect("Invalid PRESIGN_POOL_SIZE"))
+            .unwrap_or(16),
+        refilling: Mutex::new(HashSet::new()),
+        backup: AutoBackup::from_env().expect("Invalid backup configuration"),
     });
 
+    let confirm_interval = std::env::var("CONFIRM_INTERVAL_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::from_secs(2));
+    actix_web::rt::spawn(confirmer::run(
+        app_state.mpc_store_1.clone(),
+        app_state.main_store.clone(),
+        app_state.rpc_client.clone(),
+        confirm_interval,
+    ));
+    if let Ok(hours) = std::env::var("SHARE_REFRESH_HOURS") {
+        let hours: i64 = hours.parse().expect("Invalid SHARE_REFRESH_HOURS");
+        if app_state.backup.is_none() {
+            log::warn!("BACKUP_DIR is not set; backups go stale after every share refresh");
+        }
+        actix_web::rt::spawn(refresh::run(
+            app_state.mpc_store_1.clone(),
+            app_state.mpc_store_2.clone(),
+            chrono::Duration::hours(hours),
+            app_state.backup.clone(),
+        ));
+    }
+
     HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            .wrap(from_fn(metrics::track_requests))
+            .route("/metrics", get().to(metrics_endpoint))
+            .route("/healthz", get().to(healthz))
+            .route("/readyz", get().to(readyz))
             .route("/generate", post().to(generate))
             .route("/send-single", post().to(send_single))
             .route("/aggregate-keys", post().to(aggregate_keys))
+            .route("/simulate", post().to(simulate))
             .route("/agg-send-step1", post().to(agg_send_step1))
             .route("/agg-send-step2", post().to(agg_send_step2))
+            .route("/presign", post().to(presign))
+            .route("/agg-send-presigned", post().to(agg_send_presigned))
+            .route("/sign-message-step1", post().to(sign_message_step1))
+            .route("/sign-message-aggregate", post().to(sign_message_aggregate))
+            .route("/refresh-shares", post().to(refresh_shares))
             .route(
                 "/aggregate-signatures-broadcast",
                 post().to(aggregate_signatures_broadcast),
@@ -0,0 +1,41 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    Error,
+};
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+use std::sync::LazyLock;
+use telemetry::HttpMetrics;
+
+pub use telemetry::render;
+
+pub static HTTP: LazyLock<HttpMetrics> = LazyLock::new(|| HttpMetrics::register("mpc"));
+
+/// Signing sessions by outcome: `started`, `signed`, `broadcast` or `failed` while
+/// signing, then `confirmed`, `failed` or `expired` once the confirmer settles
+/// a broadcast transaction.
+pub static SESSIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "mpc_signing_sessions_total",
+        "MPC signing sessions by outcome",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+pub static STEP_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "mpc_step_duration_seconds",
+        "Latency of each MPC protocol step",
+        &["step"]
+    )
+    .unwrap()
+});
+
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    HTTP.track(req, next).await
+}
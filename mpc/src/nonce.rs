@@ -0,0 +1,179 @@
+use crate::error::Error;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+    account::Account,
+    hash::Hash,
+    instruction::Instruction,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    system_instruction, system_program,
+    transaction::Transaction,
+};
+use tokio::sync::Mutex;
+
+/// Size of a nonce account: version, state, authority, nonce and the legacy
+/// fee calculator.
+const NONCE_ACCOUNT_LEN: usize = 80;
+/// `State::Initialized` in the nonce account layout.
+const NONCE_INITIALIZED: u32 = 1;
+
+/// A nonce account and the nonce a transaction was built against. The nonce
+/// stands in for the recent blockhash and stays valid until it is advanced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DurableNonce {
+    pub account: Pubkey,
+    pub nonce: Hash,
+}
+
+impl DurableNonce {
+    /// `AdvanceNonceAccount`, which has to be the first instruction.
+    pub fn advance_instruction(&self, authority: &Pubkey) -> Instruction {
+        system_instruction::advance_nonce_account(&self.account, authority)
+    }
+}
+
+/// Creates and reads one durable nonce account per aggregated key. The
+/// account lives at an address derived from the funder and the key, and its
+/// authority is the aggregated key itself, so advancing it is covered by the
+/// MPC signature.
+pub struct NonceManager {
+    /// Pays rent for new nonce accounts.
+    funder: Keypair,
+    /// Held while looking up and creating an account, so concurrent requests
+    /// for a new key do not both try to create it.
+    creating: Mutex<()>,
+}
+
+impl NonceManager {
+    /// Reads the base58 funder keypair from `NONCE_FUNDER_KEYPAIR`. Durable
+    /// nonces are disabled when it is not set.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(encoded) = std::env::var("NONCE_FUNDER_KEYPAIR") else {
+            return Ok(None);
+        };
+        let bytes = bs58::decode(encoded.trim())
+            .into_vec()
+            .map_err(|_| "Invalid NONCE_FUNDER_KEYPAIR encoding".to_string())?;
+        let funder = Keypair::from_bytes(&bytes).map_err(|_| "Invalid NONCE_FUNDER_KEYPAIR".to_string())?;
+        Ok(Some(Self { funder, creating: Mutex::new(()) }))
+    }
+
+    /// Seeds are capped at 32 bytes; the first 32 characters of the key are
+    /// unique enough.
+    fn seed(authority: &Pubkey) -> String {
+        authority.to_string().chars().take(32).collect()
+    }
+
+    pub fn nonce_account(&self, authority: &Pubkey) -> Pubkey {
+        Pubkey::create_with_seed(&self.funder.pubkey(), &Self::seed(authority), &system_program::id())
+            .expect("seed is at most 32 bytes")
+    }
+
+    /// The current nonce for `authority`, creating its nonce account first
+    /// if there is none yet.
+    pub async fn durable_nonce(&self, rpc_client: &RpcClient, authority: &Pubkey) -> Result<DurableNonce, Error> {
+        let account = self.nonce_account(authority);
+        let existing = match Self::fetch(rpc_client, &account).await? {
+            Some(existing) => existing,
+            None => {
+                let _creating = self.creating.lock().await;
+                match Self::fetch(rpc_client, &account).await? {
+                    Some(existing) => existing,
+                    None => match self.create(rpc_client, authority).await {
+                        Ok(()) => rpc_client.get_account(&account).await?,
+                        // Another MPC node may have created it in the meantime
+                        Err(e) => Self::fetch(rpc_client, &account).await?.ok_or(e)?,
+                    },
+                }
+            }
+        };
+
+        let (nonce_authority, nonce) = nonce_state(&existing)
+            .ok_or_else(|| Error::InvalidRequest(format!("{} is not an initialized nonce account", account)))?;
+        if nonce_authority != *authority {
+            return Err(Error::InvalidRequest(format!("Nonce account {} has another authority", account)));
+        }
+        Ok(DurableNonce { account, nonce })
+    }
+
+    async fn fetch(rpc_client: &RpcClient, account: &Pubkey) -> Result<Option<Account>, Error> {
+        Ok(rpc_client.get_account_with_commitment(account, rpc_client.commitment()).await?.value)
+    }
+
+    /// Nonce currently stored in `account`, if it is an initialized nonce
+    /// account.
+    pub async fn current_nonce(rpc_client: &RpcClient, account: &Pubkey) -> Result<Option<Hash>, Error> {
+        Ok(Self::fetch(rpc_client, account).await?.as_ref().and_then(nonce_state).map(|(_, nonce)| nonce))
+    }
+
+    async fn create(&self, rpc_client: &RpcClient, authority: &Pubkey) -> Result<(), Error> {
+        let funder = self.funder.pubkey();
+        let lamports = rpc_client.get_minimum_balance_for_rent_exemption(NONCE_ACCOUNT_LEN).await?;
+        let instructions = system_instruction::create_nonce_account_with_seed(
+            &funder,
+            &self.nonce_account(authority),
+            &funder,
+            &Self::seed(authority),
+            authority,
+            lamports,
+        );
+        let blockhash = rpc_client.get_latest_blockhash().await?;
+        let transaction = Transaction::new_signed_with_payer(&instructions, Some(&funder), &[&self.funder], blockhash);
+        let signature = rpc_client.send_and_confirm_transaction(&transaction).await?;
+        log::info!("Created nonce account for {} in {}", authority, signature);
+        Ok(())
+    }
+}
+
+/// Authority and nonce of an initialized nonce account, read from the
+/// layout: version, state, authority, nonce.
+fn nonce_state(account: &Account) -> Option<(Pubkey, Hash)> {
+    if account.owner != system_program::id() || account.data.len() < NONCE_ACCOUNT_LEN {
+        return None;
+    }
+    let state = u32::from_le_bytes(account.data[4..8].try_into().ok()?);
+    if state != NONCE_INITIALIZED {
+        return None;
+    }
+    let authority = Pubkey::try_from(&account.data[8..40]).ok()?;
+    let nonce = Hash::new_from_array(account.data[40..72].try_into().ok()?);
+    Some((authority, nonce))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nonce_account(state: u32, authority: Pubkey, nonce: Hash) -> Account {
+        let mut data = vec![0; NONCE_ACCOUNT_LEN];
+        data[0..4].copy_from_slice(&1u32.to_le_bytes());
+        data[4..8].copy_from_slice(&state.to_le_bytes());
+        data[8..40].copy_from_slice(authority.as_ref());
+        data[40..72].copy_from_slice(nonce.as_ref());
+        Account {
+            lamports: 1_447_680,
+            data,
+            owner: system_program::id(),
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    #[test]
+    fn reads_initialized_nonce() {
+        let authority = Pubkey::new_unique();
+        let nonce = Hash::new_unique();
+        let account = nonce_account(NONCE_INITIALIZED, authority, nonce);
+        assert_eq!(nonce_state(&account), Some((authority, nonce)));
+    }
+
+    #[test]
+    fn ignores_uninitialized_or_foreign_accounts() {
+        let authority = Pubkey::new_unique();
+        assert_eq!(nonce_state(&nonce_account(0, authority, Hash::new_unique())), None);
+
+        let mut foreign = nonce_account(NONCE_INITIALIZED, authority, Hash::new_unique());
+        foreign.owner = Pubkey::new_unique();
+        assert_eq!(nonce_state(&foreign), None);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{message::VersionedMessage, transaction::VersionedTransaction};
+
+/// Prefix of every off-chain message, chosen so it can never start a valid
+/// transaction message.
+pub const SIGNING_DOMAIN: &[u8] = b"\xffsolana offchain";
+/// Longest message the restricted ASCII and limited UTF-8 formats allow, so
+/// the whole preamble and message fit in a packet for hardware wallets.
+const MAX_LIMITED_LEN: usize = 1212;
+/// Longest message the extended UTF-8 format allows.
+const MAX_EXTENDED_LEN: usize = u16::MAX as usize - SIGNING_DOMAIN.len() - 4;
+
+/// How the bytes handed to the MPC nodes are turned into what gets signed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    /// Signed as is, as Sign-In With Solana and most dApps expect.
+    Raw,
+    /// Wrapped in the version 0 off-chain message header.
+    #[default]
+    Offchain,
+}
+
+/// The bytes to sign for `message` in `format`. Anything that parses as a
+/// transaction, or as a transaction message, is refused so that message
+/// signing cannot be used to authorize a transfer.
+pub fn signing_payload(message: &[u8], format: MessageFormat) -> Result<Vec<u8>, Error> {
+    if message.is_empty() {
+        return Err(Error::InvalidRequest("Message is empty".to_string()));
+    }
+    if is_transaction(message) {
+        return Err(Error::InvalidRequest("Message parses as a transaction".to_string()));
+    }
+    match format {
+        MessageFormat::Raw => Ok(message.to_vec()),
+        MessageFormat::Offchain => offchain_message(message),
+    }
+}
+
+/// Version 0 off-chain message: signing domain, version, format, length and
+/// the message itself.
+fn offchain_message(message: &[u8]) -> Result<Vec<u8>, Error> {
+    let text_format = if message.iter().all(|b| (0x20..=0x7e).contains(b)) {
+        0 // Restricted ASCII
+    } else if std::str::from_utf8(message).is_ok() {
+        1 // Limited UTF-8
+    } else {
+        return Err(Error::InvalidRequest("Off-chain messages must be UTF-8".to_string()));
+    };
+    let text_format = match message.len() {
+        len if len <= MAX_LIMITED_LEN => text_format,
+        len if len <= MAX_EXTENDED_LEN => 2, // Extended UTF-8
+        _ => return Err(Error::InvalidRequest("Message is too long".to_string())),
+    };
+
+    let mut payload = Vec::with_capacity(SIGNING_DOMAIN.len() + 4 + message.len());
+    payload.extend_from_slice(SIGNING_DOMAIN);
+    payload.push(0); // Version
+    payload.push(text_format);
+    payload.extend_from_slice(&(message.len() as u16).to_le_bytes());
+    payload.extend_from_slice(message);
+    Ok(payload)
+}
+
+fn is_transaction(bytes: &[u8]) -> bool {
+    let message = bincode::deserialize::<VersionedMessage>(bytes).is_ok_and(|message| message.sanitize().is_ok());
+    let transaction =
+        bincode::deserialize::<VersionedTransaction>(bytes).is_ok_and(|transaction| transaction.sanitize().is_ok());
+    message || transaction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::{hash::Hash, message::Message, pubkey::Pubkey, system_instruction, transaction::Transaction};
+
+    fn transfer_message() -> Message {
+        let from = Pubkey::new_unique();
+        let ix = system_instruction::transfer(&from, &Pubkey::new_unique(), 1);
+        let mut message = Message::new(&[ix], Some(&from));
+        message.recent_blockhash = Hash::new_unique();
+        message
+    }
+
+    #[test]
+    fn refuses_transaction_messages() {
+        let message = transfer_message();
+        assert!(signing_payload(&message.serialize(), MessageFormat::Raw).is_err());
+        assert!(signing_payload(&message.serialize(), MessageFormat::Offchain).is_err());
+
+        let transaction = bincode::serialize(&Transaction::new_unsigned(message)).unwrap();
+        assert!(signing_payload(&transaction, MessageFormat::Raw).is_err());
+    }
+
+    #[test]
+    fn wraps_offchain_messages() {
+        let payload = signing_payload(b"hello", MessageFormat::Offchain).unwrap();
+        assert_eq!(&payload[..16], SIGNING_DOMAIN);
+        assert_eq!(payload[16..20], [0, 0, 5, 0]);
+        assert_eq!(&payload[20..], b"hello");
+
+        let payload = signing_payload("héllo".as_bytes(), MessageFormat::Offchain).unwrap();
+        assert_eq!(payload[17], 1);
+        assert!(!is_transaction(&payload));
+    }
+
+    #[test]
+    fn signs_raw_text_as_is() {
+        let message = b"example.com wants you to sign in with your Solana account:";
+        assert_eq!(signing_payload(message, MessageFormat::Raw).unwrap(), message);
+    }
+}
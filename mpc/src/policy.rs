@@ -0,0 +1,192 @@
+use crate::error::Error;
+use crate::inspect::{Action, InstructionSummary};
+use chrono::Utc;
+use store::solana::SOL_MINT;
+use store::Store;
+
+/// Longest memo in bytes. Memos are logged on chain as UTF-8; this keeps the
+/// transfer well under the transaction size limit.
+pub const MAX_MEMO_LEN: usize = 256;
+
+pub fn check_memo(memo: Option<&str>) -> Result<(), Error> {
+    match memo {
+        Some(memo) if memo.len() > MAX_MEMO_LEN => Err(Error::InvalidRequest(format!(
+            "Memo is longer than {} bytes",
+            MAX_MEMO_LEN
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Refuses SOL transfers that the user's withdrawal allowlist does not
+/// permit, whatever the amount. The backend runs the same check before
+/// starting a session; repeating it here keeps the signer from relying on
+/// every caller to do so.
+pub async fn check_transfer(store: &Store, end_user_pubkey: &str, to: &str) -> Result<(), Error> {
+    let user = store
+        .get_user_by_public_key(end_user_pubkey)
+        .await
+        .map_err(|e| Error::StoreError(e.to_string()))?
+        .ok_or(Error::KeyNotFound)?;
+
+    let allowed = store
+        .is_withdrawal_allowed(user.id, to, SOL_MINT)
+        .await
+        .map_err(|e| Error::StoreError(e.to_string()))?;
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(Error::PolicyViolation(format!("{} is not an active withdrawal address", to)))
+    }
+}
+
+/// Refuses transactions built elsewhere that could send funds outside the
+/// withdrawal allowlist while strict mode is on. Only SOL transfers from the
+/// wallet can be checked against it, so every other instruction that is not
+/// a compute budget or memo is refused.
+pub async fn check_transaction_destinations(
+    store: &Store,
+    end_user_pubkey: &str,
+    instructions: &[InstructionSummary],
+) -> Result<(), Error> {
+    let user = store
+        .get_user_by_public_key(end_user_pubkey)
+        .await
+        .map_err(|e| Error::StoreError(e.to_string()))?
+        .ok_or(Error::KeyNotFound)?;
+    let settings = store
+        .get_withdrawal_settings(user.id)
+        .await
+        .map_err(|e| Error::StoreError(e.to_string()))?;
+    if !settings.is_strict(Utc::now()) {
+        return Ok(());
+    }
+
+    for instruction in instructions {
+        match &instruction.action {
+            Action::ComputeBudget | Action::Memo { .. } => {}
+            Action::SolTransfer { from, to, .. } if from == end_user_pubkey => {
+                let allowed = store
+                    .is_withdrawal_allowed(user.id, to, SOL_MINT)
+                    .await
+                    .map_err(|e| Error::StoreError(e.to_string()))?;
+                if !allowed {
+                    return Err(Error::PolicyViolation(format!("{} is not an active withdrawal address", to)));
+                }
+            }
+            _ => {
+                return Err(Error::PolicyViolation(format!(
+                    "{} instruction cannot be checked against the withdrawal allowlist",
+                    instruction.program_id
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Refuses transactions built elsewhere that would hand control of the
+/// wallet or its token accounts to someone else: reassigning the wallet to
+/// another program, approving a delegate or changing a token account's
+/// authority. Transfers are left to simulation and the caller's limits.
+pub fn check_transaction(owner: &str, instructions: &[InstructionSummary]) -> Result<(), Error> {
+    for instruction in instructions {
+        let refused = match &instruction.action {
+            Action::Assign { account, .. } => account == owner,
+            Action::TokenApprove { owner: authority, .. } => authority == owner,
+            Action::TokenSetAuthority { authority, .. } => authority == owner,
+            _ => false,
+        };
+        if refused {
+            return Err(Error::PolicyViolation(format!(
+                "{} instruction would give away control of the wallet",
+                instruction.program_id
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::PgPool;
+    use store::user::CreateUserRequest;
+
+    const WALLET: &str = "4Nd1mBQtrMJVYVfKf2PJy9NZUZdTAsp7D4xWLs4gDB4T";
+    const DESTINATION: &str = "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin";
+
+    #[test]
+    fn memo_length_is_counted_in_bytes() {
+        assert!(check_memo(None).is_ok());
+        assert!(check_memo(Some(&"a".repeat(MAX_MEMO_LEN))).is_ok());
+        assert!(check_memo(Some(&"a".repeat(MAX_MEMO_LEN + 1))).is_err());
+        // 128 two-byte characters fit, one more does not
+        assert!(check_memo(Some(&"é".repeat(MAX_MEMO_LEN / 2))).is_ok());
+        assert!(matches!(
+            check_memo(Some(&"é".repeat(MAX_MEMO_LEN / 2 + 1))),
+            Err(Error::InvalidRequest(_))
+        ));
+    }
+
+    #[sqlx::test(migrations = "../store/migrations")]
+    async fn refuses_transfers_outside_the_allowlist_in_strict_mode(pool: PgPool) {
+        let store = Store::new(pool);
+        let user = store
+            .create_user(CreateUserRequest {
+                email: "alice@example.com".to_string(),
+                password: "password".to_string(),
+                public_key: WALLET.to_string(),
+            })
+            .await
+            .unwrap();
+        assert!(check_transfer(&store, WALLET, DESTINATION).await.is_ok());
+
+        store.enable_withdrawal_strict_mode(user.id).await.unwrap();
+        assert!(matches!(
+            check_transfer(&store, WALLET, DESTINATION).await,
+            Err(Error::PolicyViolation(_))
+        ));
+    }
+
+    fn instruction(action: Action) -> InstructionSummary {
+        InstructionSummary {
+            program_id: "11111111111111111111111111111111".to_string(),
+            accounts: Vec::new(),
+            data: String::new(),
+            action,
+        }
+    }
+
+    #[sqlx::test(migrations = "../store/migrations")]
+    async fn strict_mode_refuses_instructions_it_cannot_check(pool: PgPool) {
+        let store = Store::new(pool);
+        let user = store
+            .create_user(CreateUserRequest {
+                email: "bob@example.com".to_string(),
+                password: "password".to_string(),
+                public_key: WALLET.to_string(),
+            })
+            .await
+            .unwrap();
+        let transfer = instruction(Action::SolTransfer {
+            from: WALLET.to_string(),
+            to: DESTINATION.to_string(),
+            lamports: 1,
+        });
+        let unknown = instruction(Action::Unknown);
+        let harmless = [instruction(Action::ComputeBudget), instruction(Action::Memo { text: "ref".to_string() })];
+
+        assert!(check_transaction_destinations(&store, WALLET, &[transfer.clone(), unknown.clone()]).await.is_ok());
+
+        store.enable_withdrawal_strict_mode(user.id).await.unwrap();
+        assert!(check_transaction_destinations(&store, WALLET, &harmless).await.is_ok());
+        for refused in [transfer, unknown] {
+            assert!(matches!(
+                check_transaction_destinations(&store, WALLET, &[refused]).await,
+                Err(Error::PolicyViolation(_))
+            ));
+        }
+    }
+}
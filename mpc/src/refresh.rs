@@ -0,0 +1,416 @@
+use crate::backup::AutoBackup;
+use crate::db::{encode_scalar, MpcKey, MpcStore};
+use crate::error::Error;
+use crate::tss::{self, KeyShare};
+use chrono::Utc;
+use curv::elliptic::curves::{Ed25519, Scalar};
+use solana_sdk::pubkey::Pubkey;
+use std::{collections::BTreeSet, str::FromStr, time::Duration};
+use uuid::Uuid;
+
+/// Most keys refreshed in one pass.
+const BATCH_SIZE: i64 = 100;
+/// How often the background task looks for keys due for a refresh.
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+pub struct Refreshed {
+    pub refresh_id: Uuid,
+    pub share_version: i32,
+}
+
+/// Re-randomizes every node's share of `end_user_pubkey` while the
+/// aggregated key stays the same. Each node prepares its next share and
+/// records it in its own store; only once all of them have, and the new
+/// shares are checked to still add up to the key, does each node switch over.
+/// Nodes keep their old share until every node has committed, and the new
+/// shares are then backed up to `backup`; a failed backup does not fail the
+/// refresh and is retried by `run`. `stores` is indexed by node id
+/// minus one.
+pub async fn refresh_key(
+    stores: &[&MpcStore],
+    end_user_pubkey: &str,
+    backup: Option<&AutoBackup>,
+) -> Result<Refreshed, Error> {
+    let mut keys = load_keys(stores, end_user_pubkey).await?;
+    if resume(stores, &keys, backup).await? {
+        keys = load_keys(stores, end_user_pubkey).await?;
+    }
+
+    let pubkeys = keys
+        .iter()
+        .map(|key| Pubkey::from_str(&key.public_key))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::StoreError("Stored key share is corrupt".to_string()))?;
+    let aggkey = tss::key_agg(pubkeys.clone(), None)?.agg_public_key;
+    if Pubkey::new_from_array(aggkey.to_bytes(true)).to_string() != end_user_pubkey {
+        return Err(Error::StoreError("Key shares do not match the wallet".to_string()));
+    }
+
+    // Tweaks summing to zero keep the aggregated secret unchanged
+    let mut tweaks: Vec<Scalar<Ed25519>> = (1..keys.len()).map(|_| tss::refresh_randomness()).collect();
+    let sum = tweaks.iter().fold(Scalar::zero(), |sum, tweak| sum + tweak);
+    tweaks.push(Scalar::zero() - sum);
+
+    let next = keys
+        .iter()
+        .zip(&tweaks)
+        .map(|(key, tweak)| tss::refresh_share(&key.share()?, pubkeys.clone(), tweak))
+        .collect::<Result<Vec<_>, _>>()?;
+    let commitments: Vec<_> = next.iter().map(KeyShare::commitment).collect();
+    tss::check_share_commitments(pubkeys, &commitments)?;
+
+    let refresh_id = Uuid::new_v4();
+    for ((store, key), (share, commitment)) in stores.iter().zip(&keys).zip(next.iter().zip(&commitments)) {
+        let offset = share.offset.as_ref().map(encode_scalar).unwrap_or_default();
+        let prepared = store
+            .prepare_key_refresh(
+                refresh_id,
+                end_user_pubkey,
+                key.node_id,
+                key.share_version,
+                &bs58::encode(share.keypair.to_bytes()).into_string(),
+                &offset,
+                &hex::encode(&*commitment.to_bytes(true)),
+            )
+            .await;
+        if let Err(e) = prepared {
+            for (store, key) in stores.iter().zip(&keys) {
+                store.abort_key_refresh(refresh_id, end_user_pubkey, key.node_id).await?;
+            }
+            return Err(e);
+        }
+    }
+
+    // Node 1's commit decides the refresh. If a concurrent attempt settled
+    // it first by aborting, back out; past it the refresh only moves
+    // forward. Signing is refused until every node has committed, and a
+    // node that fails to is caught up by `run`.
+    if !stores[0].commit_key_refresh(refresh_id, end_user_pubkey, keys[0].node_id).await? {
+        for (store, key) in stores.iter().zip(&keys).skip(1) {
+            store.abort_key_refresh(refresh_id, end_user_pubkey, key.node_id).await?;
+        }
+        return Err(Error::RefreshInProgress);
+    }
+    for (store, key) in stores.iter().zip(&keys).skip(1) {
+        store.commit_key_refresh(refresh_id, end_user_pubkey, key.node_id).await?;
+    }
+    finish(stores, &keys, refresh_id, backup).await?;
+
+    log::info!("Refreshed key shares of {} ({})", end_user_pubkey, refresh_id);
+    Ok(Refreshed {
+        refresh_id,
+        share_version: keys[0].share_version + 1,
+    })
+}
+
+/// Refreshes keys whose shares are older than `max_age`, checking every few
+/// minutes, and first settles refreshes an earlier attempt left pending.
+pub async fn run(mpc_store_1: MpcStore, mpc_store_2: MpcStore, max_age: chrono::Duration, backup: Option<AutoBackup>) {
+    let stores = [&mpc_store_1, &mpc_store_2];
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Some(backup) = &backup {
+            back_up_pending(&stores, backup).await;
+        }
+
+        let mut pending = BTreeSet::new();
+        for (store, node_id) in stores.iter().zip(1..) {
+            match store.list_pending_refreshes(node_id).await {
+                Ok(keys) => pending.extend(keys),
+                Err(e) => log::error!("Failed to list pending share refreshes of node {}: {}", node_id, e),
+            }
+        }
+        for end_user_pubkey in pending {
+            let settled = match load_keys(&stores, &end_user_pubkey).await {
+                Ok(keys) => resume(&stores, &keys, backup.as_ref()).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = settled {
+                log::error!("Settling the share refresh of {} failed: {}", end_user_pubkey, e);
+            }
+        }
+
+        let due = match mpc_store_1.list_keys_due_for_refresh(1, Utc::now() - max_age, BATCH_SIZE).await {
+            Ok(due) => due,
+            Err(e) => {
+                log::error!("Failed to list keys due for a share refresh: {}", e);
+                continue;
+            }
+        };
+        for end_user_pubkey in due {
+            if let Err(e) = refresh_key(&stores, &end_user_pubkey, backup.as_ref()).await {
+                log::error!("Share refresh of {} failed: {}", end_user_pubkey, e);
+            }
+        }
+    }
+}
+
+async fn load_keys(stores: &[&MpcStore], end_user_pubkey: &str) -> Result<Vec<MpcKey>, Error> {
+    let mut keys = Vec::with_capacity(stores.len());
+    for (store, node_id) in stores.iter().zip(1..) {
+        keys.push(store.get_key(end_user_pubkey, node_id).await?);
+    }
+    Ok(keys)
+}
+
+/// Settles a refresh an earlier attempt left pending. Node 1 decides, as in
+/// `refresh_key`: aborting it there is atomic with its commit, so either the
+/// refresh is dropped everywhere or, if node 1 already committed, the others
+/// follow and then drop their old shares. Returns whether anything was
+/// pending.
+async fn resume(stores: &[&MpcStore], keys: &[MpcKey], backup: Option<&AutoBackup>) -> Result<bool, Error> {
+    let Some(refresh_id) = keys.iter().find_map(|key| key.refresh_id) else {
+        return Ok(false);
+    };
+
+    let (first, key) = (stores[0], &keys[0]);
+    let committed = !first.abort_key_refresh(refresh_id, &key.end_user_pubkey, key.node_id).await?
+        && first.key_refresh_status(refresh_id, key.node_id).await?.as_deref() == Some("committed");
+    for (store, key) in stores.iter().zip(keys).skip(1) {
+        if committed {
+            store.commit_key_refresh(refresh_id, &key.end_user_pubkey, key.node_id).await?;
+        } else {
+            store.abort_key_refresh(refresh_id, &key.end_user_pubkey, key.node_id).await?;
+        }
+    }
+    if committed {
+        finish(stores, keys, refresh_id, backup).await?;
+    }
+    log::warn!(
+        "Settled pending share refresh {} of {} by {}",
+        refresh_id,
+        keys[0].end_user_pubkey,
+        if committed { "committing" } else { "aborting" }
+    );
+    Ok(true)
+}
+
+/// Lets every node drop its old share once all of them have committed, then
+/// backs up the new ones.
+async fn finish(
+    stores: &[&MpcStore],
+    keys: &[MpcKey],
+    refresh_id: Uuid,
+    backup: Option<&AutoBackup>,
+) -> Result<(), Error> {
+    for (store, key) in stores.iter().zip(keys) {
+        store.finish_key_refresh(refresh_id, &key.end_user_pubkey, key.node_id).await?;
+    }
+    // The refresh stands either way; the marker it left gets the backup retried
+    if let Some(backup) = backup {
+        if let Err(e) = back_up(stores, &keys[0].end_user_pubkey, backup).await {
+            log::error!("Backing up the refreshed shares of {} failed: {}", keys[0].end_user_pubkey, e);
+        }
+    }
+    Ok(())
+}
+
+/// Backs up the current shares of `end_user_pubkey` and clears the
+/// backup-pending marker their refresh left.
+async fn back_up(stores: &[&MpcStore], end_user_pubkey: &str, backup: &AutoBackup) -> Result<(), Error> {
+    let keys = load_keys(stores, end_user_pubkey).await?;
+    backup.export_wallet(stores, end_user_pubkey).await?;
+    for (store, key) in stores.iter().zip(&keys) {
+        store.clear_backup_pending(end_user_pubkey, key.node_id, key.share_version).await?;
+    }
+    Ok(())
+}
+
+/// Retries the backups of refreshes that finished without one.
+async fn back_up_pending(stores: &[&MpcStore], backup: &AutoBackup) {
+    let mut pending = BTreeSet::new();
+    for (store, node_id) in stores.iter().zip(1..) {
+        match store.list_pending_backups(node_id).await {
+            Ok(keys) => pending.extend(keys),
+            Err(e) => log::error!("Failed to list pending backups of node {}: {}", node_id, e),
+        }
+    }
+    for end_user_pubkey in pending {
+        if let Err(e) = back_up(stores, &end_user_pubkey, backup).await {
+            log::error!("Backing up the refreshed shares of {} failed: {}", end_user_pubkey, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::signature::{Keypair, Signer};
+    use sqlx::PgPool;
+
+    /// Stores both nodes' shares of a fresh wallet and returns its address.
+    async fn new_wallet(store: &MpcStore) -> String {
+        let keypairs = [Keypair::new(), Keypair::new()];
+        let pubkeys: Vec<_> = keypairs.iter().map(|k| k.pubkey()).collect();
+        let aggkey = tss::key_agg(pubkeys, None).unwrap().agg_public_key;
+        let end_user_pubkey = Pubkey::new_from_array(aggkey.to_bytes(true)).to_string();
+        for (keypair, node_id) in keypairs.iter().zip(1..) {
+            let key = MpcKey {
+                end_user_pubkey: end_user_pubkey.clone(),
+                node_id,
+                public_key: keypair.pubkey().to_string(),
+                private_key: bs58::encode(keypair.to_bytes()).into_string(),
+                share_offset: None,
+                share_version: 0,
+                refresh_id: None,
+            };
+            store.store_key(&key).await.unwrap();
+        }
+        end_user_pubkey
+    }
+
+    /// Prepares a refresh on both nodes without committing it anywhere.
+    async fn prepare(stores: &[&MpcStore], keys: &[MpcKey]) -> Uuid {
+        let pubkeys: Vec<_> = keys.iter().map(|key| Pubkey::from_str(&key.public_key).unwrap()).collect();
+        let rho = tss::refresh_randomness();
+        let tweaks = [rho.clone(), Scalar::zero() - rho];
+        let refresh_id = Uuid::new_v4();
+        for ((store, key), tweak) in stores.iter().zip(keys).zip(&tweaks) {
+            let next = tss::refresh_share(&key.share().unwrap(), pubkeys.clone(), tweak).unwrap();
+            store
+                .prepare_key_refresh(
+                    refresh_id,
+                    &key.end_user_pubkey,
+                    key.node_id,
+                    key.share_version,
+                    &bs58::encode(next.keypair.to_bytes()).into_string(),
+                    &next.offset.as_ref().map(encode_scalar).unwrap_or_default(),
+                    &hex::encode(&*next.commitment().to_bytes(true)),
+                )
+                .await
+                .unwrap();
+        }
+        refresh_id
+    }
+
+    fn assert_adds_up(keys: &[MpcKey]) {
+        let pubkeys: Vec<_> = keys.iter().map(|key| Pubkey::from_str(&key.public_key).unwrap()).collect();
+        let commitments: Vec<_> = keys.iter().map(|key| key.share().unwrap().commitment()).collect();
+        tss::check_share_commitments(pubkeys, &commitments).unwrap();
+    }
+
+    async fn previous_shares(pool: &PgPool, end_user_pubkey: &str) -> Vec<Option<String>> {
+        sqlx::query_scalar("SELECT prev_private_key FROM mpc_keys WHERE end_user_pubkey = $1 ORDER BY node_id")
+            .bind(end_user_pubkey)
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = "migrations")]
+    async fn refresh_keeps_the_wallet(pool: PgPool) {
+        let store = MpcStore::new(pool.clone());
+        let stores = [&store, &store];
+        let end_user_pubkey = new_wallet(&store).await;
+        let before = load_keys(&stores, &end_user_pubkey).await.unwrap();
+
+        let refreshed = refresh_key(&stores, &end_user_pubkey, None).await.unwrap();
+        assert_eq!(refreshed.share_version, 1);
+        let after = load_keys(&stores, &end_user_pubkey).await.unwrap();
+        assert_adds_up(&after);
+        for (old, new) in before.iter().zip(&after) {
+            assert_ne!(old.private_key, new.private_key);
+            assert_eq!(new.share_version, 1);
+            new.check_settled().unwrap();
+            let status = store.key_refresh_status(refreshed.refresh_id, new.node_id).await.unwrap();
+            assert_eq!(status.as_deref(), Some("committed"));
+        }
+        // Old shares are dropped once both nodes have committed
+        assert_eq!(previous_shares(&pool, &end_user_pubkey).await, vec![None, None]);
+    }
+
+    #[sqlx::test(migrations = "migrations")]
+    async fn resume_completes_a_refresh_node_1_committed(pool: PgPool) {
+        let store = MpcStore::new(pool.clone());
+        let stores = [&store, &store];
+        let end_user_pubkey = new_wallet(&store).await;
+        let before = load_keys(&stores, &end_user_pubkey).await.unwrap();
+        let refresh_id = prepare(&stores, &before).await;
+        assert!(store.commit_key_refresh(refresh_id, &end_user_pubkey, 1).await.unwrap());
+
+        // Node 1 keeps its old share and neither node signs until node 2 commits
+        let pending = load_keys(&stores, &end_user_pubkey).await.unwrap();
+        assert_eq!(previous_shares(&pool, &end_user_pubkey).await[0].as_ref(), Some(&before[0].private_key));
+        assert!(pending.iter().all(|key| matches!(key.check_settled(), Err(Error::RefreshInProgress))));
+        assert!(!store.abort_key_refresh(refresh_id, &end_user_pubkey, 1).await.unwrap());
+        assert_eq!(store.list_pending_refreshes(1).await.unwrap(), vec![end_user_pubkey.clone()]);
+
+        assert!(resume(&stores, &pending, None).await.unwrap());
+        let after = load_keys(&stores, &end_user_pubkey).await.unwrap();
+        assert_adds_up(&after);
+        assert!(after.iter().all(|key| key.share_version == 1 && key.check_settled().is_ok()));
+        assert_eq!(previous_shares(&pool, &end_user_pubkey).await, vec![None, None]);
+        assert!(store.list_pending_refreshes(1).await.unwrap().is_empty());
+        assert!(store.list_pending_refreshes(2).await.unwrap().is_empty());
+
+        // Nothing is left to settle
+        assert!(!resume(&stores, &after, None).await.unwrap());
+    }
+
+    #[sqlx::test(migrations = "migrations")]
+    async fn resume_aborts_a_refresh_node_1_did_not_commit(pool: PgPool) {
+        let store = MpcStore::new(pool.clone());
+        let stores = [&store, &store];
+        let end_user_pubkey = new_wallet(&store).await;
+        let before = load_keys(&stores, &end_user_pubkey).await.unwrap();
+        let refresh_id = prepare(&stores, &before).await;
+
+        let pending = load_keys(&stores, &end_user_pubkey).await.unwrap();
+        assert!(resume(&stores, &pending, None).await.unwrap());
+        let after = load_keys(&stores, &end_user_pubkey).await.unwrap();
+        for (old, new) in before.iter().zip(&after) {
+            assert_eq!(old.private_key, new.private_key);
+            assert_eq!(new.share_version, 0);
+            new.check_settled().unwrap();
+            let status = store.key_refresh_status(refresh_id, new.node_id).await.unwrap();
+            assert_eq!(status.as_deref(), Some("aborted"));
+        }
+        // Too late to commit once node 1 aborted
+        assert!(!store.commit_key_refresh(refresh_id, &end_user_pubkey, 1).await.unwrap());
+    }
+
+    #[sqlx::test(migrations = "migrations")]
+    async fn refresh_settles_a_pending_refresh_first(pool: PgPool) {
+        let store = MpcStore::new(pool);
+        let stores = [&store, &store];
+        let end_user_pubkey = new_wallet(&store).await;
+        let before = load_keys(&stores, &end_user_pubkey).await.unwrap();
+        let refresh_id = prepare(&stores, &before).await;
+        assert!(store.commit_key_refresh(refresh_id, &end_user_pubkey, 1).await.unwrap());
+
+        let refreshed = refresh_key(&stores, &end_user_pubkey, None).await.unwrap();
+        assert_eq!(refreshed.share_version, 2);
+        let after = load_keys(&stores, &end_user_pubkey).await.unwrap();
+        assert_adds_up(&after);
+        assert!(after.iter().all(|key| key.share_version == 2 && key.check_settled().is_ok()));
+        assert_eq!(store.key_refresh_status(refresh_id, 2).await.unwrap().as_deref(), Some("committed"));
+    }
+
+    #[sqlx::test(migrations = "migrations")]
+    async fn failed_backup_is_retried(pool: PgPool) {
+        let store = MpcStore::new(pool);
+        let stores = [&store, &store];
+        let end_user_pubkey = new_wallet(&store).await;
+        let dir = std::env::temp_dir().join(format!("mpc-backup-{}", Uuid::new_v4()));
+        let backup = AutoBackup {
+            dir: dir.clone(),
+            recovery_keys: (0..2)
+                .map(|_| crate::backup::parse_recovery_key(&crate::backup::generate_recovery_key().1).unwrap())
+                .collect(),
+        };
+
+        // The backup directory is missing, but the refresh still goes through
+        let refreshed = refresh_key(&stores, &end_user_pubkey, Some(&backup)).await.unwrap();
+        assert_eq!(refreshed.share_version, 1);
+        assert_eq!(store.list_pending_backups(1).await.unwrap(), vec![end_user_pubkey.clone()]);
+        assert_eq!(store.list_pending_backups(2).await.unwrap(), vec![end_user_pubkey.clone()]);
+
+        std::fs::create_dir(&dir).unwrap();
+        back_up_pending(&stores, &backup).await;
+        assert!(store.list_pending_backups(1).await.unwrap().is_empty());
+        assert!(store.list_pending_backups(2).await.unwrap().is_empty());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
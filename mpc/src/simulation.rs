@@ -0,0 +1,195 @@
+use crate::error::Error;
+use crate::inspect;
+use serde::Serialize;
+use solana_account_decoder_client_types::UiAccountEncoding;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::rpc_config::{RpcSimulateTransactionAccountsConfig, RpcSimulateTransactionConfig};
+use solana_sdk::{account::Account, message::VersionedMessage, pubkey::Pubkey, transaction::VersionedTransaction};
+use std::collections::BTreeMap;
+use store::solana::SOL_MINT;
+
+const TOKEN_PROGRAM_IDS: [&str; 2] = [
+    "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
+    "TokenzQdBNbLqP5VEhdkAS6EPFLC1PEnBqCXEpPxuEb",
+];
+/// Size of the base token account layout shared by both token programs.
+const TOKEN_ACCOUNT_LEN: usize = 165;
+
+#[derive(Debug, Serialize)]
+pub struct BalanceChange {
+    pub mint: String,
+    pub before: u64,
+    pub after: u64,
+    pub change: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SimulationReport {
+    /// Network fee in lamports, already included in the SOL change.
+    pub fee: u64,
+    pub balance_changes: Vec<BalanceChange>,
+    pub units_consumed: Option<u64>,
+    pub logs: Vec<String>,
+}
+
+/// Mint and amount of a token account held by `owner`, read from the layout
+/// common to the token programs: mint, owner, then the amount.
+fn token_balance(account: &Account, owner: &Pubkey) -> Option<(String, u64)> {
+    if !TOKEN_PROGRAM_IDS.contains(&account.owner.to_string().as_str()) || account.data.len() < TOKEN_ACCOUNT_LEN {
+        return None;
+    }
+    if account.data[32..64] != owner.to_bytes() {
+        return None;
+    }
+    let mint = Pubkey::try_from(&account.data[0..32]).ok()?;
+    let amount = u64::from_le_bytes(account.data[64..72].try_into().ok()?);
+    Some((mint.to_string(), amount))
+}
+
+/// Network fee of `message` in lamports. Nodes only price messages carrying a
+/// blockhash they know, which neither a transfer built for simulation nor a
+/// durable nonce transaction has, so it is priced at the latest blockhash;
+/// the fee does not depend on which one.
+pub async fn network_fee(rpc_client: &RpcClient, message: &VersionedMessage) -> Result<u64, Error> {
+    let mut message = message.clone();
+    message.set_recent_blockhash(rpc_client.get_latest_blockhash().await?);
+    Ok(rpc_client.get_fee_for_message(&message).await?)
+}
+
+/// Runs `simulateTransaction` without signatures and works out how the
+/// owner's SOL and token balances would change. A transaction that fails in
+/// simulation is reported as `SimulationFailed`.
+///
+/// Transactions loading accounts from lookup tables are refused, since the
+/// balances of those accounts would go unreported.
+pub async fn simulate(
+    rpc_client: &RpcClient,
+    transaction: &VersionedTransaction,
+    owner: &Pubkey,
+) -> Result<SimulationReport, Error> {
+    if inspect::uses_lookup_tables(&transaction.message) {
+        return Err(Error::InvalidRequest("Transactions using address lookup tables are not supported".to_string()));
+    }
+    let keys = transaction.message.static_account_keys().to_vec();
+    let before = rpc_client.get_multiple_accounts(&keys).await?;
+
+    let config = RpcSimulateTransactionConfig {
+        sig_verify: false,
+        replace_recent_blockhash: true,
+        accounts: Some(RpcSimulateTransactionAccountsConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            addresses: keys.iter().map(Pubkey::to_string).collect(),
+        }),
+        ..Default::default()
+    };
+    let result = rpc_client.simulate_transaction_with_config(transaction, config).await?.value;
+    let logs = result.logs.unwrap_or_default();
+    if let Some(err) = result.err {
+        let last_log = logs.last().cloned().unwrap_or_default();
+        return Err(Error::SimulationFailed(format!("{:?} {}", err, last_log).trim_end().to_string()));
+    }
+    let after: Vec<Option<Account>> = result
+        .accounts
+        .unwrap_or_default()
+        .into_iter()
+        .map(|account| account.and_then(|account| account.decode()))
+        .collect();
+
+    let fee = network_fee(rpc_client, &transaction.message).await?;
+
+    let mut balances: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    for (i, key) in keys.iter().enumerate() {
+        let pre = before.get(i).and_then(Option::as_ref);
+        let post = after.get(i).and_then(Option::as_ref);
+
+        if key == owner {
+            let entry = balances.entry(SOL_MINT.to_string()).or_default();
+            entry.0 += pre.map_or(0, |a| a.lamports);
+            entry.1 += post.map_or(0, |a| a.lamports);
+            continue;
+        }
+
+        // Token accounts created or closed by the transaction only exist on
+        // one side.
+        let pre_token = pre.and_then(|a| token_balance(a, owner));
+        let post_token = post.and_then(|a| token_balance(a, owner));
+        if let Some(mint) = pre_token.as_ref().or(post_token.as_ref()).map(|(mint, _)| mint.clone()) {
+            let entry = balances.entry(mint).or_default();
+            entry.0 += pre_token.map_or(0, |(_, amount)| amount);
+            entry.1 += post_token.map_or(0, |(_, amount)| amount);
+        }
+    }
+
+    let balance_changes = balances
+        .into_iter()
+        .filter(|(_, (before, after))| before != after)
+        .map(|(mint, (before, after))| BalanceChange {
+            mint,
+            before,
+            after,
+            change: after as i64 - before as i64,
+        })
+        .collect();
+
+    Ok(SimulationReport {
+        fee,
+        balance_changes,
+        units_consumed: result.units_consumed,
+        logs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use solana_client::rpc_request::RpcRequest;
+    use solana_sdk::{hash::Hash, message::Message, system_instruction};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    fn token_account(program: &str, mint: &Pubkey, owner: &Pubkey, amount: u64) -> Account {
+        let mut data = vec![0u8; TOKEN_ACCOUNT_LEN];
+        data[0..32].copy_from_slice(&mint.to_bytes());
+        data[32..64].copy_from_slice(&owner.to_bytes());
+        data[64..72].copy_from_slice(&amount.to_le_bytes());
+        Account {
+            lamports: 2_039_280,
+            data,
+            owner: Pubkey::from_str(program).unwrap(),
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    #[test]
+    fn reads_token_accounts_of_owner() {
+        let mint = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        for program in TOKEN_PROGRAM_IDS {
+            let account = token_account(program, &mint, &owner, 42);
+            assert_eq!(token_balance(&account, &owner), Some((mint.to_string(), 42)));
+            assert_eq!(token_balance(&account, &Pubkey::new_unique()), None);
+        }
+
+        let mut not_token = token_account(TOKEN_PROGRAM_IDS[0], &mint, &owner, 42);
+        not_token.owner = Pubkey::new_unique();
+        assert_eq!(token_balance(&not_token, &owner), None);
+    }
+
+    #[tokio::test]
+    async fn prices_a_transfer_built_without_a_blockhash() {
+        let from = Pubkey::new_unique();
+        let instructions = [
+            system_instruction::transfer(&from, &Pubkey::new_unique(), 1_000),
+            spl_memo::build_memo(b"ref", &[]),
+        ];
+        let message = VersionedMessage::Legacy(Message::new_with_blockhash(&instructions, Some(&from), &Hash::default()));
+        let mut mocks = HashMap::new();
+        mocks.insert(RpcRequest::GetFeeForMessage, json!({ "context": { "slot": 1 }, "value": 5000 }));
+        let rpc_client = RpcClient::new_mock_with_mocks("succeeds".to_string(), mocks);
+
+        assert_eq!(network_fee(&rpc_client, &message).await.unwrap(), 5000);
+    }
+}
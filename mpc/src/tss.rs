@@ -1,13 +1,17 @@
 #![allow(non_snake_case)]
 
+use curv::arithmetic::Converter;
 use curv::elliptic::curves::{Ed25519, Point, Scalar};
+use curv::BigInt;
 use multi_party_eddsa::protocols::musig2::{self, PrivatePartialNonces, PublicPartialNonces};
 use multi_party_eddsa::protocols::ExpandedKeyPair;
 use solana_sdk::signature::{Keypair, Signature, Signer, SignerError};
-use solana_sdk::{hash::Hash, pubkey::Pubkey, transaction::Transaction, message::Message, system_instruction};
+use solana_sdk::{hash::Hash, pubkey::Pubkey, transaction::{Transaction, VersionedTransaction}, message::Message, system_instruction};
+use sha2::{Digest, Sha512};
 
 use crate::serialization::{AggMessage1, Error as DeserializationError, PartialSignature, SecretAggStepOne};
 use crate::error::Error;
+use crate::inspect;
 
 /// Create the aggregate public key, pass key=None if you don't care about the coefficient
 pub fn key_agg(keys: Vec<Pubkey>, key: Option<Pubkey>) -> Result<musig2::PublicKeyAgg, Error> {
@@ -22,6 +26,46 @@ pub fn key_agg(keys: Vec<Pubkey>, key: Option<Pubkey>) -> Result<musig2::PublicK
     musig2::PublicKeyAgg::key_aggregation_n(keys, &key).ok_or(Error::KeyPairIsNotInKeys)
 }
 
+/// A node's signing share. A share fresh from key generation is just its
+/// keypair. After a refresh it is the keypair's scalar plus `offset`, and
+/// `public_key` stays the key the share was generated with, so the MuSig2
+/// coefficients and the aggregated key never change.
+pub struct KeyShare {
+    pub keypair: Keypair,
+    pub public_key: Pubkey,
+    pub offset: Option<Scalar<Ed25519>>,
+}
+
+impl KeyShare {
+    /// The secret scalar this share signs with.
+    fn scalar(&self) -> Scalar<Ed25519> {
+        let hash = Sha512::digest(self.keypair.secret().to_bytes());
+        let mut expanded = [0u8; 32];
+        expanded.copy_from_slice(&hash[..32]);
+        expanded[0] &= 248;
+        expanded[31] &= 127;
+        expanded[31] |= 64;
+        let scalar = scalar_from_le_bytes(&expanded);
+        match &self.offset {
+            Some(offset) => scalar + offset,
+            None => scalar,
+        }
+    }
+
+    /// Public commitment to the share, `x * G`. Nodes exchange these to
+    /// check a refresh without revealing their shares.
+    pub fn commitment(&self) -> Point<Ed25519> {
+        Point::generator() * self.scalar()
+    }
+}
+
+impl From<Keypair> for KeyShare {
+    fn from(keypair: Keypair) -> Self {
+        let public_key = keypair.pubkey();
+        KeyShare { keypair, public_key, offset: None }
+    }
+}
+
 /// Generate Message1 which contains nonce, public nonce, and commitment to nonces
 pub fn step_one(keypair: Keypair) -> (AggMessage1, SecretAggStepOne) {
     let extended_kepair = ExpandedKeyPair::create_from_private_key(keypair.secret().to_bytes());
@@ -36,23 +80,25 @@ pub fn step_one(keypair: Keypair) -> (AggMessage1, SecretAggStepOne) {
 
 #[allow(clippy::too_many_arguments)]
 pub fn step_two(
-    keypair: Keypair,
+    share: impl Into<KeyShare>,
     message_to_sign: &[u8],
     keys: Vec<Pubkey>,
     first_messages: Vec<AggMessage1>,
     secret_state: SecretAggStepOne,
 ) -> Result<PartialSignature, Error> {
+    let share = share.into();
     let other_nonces: Vec<_> = first_messages.into_iter().map(|msg1| msg1.public_nonces.R).collect();
 
-    // Generate the aggregate key together with the coefficient of the current keypair
-    let aggkey = key_agg(keys, Some(keypair.pubkey()))?;
-    let extended_kepair = ExpandedKeyPair::create_from_private_key(keypair.secret().to_bytes());
+    // Generate the aggregate key together with the coefficient of the current share
+    let aggkey = key_agg(keys, Some(share.public_key))?;
+    let extended_kepair = ExpandedKeyPair::create_from_private_key(share.keypair.secret().to_bytes());
 
     let signer = PartialSigner {
         signer_private_nonce: secret_state.private_nonces,
         signer_public_nonce: secret_state.public_nonces,
         other_nonces,
         extended_kepair,
+        share_offset: share.offset,
         aggregated_pubkey: aggkey,
     };
 
@@ -65,9 +111,99 @@ pub fn sign_and_broadcast_transaction(
     keys: Vec<Pubkey>,
     signatures: Vec<PartialSignature>,
 ) -> Result<Transaction, Error> {
+    key_agg(keys, None)?;
+
+    // Insert the signature to the right place
+    transaction.signatures[0] = aggregate_signatures(signatures)?;
+
+    // Make sure the resulting transaction is actually valid.
+    if transaction.verify().is_err() {
+        return Err(Error::InvalidSignature);
+    }
+    Ok(transaction)
+}
+
+/// Puts the aggregated key's signature into a transaction built elsewhere.
+/// Other required signers keep whatever signature the transaction came with,
+/// so it is only fully valid once they have signed too.
+pub fn cosign_transaction(
+    mut transaction: VersionedTransaction,
+    keys: Vec<Pubkey>,
+    signatures: Vec<PartialSignature>,
+) -> Result<VersionedTransaction, Error> {
     let aggkey = key_agg(keys, None)?;
     let aggpubkey = Pubkey::new(&*aggkey.agg_public_key.to_bytes(true));
+    let index = inspect::signer_index(&transaction.message, &aggpubkey).ok_or(Error::KeyPairIsNotInKeys)?;
+
+    let sig = aggregate_signatures(signatures)?;
+    if !sig.verify(aggpubkey.as_ref(), &transaction.message.serialize()) {
+        return Err(Error::InvalidSignature);
+    }
+    let required = transaction.message.header().num_required_signatures as usize;
+    transaction.signatures.resize(required, Signature::default());
+    transaction.signatures[index] = sig;
+    Ok(transaction)
+}
+
+/// Adds up the partial signatures into one signature by the aggregated key
+/// over a plain message, checking that it verifies.
+pub fn sign_message(message: &[u8], keys: Vec<Pubkey>, signatures: Vec<PartialSignature>) -> Result<Signature, Error> {
+    let aggkey = key_agg(keys, None)?;
+    let aggpubkey = Pubkey::new(&*aggkey.agg_public_key.to_bytes(true));
+    let sig = aggregate_signatures(signatures)?;
+    if !sig.verify(aggpubkey.as_ref(), message) {
+        return Err(Error::InvalidSignature);
+    }
+    Ok(sig)
+}
+
+/// Shared randomness for one refresh round.
+pub fn refresh_randomness() -> Scalar<Ed25519> {
+    Scalar::random()
+}
+
+/// The share that replaces `share` in a refresh. Each node adds `tweak`
+/// divided by its own MuSig2 coefficient, and the tweaks of all nodes sum to
+/// zero, so the coefficient-weighted sum of the shares, the aggregated
+/// secret, stays the same. The new share gets a fresh keypair so nothing of
+/// the old one is kept.
+pub fn refresh_share(share: &KeyShare, keys: Vec<Pubkey>, tweak: &Scalar<Ed25519>) -> Result<KeyShare, Error> {
+    let coefficient = key_agg(keys, Some(share.public_key))?.musig_coefficient;
+    let inverse = coefficient.invert().ok_or(Error::KeyPairIsNotInKeys)?;
+    let target = share.scalar() + tweak * inverse;
+
+    let keypair = Keypair::new();
+    let fresh = KeyShare::from(keypair.insecure_clone()).scalar();
+    Ok(KeyShare { keypair, public_key: share.public_key, offset: Some(target - fresh) })
+}
+
+/// Checks that shares committed to by `commitments`, in the order of `keys`,
+/// still add up to the aggregated key of `keys`.
+pub fn check_share_commitments(keys: Vec<Pubkey>, commitments: &[Point<Ed25519>]) -> Result<(), Error> {
+    if keys.len() != commitments.len() {
+        return Err(Error::MismatchMessages);
+    }
+    let aggkey = key_agg(keys.clone(), None)?.agg_public_key;
+    let mut sum = Point::zero();
+    for (key, commitment) in keys.iter().zip(commitments) {
+        let coefficient = key_agg(keys.clone(), Some(*key))?.musig_coefficient;
+        sum = sum + commitment * coefficient;
+    }
+    if sum != aggkey {
+        return Err(Error::InvalidSignature);
+    }
+    Ok(())
+}
+
+/// Ed25519 hashes and signature scalars are little endian, curv's `BigInt`
+/// bytes are big endian.
+fn scalar_from_le_bytes(bytes: &[u8]) -> Scalar<Ed25519> {
+    let mut big_endian = bytes.to_vec();
+    big_endian.reverse();
+    Scalar::from_bigint(&BigInt::from_bytes(&big_endian))
+}
 
+fn aggregate_signatures(signatures: Vec<PartialSignature>) -> Result<Signature, Error> {
     // Make sure all the `R`s are the same
     if !signatures[1..].iter().map(|s| &s.0.as_ref()[..32]).all(|s| s == &signatures[0].0.as_ref()[..32]) {
         return Err(Error::MismatchMessages);
@@ -99,16 +235,7 @@ pub fn sign_and_broadcast_transaction(
     let mut sig_bytes = [0u8; 64];
     sig_bytes[..32].copy_from_slice(&*full_sig.R.to_bytes(true));
     sig_bytes[32..].copy_from_slice(&full_sig.s.to_bytes());
-    let sig = Signature::new(&sig_bytes);
-
-    // Insert the signature to the right place
-    transaction.signatures[0] = sig;
-
-    // Make sure the resulting transaction is actually valid.
-    if transaction.verify().is_err() {
-        return Err(Error::InvalidSignature);
-    }
-    Ok(transaction)
+    Ok(Signature::new(&sig_bytes))
 }
 
 struct PartialSigner {
@@ -116,6 +243,8 @@ struct PartialSigner {
     signer_public_nonce: PublicPartialNonces,
     other_nonces: Vec<[Point<Ed25519>; 2]>,
     extended_kepair: ExpandedKeyPair,
+    /// Added to the keypair's scalar for a refreshed share.
+    share_offset: Option<Scalar<Ed25519>>,
     aggregated_pubkey: musig2::PublicKeyAgg,
 }
 
@@ -133,9 +262,19 @@ impl Signer for PartialSigner {
             &self.extended_kepair,
             message,
         );
+        let R = sig.R.to_bytes(true);
+        let mut partial_s = sig.my_partial_s;
+        if let Some(offset) = &self.share_offset {
+            // s_i = r_i + c * a_i * x_i is linear in x_i, so the offset's part
+            // can be added after signing with the keypair's scalar.
+            let aggpubkey = self.aggregated_pubkey.agg_public_key.to_bytes(true);
+            let challenge = Sha512::new().chain_update(&*R).chain_update(&*aggpubkey).chain_update(message).finalize();
+            let challenge = scalar_from_le_bytes(&challenge);
+            partial_s = partial_s + challenge * &self.aggregated_pubkey.musig_coefficient * offset;
+        }
         let mut sig_bytes = [0u8; 64];
-        sig_bytes[..32].copy_from_slice(&*sig.R.to_bytes(true));
-        sig_bytes[32..].copy_from_slice(&sig.my_partial_s.to_bytes());
+        sig_bytes[..32].copy_from_slice(&*R);
+        sig_bytes[32..].copy_from_slice(&partial_s.to_bytes());
         Ok(Signature::new(&sig_bytes))
     }
 
@@ -148,7 +287,11 @@ impl Signer for PartialSigner {
 mod tests {
     use crate::native_token::lamports_to_sol;
     use crate::serialization::Serialize;
-    use crate::tss::{key_agg, sign_and_broadcast_transaction, step_one, step_two};
+    use crate::tss::{
+        check_share_commitments, key_agg, refresh_randomness, refresh_share, sign_and_broadcast_transaction, sign_message,
+        step_one, step_two, KeyShare,
+    };
+    use curv::elliptic::curves::Scalar;
     use solana_sdk::pubkey::Pubkey;
     use solana_sdk::signature::{Keypair, Signer};
     use solana_streamer::socket::SocketAddrSpace;
@@ -214,4 +357,72 @@ mod tests {
         // Wait for confirmation
         rpc_client.confirm_transaction_with_spinner(&sig, &recent_block_hash, rpc_client.commitment()).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn refreshed_shares_sign_for_the_same_key() {
+        let mut rng = rand07::thread_rng();
+        let keys: Vec<_> = (0..2).map(|_| Keypair::generate(&mut rng)).collect();
+        let pubkeys: Vec<_> = keys.iter().map(|k| k.pubkey()).collect();
+        let aggpubkey = Pubkey::new(&*key_agg(pubkeys.clone(), None).unwrap().agg_public_key.to_bytes(true));
+
+        let rho = refresh_randomness();
+        let tweaks = [rho.clone(), Scalar::zero() - rho];
+        let shares: Vec<KeyShare> = keys
+            .iter()
+            .zip(&tweaks)
+            .map(|(key, tweak)| refresh_share(&KeyShare::from(clone_keypair(key)), pubkeys.clone(), tweak).unwrap())
+            .collect();
+        let commitments: Vec<_> = shares.iter().map(KeyShare::commitment).collect();
+        check_share_commitments(pubkeys.clone(), &commitments).unwrap();
+
+        // A stale share mixed with a refreshed one no longer adds up
+        let stale = [KeyShare::from(clone_keypair(&keys[0])).commitment(), commitments[1].clone()];
+        assert!(check_share_commitments(pubkeys.clone(), &stale).is_err());
+
+        let message = b"refresh";
+        let (first_msgs, first_secrets): (Vec<_>, Vec<_>) =
+            shares.iter().map(|share| step_one(clone_keypair(&share.keypair))).unzip();
+        let partial_sigs: Vec<_> = shares
+            .into_iter()
+            .zip(first_secrets)
+            .enumerate()
+            .map(|(i, (share, secret))| {
+                let mut first_msgs: Vec<_> = first_msgs.iter().map(clone_serialize).collect();
+                first_msgs.remove(i);
+                step_two(share, message, pubkeys.clone(), first_msgs, secret).unwrap()
+            })
+            .collect();
+        let sig = sign_message(message, pubkeys, partial_sigs).unwrap();
+        assert!(sig.verify(aggpubkey.as_ref(), message));
+    }
+
+    #[test]
+    fn presigned_nonces_sign_after_being_stored() {
+        let mut rng = rand07::thread_rng();
+        let keys: Vec<_> = (0..2).map(|_| Keypair::generate(&mut rng)).collect();
+        let pubkeys: Vec<_> = keys.iter().map(|k| k.pubkey()).collect();
+        let aggpubkey = Pubkey::new(&*key_agg(pubkeys.clone(), None).unwrap().agg_public_key.to_bytes(true));
+
+        // Generated before the message is known and kept as the store does
+        let (first_msgs, first_secrets): (Vec<_>, Vec<_>) = keys.iter().map(clone_keypair).map(step_one).unzip();
+        let stored: Vec<_> = first_secrets
+            .iter()
+            .zip(first_msgs.iter().rev())
+            .map(|(secret, peer)| (serde_json::to_vec(secret).unwrap(), serde_json::to_string(peer).unwrap()))
+            .collect();
+
+        let message = b"presigned";
+        let partial_sigs: Vec<_> = keys
+            .iter()
+            .map(clone_keypair)
+            .zip(stored)
+            .map(|(key, (secret, peer))| {
+                let secret = serde_json::from_slice(&secret).unwrap();
+                let peer = serde_json::from_str(&peer).unwrap();
+                step_two(key, message, pubkeys.clone(), vec![peer], secret).unwrap()
+            })
+            .collect();
+        let sig = sign_message(message, pubkeys, partial_sigs).unwrap();
+        assert!(sig.verify(aggpubkey.as_ref(), message));
+    }
+}
@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS transactions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    asset_id UUID NOT NULL REFERENCES assets(id),
+    signature TEXT NOT NULL,
+    direction TEXT NOT NULL CHECK (direction IN ('incoming', 'outgoing')),
+    amount BIGINT NOT NULL,
+    counterparty TEXT,
+    slot BIGINT,
+    block_time TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    kind TEXT NOT NULL DEFAULT 'transfer',
+    status TEXT NOT NULL DEFAULT 'confirmed',
+    mpc_session_id UUID,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (user_id, signature, asset_id)
+);
+
+CREATE INDEX IF NOT EXISTS transactions_user_history_idx
+    ON transactions (user_id, block_time DESC, signature DESC);
@@ -0,0 +1,13 @@
+-- Per-token-account holdings. `balances` keeps the per-user total for each
+-- mint, recomputed from these rows whenever one of them changes.
+CREATE TABLE IF NOT EXISTS token_account_balances (
+    token_account TEXT PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    asset_id UUID NOT NULL REFERENCES assets(id),
+    amount BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS token_account_balances_user_asset_idx
+    ON token_account_balances (user_id, asset_id);
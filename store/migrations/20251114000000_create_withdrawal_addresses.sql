@@ -0,0 +1,21 @@
+-- When set, /send may only target active allowlisted addresses.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS withdrawal_allowlist_strict BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Address book of withdrawal destinations. An entry is active once it has
+-- been confirmed from the emailed link and its cooling-off period is over.
+-- A NULL mint allows any asset.
+CREATE TABLE IF NOT EXISTS withdrawal_addresses (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    address TEXT NOT NULL,
+    label TEXT NOT NULL,
+    mint TEXT,
+    confirmation_token_hash TEXT UNIQUE,
+    confirmation_expires_at TIMESTAMPTZ NOT NULL,
+    confirmed_at TIMESTAMPTZ,
+    activates_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS withdrawal_addresses_user_address_mint_idx
+    ON withdrawal_addresses (user_id, address, COALESCE(mint, ''));
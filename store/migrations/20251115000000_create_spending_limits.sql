@@ -0,0 +1,31 @@
+-- Limits in base units of the mint. Rows without a user apply to everyone
+-- unless the user has a row of their own for the mint, which replaces them.
+-- A NULL limit means no limit of that kind.
+CREATE TABLE IF NOT EXISTS spending_limits (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID REFERENCES users(id) ON DELETE CASCADE,
+    mint TEXT NOT NULL,
+    per_transaction_limit BIGINT CHECK (per_transaction_limit >= 0),
+    daily_limit BIGINT CHECK (daily_limit >= 0),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS spending_limits_user_mint_idx
+    ON spending_limits (COALESCE(user_id, '00000000-0000-0000-0000-000000000000'::uuid), mint);
+
+-- Every outgoing send and swap, written before signing starts so concurrent
+-- requests count against the same allowance.
+CREATE TABLE IF NOT EXISTS spending_ledger (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    mint TEXT NOT NULL,
+    amount BIGINT NOT NULL CHECK (amount >= 0),
+    kind TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'initiated' CHECK (status IN ('initiated', 'confirmed', 'failed')),
+    signature TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS spending_ledger_user_mint_created_at_idx
+    ON spending_ledger (user_id, mint, created_at);
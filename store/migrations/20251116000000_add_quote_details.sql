@@ -0,0 +1,29 @@
+-- Typed copies of the fields swaps rely on, so they no longer have to be read
+-- out of the raw Jupiter response, plus expiry and single use.
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS input_mint TEXT;
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS output_mint TEXT;
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS in_amount BIGINT;
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS out_amount BIGINT;
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS slippage_bps INTEGER;
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS swap_mode TEXT;
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS executed_at TIMESTAMPTZ;
+
+-- Existing quotes are long stale, so they are backfilled as already expired.
+UPDATE quotes
+SET input_mint = COALESCE(quote_response->>'inputMint', ''),
+    output_mint = COALESCE(quote_response->>'outputMint', ''),
+    in_amount = COALESCE((quote_response->>'inAmount')::BIGINT, 0),
+    out_amount = COALESCE((quote_response->>'outAmount')::BIGINT, 0),
+    slippage_bps = COALESCE((quote_response->>'slippageBps')::INTEGER, 50),
+    swap_mode = COALESCE(quote_response->>'swapMode', 'ExactIn'),
+    expires_at = created_at
+WHERE expires_at IS NULL;
+
+ALTER TABLE quotes ALTER COLUMN input_mint SET NOT NULL;
+ALTER TABLE quotes ALTER COLUMN output_mint SET NOT NULL;
+ALTER TABLE quotes ALTER COLUMN in_amount SET NOT NULL;
+ALTER TABLE quotes ALTER COLUMN out_amount SET NOT NULL;
+ALTER TABLE quotes ALTER COLUMN slippage_bps SET NOT NULL;
+ALTER TABLE quotes ALTER COLUMN swap_mode SET NOT NULL;
+ALTER TABLE quotes ALTER COLUMN expires_at SET NOT NULL;
@@ -0,0 +1,6 @@
+-- History pages are keyed on (block_time, signature, id): one signature can
+-- produce a row per asset, so the id is needed to keep the order total.
+CREATE INDEX IF NOT EXISTS transactions_user_history_id_idx
+    ON transactions (user_id, block_time DESC, signature DESC, id DESC);
+
+DROP INDEX IF EXISTS transactions_user_history_idx;
@@ -0,0 +1,11 @@
+-- Account updates the indexer gave up writing after repeated failures.
+CREATE TABLE IF NOT EXISTS indexer_dead_letters (
+    id BIGSERIAL PRIMARY KEY,
+    account TEXT NOT NULL,
+    change TEXT NOT NULL,
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS indexer_dead_letters_account_idx ON indexer_dead_letters (account);
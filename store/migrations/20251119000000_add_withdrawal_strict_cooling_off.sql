@@ -0,0 +1,3 @@
+-- Turning strict mode off waits out the same cooling-off period as adding an
+-- address; until then the allowlist stays enforced.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS withdrawal_allowlist_relaxes_at TIMESTAMPTZ;
@@ -0,0 +1,4 @@
+-- A user's own limits no longer replace the global ones for a mint: the
+-- lower of the two applies, so a per-user row can only tighten them.
+COMMENT ON TABLE spending_limits IS
+    'Limits in base units of the mint. Rows without a user apply to everyone; a user''s own row can only lower them. A NULL limit means no limit of that kind.';
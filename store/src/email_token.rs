@@ -0,0 +1,263 @@
+use crate::user::{validate_password, UserError};
+use crate::Store;
+use bcrypt::{hash, DEFAULT_COST};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailTokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl EmailTokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmailTokenPurpose::VerifyEmail => "verify_email",
+            EmailTokenPurpose::ResetPassword => "reset_password",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EmailTokenError {
+    /// Unknown, expired or already used.
+    InvalidToken,
+    InvalidInput(String),
+    DatabaseError(String),
+}
+
+impl std::fmt::Display for EmailTokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmailTokenError::InvalidToken => write!(f, "Invalid or expired token"),
+            EmailTokenError::InvalidInput(msg) => write!(f, "Invalid input: {}", msg),
+            EmailTokenError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for EmailTokenError {}
+
+impl From<UserError> for EmailTokenError {
+    fn from(e: UserError) -> Self {
+        match e {
+            UserError::InvalidInput(msg) => EmailTokenError::InvalidInput(msg),
+            other => EmailTokenError::DatabaseError(other.to_string()),
+        }
+    }
+}
+
+impl Store {
+    /// Stores a new token and invalidates any earlier unused one with the same
+    /// purpose, so only the most recent email works.
+    pub async fn create_email_token(
+        &self,
+        user_id: Uuid,
+        purpose: EmailTokenPurpose,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), EmailTokenError> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        sqlx::query!(
+            r#"
+            UPDATE email_tokens
+            SET used_at = NOW()
+            WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL
+            "#,
+            user_id,
+            purpose.as_str()
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO email_tokens (user_id, purpose, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            user_id,
+            purpose.as_str(),
+            token_hash,
+            expires_at
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Consumes a verification token and marks the user's email as verified.
+    pub async fn verify_email(&self, token_hash: &str) -> Result<Uuid, EmailTokenError> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        let user_id = consume_email_token(&mut tx, EmailTokenPurpose::VerifyEmail, token_hash).await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW()
+            WHERE id = $1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        Ok(user_id)
+    }
+
+    /// Consumes a reset token, sets the new password and revokes every session,
+    /// so whoever knew the old password is signed out.
+    pub async fn reset_password(&self, token_hash: &str, new_password: &str) -> Result<Uuid, EmailTokenError> {
+        validate_password(new_password)?;
+        let password_hash = hash(new_password, DEFAULT_COST)
+            .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        let user_id = consume_email_token(&mut tx, EmailTokenPurpose::ResetPassword, token_hash).await?;
+
+        // Following the link proves ownership of the address as well
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET password_hash = $2,
+                email_verified_at = COALESCE(email_verified_at, NOW()),
+                updated_at = NOW()
+            WHERE id = $1
+            "#,
+            user_id,
+            password_hash
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+        Ok(user_id)
+    }
+}
+
+async fn consume_email_token(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    purpose: EmailTokenPurpose,
+    token_hash: &str,
+) -> Result<Uuid, EmailTokenError> {
+    let user_id = sqlx::query_scalar!(
+        r#"
+        UPDATE email_tokens
+        SET used_at = NOW()
+        WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING user_id
+        "#,
+        token_hash,
+        purpose.as_str()
+    )
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(|e| EmailTokenError::DatabaseError(e.to_string()))?;
+
+    user_id.ok_or(EmailTokenError::InvalidToken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::new_user;
+    use chrono::Duration;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn test_verification_token_is_single_use(pool: PgPool) {
+        let store = Store::new(pool);
+        let user_id = new_user(&store).await;
+        store
+            .create_email_token(user_id, EmailTokenPurpose::VerifyEmail, "hash", Utc::now() + Duration::hours(1))
+            .await
+            .unwrap();
+
+        assert_eq!(store.verify_email("hash").await.unwrap(), user_id);
+        assert!(store.get_user_by_id(user_id).await.unwrap().unwrap().email_verified_at.is_some());
+        assert!(matches!(store.verify_email("hash").await, Err(EmailTokenError::InvalidToken)));
+    }
+
+    #[sqlx::test]
+    async fn test_expired_token_is_rejected(pool: PgPool) {
+        let store = Store::new(pool);
+        let user_id = new_user(&store).await;
+        store
+            .create_email_token(user_id, EmailTokenPurpose::VerifyEmail, "hash", Utc::now() - Duration::seconds(1))
+            .await
+            .unwrap();
+
+        assert!(matches!(store.verify_email("hash").await, Err(EmailTokenError::InvalidToken)));
+        assert!(store.get_user_by_id(user_id).await.unwrap().unwrap().email_verified_at.is_none());
+    }
+
+    #[sqlx::test]
+    async fn test_new_token_replaces_earlier_one(pool: PgPool) {
+        let store = Store::new(pool);
+        let user_id = new_user(&store).await;
+        let expires_at = Utc::now() + Duration::hours(1);
+        store
+            .create_email_token(user_id, EmailTokenPurpose::ResetPassword, "first", expires_at)
+            .await
+            .unwrap();
+        store
+            .create_email_token(user_id, EmailTokenPurpose::ResetPassword, "second", expires_at)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            store.reset_password("first", "new-password").await,
+            Err(EmailTokenError::InvalidToken)
+        ));
+        // A reset token does not verify the email through the wrong endpoint
+        assert!(matches!(store.verify_email("second").await, Err(EmailTokenError::InvalidToken)));
+        assert_eq!(store.reset_password("second", "new-password").await.unwrap(), user_id);
+        assert!(matches!(
+            store.reset_password("second", "new-password").await,
+            Err(EmailTokenError::InvalidToken)
+        ));
+    }
+}
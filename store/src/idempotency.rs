@@ -0,0 +1,145 @@
+use crate::models::idempotency::IdempotencyRecord;
+use crate::Store;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum IdempotencyError {
+    DatabaseError(String),
+}
+
+impl std::fmt::Display for IdempotencyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdempotencyError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for IdempotencyError {}
+
+#[derive(Debug)]
+pub enum IdempotencyClaim {
+    /// The key was unused, or held by a request whose lock ran out, and is
+    /// now held by this request.
+    Claimed,
+    /// The key was used before; the record says by what and with which result.
+    Existing(IdempotencyRecord),
+}
+
+impl Store {
+    /// Claims the key for a new request until `locked_until`, or returns the
+    /// record of the request that claimed it first. An unfinished claim for
+    /// the same request whose lock has run out is taken over, so a retry
+    /// after a crash is not refused forever.
+    pub async fn claim_idempotency_key(
+        &self,
+        user_id: Uuid,
+        key: &str,
+        endpoint: &str,
+        request_hash: &str,
+        locked_until: DateTime<Utc>,
+    ) -> Result<IdempotencyClaim, IdempotencyError> {
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO idempotency_keys (user_id, key, endpoint, request_hash, locked_until)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_id, key) DO UPDATE
+            SET locked_until = EXCLUDED.locked_until, updated_at = NOW()
+            WHERE idempotency_keys.status = 'in_progress'
+              AND idempotency_keys.locked_until < NOW()
+              AND idempotency_keys.endpoint = EXCLUDED.endpoint
+              AND idempotency_keys.request_hash = EXCLUDED.request_hash
+            RETURNING key
+            "#,
+            user_id,
+            key,
+            endpoint,
+            request_hash,
+            locked_until
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| IdempotencyError::DatabaseError(e.to_string()))?;
+
+        if inserted.is_some() {
+            return Ok(IdempotencyClaim::Claimed);
+        }
+
+        let record = sqlx::query_as!(
+            IdempotencyRecord,
+            r#"
+            SELECT user_id, key, endpoint, request_hash, status, response_status,
+                   response_body, signature, locked_until, created_at, updated_at
+            FROM idempotency_keys
+            WHERE user_id = $1 AND key = $2
+            "#,
+            user_id,
+            key
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| IdempotencyError::DatabaseError(e.to_string()))?;
+
+        Ok(IdempotencyClaim::Existing(record))
+    }
+
+    pub async fn complete_idempotency_key(
+        &self,
+        user_id: Uuid,
+        key: &str,
+        response_status: i32,
+        response_body: &Value,
+        signature: Option<&str>,
+    ) -> Result<(), IdempotencyError> {
+        sqlx::query!(
+            r#"
+            UPDATE idempotency_keys
+            SET status = 'completed', response_status = $3, response_body = $4,
+                signature = $5, updated_at = NOW()
+            WHERE user_id = $1 AND key = $2
+            "#,
+            user_id,
+            key,
+            response_status,
+            response_body,
+            signature
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| IdempotencyError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Frees a key whose request was rejected before anything was signed, so
+    /// the client can retry with it.
+    pub async fn release_idempotency_key(&self, user_id: Uuid, key: &str) -> Result<(), IdempotencyError> {
+        sqlx::query!(
+            r#"
+            DELETE FROM idempotency_keys
+            WHERE user_id = $1 AND key = $2 AND status = 'in_progress'
+            "#,
+            user_id,
+            key
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| IdempotencyError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    pub async fn prune_idempotency_keys(&self, created_before: DateTime<Utc>) -> Result<u64, IdempotencyError> {
+        let result = sqlx::query!(
+            "DELETE FROM idempotency_keys WHERE created_at < $1",
+            created_before
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| IdempotencyError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected())
+    }
+}
@@ -2,6 +2,16 @@ pub mod models;
 pub mod user;
 pub mod solana;
 pub mod public_key;
+pub mod transaction;
+pub mod session;
+pub mod totp;
+pub mod email_token;
+pub mod rate_limit;
+pub mod idempotency;
+pub mod withdrawal_address;
+pub mod spending;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
 use sqlx::PgPool;
 
@@ -13,4 +23,10 @@ impl Store {
     pub fn new(pool: PgPool) -> Self {
         Self { pool }
     }
+
+    /// Checks that the database is reachable.
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
 }
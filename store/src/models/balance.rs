@@ -12,3 +12,13 @@ pub struct Balance {
     pub user_id: Uuid,
     pub asset_id: Uuid,
 }
+
+/// Amount held in a single token account, with its owner and mint.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct TokenAccountHolding {
+    pub token_account: String,
+    pub owner: String,
+    pub mint_address: String,
+    pub amount: i64,
+    pub updated_at: DateTime<Utc>,
+}
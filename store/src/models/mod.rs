@@ -3,3 +3,8 @@ pub mod asset;
 pub mod balance;
 pub mod quote;
 pub mod public_key;
+pub mod transaction;
+pub mod session;
+pub mod totp;
+pub mod idempotency;
+pub mod withdrawal_address;
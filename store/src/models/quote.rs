@@ -8,6 +8,16 @@ use uuid::Uuid;
 pub struct Quote {
     pub id: Uuid,
     pub user_id: Uuid,
+    pub input_mint: String,
+    pub output_mint: String,
+    pub in_amount: i64,
+    pub out_amount: i64,
+    pub slippage_bps: i32,
+    /// `ExactIn` or `ExactOut`.
+    pub swap_mode: String,
     pub quote_response: Value,
+    pub expires_at: DateTime<Utc>,
+    /// Set when a swap starts using the quote.
+    pub executed_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
 }
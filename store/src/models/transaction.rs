@@ -0,0 +1,40 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Transaction {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub asset_id: Uuid,
+    pub signature: String,
+    pub direction: String,
+    pub amount: i64,
+    pub counterparty: Option<String>,
+    pub slot: Option<i64>,
+    pub block_time: DateTime<Utc>,
+    pub kind: String,
+    pub status: String,
+    pub mpc_session_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A transaction joined with the asset it moved.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct TransactionEntry {
+    pub id: Uuid,
+    pub signature: String,
+    pub direction: String,
+    pub amount: i64,
+    pub counterparty: Option<String>,
+    pub slot: Option<i64>,
+    pub block_time: DateTime<Utc>,
+    pub kind: String,
+    pub status: String,
+    pub mpc_session_id: Option<Uuid>,
+    pub mint_address: String,
+    pub symbol: String,
+    pub decimals: i32,
+}
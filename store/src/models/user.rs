@@ -9,6 +9,7 @@ pub struct User {
     pub email: String,
     pub password_hash: String,
     pub public_key: String,
+    pub email_verified_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
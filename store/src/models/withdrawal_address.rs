@@ -0,0 +1,72 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct WithdrawalAddress {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub address: String,
+    pub label: String,
+    pub mint: Option<String>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+    pub activates_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl WithdrawalAddress {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.confirmed_at.is_some() && self.activates_at <= now
+    }
+}
+
+/// Strict mode as the user set it. Turning it off only takes effect at
+/// `relaxes_at`, once the cooling-off period has passed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct WithdrawalSettings {
+    pub strict: bool,
+    pub relaxes_at: Option<DateTime<Utc>>,
+}
+
+impl WithdrawalSettings {
+    pub fn is_strict(&self, now: DateTime<Utc>) -> bool {
+        self.strict && self.relaxes_at.is_none_or(|relaxes_at| relaxes_at > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_strict_mode_holds_until_cooling_off_ends() {
+        let now = Utc::now();
+        let settings = |strict, relaxes_at| WithdrawalSettings { strict, relaxes_at };
+
+        assert!(!settings(false, None).is_strict(now));
+        assert!(settings(true, None).is_strict(now));
+        assert!(settings(true, Some(now + Duration::hours(1))).is_strict(now));
+        assert!(!settings(true, Some(now)).is_strict(now));
+    }
+
+    #[test]
+    fn test_address_is_active_once_confirmed_and_cooled_off() {
+        let now = Utc::now();
+        let address = |confirmed_at, activates_at| WithdrawalAddress {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            address: "address".to_string(),
+            label: "label".to_string(),
+            mint: None,
+            confirmed_at,
+            activates_at,
+            created_at: now,
+        };
+
+        assert!(address(Some(now), now).is_active(now));
+        assert!(!address(None, now).is_active(now));
+        assert!(!address(Some(now), now + Duration::hours(1)).is_active(now));
+    }
+}
@@ -0,0 +1,130 @@
+use crate::Store;
+use chrono::{DateTime, Utc};
+
+#[derive(Debug)]
+pub enum RateLimitError {
+    DatabaseError(String),
+}
+
+impl std::fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RateLimitError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+impl Store {
+    /// Refills the bucket for the time since its last use and takes one token
+    /// if available. Returns whether the request is allowed and the tokens left.
+    pub async fn take_rate_limit_token(
+        &self,
+        key: &str,
+        capacity: f64,
+        refill_per_second: f64,
+    ) -> Result<(bool, f64), RateLimitError> {
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO rate_limit_buckets (key, tokens, allowed, updated_at)
+            VALUES ($1, $2 - 1, TRUE, NOW())
+            ON CONFLICT (key) DO UPDATE
+            SET allowed = LEAST($2, rate_limit_buckets.tokens
+                    + EXTRACT(EPOCH FROM NOW() - rate_limit_buckets.updated_at)::DOUBLE PRECISION * $3) >= 1,
+                tokens = LEAST($2, rate_limit_buckets.tokens
+                    + EXTRACT(EPOCH FROM NOW() - rate_limit_buckets.updated_at)::DOUBLE PRECISION * $3)
+                    - CASE WHEN LEAST($2, rate_limit_buckets.tokens
+                        + EXTRACT(EPOCH FROM NOW() - rate_limit_buckets.updated_at)::DOUBLE PRECISION * $3) >= 1
+                      THEN 1 ELSE 0 END,
+                updated_at = NOW()
+            RETURNING allowed, tokens
+            "#,
+            key,
+            capacity,
+            refill_per_second
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| RateLimitError::DatabaseError(e.to_string()))?;
+
+        Ok((row.allowed, row.tokens))
+    }
+
+    /// Deletes buckets untouched since `idle_since`. They would be full again
+    /// by now, so dropping them changes nothing.
+    pub async fn prune_rate_limit_buckets(&self, idle_since: DateTime<Utc>) -> Result<u64, RateLimitError> {
+        let result = sqlx::query!(
+            "DELETE FROM rate_limit_buckets WHERE updated_at < $1",
+            idle_since
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| RateLimitError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Returns when the email may next try to sign in, if it is locked out.
+    pub async fn get_login_lock(&self, email: &str) -> Result<Option<DateTime<Utc>>, RateLimitError> {
+        let locked_until = sqlx::query_scalar!(
+            r#"
+            SELECT locked_until AS "locked_until!"
+            FROM login_attempts
+            WHERE email = $1 AND locked_until > NOW()
+            "#,
+            email
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| RateLimitError::DatabaseError(e.to_string()))?;
+
+        Ok(locked_until)
+    }
+
+    /// Counts a failed sign-in and returns the number of consecutive failures.
+    /// The count starts over after a day without failures.
+    pub async fn record_failed_login(&self, email: &str) -> Result<i32, RateLimitError> {
+        let failed_count = sqlx::query_scalar!(
+            r#"
+            INSERT INTO login_attempts (email, failed_count, last_failed_at)
+            VALUES ($1, 1, NOW())
+            ON CONFLICT (email) DO UPDATE
+            SET failed_count = CASE
+                    WHEN login_attempts.last_failed_at < NOW() - INTERVAL '1 day' THEN 1
+                    ELSE login_attempts.failed_count + 1
+                END,
+                last_failed_at = NOW()
+            RETURNING failed_count
+            "#,
+            email
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| RateLimitError::DatabaseError(e.to_string()))?;
+
+        Ok(failed_count)
+    }
+
+    pub async fn lock_login(&self, email: &str, locked_until: DateTime<Utc>) -> Result<(), RateLimitError> {
+        sqlx::query!(
+            "UPDATE login_attempts SET locked_until = $2 WHERE email = $1",
+            email,
+            locked_until
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| RateLimitError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    pub async fn clear_failed_logins(&self, email: &str) -> Result<(), RateLimitError> {
+        sqlx::query!("DELETE FROM login_attempts WHERE email = $1", email)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| RateLimitError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+}
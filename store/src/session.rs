@@ -0,0 +1,155 @@
+use crate::models::session::Session;
+use crate::Store;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum SessionError {
+    NotFound,
+    /// A refresh token that was already rotated away was presented again.
+    TokenReused,
+    DatabaseError(String),
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::NotFound => write!(f, "Session not found"),
+            SessionError::TokenReused => write!(f, "Refresh token reused"),
+            SessionError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl Store {
+    pub async fn create_session(
+        &self,
+        user_id: Uuid,
+        refresh_token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Session, SessionError> {
+        let session = sqlx::query_as!(
+            Session,
+            r#"
+            INSERT INTO sessions (user_id, refresh_token_hash, expires_at)
+            VALUES ($1, $2, $3)
+            RETURNING id, user_id, refresh_token_hash, previous_refresh_token_hash,
+                      expires_at, revoked_at, last_used_at, created_at
+            "#,
+            user_id,
+            refresh_token_hash,
+            expires_at
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| SessionError::DatabaseError(e.to_string()))?;
+
+        Ok(session)
+    }
+
+    /// Swaps the session's refresh token for a new one. Presenting a token that
+    /// was already rotated away revokes the whole session, since either the
+    /// client or an attacker holds a stale copy.
+    pub async fn rotate_session(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Session, SessionError> {
+        let session = sqlx::query_as!(
+            Session,
+            r#"
+            UPDATE sessions
+            SET refresh_token_hash = $2,
+                previous_refresh_token_hash = refresh_token_hash,
+                expires_at = $3,
+                last_used_at = NOW()
+            WHERE refresh_token_hash = $1 AND revoked_at IS NULL AND expires_at > NOW()
+            RETURNING id, user_id, refresh_token_hash, previous_refresh_token_hash,
+                      expires_at, revoked_at, last_used_at, created_at
+            "#,
+            refresh_token_hash,
+            new_refresh_token_hash,
+            expires_at
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| SessionError::DatabaseError(e.to_string()))?;
+
+        if let Some(session) = session {
+            return Ok(session);
+        }
+
+        let reused = sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET revoked_at = COALESCE(revoked_at, NOW())
+            WHERE previous_refresh_token_hash = $1
+            RETURNING id
+            "#,
+            refresh_token_hash
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| SessionError::DatabaseError(e.to_string()))?;
+
+        match reused {
+            Some(_) => Err(SessionError::TokenReused),
+            None => Err(SessionError::NotFound),
+        }
+    }
+
+    /// Whether the session exists, has not expired and has not been revoked.
+    pub async fn is_session_active(&self, session_id: Uuid) -> Result<bool, SessionError> {
+        let active = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM sessions
+                WHERE id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+            ) AS "active!"
+            "#,
+            session_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| SessionError::DatabaseError(e.to_string()))?;
+
+        Ok(active)
+    }
+
+    pub async fn revoke_session(&self, user_id: Uuid, session_id: Uuid) -> Result<(), SessionError> {
+        sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+            session_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| SessionError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Revokes every active session of the user and returns how many there were.
+    pub async fn revoke_all_sessions(&self, user_id: Uuid) -> Result<u64, SessionError> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL
+            "#,
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| SessionError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected())
+    }
+}
@@ -1,18 +1,60 @@
 use crate::models::asset::Asset;
-use crate::models::balance::Balance;
+use crate::models::balance::{Balance, TokenAccountHolding};
 use crate::models::quote::Quote;
 use crate::Store;
+use chrono::{DateTime, Utc};
 use serde_json::Value;
 use uuid::Uuid;
 
+pub const SOL_MINT: &str = "So11111111111111111111111111111111111111112";
+
+#[derive(Debug, Clone)]
+pub struct NewQuote {
+    pub user_id: Uuid,
+    pub input_mint: String,
+    pub output_mint: String,
+    pub in_amount: i64,
+    pub out_amount: i64,
+    pub slippage_bps: i32,
+    pub swap_mode: String,
+    pub quote_response: Value,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A balance written directly, without per-account holdings.
+#[derive(Debug, Clone)]
+pub struct BalanceUpdate {
+    pub user_id: Uuid,
+    pub asset_id: Uuid,
+    pub amount: i64,
+}
+
+/// The latest amount held in a single token account.
+#[derive(Debug, Clone)]
+pub struct TokenAccountBalanceUpdate {
+    pub token_account: String,
+    pub user_id: Uuid,
+    pub asset_id: Uuid,
+    pub amount: i64,
+}
+
 #[derive(Debug)]
 pub enum QuoteError {
+    NotFound,
+    /// The quote belongs to another user.
+    NotOwner,
+    Expired,
+    AlreadyExecuted,
     DatabaseError(String),
 }
 
 impl std::fmt::Display for QuoteError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            QuoteError::NotFound => write!(f, "Quote not found"),
+            QuoteError::NotOwner => write!(f, "Quote belongs to another user"),
+            QuoteError::Expired => write!(f, "Quote has expired"),
+            QuoteError::AlreadyExecuted => write!(f, "Quote was already executed"),
             QuoteError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
         }
     }
@@ -21,20 +63,26 @@ impl std::fmt::Display for QuoteError {
 impl std::error::Error for QuoteError {}
 
 impl Store {
-    pub async fn create_quote(
-        &self,
-        user_id: Uuid,
-        quote_response: Value,
-    ) -> Result<Quote, QuoteError> {
+    pub async fn create_quote(&self, new_quote: NewQuote) -> Result<Quote, QuoteError> {
         let quote = sqlx::query_as!(
             Quote,
             r#"
-            INSERT INTO quotes (user_id, quote_response)
-            VALUES ($1, $2)
-            RETURNING id, user_id, quote_response, created_at
+            INSERT INTO quotes
+                (user_id, input_mint, output_mint, in_amount, out_amount,
+                 slippage_bps, swap_mode, quote_response, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id, user_id, input_mint, output_mint, in_amount, out_amount,
+                      slippage_bps, swap_mode, quote_response, expires_at, executed_at, created_at
             "#,
-            user_id,
-            quote_response
+            new_quote.user_id,
+            new_quote.input_mint,
+            new_quote.output_mint,
+            new_quote.in_amount,
+            new_quote.out_amount,
+            new_quote.slippage_bps,
+            new_quote.swap_mode,
+            new_quote.quote_response,
+            new_quote.expires_at
         )
         .fetch_one(&self.pool)
         .await
@@ -47,7 +95,8 @@ impl Store {
         let quote = sqlx::query_as!(
             Quote,
             r#"
-            SELECT id, user_id, quote_response, created_at
+            SELECT id, user_id, input_mint, output_mint, in_amount, out_amount,
+                   slippage_bps, swap_mode, quote_response, expires_at, executed_at, created_at
             FROM quotes
             WHERE id = $1
             "#,
@@ -60,8 +109,49 @@ impl Store {
         Ok(quote)
     }
 
+    /// Marks the user's quote as executed if it is still fresh and unused, so
+    /// two swaps cannot run from the same quote.
+    pub async fn claim_quote(&self, quote_id: Uuid, user_id: Uuid) -> Result<Quote, QuoteError> {
+        let claimed = sqlx::query_as!(
+            Quote,
+            r#"
+            UPDATE quotes
+            SET executed_at = NOW()
+            WHERE id = $1 AND user_id = $2 AND executed_at IS NULL AND expires_at > NOW()
+            RETURNING id, user_id, input_mint, output_mint, in_amount, out_amount,
+                      slippage_bps, swap_mode, quote_response, expires_at, executed_at, created_at
+            "#,
+            quote_id,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+
+        if let Some(quote) = claimed {
+            return Ok(quote);
+        }
+
+        match self.get_quote(quote_id).await? {
+            None => Err(QuoteError::NotFound),
+            Some(quote) if quote.user_id != user_id => Err(QuoteError::NotOwner),
+            Some(quote) if quote.executed_at.is_some() => Err(QuoteError::AlreadyExecuted),
+            Some(_) => Err(QuoteError::Expired),
+        }
+    }
+
+    /// Makes a claimed quote usable again after a swap failed before anything
+    /// was broadcast.
+    pub async fn release_quote(&self, quote_id: Uuid) -> Result<(), QuoteError> {
+        sqlx::query!("UPDATE quotes SET executed_at = NULL WHERE id = $1", quote_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
     pub async fn get_sol_balance(&self, user_id: Uuid) -> Result<Option<Balance>, QuoteError> {
-        let sol_mint_address = "So11111111111111111111111111111111111111112";
         let balance = sqlx::query_as!(
             Balance,
             r#"
@@ -71,7 +161,7 @@ impl Store {
             WHERE b.user_id = $1 AND a.mint_address = $2
             "#,
             user_id,
-            sol_mint_address
+            SOL_MINT
         )
         .fetch_optional(&self.pool)
         .await
@@ -120,6 +210,22 @@ impl Store {
         Ok(result)
     }
 
+    pub async fn get_asset_by_mint(&self, mint_address: &str) -> Result<Option<Asset>, QuoteError> {
+        let asset = sqlx::query_as!(
+            Asset,
+            r#"
+            SELECT id, mint_address, decimals, name, symbol, logo_url, created_at, updated_at
+            FROM assets
+            WHERE mint_address = $1
+            "#,
+            mint_address
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+        Ok(asset)
+    }
+
     pub async fn upsert_asset(
         &self,
         mint_address: &str,
@@ -169,4 +275,219 @@ impl Store {
         .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
         Ok(balance)
     }
+
+    /// Applies a batch of balance changes in a single database transaction.
+    ///
+    /// `balances` are written as-is (native SOL). Token accounts are stored per
+    /// account, and every user/asset pair they touch, including the previous
+    /// owner of a reassigned account, gets its balance recomputed as the sum
+    /// over the user's remaining accounts for that mint.
+    pub async fn apply_balance_batch(
+        &self,
+        balances: &[BalanceUpdate],
+        token_accounts: &[TokenAccountBalanceUpdate],
+        closed_token_accounts: &[String],
+    ) -> Result<(), QuoteError> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+
+        if !balances.is_empty() {
+            let user_ids: Vec<Uuid> = balances.iter().map(|b| b.user_id).collect();
+            let asset_ids: Vec<Uuid> = balances.iter().map(|b| b.asset_id).collect();
+            let amounts: Vec<i64> = balances.iter().map(|b| b.amount).collect();
+
+            sqlx::query!(
+                r#"
+                INSERT INTO balances (user_id, asset_id, amount)
+                SELECT * FROM UNNEST($1::uuid[], $2::uuid[], $3::bigint[])
+                ON CONFLICT (user_id, asset_id) DO UPDATE SET amount = EXCLUDED.amount, updated_at = NOW()
+                "#,
+                &user_ids,
+                &asset_ids,
+                &amounts
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+        }
+
+        let mut affected: Vec<(Uuid, Uuid)> = Vec::new();
+
+        if !token_accounts.is_empty() {
+            let addresses: Vec<String> = token_accounts.iter().map(|t| t.token_account.clone()).collect();
+            let user_ids: Vec<Uuid> = token_accounts.iter().map(|t| t.user_id).collect();
+            let asset_ids: Vec<Uuid> = token_accounts.iter().map(|t| t.asset_id).collect();
+            let amounts: Vec<i64> = token_accounts.iter().map(|t| t.amount).collect();
+
+            let previous = sqlx::query!(
+                r#"
+                SELECT user_id, asset_id FROM token_account_balances
+                WHERE token_account = ANY($1)
+                FOR UPDATE
+                "#,
+                &addresses
+            )
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+            affected.extend(previous.into_iter().map(|p| (p.user_id, p.asset_id)));
+
+            sqlx::query!(
+                r#"
+                INSERT INTO token_account_balances (token_account, user_id, asset_id, amount)
+                SELECT * FROM UNNEST($1::text[], $2::uuid[], $3::uuid[], $4::bigint[])
+                ON CONFLICT (token_account) DO UPDATE
+                SET user_id = EXCLUDED.user_id, asset_id = EXCLUDED.asset_id,
+                    amount = EXCLUDED.amount, updated_at = NOW()
+                "#,
+                &addresses,
+                &user_ids,
+                &asset_ids,
+                &amounts
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+            affected.extend(user_ids.into_iter().zip(asset_ids));
+        }
+
+        if !closed_token_accounts.is_empty() {
+            let removed = sqlx::query!(
+                r#"
+                DELETE FROM token_account_balances
+                WHERE token_account = ANY($1)
+                RETURNING user_id, asset_id
+                "#,
+                closed_token_accounts
+            )
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+            affected.extend(removed.into_iter().map(|r| (r.user_id, r.asset_id)));
+        }
+
+        affected.sort();
+        affected.dedup();
+        if !affected.is_empty() {
+            refresh_balances_from_holdings(&mut tx, &affected).await?;
+        }
+
+        tx.commit()
+            .await
+            .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Sets aside an account update that could not be written, so it can be
+    /// inspected and replayed by hand.
+    pub async fn record_dead_letter(
+        &self,
+        account: &str,
+        change: &str,
+        error: &str,
+        attempts: i32,
+    ) -> Result<(), QuoteError> {
+        sqlx::query!(
+            r#"
+            INSERT INTO indexer_dead_letters (account, change, error, attempts)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            account,
+            change,
+            error,
+            attempts
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+        Ok(())
+    }
+
+    pub async fn get_token_account_holdings(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<TokenAccountHolding>, QuoteError> {
+        let holdings = sqlx::query_as!(
+            TokenAccountHolding,
+            r#"
+            SELECT t.token_account, u.public_key AS owner, a.mint_address, t.amount, t.updated_at
+            FROM token_account_balances t
+            JOIN users u ON t.user_id = u.id
+            JOIN assets a ON t.asset_id = a.id
+            WHERE t.user_id = $1
+            ORDER BY a.mint_address, t.amount DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+        Ok(holdings)
+    }
+
+    pub async fn get_all_token_account_holdings(
+        &self,
+    ) -> Result<Vec<TokenAccountHolding>, QuoteError> {
+        let holdings = sqlx::query_as!(
+            TokenAccountHolding,
+            r#"
+            SELECT t.token_account, u.public_key AS owner, a.mint_address, t.amount, t.updated_at
+            FROM token_account_balances t
+            JOIN users u ON t.user_id = u.id
+            JOIN assets a ON t.asset_id = a.id
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+        Ok(holdings)
+    }
+}
+
+/// Sets each user's balance for an asset to the sum of their token accounts,
+/// deleting it once no accounts are left.
+async fn refresh_balances_from_holdings(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    pairs: &[(Uuid, Uuid)],
+) -> Result<(), QuoteError> {
+    let user_ids: Vec<Uuid> = pairs.iter().map(|(user_id, _)| *user_id).collect();
+    let asset_ids: Vec<Uuid> = pairs.iter().map(|(_, asset_id)| *asset_id).collect();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO balances (user_id, asset_id, amount)
+        SELECT t.user_id, t.asset_id, SUM(t.amount)::BIGINT
+        FROM UNNEST($1::uuid[], $2::uuid[]) AS p(user_id, asset_id)
+        JOIN token_account_balances t ON t.user_id = p.user_id AND t.asset_id = p.asset_id
+        GROUP BY t.user_id, t.asset_id
+        ON CONFLICT (user_id, asset_id) DO UPDATE SET amount = EXCLUDED.amount, updated_at = NOW()
+        "#,
+        &user_ids,
+        &asset_ids
+    )
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM balances b
+        USING UNNEST($1::uuid[], $2::uuid[]) AS p(user_id, asset_id)
+        WHERE b.user_id = p.user_id AND b.asset_id = p.asset_id
+          AND NOT EXISTS (
+              SELECT 1 FROM token_account_balances t
+              WHERE t.user_id = p.user_id AND t.asset_id = p.asset_id
+          )
+        "#,
+        &user_ids,
+        &asset_ids
+    )
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| QuoteError::DatabaseError(e.to_string()))?;
+
+    Ok(())
 }
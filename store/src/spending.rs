@@ -0,0 +1,242 @@
+use crate::Store;
+use uuid::Uuid;
+
+/// Effective limits for one mint and what was spent against them in the last
+/// 24 hours.
+#[derive(Debug, Clone)]
+pub struct SpendingAllowance {
+    pub mint: String,
+    pub per_transaction_limit: Option<i64>,
+    pub daily_limit: Option<i64>,
+    pub spent_24h: i64,
+}
+
+impl SpendingAllowance {
+    pub fn remaining_24h(&self) -> Option<i64> {
+        self.daily_limit.map(|limit| (limit - self.spent_24h).max(0))
+    }
+
+    /// Checks whether `amount` fits within both limits.
+    pub fn check(&self, amount: i64) -> Result<(), LimitExceeded> {
+        if let Some(limit) = self.per_transaction_limit {
+            if amount > limit {
+                return Err(LimitExceeded::PerTransaction { limit });
+            }
+        }
+        if let Some(remaining) = self.remaining_24h() {
+            if amount > remaining {
+                return Err(LimitExceeded::Daily { remaining });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LimitExceeded {
+    PerTransaction { limit: i64 },
+    Daily { remaining: i64 },
+}
+
+#[derive(Debug)]
+pub enum SpendingError {
+    LimitExceeded(LimitExceeded),
+    DatabaseError(String),
+}
+
+impl std::fmt::Display for SpendingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpendingError::LimitExceeded(LimitExceeded::PerTransaction { limit }) => {
+                write!(f, "Amount exceeds the per-transaction limit of {}", limit)
+            }
+            SpendingError::LimitExceeded(LimitExceeded::Daily { remaining }) => {
+                write!(f, "Amount exceeds the remaining 24-hour allowance of {}", remaining)
+            }
+            SpendingError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SpendingError {}
+
+impl Store {
+    /// Checks the amount against the user's limits for the mint and, if it
+    /// fits, records it in the ledger as initiated. The user row is locked
+    /// while doing so, so concurrent requests cannot both use the same
+    /// allowance.
+    pub async fn reserve_spend(
+        &self,
+        user_id: Uuid,
+        mint: &str,
+        amount: i64,
+        kind: &str,
+    ) -> Result<Uuid, SpendingError> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| SpendingError::DatabaseError(e.to_string()))?;
+
+        sqlx::query!("SELECT id FROM users WHERE id = $1 FOR UPDATE", user_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| SpendingError::DatabaseError(e.to_string()))?;
+
+        let allowance = sqlx::query_as!(
+            SpendingAllowance,
+            r#"
+            SELECT
+                $2::TEXT AS "mint!",
+                l.per_transaction_limit AS "per_transaction_limit?",
+                l.daily_limit AS "daily_limit?",
+                (SELECT COALESCE(SUM(amount), 0)::BIGINT FROM spending_ledger
+                 WHERE user_id = $1 AND mint = $2 AND status <> 'failed'
+                   AND created_at > NOW() - INTERVAL '24 hours') AS "spent_24h!"
+            FROM (SELECT 1) AS one
+            LEFT JOIN LATERAL (
+                SELECT MIN(per_transaction_limit) AS per_transaction_limit, MIN(daily_limit) AS daily_limit
+                FROM spending_limits
+                WHERE (user_id IS NULL OR user_id = $1) AND mint = $2
+            ) l ON TRUE
+            "#,
+            user_id,
+            mint
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| SpendingError::DatabaseError(e.to_string()))?;
+
+        allowance.check(amount).map_err(SpendingError::LimitExceeded)?;
+
+        let ledger_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO spending_ledger (user_id, mint, amount, kind)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id
+            "#,
+            user_id,
+            mint,
+            amount,
+            kind
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| SpendingError::DatabaseError(e.to_string()))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| SpendingError::DatabaseError(e.to_string()))?;
+
+        Ok(ledger_id)
+    }
+
+    pub async fn confirm_spend(&self, ledger_id: Uuid, signature: &str) -> Result<(), SpendingError> {
+        sqlx::query!(
+            r#"
+            UPDATE spending_ledger
+            SET status = 'confirmed', signature = $2, updated_at = NOW()
+            WHERE id = $1
+            "#,
+            ledger_id,
+            signature
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| SpendingError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Gives the allowance back for a request that never reached the chain.
+    pub async fn fail_spend(&self, ledger_id: Uuid) -> Result<(), SpendingError> {
+        sqlx::query!(
+            r#"
+            UPDATE spending_ledger
+            SET status = 'failed', updated_at = NOW()
+            WHERE id = $1 AND status = 'initiated'
+            "#,
+            ledger_id
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| SpendingError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Allowances for every mint that has a limit for the user or for
+    /// everyone, or that the user spent in the last 24 hours. A user's own
+    /// limits can only tighten the global ones for that mint: the lower of
+    /// the two applies.
+    pub async fn get_spending_allowances(&self, user_id: Uuid) -> Result<Vec<SpendingAllowance>, SpendingError> {
+        let allowances = sqlx::query_as!(
+            SpendingAllowance,
+            r#"
+            WITH limits AS (
+                SELECT mint, MIN(per_transaction_limit) AS per_transaction_limit, MIN(daily_limit) AS daily_limit
+                FROM spending_limits
+                WHERE user_id IS NULL OR user_id = $1
+                GROUP BY mint
+            ),
+            spent AS (
+                SELECT mint, SUM(amount)::BIGINT AS spent_24h
+                FROM spending_ledger
+                WHERE user_id = $1 AND status <> 'failed'
+                  AND created_at > NOW() - INTERVAL '24 hours'
+                GROUP BY mint
+            )
+            SELECT
+                COALESCE(l.mint, s.mint) AS "mint!",
+                l.per_transaction_limit AS "per_transaction_limit?",
+                l.daily_limit AS "daily_limit?",
+                COALESCE(s.spent_24h, 0) AS "spent_24h!"
+            FROM limits l
+            FULL OUTER JOIN spent s ON s.mint = l.mint
+            ORDER BY 1
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| SpendingError::DatabaseError(e.to_string()))?;
+
+        Ok(allowances)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowance(per_transaction_limit: Option<i64>, daily_limit: Option<i64>, spent_24h: i64) -> SpendingAllowance {
+        SpendingAllowance {
+            mint: "mint".to_string(),
+            per_transaction_limit,
+            daily_limit,
+            spent_24h,
+        }
+    }
+
+    #[test]
+    fn test_remaining_24h() {
+        assert_eq!(allowance(None, None, 50).remaining_24h(), None);
+        assert_eq!(allowance(None, Some(100), 30).remaining_24h(), Some(70));
+        // Spending past the limit, e.g. after it was lowered, leaves nothing
+        assert_eq!(allowance(None, Some(100), 130).remaining_24h(), Some(0));
+    }
+
+    #[test]
+    fn test_check_applies_both_limits() {
+        assert!(allowance(None, None, i64::MAX).check(i64::MAX).is_ok());
+
+        let limited = allowance(Some(50), Some(100), 60);
+        assert!(limited.check(40).is_ok());
+        assert!(matches!(limited.check(51), Err(LimitExceeded::PerTransaction { limit: 50 })));
+        assert!(matches!(limited.check(41), Err(LimitExceeded::Daily { remaining: 40 })));
+
+        // Up to the limit exactly is fine
+        assert!(allowance(Some(50), None, 0).check(50).is_ok());
+        assert!(allowance(None, Some(100), 0).check(100).is_ok());
+    }
+}
@@ -0,0 +1,21 @@
+//! Fixtures shared by the tests of this crate and the crates built on it.
+
+use uuid::Uuid;
+use crate::user::CreateUserRequest;
+use crate::Store;
+
+/// Password of every user made by [`new_user`].
+pub const PASSWORD: &str = "password";
+
+/// Creates a user with a unique email and returns its id.
+pub async fn new_user(store: &Store) -> Uuid {
+    store
+        .create_user(CreateUserRequest {
+            email: format!("{}@example.com", Uuid::new_v4()),
+            password: PASSWORD.to_string(),
+            public_key: Uuid::new_v4().to_string(),
+        })
+        .await
+        .unwrap()
+        .id
+}
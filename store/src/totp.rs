@@ -0,0 +1,227 @@
+use crate::models::totp::UserTotp;
+use crate::Store;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum TotpError {
+    NotEnrolled,
+    AlreadyEnabled,
+    DatabaseError(String),
+}
+
+impl std::fmt::Display for TotpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TotpError::NotEnrolled => write!(f, "Two-factor authentication is not set up"),
+            TotpError::AlreadyEnabled => write!(f, "Two-factor authentication is already enabled"),
+            TotpError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TotpError {}
+
+impl Store {
+    /// Stores a new pending secret, replacing any earlier unconfirmed one.
+    pub async fn begin_totp_enrollment(&self, user_id: Uuid, secret: &str) -> Result<UserTotp, TotpError> {
+        let totp = sqlx::query_as!(
+            UserTotp,
+            r#"
+            INSERT INTO user_totp (user_id, secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET secret = EXCLUDED.secret, last_used_step = NULL, updated_at = NOW()
+            WHERE user_totp.confirmed_at IS NULL
+            RETURNING user_id, secret, confirmed_at, last_used_step, created_at, updated_at
+            "#,
+            user_id,
+            secret
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        totp.ok_or(TotpError::AlreadyEnabled)
+    }
+
+    pub async fn get_totp(&self, user_id: Uuid) -> Result<Option<UserTotp>, TotpError> {
+        let totp = sqlx::query_as!(
+            UserTotp,
+            r#"
+            SELECT user_id, secret, confirmed_at, last_used_step, created_at, updated_at
+            FROM user_totp
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        Ok(totp)
+    }
+
+    /// Enables the pending secret and issues a fresh set of recovery codes.
+    pub async fn confirm_totp(
+        &self,
+        user_id: Uuid,
+        step: i64,
+        recovery_code_hashes: &[String],
+    ) -> Result<(), TotpError> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        let confirmed = sqlx::query!(
+            r#"
+            UPDATE user_totp
+            SET confirmed_at = NOW(), last_used_step = $2, updated_at = NOW()
+            WHERE user_id = $1 AND confirmed_at IS NULL
+            "#,
+            user_id,
+            step
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        if confirmed.rows_affected() == 0 {
+            return Err(TotpError::NotEnrolled);
+        }
+
+        insert_recovery_codes(&mut tx, user_id, recovery_code_hashes).await?;
+
+        tx.commit()
+            .await
+            .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Records that the code for `step` was used. Returns false if that step or
+    /// a later one was already used, so each code is accepted at most once.
+    pub async fn mark_totp_step_used(&self, user_id: Uuid, step: i64) -> Result<bool, TotpError> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE user_totp
+            SET last_used_step = $2, updated_at = NOW()
+            WHERE user_id = $1
+              AND confirmed_at IS NOT NULL
+              AND (last_used_step IS NULL OR last_used_step < $2)
+            "#,
+            user_id,
+            step
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Consumes an unused recovery code. Returns false if it does not match.
+    pub async fn use_recovery_code(&self, user_id: Uuid, code_hash: &str) -> Result<bool, TotpError> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE recovery_codes
+            SET used_at = NOW()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            "#,
+            user_id,
+            code_hash
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn count_unused_recovery_codes(&self, user_id: Uuid) -> Result<i64, TotpError> {
+        let count = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!"
+            FROM recovery_codes
+            WHERE user_id = $1 AND used_at IS NULL
+            "#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        Ok(count)
+    }
+
+    /// Invalidates all existing recovery codes and stores a new set.
+    pub async fn replace_recovery_codes(
+        &self,
+        user_id: Uuid,
+        recovery_code_hashes: &[String],
+    ) -> Result<(), TotpError> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        insert_recovery_codes(&mut tx, user_id, recovery_code_hashes).await?;
+
+        tx.commit()
+            .await
+            .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    pub async fn disable_totp(&self, user_id: Uuid) -> Result<(), TotpError> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        sqlx::query!("DELETE FROM user_totp WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+async fn insert_recovery_codes(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_id: Uuid,
+    recovery_code_hashes: &[String],
+) -> Result<(), TotpError> {
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO recovery_codes (user_id, code_hash)
+        SELECT $1, code_hash FROM UNNEST($2::text[]) AS code_hash
+        "#,
+        user_id,
+        recovery_code_hashes
+    )
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| TotpError::DatabaseError(e.to_string()))?;
+
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use crate::models::transaction::{Transaction, TransactionEntry};
+use crate::Store;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum TransactionError {
+    DatabaseError(String),
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+#[derive(Debug)]
+pub struct NewTransaction {
+    pub user_id: Uuid,
+    pub asset_id: Uuid,
+    pub signature: String,
+    pub direction: String,
+    pub amount: i64,
+    pub counterparty: Option<String>,
+    pub slot: Option<i64>,
+    pub block_time: DateTime<Utc>,
+    pub kind: String,
+    pub status: String,
+    pub mpc_session_id: Option<Uuid>,
+}
+
+#[derive(Debug, Default)]
+pub struct TransactionFilter {
+    pub mint: Option<String>,
+    pub direction: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Only return entries strictly older than this `(block_time, signature, id)`
+    /// position. The id breaks ties between the rows one signature produces
+    /// for several assets.
+    pub before: Option<(DateTime<Utc>, String, Uuid)>,
+}
+
+impl Store {
+    /// Inserts a history entry, or merges it into the existing one for the same
+    /// signature. The backend records fund-moving requests before the indexer sees
+    /// them on chain, so the session and kind it wrote are kept on conflict.
+    pub async fn record_transaction(
+        &self,
+        tx: NewTransaction,
+    ) -> Result<Transaction, TransactionError> {
+        upsert_transaction(&self.pool, tx).await
+    }
+
+    /// Records several history entries in one database transaction, merging
+    /// each like `record_transaction`.
+    pub async fn record_transactions(&self, txs: Vec<NewTransaction>) -> Result<(), TransactionError> {
+        let mut db_tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| TransactionError::DatabaseError(e.to_string()))?;
+        for tx in txs {
+            upsert_transaction(&mut *db_tx, tx).await?;
+        }
+        db_tx
+            .commit()
+            .await
+            .map_err(|e| TransactionError::DatabaseError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Returns a page of the user's history, newest first.
+    pub async fn list_transactions(
+        &self,
+        user_id: Uuid,
+        filter: &TransactionFilter,
+        limit: i64,
+    ) -> Result<Vec<TransactionEntry>, TransactionError> {
+        let (before_time, before_signature, before_id) = match &filter.before {
+            Some((time, signature, id)) => (Some(*time), Some(signature.clone()), Some(*id)),
+            None => (None, None, None),
+        };
+
+        let entries = sqlx::query_as!(
+            TransactionEntry,
+            r#"
+            SELECT t.id, t.signature, t.direction, t.amount, t.counterparty, t.slot,
+                   t.block_time, t.kind, t.status, t.mpc_session_id,
+                   a.mint_address, a.symbol, a.decimals
+            FROM transactions t
+            JOIN assets a ON t.asset_id = a.id
+            WHERE t.user_id = $1
+              AND ($2::text IS NULL OR a.mint_address = $2)
+              AND ($3::text IS NULL OR t.direction = $3)
+              AND ($4::timestamptz IS NULL OR t.block_time >= $4)
+              AND ($5::timestamptz IS NULL OR t.block_time < $5)
+              AND ($6::timestamptz IS NULL OR (t.block_time, t.signature, t.id) < ($6, $7::text, $8::uuid))
+            ORDER BY t.block_time DESC, t.signature DESC, t.id DESC
+            LIMIT $9
+            "#,
+            user_id,
+            filter.mint,
+            filter.direction,
+            filter.from,
+            filter.to,
+            before_time,
+            before_signature,
+            before_id,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| TransactionError::DatabaseError(e.to_string()))?;
+
+        Ok(entries)
+    }
+
+    /// The outgoing entry the backend recorded for an MPC signing session.
+    pub async fn get_transaction_by_session(
+        &self,
+        user_id: Uuid,
+        session_id: Uuid,
+    ) -> Result<Option<TransactionEntry>, TransactionError> {
+        let entry = sqlx::query_as!(
+            TransactionEntry,
+            r#"
+            SELECT t.id, t.signature, t.direction, t.amount, t.counterparty, t.slot,
+                   t.block_time, t.kind, t.status, t.mpc_session_id,
+                   a.mint_address, a.symbol, a.decimals
+            FROM transactions t
+            JOIN assets a ON t.asset_id = a.id
+            WHERE t.user_id = $1 AND t.mpc_session_id = $2
+            LIMIT 1
+            "#,
+            user_id,
+            session_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| TransactionError::DatabaseError(e.to_string()))?;
+
+        Ok(entry)
+    }
+
+    /// Records how a submitted transaction ended up. When it failed or its
+    /// blockhash expired, the amount no longer counts against spending limits.
+    pub async fn settle_outgoing_transaction(&self, signature: &str, status: &str) -> Result<(), TransactionError> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| TransactionError::DatabaseError(e.to_string()))?;
+
+        sqlx::query!(
+            r#"
+            UPDATE transactions
+            SET status = $2, updated_at = NOW()
+            WHERE signature = $1 AND direction = 'outgoing'
+            "#,
+            signature,
+            status
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| TransactionError::DatabaseError(e.to_string()))?;
+
+        if status != "confirmed" {
+            sqlx::query!(
+                r#"
+                UPDATE spending_ledger
+                SET status = 'failed', updated_at = NOW()
+                WHERE signature = $1
+                "#,
+                signature
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| TransactionError::DatabaseError(e.to_string()))?;
+        }
+
+        tx.commit()
+            .await
+            .map_err(|e| TransactionError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+async fn upsert_transaction<'e>(
+    executor: impl sqlx::PgExecutor<'e>,
+    tx: NewTransaction,
+) -> Result<Transaction, TransactionError> {
+    let transaction = sqlx::query_as!(
+        Transaction,
+        r#"
+        INSERT INTO transactions
+        (user_id, asset_id, signature, direction, amount, counterparty, slot, block_time, kind, status, mpc_session_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        ON CONFLICT (user_id, signature, asset_id) DO UPDATE SET
+            slot = COALESCE(EXCLUDED.slot, transactions.slot),
+            counterparty = COALESCE(transactions.counterparty, EXCLUDED.counterparty),
+            status = EXCLUDED.status,
+            mpc_session_id = COALESCE(transactions.mpc_session_id, EXCLUDED.mpc_session_id),
+            kind = CASE WHEN transactions.mpc_session_id IS NULL THEN EXCLUDED.kind ELSE transactions.kind END,
+            updated_at = NOW()
+        RETURNING id, user_id, asset_id, signature, direction, amount, counterparty, slot,
+                  block_time, kind, status, mpc_session_id, created_at, updated_at
+        "#,
+        tx.user_id,
+        tx.asset_id,
+        tx.signature,
+        tx.direction,
+        tx.amount,
+        tx.counterparty,
+        tx.slot,
+        tx.block_time,
+        tx.kind,
+        tx.status,
+        tx.mpc_session_id
+    )
+    .fetch_one(executor)
+    .await
+    .map_err(|e| TransactionError::DatabaseError(e.to_string()))?;
+
+    Ok(transaction)
+}
@@ -31,17 +31,40 @@ impl std::fmt::Display for UserError {
 
 impl std::error::Error for UserError {}
 
+/// Rejects addresses that cannot be delivered to. Ownership is confirmed
+/// separately through the verification email.
+fn is_valid_email(email: &str) -> bool {
+    if email.len() > 254 || email.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return false;
+    }
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && local.len() <= 64
+        && !domain.contains('@')
+        && domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && !label.starts_with('-') && !label.ends_with('-'))
+}
+
+pub fn validate_password(password: &str) -> Result<(), UserError> {
+    if password.len() < 6 {
+        return Err(UserError::InvalidInput(
+            "Password must be at least 6 characters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
 impl Store {
     pub async fn create_user(&self, request: CreateUserRequest) -> Result<User, UserError> {
-        if !request.email.contains('@') {
+        if !is_valid_email(&request.email) {
             return Err(UserError::InvalidInput("Invalid email format".to_string()));
         }
 
-        if request.password.len() < 6 {
-            return Err(UserError::InvalidInput(
-                "Password must be at least 6 characters".to_string(),
-            ));
-        }
+        validate_password(&request.password)?;
 
         let existing_user = self.get_user_by_email(&request.email).await?;
 
@@ -57,7 +80,7 @@ impl Store {
             r#"
             INSERT INTO users (email, password_hash, public_key)
             VALUES ($1, $2, $3)
-            RETURNING id, email, password_hash, public_key, created_at, updated_at
+            RETURNING id, email, password_hash, public_key, email_verified_at, created_at, updated_at
             "#,
             request.email,
             password_hash,
@@ -74,7 +97,7 @@ impl Store {
         let user = sqlx::query_as!(
             User,
             r#"
-            SELECT id, email, password_hash, public_key, created_at, updated_at
+            SELECT id, email, password_hash, public_key, email_verified_at, created_at, updated_at
             FROM users
             WHERE email = $1
             "#,
@@ -91,7 +114,7 @@ impl Store {
         let user = sqlx::query_as!(
             User,
             r#"
-            SELECT id, email, password_hash, public_key, created_at, updated_at
+            SELECT id, email, password_hash, public_key, email_verified_at, created_at, updated_at
             FROM users
             WHERE id = $1
             "#,
@@ -108,7 +131,7 @@ impl Store {
         let user = sqlx::query_as!(
             User,
             r#"
-            SELECT id, email, password_hash, public_key, created_at, updated_at
+            SELECT id, email, password_hash, public_key, email_verified_at, created_at, updated_at
             FROM users
             WHERE public_key = $1
             "#,
@@ -120,4 +143,45 @@ impl Store {
 
         Ok(user)
     }
+
+    /// Resolves user ids for many public keys in one round trip. Keys with no
+    /// user are left out of the result.
+    pub async fn get_user_ids_by_public_keys(
+        &self,
+        public_keys: &[String],
+    ) -> Result<Vec<(String, Uuid)>, UserError> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, public_key
+            FROM users
+            WHERE public_key = ANY($1)
+            "#,
+            public_keys
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| UserError::DatabaseError(e.to_string()))?;
+
+        Ok(rows.into_iter().map(|row| (row.public_key, row.id)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_valid_email;
+
+    #[test]
+    fn test_is_valid_email() {
+        assert!(is_valid_email("alice@example.com"));
+        assert!(is_valid_email("a.b+tag@mail.example.co.uk"));
+
+        assert!(!is_valid_email("alice"));
+        assert!(!is_valid_email("@example.com"));
+        assert!(!is_valid_email("alice@localhost"));
+        assert!(!is_valid_email("alice@@example.com"));
+        assert!(!is_valid_email("alice@example..com"));
+        assert!(!is_valid_email("alice@-example.com"));
+        assert!(!is_valid_email("al ice@example.com"));
+        assert!(!is_valid_email(&format!("{}@example.com", "a".repeat(65))));
+    }
 }
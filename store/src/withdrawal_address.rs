@@ -0,0 +1,313 @@
+use crate::models::withdrawal_address::{WithdrawalAddress, WithdrawalSettings};
+use crate::Store;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct NewWithdrawalAddress {
+    pub user_id: Uuid,
+    pub address: String,
+    pub label: String,
+    pub mint: Option<String>,
+    pub confirmation_token_hash: String,
+    pub confirmation_expires_at: DateTime<Utc>,
+    pub activates_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub enum WithdrawalAddressError {
+    NotFound,
+    AlreadyExists,
+    /// Unknown, expired or already used confirmation token.
+    InvalidToken,
+    DatabaseError(String),
+}
+
+impl std::fmt::Display for WithdrawalAddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WithdrawalAddressError::NotFound => write!(f, "Withdrawal address not found"),
+            WithdrawalAddressError::AlreadyExists => write!(f, "Withdrawal address already exists"),
+            WithdrawalAddressError::InvalidToken => write!(f, "Invalid or expired confirmation token"),
+            WithdrawalAddressError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for WithdrawalAddressError {}
+
+impl Store {
+    pub async fn add_withdrawal_address(
+        &self,
+        new_address: NewWithdrawalAddress,
+    ) -> Result<WithdrawalAddress, WithdrawalAddressError> {
+        let address = sqlx::query_as!(
+            WithdrawalAddress,
+            r#"
+            INSERT INTO withdrawal_addresses
+                (user_id, address, label, mint, confirmation_token_hash,
+                 confirmation_expires_at, activates_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, user_id, address, label, mint, confirmed_at, activates_at, created_at
+            "#,
+            new_address.user_id,
+            new_address.address,
+            new_address.label,
+            new_address.mint,
+            new_address.confirmation_token_hash,
+            new_address.confirmation_expires_at,
+            new_address.activates_at
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(db) if db.is_unique_violation() => WithdrawalAddressError::AlreadyExists,
+            e => WithdrawalAddressError::DatabaseError(e.to_string()),
+        })?;
+
+        Ok(address)
+    }
+
+    pub async fn list_withdrawal_addresses(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<WithdrawalAddress>, WithdrawalAddressError> {
+        let addresses = sqlx::query_as!(
+            WithdrawalAddress,
+            r#"
+            SELECT id, user_id, address, label, mint, confirmed_at, activates_at, created_at
+            FROM withdrawal_addresses
+            WHERE user_id = $1
+            ORDER BY created_at
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| WithdrawalAddressError::DatabaseError(e.to_string()))?;
+
+        Ok(addresses)
+    }
+
+    /// Confirms the address the token was issued for. The token is cleared so
+    /// it cannot be used again.
+    pub async fn confirm_withdrawal_address(
+        &self,
+        confirmation_token_hash: &str,
+    ) -> Result<WithdrawalAddress, WithdrawalAddressError> {
+        let address = sqlx::query_as!(
+            WithdrawalAddress,
+            r#"
+            UPDATE withdrawal_addresses
+            SET confirmed_at = NOW(), confirmation_token_hash = NULL
+            WHERE confirmation_token_hash = $1
+              AND confirmed_at IS NULL
+              AND confirmation_expires_at > NOW()
+            RETURNING id, user_id, address, label, mint, confirmed_at, activates_at, created_at
+            "#,
+            confirmation_token_hash
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| WithdrawalAddressError::DatabaseError(e.to_string()))?;
+
+        address.ok_or(WithdrawalAddressError::InvalidToken)
+    }
+
+    pub async fn remove_withdrawal_address(&self, user_id: Uuid, id: Uuid) -> Result<(), WithdrawalAddressError> {
+        let result = sqlx::query!(
+            "DELETE FROM withdrawal_addresses WHERE id = $1 AND user_id = $2",
+            id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| WithdrawalAddressError::DatabaseError(e.to_string()))?;
+
+        if result.rows_affected() == 0 {
+            return Err(WithdrawalAddressError::NotFound);
+        }
+        Ok(())
+    }
+
+    pub async fn get_withdrawal_settings(&self, user_id: Uuid) -> Result<WithdrawalSettings, WithdrawalAddressError> {
+        let settings = sqlx::query_as!(
+            WithdrawalSettings,
+            r#"
+            SELECT withdrawal_allowlist_strict AS strict, withdrawal_allowlist_relaxes_at AS relaxes_at
+            FROM users
+            WHERE id = $1
+            "#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| WithdrawalAddressError::DatabaseError(e.to_string()))?;
+
+        settings.ok_or(WithdrawalAddressError::NotFound)
+    }
+
+    /// Turns strict mode on right away, cancelling any pending switch-off.
+    pub async fn enable_withdrawal_strict_mode(&self, user_id: Uuid) -> Result<(), WithdrawalAddressError> {
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET withdrawal_allowlist_strict = TRUE,
+                withdrawal_allowlist_relaxes_at = NULL,
+                updated_at = NOW()
+            WHERE id = $1
+            "#,
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| WithdrawalAddressError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Schedules strict mode to turn off at `relaxes_at`. Asking again does
+    /// not push back a switch-off already scheduled.
+    pub async fn relax_withdrawal_strict_mode(
+        &self,
+        user_id: Uuid,
+        relaxes_at: DateTime<Utc>,
+    ) -> Result<WithdrawalSettings, WithdrawalAddressError> {
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET withdrawal_allowlist_relaxes_at = COALESCE(withdrawal_allowlist_relaxes_at, $2),
+                updated_at = NOW()
+            WHERE id = $1 AND withdrawal_allowlist_strict
+            "#,
+            user_id,
+            relaxes_at
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| WithdrawalAddressError::DatabaseError(e.to_string()))?;
+
+        self.get_withdrawal_settings(user_id).await
+    }
+
+    /// Whether the user may send `mint` to `address`: always outside strict
+    /// mode, otherwise only to an active allowlist entry for that mint or for
+    /// any mint. Strict mode holds until a scheduled switch-off is due.
+    pub async fn is_withdrawal_allowed(
+        &self,
+        user_id: Uuid,
+        address: &str,
+        mint: &str,
+    ) -> Result<bool, WithdrawalAddressError> {
+        let allowed = sqlx::query_scalar!(
+            r#"
+            SELECT (
+                NOT u.withdrawal_allowlist_strict
+                OR u.withdrawal_allowlist_relaxes_at <= NOW()
+                OR EXISTS (
+                    SELECT 1 FROM withdrawal_addresses w
+                    WHERE w.user_id = u.id
+                      AND w.address = $2
+                      AND (w.mint IS NULL OR w.mint = $3)
+                      AND w.confirmed_at IS NOT NULL
+                      AND w.activates_at <= NOW()
+                )
+            ) AS "allowed!"
+            FROM users u
+            WHERE u.id = $1
+            "#,
+            user_id,
+            address,
+            mint
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| WithdrawalAddressError::DatabaseError(e.to_string()))?;
+
+        allowed.ok_or(WithdrawalAddressError::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::new_user;
+    use chrono::Duration;
+    use sqlx::PgPool;
+
+    const ADDRESS: &str = "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin";
+    const MINT: &str = "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v";
+
+    async fn add_address(store: &Store, user_id: Uuid, mint: Option<&str>, activates_at: DateTime<Utc>) {
+        store
+            .add_withdrawal_address(NewWithdrawalAddress {
+                user_id,
+                address: ADDRESS.to_string(),
+                label: "cold wallet".to_string(),
+                mint: mint.map(str::to_string),
+                confirmation_token_hash: "hash".to_string(),
+                confirmation_expires_at: Utc::now() + Duration::hours(1),
+                activates_at,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn test_strict_mode_only_allows_active_addresses(pool: PgPool) {
+        let store = Store::new(pool);
+        let user_id = new_user(&store).await;
+        assert!(store.is_withdrawal_allowed(user_id, ADDRESS, MINT).await.unwrap());
+
+        store.enable_withdrawal_strict_mode(user_id).await.unwrap();
+        assert!(!store.is_withdrawal_allowed(user_id, ADDRESS, MINT).await.unwrap());
+
+        // Unconfirmed, then confirmed but cooling off
+        add_address(&store, user_id, Some(MINT), Utc::now() + Duration::hours(1)).await;
+        assert!(!store.is_withdrawal_allowed(user_id, ADDRESS, MINT).await.unwrap());
+        store.confirm_withdrawal_address("hash").await.unwrap();
+        assert!(!store.is_withdrawal_allowed(user_id, ADDRESS, MINT).await.unwrap());
+
+        sqlx::query!("UPDATE withdrawal_addresses SET activates_at = NOW() WHERE user_id = $1", user_id)
+            .execute(&store.pool)
+            .await
+            .unwrap();
+        assert!(store.is_withdrawal_allowed(user_id, ADDRESS, MINT).await.unwrap());
+        // The entry is for one mint only
+        assert!(!store.is_withdrawal_allowed(user_id, ADDRESS, crate::solana::SOL_MINT).await.unwrap());
+    }
+
+    #[sqlx::test]
+    async fn test_turning_strict_mode_off_waits_for_cooling_off(pool: PgPool) {
+        let store = Store::new(pool);
+        let user_id = new_user(&store).await;
+        store.enable_withdrawal_strict_mode(user_id).await.unwrap();
+
+        let relaxes_at = Utc::now() + Duration::hours(24);
+        let settings = store.relax_withdrawal_strict_mode(user_id, relaxes_at).await.unwrap();
+        assert!(settings.is_strict(Utc::now()));
+        assert!(!store.is_withdrawal_allowed(user_id, ADDRESS, MINT).await.unwrap());
+
+        // Asking again does not move the switch-off
+        let again = store
+            .relax_withdrawal_strict_mode(user_id, relaxes_at + Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(again.relaxes_at, settings.relaxes_at);
+
+        sqlx::query!(
+            "UPDATE users SET withdrawal_allowlist_relaxes_at = NOW() WHERE id = $1",
+            user_id
+        )
+        .execute(&store.pool)
+        .await
+        .unwrap();
+        assert!(store.is_withdrawal_allowed(user_id, ADDRESS, MINT).await.unwrap());
+
+        // Turning it back on is immediate and cancels the switch-off
+        store.enable_withdrawal_strict_mode(user_id).await.unwrap();
+        let settings = store.get_withdrawal_settings(user_id).await.unwrap();
+        assert!(settings.is_strict(Utc::now()));
+        assert_eq!(settings.relaxes_at, None);
+    }
+}
@@ -0,0 +1,73 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    Error,
+};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+use std::time::Instant;
+
+/// Request count and latency of one HTTP service.
+pub struct HttpMetrics {
+    requests: IntCounterVec,
+    duration: HistogramVec,
+}
+
+impl HttpMetrics {
+    /// Registers `<prefix>_http_requests_total` and
+    /// `<prefix>_http_request_duration_seconds`.
+    pub fn register(prefix: &str) -> Self {
+        let requests = register_int_counter_vec!(
+            format!("{}_http_requests_total", prefix),
+            "HTTP requests by route, method and status",
+            &["route", "method", "status"]
+        )
+        .unwrap();
+        let duration = register_histogram_vec!(
+            format!("{}_http_request_duration_seconds", prefix),
+            "HTTP request latency by route and method",
+            &["route", "method"]
+        )
+        .unwrap();
+        Self { requests, duration }
+    }
+
+    /// Records count and latency of a request, labelled with the matched
+    /// route pattern so path parameters do not blow up cardinality.
+    pub async fn track<B: MessageBody>(
+        &self,
+        req: ServiceRequest,
+        next: Next<B>,
+    ) -> Result<ServiceResponse<B>, Error> {
+        let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+        let method = req.method().to_string();
+        let started = Instant::now();
+
+        let res = next.call(req).await;
+
+        let status = match &res {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        self.requests
+            .with_label_values(&[route.as_str(), method.as_str(), status.as_str()])
+            .inc();
+        self.duration
+            .with_label_values(&[route.as_str(), method.as_str()])
+            .observe(started.elapsed().as_secs_f64());
+
+        res
+    }
+}
+
+/// Renders every registered metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}